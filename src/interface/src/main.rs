@@ -1,7 +1,8 @@
 // Copyright 2022 Garry Xu
 // SPDX-License-Identifier: Apache-2.0
 
-use vmm::config::VmConfig;
+use std::sync::atomic::{AtomicBool, Ordering};
+use vmm::{ExitReason, Vmm, config::{ConfigMode, VmConfig}, serial::TerminalGuard};
 
 /// Exit codes returned by the executable.
 /// Refers to: https://tldp.org/LDP/abs/html/exitcodes.html
@@ -11,13 +12,44 @@ enum ExitCode {
     Ok = 0,
     /// Error coccurs and the error message should be checked.
     GeneralError = 1,
+    /// The guest rebooted.
+    GuestReboot = 64,
+    /// The guest reported a crash.
+    GuestCrash = 65,
+    /// A vcpu failed and the VM had to exit.
+    VcpuFailure = 66,
+    /// The VM was killed, e.g. the guest didn't respond to the power button.
+    Killed = 67,
+}
+
+impl From<ExitReason> for ExitCode {
+    fn from(reason: ExitReason) -> Self {
+        match reason {
+            ExitReason::Poweroff => ExitCode::Ok,
+            ExitReason::Reboot => ExitCode::GuestReboot,
+            ExitReason::Crash => ExitCode::GuestCrash,
+            ExitReason::VcpuFailure(..) => ExitCode::VcpuFailure,
+            ExitReason::Killed => ExitCode::Killed,
+        }
+    }
+}
+
+/// Set when the hypervisor is asked to stop by a signal.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// Handler for SIGINT and SIGTERM, which shut down the VM gracefully.
+extern "C" fn handle_stop(_: libc::c_int) {
+    STOP.store(true, Ordering::Relaxed);
 }
 
 /// Help message for the executable.
+#[allow(clippy::print_with_newline)]
 fn usage() {
     print!("ShuaiRan v{}\n", env!("CARGO_PKG_VERSION"));
     print!("Usage:\n");
     print!("./shuairan <config>     Start a vm with the given config file.\n");
+    print!("./shuairan --lenient <config>\n");
+    print!("                        Start a vm, ignoring unknown keys in the config file.\n");
 }
 
 /// The entry point function for the hypervisor.  
@@ -26,24 +58,47 @@ fn usage() {
 /// 
 /// # Arguments
 /// * `path`: Path to a VM's configuration file.
-fn vmm_entry(path: &str) -> ExitCode {
+/// * `mode`: How unknown keys in the configuration file are treated.
+fn vmm_entry(path: &str, mode: ConfigMode) -> ExitCode {
     // Convert JSON config to VmConfig 
-    match VmConfig::from_file(path) {
-        Ok(config) => {
-            println!("{:?}", config);
-            ExitCode::Ok
+    let result = VmConfig::from_file_with_mode(path, mode)
+        .and_then(Vmm::new)
+        .and_then(|mut vmm| {
+            if let Some(path) = vmm.serial().pty() {
+                eprintln!("Serial console is at {}", path);
+            }
+            // Safe because the handler only stores an atomic flag.
+            unsafe {
+                libc::signal(libc::SIGINT, handle_stop as *const () as libc::sighandler_t);
+                libc::signal(libc::SIGTERM, handle_stop as *const () as libc::sighandler_t);
+            }
+            vmm.run(&STOP)
+        });
+    match result {
+        Ok(reason) => {
+            println!("VM exited, reason={:?}", reason);
+            reason.into()
         },
-        _ => ExitCode::GeneralError
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::GeneralError
+        }
     }
 }
 
 fn main() {
     let args = std::env::args().collect::<Vec<String>>();
-    let code = if args.len() != 2 {
-        usage();
-        ExitCode::GeneralError
-    } else {
-        vmm_entry(&args[1])
+    // The serial console may put the terminal in raw mode, restore it
+    // before exiting since the vcpu threads may still hold the console.
+    let terminal = TerminalGuard::new();
+    let code = match &args[1..] {
+        [path] => vmm_entry(path, ConfigMode::Strict),
+        [flag, path] if flag == "--lenient" => vmm_entry(path, ConfigMode::Lenient),
+        _ => {
+            usage();
+            ExitCode::GeneralError
+        },
     };
+    drop(terminal);
     std::process::exit(code as i32);
 }
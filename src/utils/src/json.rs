@@ -3,14 +3,11 @@
 
 use nom::{
     branch::alt,
-    bytes::complete::{escaped, tag, take_till1},
-    character::complete::multispace0,
-    character::complete::one_of,
-    combinator::map,
-    error::ParseError,
-    multi::separated_list0,
-    number::complete::double,
-    sequence::{delimited, separated_pair},
+    bytes::complete::tag,
+    character::complete::{char, digit0, digit1, multispace0, one_of},
+    combinator::{cut, map, map_res, opt, recognize},
+    error::{context, ContextError, FromExternalError, ParseError},
+    sequence::{pair, preceded, terminated, tuple},
     Err as NomErr, IResult,
 };
 use std::collections::HashMap;
@@ -18,14 +15,18 @@ use std::fmt;
 use std::fs;
 use std::str::FromStr;
 
-type Result<I, O> = IResult<I, O, Error>;
+type Result<I, O> = IResult<I, O, Located<I>>;
+
+/// Arrays and objects nested deeper are rejected rather than overflowing
+/// the stack.
+const MAX_DEPTH: usize = 128;
 
 /// Errors associated with parsing JSON strings or files.
 #[derive(Debug, PartialEq)]
 pub enum Error {
     /// Errors generated during parsing string slice to a specific JSON value.
-    /// More informance can be retrieved from the ErrorKind field.
-    ParsingError(ErrorKind),
+    /// More informance can be retrieved from the SyntaxError field.
+    ParsingError(SyntaxError),
     /// Errors generated during file operations.
     IOError(String),
 }
@@ -35,7 +36,7 @@ impl fmt::Display for Error {
         use Error::*;
 
         match self {
-            ParsingError(kind) => write!(f, "{}", kind),
+            ParsingError(e) => write!(f, "{}", e),
             IOError(s) => write!(
                 f,
                 "An I/O error occurs during loading the JSON file, error={}.",
@@ -45,10 +46,73 @@ impl fmt::Display for Error {
     }
 }
 
-impl<I> ParseError<I> for Error {
-    // Convert a nom::error::Error to a Error::ParseError
-    fn from_error_kind(_: I, _: nom::error::ErrorKind) -> Self {
-        Self::ParsingError(ErrorKind::Other)
+impl From<std::io::Error> for Error {
+    // Convert a io::Error to json::Error
+    fn from(e: std::io::Error) -> Self {
+        Error::IOError(e.to_string())
+    }
+}
+
+/// Where and why a JSON text can't be parsed.
+#[derive(Debug, PartialEq)]
+pub struct SyntaxError {
+    pub kind: ErrorKind,
+    /// Byte offset of the failure in the text.
+    pub offset: usize,
+    /// Line of the failure, starting from 1.
+    pub line: usize,
+    /// Column of the failure in characters, starting from 1.
+    pub column: usize,
+    /// What was expected at the failure, e.g. `` `}` `` or `a value`.
+    pub expected: Option<&'static str>,
+    /// The line of the failure with a caret under the column.
+    pub excerpt: String,
+}
+
+impl SyntaxError {
+    /// Locate the failure at `offset` of `text`.
+    fn new(text: &str, offset: usize, kind: ErrorKind, expected: Option<&'static str>) -> Self {
+        let start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let end = text[offset..].find('\n').map_or(text.len(), |i| offset + i);
+        let line = text[start..end].trim_end_matches('\r');
+        // Keep tabs so that the caret lines up with the column.
+        let indent = text[start..offset]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        SyntaxError {
+            kind,
+            offset,
+            line: text[..offset].matches('\n').count() + 1,
+            column: text[start..offset].chars().count() + 1,
+            expected,
+            excerpt: format!("{}\n{}^", line, indent),
+        }
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at line {}, column {}", self.kind, self.line, self.column)?;
+        if let Some(expected) = self.expected {
+            write!(f, ", expected {}", expected)?;
+        }
+        write!(f, ":\n{}", self.excerpt)
+    }
+}
+
+/// Errors of the parsers, located by the input left at the failure.
+#[derive(Debug, PartialEq)]
+struct Located<I> {
+    input: I,
+    kind: ErrorKind,
+    expected: Option<&'static str>,
+}
+
+impl<I> ParseError<I> for Located<I> {
+    // Convert a nom::error::Error to a Located
+    fn from_error_kind(input: I, _: nom::error::ErrorKind) -> Self {
+        Located { input, kind: ErrorKind::Other, expected: None }
     }
 
     fn append(_: I, _: nom::error::ErrorKind, other: Self) -> Self {
@@ -56,10 +120,17 @@ impl<I> ParseError<I> for Error {
     }
 }
 
-impl From<std::io::Error> for Error {
-    // Convert a io::Error to json::Error
-    fn from(e: std::io::Error) -> Self {
-        Error::IOError(e.to_string())
+impl<I, E> FromExternalError<I, E> for Located<I> {
+    fn from_external_error(input: I, _: nom::error::ErrorKind, _: E) -> Self {
+        Located { input, kind: ErrorKind::Other, expected: None }
+    }
+}
+
+impl<I> ContextError<I> for Located<I> {
+    // The innermost context is the most precise one.
+    fn add_context(_: I, ctx: &'static str, mut other: Self) -> Self {
+        other.expected.get_or_insert(ctx);
+        other
     }
 }
 
@@ -71,6 +142,13 @@ pub enum ErrorKind {
     InvalidString,
     InvalidArray,
     InvalidObject,
+    InvalidValue,
+    /// Something other than whitespaces follows the JSON value.
+    TrailingCharacters,
+    /// A key appears twice in an object, see `DuplicateKeys`.
+    DuplicateKey,
+    /// Arrays and objects are nested deeper than `MAX_DEPTH`.
+    TooDeep,
     Other,
 }
 
@@ -103,6 +181,23 @@ impl fmt::Display for ErrorKind {
                 f,
                 "The given input can't be parsed into a valid JSON object"
             ),
+            InvalidValue => write!(
+                f,
+                "The given input can't be parsed into a valid JSON value"
+            ),
+            TrailingCharacters => write!(
+                f,
+                "The given input has characters after the JSON value"
+            ),
+            DuplicateKey => write!(
+                f,
+                "The given JSON object has a duplicate key"
+            ),
+            TooDeep => write!(
+                f,
+                "The given input nests arrays and objects deeper than {} levels",
+                MAX_DEPTH
+            ),
             _ => write!(f, "An internal error occurs."),
         }
     }
@@ -119,47 +214,68 @@ pub enum Json {
     Object(HashMap<String, Json>),
 }
 
+/// What to do with a key appearing more than once in an object, which
+/// RFC 8259 leaves to the implementation.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum DuplicateKeys {
+    /// Fail the parsing, since either value may have been meant.
+    #[default]
+    Reject,
+    /// Keep the last value, as most JSON parsers do.
+    KeepLast,
+}
+
 impl FromStr for Json {
     type Err = Error;
 
-    // Generate a json object from the string slice.
+    // Generate a json value from the string slice, duplicate keys are
+    // rejected.
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        Ok(
-            delimited(
-                multispace0, 
-                Self::object, 
-                multispace0
-            )(s).map_err(
-                |e: NomErr<Error>| match e {
-                    // Unwrap the nom::error::Error
-                    NomErr::Error(v) => v,
-                    _ => Error::ParsingError(ErrorKind::InvalidObject)
-                }
-            )?.1
-        )
+        Self::parse(s, DuplicateKeys::default())
     }
 }
-
 /// Map a nom error to corresponding ErrorKind
 macro_rules! map_err {
     ($result:expr, $kind:expr) => {
-        $result.map_err(|e: NomErr<Error>| match e {
-            NomErr::Error(Error::ParsingError(ErrorKind::Other)) => {
-                NomErr::Error(Error::ParsingError($kind))
-            }
+        $result.map_err(|e: NomErr<Located<&str>>| e.map(|e| match e.kind {
+            ErrorKind::Other => Located { kind: $kind, ..e },
             _ => e,
-        })
+        }))
     };
 }
 
 impl Json {
-    /// Generate a json object by reading provided file.
+    /// Generate a json value by reading provided file.
+    #[allow(clippy::needless_question_mark)]
     pub fn from_file(path: &str) -> std::result::Result<Self, Error> {
         Ok(fs::read_to_string(path)?.parse::<Json>()?)
     }
 
+    /// Generate a json value from the string slice, which is a JSON text
+    /// as of RFC 8259, with `keys` deciding on duplicate keys.
+    pub fn parse(s: &str, keys: DuplicateKeys) -> std::result::Result<Self, Error> {
+        let result = terminated(
+            preceded(multispace0, |s| Self::value(s, keys, 0)),
+            multispace0
+        )(s);
+        match result {
+            Ok(("", json)) => Ok(json),
+            Ok((rest, _)) => Err(Error::ParsingError(SyntaxError::new(
+                s, s.len() - rest.len(), ErrorKind::TrailingCharacters, Some("the end")
+            ))),
+            Err(NomErr::Error(e)) | Err(NomErr::Failure(e)) => {
+                Err(Error::ParsingError(
+                    SyntaxError::new(s, s.len() - e.input.len(), e.kind, e.expected)
+                ))
+            },
+            Err(NomErr::Incomplete(_)) => Err(Error::ParsingError(
+                SyntaxError::new(s, s.len(), ErrorKind::Other, None)
+            )),
+        }
+    }
+
     /// Take a JSON value from a JSON object.
-    fn take(&mut self, key: &str) -> Option<Json> {
+    pub fn take(&mut self, key: &str) -> Option<Json> {
         match self {
             Json::Object(map) => map.remove(key),
             _ => None
@@ -173,6 +289,14 @@ impl Json {
         }
     }
 
+    /// Take a JSON value and convert it into a boolean.
+    pub fn take_boolean(&mut self, key: &str) -> Option<bool> {
+        match self.take(key) {
+            Some(Json::Boolean(v)) => Some(v),
+            _ => None
+        }
+    }
+
     /// Take a JSON value and convert it into a string.
     pub fn take_string(&mut self, key: &str) -> Option<String> {
         match self.take(key) {
@@ -216,95 +340,180 @@ impl Json {
     /// Parse a JSON number.
     fn number(s: &str) -> Result<&str, Json> {
         map_err!(
-            double(s).map(|(s, v)| (s, Json::Number(v))),
+            map_res(
+                recognize(tuple((
+                    opt(char('-')),
+                    alt((tag("0"), recognize(pair(one_of("123456789"), digit0)))),
+                    opt(pair(char('.'), cut(context("a digit", digit1)))),
+                    opt(tuple((
+                        one_of("eE"),
+                        opt(one_of("+-")),
+                        cut(context("a digit", digit1))
+                    ))),
+                ))),
+                |n: &str| n.parse::<f64>().map(Json::Number)
+            )(s),
             ErrorKind::InvalidNumber
         )
     }
 
-    /// Parse a JSON string value.
-    fn string(s: &str) -> Result<&str, &str> {
-        map_err!(
-            delimited(
-                tag("\""),
-                escaped(
-                    take_till1(
-                        |c: char| c == '\\' || c == '\"' || c.is_ascii_control()
-                    ),
-                    '\\',
-                    one_of(r#""\/bfnrtu"#),
-                ),
-                tag("\""),
-            )(s),
-            ErrorKind::InvalidString
-        )
+    /// Parse a JSON string value and decode its escapes, it fails without
+    /// backtracking after the opening quote.
+    fn string(s: &str) -> Result<&str, String> {
+        let (mut s, _) = map_err!(tag("\"")(s), ErrorKind::InvalidString)?;
+        let mut value = String::new();
+        loop {
+            let end = s.find(|c| c == '"' || c == '\\' || c < ' ').unwrap_or(s.len());
+            value.push_str(&s[..end]);
+            s = &s[end..];
+            match s.chars().next() {
+                Some('"') => return Ok((&s[1..], value)),
+                Some('\\') => {
+                    let (rest, c) = Self::escape(s)?;
+                    value.push(c);
+                    s = rest;
+                },
+                // Control characters must be escaped.
+                _ => return Err(NomErr::Failure(Located {
+                    input: s,
+                    kind: ErrorKind::InvalidString,
+                    expected: Some("`\"`"),
+                })),
+            }
+        }
     }
 
-    /// Parse a JSON array.
-    fn array(s: &str) -> Result<&str, Json> {
+    /// Decode the escape sequence at the backslash, a UTF-16 surrogate
+    /// pair makes a single character.
+    fn escape(s: &str) -> Result<&str, char> {
+        let fail = |expected| NomErr::Failure(Located {
+            input: s,
+            kind: ErrorKind::InvalidString,
+            expected: Some(expected),
+        });
+        let c = match s[1..].chars().next() {
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some('/') => '/',
+            Some('b') => '\u{8}',
+            Some('f') => '\u{c}',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('u') => {
+                let (rest, high) = Self::hex(&s[2..]).ok_or_else(|| fail("4 hex digits"))?;
+                if !(0xd800..0xdc00).contains(&high) {
+                    let c = char::from_u32(high).ok_or_else(|| fail("a high surrogate"))?;
+                    return Ok((rest, c));
+                }
+                let (rest, low) = rest.strip_prefix("\\u")
+                    .and_then(Self::hex)
+                    .filter(|(_, low)| (0xdc00..0xe000).contains(low))
+                    .ok_or_else(|| fail("a surrogate pair"))?;
+                let c = 0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00);
+                return Ok((rest, char::from_u32(c).unwrap()));
+            },
+            _ => return Err(fail("an escape, e.g. `\\n` or `\\u00e9`")),
+        };
+        Ok((&s[1 + c.len_utf8()..], c))
+    }
+
+    /// Decode the 4 hex digits of a `\u` escape.
+    fn hex(s: &str) -> Option<(&str, u32)> {
+        s.get(..4)
+            .filter(|h| h.chars().all(|c| c.is_ascii_hexdigit()))
+            .and_then(|h| u32::from_str_radix(h, 16).ok())
+            .map(|u| (&s[4..], u))
+    }
+
+    /// Parse items between `open` and `close` separated by commas at
+    /// `depth` of nesting, it fails without backtracking after `open`.
+    /// `expected` describes what may follow an item.
+    fn sequence<'a, T>(
+        s: &'a str,
+        open: &'static str,
+        close: &'static str,
+        expected: &'static str,
+        depth: usize,
+        item: impl Fn(&'a str) -> Result<&'a str, T>,
+    ) -> Result<&'a str, Vec<T>> {
+        let (rest, _) = terminated(tag(open), multispace0)(s)?;
+        if depth >= MAX_DEPTH {
+            return Err(NomErr::Failure(Located { input: s, kind: ErrorKind::TooDeep, expected: None }));
+        }
+        let mut s = rest;
+        let mut items = Vec::new();
+        if let Ok((s, _)) = tag::<_, _, Located<&str>>(close)(s) {
+            return Ok((s, items));
+        }
+        loop {
+            let (rest, value) = cut(terminated(&item, multispace0))(s)?;
+            items.push(value);
+            let (rest, sep) = cut(context(expected, alt((tag(","), tag(close)))))(rest)?;
+            if sep == close {
+                return Ok((rest, items));
+            }
+            s = multispace0(rest)?.0;
+        }
+    }
+
+    /// Parse a JSON array at `depth` of nesting.
+    fn array(s: &str, keys: DuplicateKeys, depth: usize) -> Result<&str, Json> {
         map_err!(
-            delimited(
-                tag("["),
-                separated_list0(
-                    tag(","), 
-                    delimited(multispace0, Self::value, multispace0)
-                ),
-                tag("]"),
-            )(s)
-            .map(|(s, v)| (s, Json::Array(v))),
+            Self::sequence(s, "[", "]", "`,` or `]`", depth, |s| Self::value(s, keys, depth + 1))
+                .map(|(s, v)| (s, Json::Array(v))),
             ErrorKind::InvalidArray
         )
     }
 
-    /// Parse a JSON object.
-    fn object(s: &str) -> Result<&str, Json> {
-        map_err!(
-            map(
-                delimited(
-                    tag("{"),
-                    separated_list0(
-                        tag(","),
-                        delimited(
-                            multispace0,
-                            separated_pair(
-                                delimited(
-                                    multispace0, 
-                                    Self::string, 
-                                    multispace0
-                                ),
-                                tag(":"),
-                                delimited(
-                                    multispace0, 
-                                    Self::value, 
-                                    multispace0
-                                ),
-                            ),
-                            multispace0,
-                        ),
-                    ),
-                    tag("}"),
-                ),
-                |vec: Vec<(&str, Json)>| {
-                    Json::Object(
-                        vec.into_iter().map(
-                            |(s, v)| (s.to_string(), v)
-                        ).collect()
-                    )
-                },
-            )(s),
+    /// Parse a member of a JSON object at `depth` of nesting, along with
+    /// where its key is.
+    fn member(s: &str, keys: DuplicateKeys, depth: usize) -> Result<&str, (&str, String, Json)> {
+        let (rest, key) = context("a string", Self::string)(s)?;
+        let (rest, _) = cut(preceded(multispace0, context("`:`", tag(":"))))(rest)?;
+        let (rest, value) = cut(preceded(multispace0, |s| Self::value(s, keys, depth)))(rest)?;
+        Ok((rest, (s, key, value)))
+    }
+
+    /// Parse a JSON object at `depth` of nesting.
+    fn object(s: &str, keys: DuplicateKeys, depth: usize) -> Result<&str, Json> {
+        let member = |s| Self::member(s, keys, depth + 1);
+        let (s, members) = map_err!(
+            context("`{`", |s| Self::sequence(s, "{", "}", "`,` or `}`", depth, member))(s),
             ErrorKind::InvalidObject
-        )
+        )?;
+        let mut map = HashMap::new();
+        for (at, key, value) in members {
+            if map.insert(key, value).is_some() && keys == DuplicateKeys::Reject {
+                return Err(NomErr::Failure(Located {
+                    input: at,
+                    kind: ErrorKind::DuplicateKey,
+                    expected: None,
+                }));
+            }
+        }
+        Ok((s, Json::Object(map)))
     }
 
-    /// Parse a JSON value
-    fn value(s: &str) -> Result<&str, Json> {
-        alt((
+    /// Parse a JSON value at `depth` of nesting.
+    fn value(s: &str, keys: DuplicateKeys, depth: usize) -> Result<&str, Json> {
+        let result = alt((
             Self::null,
             Self::boolean,
             Self::number,
-            map(Self::string, |v: &str| Json::String(v.to_string())),
-            Self::array,
-            Self::object,
-        ))(s)
+            map(Self::string, Json::String),
+            |s| Self::array(s, keys, depth),
+            |s| Self::object(s, keys, depth),
+        ))(s);
+        // None of them starts here.
+        result.map_err(|e| match e {
+            NomErr::Error(_) => NomErr::Error(Located {
+                input: s,
+                kind: ErrorKind::InvalidValue,
+                expected: Some("a value"),
+            }),
+            e => e,
+        })
     }
 }
 #[test]
@@ -312,7 +521,11 @@ pub fn test_null() {
     assert_eq!(Json::null("null"), Ok(("", Json::Null)));
     assert_eq!(
         Json::null("NULL"),
-        Err(NomErr::Error(Error::ParsingError(ErrorKind::InvalidNull)))
+        Err(NomErr::Error(Located {
+            input: "NULL",
+            kind: ErrorKind::InvalidNull,
+            expected: None
+        }))
     );
 }
 
@@ -322,45 +535,95 @@ pub fn test_boolean() {
     assert_eq!(Json::boolean("false"), Ok(("", Json::Boolean(false))));
     assert_eq!(
         Json::boolean("True"),
-        Err(NomErr::Error(Error::ParsingError(
-            ErrorKind::InvalidBoolean
-        )))
+        Err(NomErr::Error(Located {
+            input: "True",
+            kind: ErrorKind::InvalidBoolean,
+            expected: None
+        }))
     );
 }
 
 #[test]
 pub fn test_number() {
     assert_eq!(Json::number("2.0"), Ok(("", Json::Number(2.0f64))));
-    assert_eq!(Json::number("2.#"), Ok(("#", Json::Number(2.0f64))));
+    assert_eq!(Json::number("-0.5e+2,"), Ok((",", Json::Number(-50f64))));
+    assert_eq!(Json::number("012"), Ok(("12", Json::Number(0f64))));
+    assert_eq!(
+        Json::number("2.#"),
+        Err(NomErr::Failure(Located {
+            input: "#",
+            kind: ErrorKind::InvalidNumber,
+            expected: Some("a digit")
+        }))
+    );
+    assert_eq!(
+        Json::number("inf"),
+        Err(NomErr::Error(Located {
+            input: "inf",
+            kind: ErrorKind::InvalidNumber,
+            expected: None
+        }))
+    );
     assert_eq!(
         Json::number("a2"),
-        Err(NomErr::Error(Error::ParsingError(ErrorKind::InvalidNumber)))
+        Err(NomErr::Error(Located {
+            input: "a2",
+            kind: ErrorKind::InvalidNumber,
+            expected: None
+        }))
     );
 }
 
 #[test]
 pub fn test_string() {
-    assert_eq!(Json::string(r#""abc""#), Ok(("", r#"abc"#)));
-    assert_eq!(Json::string(r#""abc02""#), Ok(("", r#"abc02"#)));
-    assert_eq!(Json::string(r#""abc_def""#), Ok(("", r#"abc_def"#)));
-    assert_eq!(Json::string(r#""abc_\bef""#), Ok(("", r#"abc_\bef"#)));
-    assert_eq!(Json::string(r#""abc_\nef""#), Ok(("", r#"abc_\nef"#)));
-    assert_eq!(Json::string(r#""abc_\"ef""#), Ok(("", r#"abc_\"ef"#)));
-    assert_eq!(Json::string(r#""a""#), Ok(("", r#"a"#)));
+    assert_eq!(Json::string(r#""abc""#), Ok(("", "abc".to_string())));
+    assert_eq!(Json::string(r#""abc02""#), Ok(("", "abc02".to_string())));
+    assert_eq!(Json::string(r#""abc_def""#), Ok(("", "abc_def".to_string())));
+    assert_eq!(Json::string(r#""abc_\bef""#), Ok(("", "abc_\u{8}ef".to_string())));
+    assert_eq!(Json::string(r#""abc_\nef""#), Ok(("", "abc_\nef".to_string())));
+    assert_eq!(Json::string(r#""abc_\"ef""#), Ok(("", "abc_\"ef".to_string())));
+    assert_eq!(Json::string(r#""\/\\\u00e9""#), Ok(("", "/\\é".to_string())));
+    assert_eq!(Json::string(r#""\ud83d\ude00""#), Ok(("", "😀".to_string())));
+    assert_eq!(Json::string(r#""a""#), Ok(("", "a".to_string())));
+    assert_eq!(Json::string(r#""""#), Ok(("", "".to_string())));
+    assert_eq!(
+        Json::string("\"a\tb\""),
+        Err(NomErr::Failure(Located {
+            input: "\tb\"",
+            kind: ErrorKind::InvalidString,
+            expected: Some("`\"`")
+        }))
+    );
+    assert_eq!(
+        Json::string(r#""a\ud83d""#),
+        Err(NomErr::Failure(Located {
+            input: r#"\ud83d""#,
+            kind: ErrorKind::InvalidString,
+            expected: Some("a surrogate pair")
+        }))
+    );
     assert_eq!(
-        Json::string(r#""""#),
-        Err(NomErr::Error(Error::ParsingError(ErrorKind::InvalidString)))
+        Json::string(r#""\x""#),
+        Err(NomErr::Failure(Located {
+            input: r#"\x""#,
+            kind: ErrorKind::InvalidString,
+            expected: Some("an escape, e.g. `\\n` or `\\u00e9`")
+        }))
     );
     assert_eq!(
         Json::string(r#"abc"#),
-        Err(NomErr::Error(Error::ParsingError(ErrorKind::InvalidString)))
+        Err(NomErr::Error(Located {
+            input: "abc",
+            kind: ErrorKind::InvalidString,
+            expected: None
+        }))
     );
 }
 
 #[test]
 pub fn test_array() {
     assert_eq!(
-        Json::array(r#"["abc", 234, true, null]"#),
+        Json::array(r#"["abc", 234, true, null]"#, DuplicateKeys::Reject, 0),
         Ok((
             "",
             Json::Array(vec![
@@ -372,12 +635,16 @@ pub fn test_array() {
         )),
     );
     assert_eq!(
-        Json::array(r#"[ "abc" ]"#),
+        Json::array(r#"[ "abc" ]"#, DuplicateKeys::Reject, 0),
         Ok(("", Json::Array(vec![Json::String("abc".to_string())]))),
     );
     assert_eq!(
-        Json::array(r#"["abc", 234, true, null, "bug":"bug"]"#),
-        Err(NomErr::Error(Error::ParsingError(ErrorKind::InvalidArray))),
+        Json::array(r#"["abc", 234, true, null, "bug":"bug"]"#, DuplicateKeys::Reject, 0),
+        Err(NomErr::Failure(Located {
+            input: r#":"bug"]"#,
+            kind: ErrorKind::InvalidArray,
+            expected: Some("`,` or `]`")
+        })),
     );
 }
 
@@ -404,7 +671,81 @@ pub fn test_object() {
     sub_map.insert("str".to_string(), Json::String("value".to_string()));
     map.insert("object".to_string(), Json::Object(sub_map));
 
-    assert_eq!(Json::object(ok_data), Ok(("", Json::Object(map))),);
+    assert_eq!(
+        Json::object(ok_data, DuplicateKeys::Reject, 0),
+        Ok(("", Json::Object(map))),
+    );
+
+    let duplicated = r#"{"a": 1, "b": 2, "a": 3}"#;
+    assert_eq!(
+        Json::object(duplicated, DuplicateKeys::Reject, 0),
+        Err(NomErr::Failure(Located {
+            input: r#""a": 3}"#,
+            kind: ErrorKind::DuplicateKey,
+            expected: None
+        })),
+    );
+    let (_, mut json) = Json::object(duplicated, DuplicateKeys::KeepLast, 0).unwrap();
+    assert_eq!(json.take_number("a"), Some(3f64));
+}
+
+#[test]
+pub fn test_syntax_error() {
+    let error = |s: &str| match Json::from_str(s) {
+        Err(Error::ParsingError(e)) => (e.kind, e.line, e.column, e.expected),
+        r => panic!("{:?}", r),
+    };
+    assert_eq!(
+        error(r#"{"a": 1,}"#),
+        (ErrorKind::InvalidString, 1, 9, Some("a string"))
+    );
+    assert_eq!(
+        error("{\n  \"a\": [1, 2,]\n}"),
+        (ErrorKind::InvalidValue, 2, 14, Some("a value"))
+    );
+    assert_eq!(
+        error("{\n  \"a\": {\"b\": true\n"),
+        (ErrorKind::InvalidObject, 3, 1, Some("`,` or `}`"))
+    );
+    assert_eq!(
+        error(r#"{"a" 1}"#),
+        (ErrorKind::InvalidObject, 1, 6, Some("`:`"))
+    );
+    assert_eq!(
+        error(r#"{"a": tru}"#),
+        (ErrorKind::InvalidValue, 1, 7, Some("a value"))
+    );
+    assert_eq!(
+        error(r#"{"é": "x}"#),
+        (ErrorKind::InvalidString, 1, 10, Some("`\"`"))
+    );
+    assert_eq!(error("[1] 2"), (ErrorKind::TrailingCharacters, 1, 5, Some("the end")));
+    assert_eq!(error(""), (ErrorKind::InvalidValue, 1, 1, Some("a value")));
+    assert_eq!(error(&"[".repeat(200)), (ErrorKind::TooDeep, 1, 129, None));
+    assert_eq!(Json::from_str("  [1]\n"), Ok(Json::Array(vec![Json::Number(1f64)])));
+    assert_eq!(Json::from_str(r#""\u00e9""#), Ok(Json::String("é".to_string())));
+
+    let e = Json::from_str("{\n\t\"cpu\": {\"count\": 2,}\r\n}").unwrap_err();
+    assert_eq!(
+        e,
+        Error::ParsingError(SyntaxError {
+            kind: ErrorKind::InvalidString,
+            offset: 22,
+            line: 2,
+            column: 21,
+            expected: Some("a string"),
+            excerpt: "\t\"cpu\": {\"count\": 2,}\n\t                   ^".to_string(),
+        })
+    );
+    assert_eq!(
+        e.to_string(),
+        concat!(
+            "The given input can't be parsed into a valid string at line 2, ",
+            "column 21, expected a string:\n",
+            "\t\"cpu\": {\"count\": 2,}\n",
+            "\t                   ^",
+        )
+    );
 }
 
 #[test]
@@ -484,7 +825,7 @@ pub fn test_from_file() {
                     },
                     "cmdline".to_string() => {
                         Json::String(
-                            "console=ttyS0 reboot=k panic=1 pci=off".to_string()
+                            "console=ttyS0 reboot=k panic=1".to_string()
                         )
                     }
             ]
@@ -502,3 +843,25 @@ pub fn test_from_file() {
     ]);
     assert_eq!(object, Ok(data));
 }
+
+#[test]
+pub fn test_json_test_suite() {
+    let mut count = 0;
+    for entry in fs::read_dir("../../resources/json-test-suite").unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        if !name.ends_with(".json") {
+            continue;
+        }
+        // A JSON text must be UTF-8, anything else is rejected.
+        let result = String::from_utf8(fs::read(&path).unwrap())
+            .map(|s| Json::parse(&s, DuplicateKeys::KeepLast).is_ok());
+        match &name[..2] {
+            "y_" => assert_eq!(result, Ok(true), "{}", name),
+            "n_" => assert_ne!(result, Ok(true), "{}", name),
+            _ => (),
+        }
+        count += 1;
+    }
+    assert!(count > 0);
+}
@@ -12,7 +12,7 @@ use chrono::Local;
 /// - A logger runs at some `LogLevel` L1.
 /// - A messsage needs to be logged and labeled with `LogLevel` L2.
 /// - If L2 < L1, the message will be ignored. Elsewise, it will be properly logged.
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum LogLevel {
     /// Messages with this label are for debug perpose and can be ignored.
     /// Loggers with this label record all incoming messages. 
@@ -55,6 +55,7 @@ pub trait Logger {
 }
 
 /// A implementation of `Logger` with file backend. To be noticed, it is not thread safe.
+#[allow(dead_code)]
 struct FileLogger {
     /// `LogLevel` for this logger.
     level: LogLevel,
@@ -63,6 +64,7 @@ struct FileLogger {
 }
 
 impl FileLogger {
+    #[allow(dead_code)]
     fn new(path: &str, level: LogLevel) -> Result<Self> {
         Ok(FileLogger { 
             level, 
@@ -92,6 +94,7 @@ impl Logger for FileLogger {
 }
 
 #[test]
+#[allow(clippy::bool_assert_comparison)]
 pub fn test_file_log() {
     let path = "../../resources/shuairan.log";    
     // Cleanup 
@@ -0,0 +1,411 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+use super::error::{Error, Result};
+use super::memory::{HIGH_MEMORY_START, PCI_MMIO_START, RESERVED_HOLE_START};
+use super::pci::{CONFIG_ADDRESS, CONFIG_LEN};
+use super::power::{
+    PM1_CNT_BLK, PM1_CNT_LEN, PM1_EVT_BLK, PM1_EVT_LEN, RESET_PORT, RESET_VALUE,
+    S5_SLP_TYP, SCI_IRQ,
+};
+
+/// Address of the RSDP, inside the BIOS area scanned by the guest OS.
+pub const RSDP_START: u64 = 0xe0000;
+/// Address where the other tables are placed one after another.
+const TABLES_START: u64 = RSDP_START + 0x40;
+/// Address of the IOAPIC.
+pub const IOAPIC_START: u32 = 0xfec0_0000;
+/// Address of the LAPIC.
+pub const LAPIC_START: u32 = 0xfee0_0000;
+
+/// Identifiers written into every table.
+const OEM_ID: &[u8; 6] = b"SHUAIR";
+const OEM_TABLE_ID: &[u8; 8] = b"SHUAIRAN";
+const CREATOR_ID: &[u8; 4] = b"SRVM";
+
+/// Size of the header shared by all the system description tables.
+const HEADER_SIZE: usize = 36;
+
+/// FADT flags.
+const FADT_WBINVD: u32 = 1 << 0;
+const FADT_SLP_BUTTON: u32 = 1 << 5;
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+
+/// Address space ID of the system I/O space in generic addresses.
+const GAS_SYSTEM_IO: u8 = 1;
+
+/// Distance from a NUMA node to itself.
+pub const LOCAL_DISTANCE: u8 = 10;
+/// Default distance between two different NUMA nodes.
+pub const REMOTE_DISTANCE: u8 = 20;
+
+/// Compute the byte which makes the sum of `data` zero.
+fn checksum(data: &[u8]) -> u8 {
+    (0u8).wrapping_sub(data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)))
+}
+
+/// A system description table, i.e. a header followed by the table body.
+struct Sdt {
+    data: Vec<u8>,
+}
+
+impl Sdt {
+    /// Create a table with the given signature and revision.
+    fn new(signature: &[u8; 4], revision: u8) -> Self {
+        let mut data = Vec::with_capacity(HEADER_SIZE);
+        data.extend_from_slice(signature);
+        // Length and checksum are filled in `finish`.
+        data.extend_from_slice(&[0; 4]);
+        data.push(revision);
+        data.push(0);
+        data.extend_from_slice(OEM_ID);
+        data.extend_from_slice(OEM_TABLE_ID);
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(CREATOR_ID);
+        data.extend_from_slice(&1u32.to_le_bytes());
+        Sdt { data }
+    }
+
+    fn u8(&mut self, v: u8) -> &mut Self {
+        self.data.push(v);
+        self
+    }
+
+    fn u16(&mut self, v: u16) -> &mut Self {
+        self.data.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, v: u32) -> &mut Self {
+        self.data.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, v: u64) -> &mut Self {
+        self.data.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn bytes(&mut self, v: &[u8]) -> &mut Self {
+        self.data.extend_from_slice(v);
+        self
+    }
+
+    /// Set the length and checksum of the table.
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.data.len() as u32;
+        self.data[4..8].copy_from_slice(&len.to_le_bytes());
+        self.data[9] = checksum(&self.data);
+        self.data
+    }
+}
+
+/// A guest NUMA node described to the guest OS.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NumaNode {
+    /// APIC IDs of the vcpus in this node.
+    pub cpus: Vec<u32>,
+    /// Guest physical ranges of the memory in this node, as (start, size).
+    pub memory: Vec<(GuestAddress, u64)>,
+    /// Distances from this node to all the nodes, in the order of nodes.
+    pub distances: Vec<u8>,
+}
+
+/// Encode the length of an AML package, which counts the encoding itself.
+fn pkg_length(len: usize) -> Vec<u8> {
+    if len + 1 < 0x40 {
+        vec![(len + 1) as u8]
+    } else if len + 2 < 0x1000 {
+        let len = len + 2;
+        vec![0x40 | (len & 0xf) as u8, (len >> 4) as u8]
+    } else {
+        let len = len + 3;
+        vec![0x80 | (len & 0xf) as u8, (len >> 4) as u8, (len >> 12) as u8]
+    }
+}
+
+/// Build the AML of `\_SB.PCI0`, the host bridge of PCI bus 0 decoding the
+/// configuration ports and the MMIO window for BARs.
+fn pci_host_bridge() -> Vec<u8> {
+    let mut crs = Vec::new();
+    // WordBusNumber (ResourceProducer, MinFixed, MaxFixed, PosDecode,
+    //     0, 0, 0, 0, 1)
+    crs.extend_from_slice(&[0x88, 0x0d, 0x00, 0x02, 0x0c, 0x00]);
+    for v in [0u16, 0, 0, 0, 1] {
+        crs.extend_from_slice(&v.to_le_bytes());
+    }
+    // IO (Decode16, 0xcf8, 0xcf8, 1, 8)
+    crs.extend_from_slice(&[0x47, 0x01]);
+    crs.extend_from_slice(&CONFIG_ADDRESS.to_le_bytes());
+    crs.extend_from_slice(&CONFIG_ADDRESS.to_le_bytes());
+    crs.extend_from_slice(&[1, CONFIG_LEN as u8]);
+    // DWordMemory (ResourceProducer, PosDecode, MinFixed, MaxFixed,
+    //     NonCacheable, ReadWrite, 0, start, end, 0, length)
+    crs.extend_from_slice(&[0x87, 0x17, 0x00, 0x00, 0x0c, 0x01]);
+    let window = [
+        0,
+        PCI_MMIO_START as u32,
+        (RESERVED_HOLE_START - 1) as u32,
+        0,
+        (RESERVED_HOLE_START - PCI_MMIO_START) as u32,
+    ];
+    for v in window {
+        crs.extend_from_slice(&v.to_le_bytes());
+    }
+    // EndTag
+    crs.extend_from_slice(&[0x79, 0x00]);
+
+    let mut buffer = vec![0x0a, crs.len() as u8];
+    buffer.extend_from_slice(&crs);
+    let mut body = b"\\\x2e_SB_PCI0".to_vec();
+    // Name (_HID, EisaId ("PNP0A03"))
+    body.extend_from_slice(b"\x08_HID\x0c\x41\xd0\x0a\x03");
+    // Name (_UID, Zero)
+    body.extend_from_slice(b"\x08_UID\x00");
+    // Name (_CRS, Buffer () { ... })
+    body.extend_from_slice(b"\x08_CRS\x11");
+    body.extend_from_slice(&pkg_length(buffer.len()));
+    body.extend_from_slice(&buffer);
+
+    let mut device = vec![0x5b, 0x82];
+    device.extend_from_slice(&pkg_length(body.len()));
+    device.extend_from_slice(&body);
+    device
+}
+
+/// Build the DSDT, which defines the S5 (soft off) sleep state, and the
+/// PCI host bridge if `pci` is set.
+fn dsdt(pci: bool) -> Vec<u8> {
+    let mut dsdt = Sdt::new(b"DSDT", 6);
+    // Name (_S5, Package (4) { S5_SLP_TYP, 0, 0, 0 })
+    dsdt.u8(0x08).bytes(b"_S5_").u8(0x12).u8(7).u8(4)
+        .u8(0x0a).u8(S5_SLP_TYP).u8(0).u8(0).u8(0);
+    if pci {
+        dsdt.bytes(&pci_host_bridge());
+    }
+    dsdt.finish()
+}
+
+/// Build the FADT with fixed-feature PM1 registers and a reset register.
+fn fadt(dsdt: u64) -> Vec<u8> {
+    let mut fadt = Sdt::new(b"FACP", 6);
+    // FIRMWARE_CTRL, DSDT, reserved, preferred PM profile, SCI_INT.
+    fadt.u32(0).u32(0).u8(0).u8(0).u16(SCI_IRQ as u16);
+    // SMI_CMD is 0, so the platform is always in ACPI mode. ACPI_ENABLE, 
+    // ACPI_DISABLE, S4BIOS_REQ and PSTATE_CNT are unused.
+    fadt.u32(0).u32(0);
+    // PM1a/PM1b event and control blocks, PM2 control, PM timer and GPEs.
+    fadt.u32(PM1_EVT_BLK as u32).u32(0).u32(PM1_CNT_BLK as u32).u32(0)
+        .u32(0).u32(0).u32(0).u32(0);
+    // Lengths of the blocks above, GPE1_BASE and CST_CNT.
+    fadt.u8(PM1_EVT_LEN).u8(PM1_CNT_LEN).bytes(&[0; 6]);
+    // From P_LVL2_LAT to IAPC_BOOT_ARCH, nothing is supported.
+    fadt.bytes(&[0; 111 - 96]);
+    // Reserved, flags.
+    fadt.u8(0).u32(FADT_WBINVD | FADT_SLP_BUTTON | FADT_RESET_REG_SUP);
+    // RESET_REG: address space, bit width, bit offset, access size (byte), 
+    // address.
+    fadt.u8(GAS_SYSTEM_IO).u8(8).u8(0).u8(1).u64(RESET_PORT as u64);
+    // RESET_VALUE, ARM_BOOT_ARCH.
+    fadt.u8(RESET_VALUE).u16(0);
+    // FADT minor version, X_FIRMWARE_CTRL, X_DSDT.
+    fadt.u8(0).u64(0).u64(dsdt);
+    // Extended register blocks, sleep registers and hypervisor vendor.
+    fadt.bytes(&[0; 276 - 148]);
+    fadt.finish()
+}
+
+/// Build the MADT with a LAPIC for each vcpu and a single IOAPIC.
+fn madt(vcpus: u32) -> Vec<u8> {
+    let mut madt = Sdt::new(b"APIC", 5);
+    // Local interrupt controller address, flags (PCAT_COMPAT).
+    madt.u32(LAPIC_START).u32(1);
+    for id in 0..vcpus {
+        if id < 0xff {
+            // Processor local APIC: type, length, UID, APIC ID, flags (enabled).
+            madt.u8(0).u8(8).u8(id as u8).u8(id as u8).u32(1);
+        } else {
+            // Processor local x2APIC: type, length, reserved, x2APIC ID,
+            // flags (enabled), UID.
+            madt.u8(9).u8(16).u16(0).u32(id).u32(1).u32(id);
+        }
+    }
+    // IOAPIC: type, length, ID, reserved, address, GSI base.
+    madt.u8(1).u8(12).u8(0).u8(0).u32(IOAPIC_START).u32(0);
+    // The PIT is connected to GSI 2 instead of IRQ 0.
+    madt.u8(2).u8(10).u8(0).u8(0).u32(2).u16(0);
+    // The SCI is edge triggered and active high, flags: 0b0101.
+    madt.u8(2).u8(10).u8(0).u8(SCI_IRQ as u8).u32(SCI_IRQ).u16(0x5);
+    madt.finish()
+}
+
+/// Build the SRAT mapping vcpus and memory to NUMA nodes.
+fn srat(nodes: &[NumaNode]) -> Vec<u8> {
+    let mut srat = Sdt::new(b"SRAT", 3);
+    // Reserved, must be 1 for backward compatibility.
+    srat.u32(1).u64(0);
+    for (domain, node) in nodes.iter().enumerate() {
+        let domain = domain as u32;
+        for id in node.cpus.iter() {
+            if *id < 0xff {
+                // Processor local APIC affinity: type, length, domain[7:0],
+                // APIC ID, flags (enabled), SAPIC EID, domain[31:8], clock domain.
+                srat.u8(0).u8(16).u8(domain as u8).u8(*id as u8).u32(1).u8(0)
+                    .bytes(&domain.to_le_bytes()[1..]).u32(0);
+            } else {
+                // Processor local x2APIC affinity: type, length, reserved,
+                // domain, x2APIC ID, flags (enabled), clock domain, reserved.
+                srat.u8(2).u8(24).u16(0).u32(domain).u32(*id).u32(1).u32(0).u32(0);
+            }
+        }
+        for (start, size) in node.memory.iter() {
+            // Memory affinity: type, length, domain, reserved, base, length,
+            // reserved, flags (enabled), reserved.
+            srat.u8(1).u8(40).u32(domain).u16(0).u64(start.0).u64(*size)
+                .u32(0).u32(1).u64(0);
+        }
+    }
+    srat.finish()
+}
+
+/// Build the SLIT with distances among NUMA nodes.
+fn slit(nodes: &[NumaNode]) -> Vec<u8> {
+    let mut slit = Sdt::new(b"SLIT", 1);
+    slit.u64(nodes.len() as u64);
+    for node in nodes.iter() {
+        slit.bytes(&node.distances);
+    }
+    slit.finish()
+}
+
+/// Build the XSDT pointing to the given tables.
+fn xsdt(tables: &[u64]) -> Vec<u8> {
+    let mut xsdt = Sdt::new(b"XSDT", 1);
+    for addr in tables.iter() {
+        xsdt.u64(*addr);
+    }
+    xsdt.finish()
+}
+
+/// Build the RSDP (revision 2) pointing to the XSDT.
+fn rsdp(xsdt: u64) -> Vec<u8> {
+    let mut rsdp = Vec::with_capacity(36);
+    rsdp.extend_from_slice(b"RSD PTR ");
+    rsdp.push(0);
+    rsdp.extend_from_slice(OEM_ID);
+    rsdp.push(2);
+    // RSDT address is not used.
+    rsdp.extend_from_slice(&0u32.to_le_bytes());
+    rsdp.extend_from_slice(&36u32.to_le_bytes());
+    rsdp.extend_from_slice(&xsdt.to_le_bytes());
+    rsdp.extend_from_slice(&[0; 4]);
+    // The first checksum covers the ACPI 1.0 part, the second one all.
+    rsdp[8] = checksum(&rsdp[..20]);
+    rsdp[32] = checksum(&rsdp);
+    rsdp
+}
+
+/// Write the ACPI tables describing vcpus and NUMA topology to `memory`.
+///
+/// SRAT and SLIT are only created when `nodes` is not empty, and the PCI
+/// host bridge is only described when `pci` is set.
+pub fn create_acpi_tables(
+    memory: &GuestMemoryMmap,
+    vcpus: u32,
+    nodes: &[NumaNode],
+    pci: bool,
+) -> Result<GuestAddress> {
+    let mut addr = TABLES_START;
+    let mut write = |table: Vec<u8>| -> Result<u64> {
+        let start = addr;
+        if start + table.len() as u64 > HIGH_MEMORY_START {
+            return Err(Error::BootError(
+                "ACPI tables overflow the BIOS area.".to_string()
+            ));
+        }
+        memory.write_slice(&table, GuestAddress(start))
+            .map_err(|e| Error::BootError(e.to_string()))?;
+        // Keep tables 8-byte aligned.
+        addr = (start + table.len() as u64 + 7) & !7;
+        Ok(start)
+    };
+    let dsdt = write(dsdt(pci))?;
+    let mut tables = vec![write(fadt(dsdt))?, write(madt(vcpus))?];
+    if !nodes.is_empty() {
+        tables.push(write(srat(nodes))?);
+        tables.push(write(slit(nodes))?);
+    }
+    let xsdt = write(xsdt(&tables))?;
+    memory.write_slice(&rsdp(xsdt), GuestAddress(RSDP_START))
+        .map_err(|e| Error::BootError(e.to_string()))?;
+    Ok(GuestAddress(RSDP_START))
+}
+
+#[test]
+fn test_acpi_tables() {
+    let memory = GuestMemoryMmap::from_ranges(&[
+        (GuestAddress(0), 0x10_0000)
+    ]).unwrap();
+    let nodes = vec![
+        NumaNode {
+            cpus: vec![0],
+            memory: vec![(GuestAddress(0), 0x8_0000)],
+            distances: vec![LOCAL_DISTANCE, REMOTE_DISTANCE],
+        },
+        NumaNode {
+            cpus: vec![1, 300],
+            memory: vec![(GuestAddress(0x8_0000), 0x8_0000)],
+            distances: vec![REMOTE_DISTANCE, LOCAL_DISTANCE],
+        },
+    ];
+    let rsdp_addr = create_acpi_tables(&memory, 2, &nodes, true).unwrap();
+    assert_eq!(rsdp_addr, GuestAddress(RSDP_START));
+
+    let mut rsdp = [0u8; 36];
+    memory.read_slice(&mut rsdp, rsdp_addr).unwrap();
+    assert_eq!(&rsdp[..8], b"RSD PTR ");
+    assert_eq!(checksum(&rsdp[..20]), 0);
+    assert_eq!(checksum(&rsdp), 0);
+
+    // Walk through the XSDT and check all the tables.
+    let read_table = |addr: u64| {
+        let len: u32 = memory.read_obj(GuestAddress(addr + 4)).unwrap();
+        let mut table = vec![0u8; len as usize];
+        memory.read_slice(&mut table, GuestAddress(addr)).unwrap();
+        assert_eq!(checksum(&table), 0);
+        table
+    };
+    let xsdt = read_table(u64::from_le_bytes(rsdp[24..32].try_into().unwrap()));
+    let signatures = xsdt[HEADER_SIZE..]
+        .chunks(8)
+        .map(|c| read_table(u64::from_le_bytes(c.try_into().unwrap())))
+        .map(|t| {
+            match &t[..4] {
+                b"FACP" => {
+                    assert_eq!(t.len(), 276);
+                    // SCI_INT, PM1a_EVT_BLK, PM1a_CNT_BLK and RESET_REG.
+                    assert_eq!(&t[46..48], &(SCI_IRQ as u16).to_le_bytes());
+                    assert_eq!(&t[56..60], &(PM1_EVT_BLK as u32).to_le_bytes());
+                    assert_eq!(&t[64..68], &(PM1_CNT_BLK as u32).to_le_bytes());
+                    assert_eq!(&t[120..128], &(RESET_PORT as u64).to_le_bytes());
+                    assert_eq!(t[128], RESET_VALUE);
+                    // X_DSDT points to the DSDT containing _S5_.
+                    let dsdt = read_table(u64::from_le_bytes(t[140..148].try_into().unwrap()));
+                    assert_eq!(&dsdt[HEADER_SIZE + 1..HEADER_SIZE + 5], b"_S5_");
+                    // Device (\_SB.PCI0) follows, spanning the rest.
+                    let pci = &dsdt[HEADER_SIZE + 13..];
+                    assert_eq!(&pci[..2], &[0x5b, 0x82]);
+                    assert_eq!((pci[2] & 0xf) as usize | (pci[3] as usize) << 4, pci.len() - 2);
+                    assert_eq!(&pci[4..14], b"\\\x2e_SB_PCI0");
+                    assert!(pci.windows(4).any(|w| w == (PCI_MMIO_START as u32).to_le_bytes()));
+                },
+                b"SRAT" => assert_eq!(t.len(), HEADER_SIZE + 12 + 16 + 40 + 16 + 24 + 40),
+                b"SLIT" => assert_eq!(&t[HEADER_SIZE + 8..], &[10, 20, 20, 10]),
+                _ => {}
+            }
+            String::from_utf8(t[..4].to_vec()).unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(signatures, vec!["FACP", "APIC", "SRAT", "SLIT"]);
+}
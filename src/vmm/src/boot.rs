@@ -0,0 +1,550 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use kvm_bindings::{
+    kvm_fpu, kvm_msr_entry, kvm_regs, kvm_segment, kvm_sregs, CpuId, Msrs,
+};
+use kvm_ioctls::VcpuFd;
+use linux_loader::{
+    cmdline::Cmdline,
+    configurator::{
+        BootConfigurator, BootParams, linux::LinuxBootConfigurator
+    },
+    loader::{
+        self, KernelLoader, KernelLoaderResult, load_cmdline,
+        bootparam::{boot_e820_entry, boot_params},
+        bzimage::BzImage, elf::{self, Elf},
+    },
+};
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
+use super::acpi;
+use super::config::OsConfig;
+use super::error::{ConfigIssue, ConfigIssueKind, Error, Result};
+use super::memory::{HIGH_MEMORY_START, MemoryLayout, RegionKind};
+
+/// Address of the GDT used during boot.
+const BOOT_GDT_START: u64 = 0x500;
+/// Address of the (empty) IDT used during boot.
+const BOOT_IDT_START: u64 = 0x520;
+/// Address of the zero page, i.e. `boot_params`.
+const ZERO_PAGE_START: u64 = 0x7000;
+/// Initial stack pointer of the boot vcpu.
+const BOOT_STACK_POINTER: u64 = 0x8ff0;
+/// Address of the PML4 table for the identity mapping.
+const PML4_START: u64 = 0x9000;
+/// Address of the PDPT table for the identity mapping.
+const PDPTE_START: u64 = 0xa000;
+/// Address of the PD table for the identity mapping.
+const PDE_START: u64 = 0xb000;
+/// Address of the kernel command line.
+const CMDLINE_START: u64 = 0x20000;
+/// Maximum size of the kernel command line, including the terminating null.
+const CMDLINE_MAX_SIZE: usize = 0x10000;
+/// Offset of the 64-bit entry point in a bzImage protected mode kernel.
+const BZIMAGE_64BIT_ENTRY_OFFSET: u64 = 0x200;
+/// Page size used for aligning the initrd.
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Magic numbers and flags for the linux boot protocol.
+const KERNEL_BOOT_FLAG_MAGIC: u16 = 0xaa55;
+const KERNEL_HDR_MAGIC: u32 = 0x5372_6448;
+const KERNEL_LOADER_OTHER: u8 = 0xff;
+const KERNEL_MIN_ALIGNMENT_BYTES: u32 = 0x0100_0000;
+
+/// Types of e820 entries.
+const E820_RAM: u32 = 1;
+const E820_RESERVED: u32 = 2;
+
+/// Control register and EFER bits needed to enter long mode.
+const X86_CR0_PE: u64 = 0x1;
+const X86_CR0_PG: u64 = 0x8000_0000;
+const X86_CR4_PAE: u64 = 0x20;
+const EFER_LME: u64 = 0x100;
+const EFER_LMA: u64 = 0x400;
+
+/// MSRs initialized for the boot vcpu.
+const MSR_IA32_SYSENTER_CS: u32 = 0x174;
+const MSR_IA32_SYSENTER_ESP: u32 = 0x175;
+const MSR_IA32_SYSENTER_EIP: u32 = 0x176;
+const MSR_STAR: u32 = 0xc000_0081;
+const MSR_LSTAR: u32 = 0xc000_0082;
+const MSR_CSTAR: u32 = 0xc000_0083;
+const MSR_SYSCALL_MASK: u32 = 0xc000_0084;
+const MSR_KERNEL_GS_BASE: u32 = 0xc000_0102;
+const MSR_IA32_TSC: u32 = 0x10;
+const MSR_IA32_MISC_ENABLE: u32 = 0x1a0;
+const MSR_IA32_MISC_ENABLE_FAST_STRING: u64 = 0x1;
+
+/// Where the guest starts executing after the OS is loaded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KernelEntry {
+    /// Guest physical address of the 64-bit entry point.
+    pub entry: GuestAddress,
+}
+
+/// Load the kernel, initrd and command line given by `config` into `memory`,
+/// and write the zero page for the linux boot protocol.
+///
+/// Both ELF vmlinux and bzImage kernels are supported. `args` are appended
+/// to the command line, e.g. for the guest to find devices.
+pub fn load_os(
+    memory: &GuestMemoryMmap,
+    layout: &MemoryLayout,
+    config: &OsConfig,
+    args: &[String]
+) -> Result<KernelEntry> {
+    let path = config.kernel.as_ref().ok_or_else(|| Error::InvalidConfig(vec![
+        ConfigIssue::new("os.kernel", ConfigIssueKind::Missing, "is required")
+    ]))?;
+    let mut kernel = File::open(path).map_err(
+        |e| Error::IOError(format!("{}: {}", path, e))
+    )?;
+    let (result, entry) = load_kernel(memory, &mut kernel)?;
+
+    let mut params = boot_params::default();
+    if let Some(hdr) = result.setup_header {
+        params.hdr = hdr;
+    } else {
+        params.hdr.boot_flag = KERNEL_BOOT_FLAG_MAGIC;
+        params.hdr.header = KERNEL_HDR_MAGIC;
+        params.hdr.kernel_alignment = KERNEL_MIN_ALIGNMENT_BYTES;
+    }
+    params.hdr.type_of_loader = KERNEL_LOADER_OTHER;
+
+    let cmdline = build_cmdline(config, args)?;
+    load_cmdline(memory, GuestAddress(CMDLINE_START), &cmdline)
+        .map_err(|e| Error::BootError(e.to_string()))?;
+    params.hdr.cmd_line_ptr = CMDLINE_START as u32;
+    params.hdr.cmdline_size = cmdline.as_cstring()
+        .map_err(|e| Error::BootError(e.to_string()))?
+        .as_bytes()
+        .len() as u32;
+
+    if let Some(path) = config.initrd.as_ref() {
+        let (addr, size) = load_initrd(memory, layout, path, &result, &params)?;
+        params.hdr.ramdisk_image = addr.raw_value() as u32;
+        params.hdr.ramdisk_size = size as u32;
+    }
+
+    let e820 = e820_entries(layout);
+    if e820.len() > params.e820_table.len() {
+        return Err(Error::BootError("Too many e820 entries.".to_string()));
+    }
+    params.e820_entries = e820.len() as u8;
+    params.e820_table[..e820.len()].copy_from_slice(&e820);
+    // The guest would find the RSDP by scanning the BIOS area anyway.
+    params.acpi_rsdp_addr = acpi::RSDP_START;
+
+    LinuxBootConfigurator::write_bootparams::<GuestMemoryMmap>(
+        &BootParams::new::<boot_params>(&params, GuestAddress(ZERO_PAGE_START)),
+        memory,
+    ).map_err(|e| Error::BootError(e.to_string()))?;
+
+    Ok(entry)
+}
+
+/// Load an ELF vmlinux, fallback to bzImage if the kernel is not an ELF file.
+fn load_kernel(
+    memory: &GuestMemoryMmap,
+    kernel: &mut File
+) -> Result<(KernelLoaderResult, KernelEntry)> {
+    let high_memory = Some(GuestAddress(HIGH_MEMORY_START));
+    match Elf::load(memory, None, kernel, high_memory) {
+        Ok(result) => {
+            let entry = KernelEntry { entry: result.kernel_load };
+            Ok((result, entry))
+        },
+        Err(loader::Error::Elf(elf::Error::InvalidElfMagicNumber)) => {
+            let result = BzImage::load(memory, None, kernel, high_memory)
+                .map_err(|e| Error::BootError(e.to_string()))?;
+            let entry = KernelEntry {
+                entry: result.kernel_load.unchecked_add(BZIMAGE_64BIT_ENTRY_OFFSET),
+            };
+            Ok((result, entry))
+        },
+        Err(e) => Err(Error::BootError(e.to_string())),
+    }
+}
+
+/// Build the kernel command line from the OS configuration and `args`.
+fn build_cmdline(config: &OsConfig, args: &[String]) -> Result<Cmdline> {
+    let mut cmdline = Cmdline::new(CMDLINE_MAX_SIZE);
+    let user = config.cmdline.iter();
+    for args in user.chain(args.iter()) {
+        cmdline.insert_str(args).map_err(
+            |e| Error::InvalidConfig(vec![
+                ConfigIssue::new("os.cmdline", ConfigIssueKind::Illegal, &e.to_string())
+            ])
+        )?;
+    }
+    Ok(cmdline)
+}
+
+/// Load the initrd at the top of the low memory, returns its address and size.
+fn load_initrd(
+    memory: &GuestMemoryMmap,
+    layout: &MemoryLayout,
+    path: &str,
+    kernel: &KernelLoaderResult,
+    params: &boot_params,
+) -> Result<(GuestAddress, u64)> {
+    let mut file = File::open(path).map_err(
+        |e| Error::IOError(format!("{}: {}", path, e))
+    )?;
+    let size = file.metadata().map_err(
+        |e| Error::IOError(format!("{}: {}", path, e))
+    )?.len();
+
+    // The initrd must stay below both the end of the low RAM and the limit 
+    // given by the kernel, or 4 GiB if the kernel doesn't say so.
+    let region_end = layout.low_ram_end().raw_value();
+    let addr_max = match params.hdr.initrd_addr_max {
+        0 => u32::MAX as u64,
+        max => max as u64,
+    };
+    let top = std::cmp::min(region_end, addr_max + 1);
+    let addr = top.checked_sub(size)
+        .map(|a| a & !(PAGE_SIZE - 1))
+        .filter(|a| *a >= kernel.kernel_end)
+        .ok_or_else(|| Error::BootError(
+            format!("The initrd {} is too large.", path)
+        ))?;
+    memory.read_exact_from(GuestAddress(addr), &mut file, size as usize)
+        .map_err(|e| Error::BootError(e.to_string()))?;
+    Ok((GuestAddress(addr), size))
+}
+
+/// Build the e820 map from the memory layout.
+fn e820_entries(layout: &MemoryLayout) -> Vec<boot_e820_entry> {
+    layout.regions()
+        .iter()
+        .filter_map(|r| {
+            let type_ = match r.kind {
+                RegionKind::Ram => E820_RAM,
+                RegionKind::Reserved | RegionKind::System => E820_RESERVED,
+                // The MMIO hole is left out for the guest to find devices.
+                RegionKind::Mmio => return None,
+            };
+            Some(boot_e820_entry { addr: r.start.raw_value(), size: r.size, type_ })
+        })
+        .collect()
+}
+
+/// Setup the boot vcpu with the given `id` to enter the kernel in long mode.
+///
+/// Other vcpus only get the CPUID and MSRs, and wait for the boot vcpu to
+/// start them.
+pub fn configure_vcpu(
+    fd: &VcpuFd,
+    id: u32,
+    entry: KernelEntry,
+    memory: &GuestMemoryMmap,
+    cpuid: &CpuId
+) -> Result<()> {
+    let mut cpuid = cpuid.clone();
+    patch_cpuid(&mut cpuid, id);
+    fd.set_cpuid2(&cpuid)?;
+    setup_msrs(fd)?;
+    if id == 0 {
+        setup_regs(fd, entry)?;
+        setup_sregs(fd, memory)?;
+        setup_fpu(fd)?;
+    }
+    Ok(())
+}
+
+/// Set the APIC ID of the vcpu in CPUID leaves.
+fn patch_cpuid(cpuid: &mut CpuId, id: u32) {
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        match entry.function {
+            // Initial APIC ID in EBX[31:24].
+            0x1 => entry.ebx = (entry.ebx & 0x00ff_ffff) | (id << 24),
+            // x2APIC ID in EDX.
+            0xb | 0x1f => entry.edx = id,
+            _ => {}
+        }
+    }
+}
+
+/// Initialize MSRs needed for booting a 64-bit kernel.
+fn setup_msrs(fd: &VcpuFd) -> Result<()> {
+    let entry = |index: u32, data: u64| kvm_msr_entry {
+        index,
+        data,
+        ..Default::default()
+    };
+    let entries = [
+        entry(MSR_IA32_SYSENTER_CS, 0),
+        entry(MSR_IA32_SYSENTER_ESP, 0),
+        entry(MSR_IA32_SYSENTER_EIP, 0),
+        entry(MSR_STAR, 0),
+        entry(MSR_CSTAR, 0),
+        entry(MSR_KERNEL_GS_BASE, 0),
+        entry(MSR_SYSCALL_MASK, 0),
+        entry(MSR_LSTAR, 0),
+        entry(MSR_IA32_TSC, 0),
+        entry(MSR_IA32_MISC_ENABLE, MSR_IA32_MISC_ENABLE_FAST_STRING),
+    ];
+    let msrs = Msrs::from_entries(&entries)
+        .map_err(|e| Error::BootError(format!("{:?}", e)))?;
+    let written = fd.set_msrs(&msrs)?;
+    if written != entries.len() {
+        return Err(Error::BootError(
+            format!("Only {} of {} MSRs are set.", written, entries.len())
+        ));
+    }
+    Ok(())
+}
+
+/// Point the vcpu at the kernel entry with the zero page in RSI.
+fn setup_regs(fd: &VcpuFd, entry: KernelEntry) -> Result<()> {
+    fd.set_regs(&kvm_regs {
+        rflags: 0x2,
+        rip: entry.entry.raw_value(),
+        rsp: BOOT_STACK_POINTER,
+        rbp: BOOT_STACK_POINTER,
+        rsi: ZERO_PAGE_START,
+        ..Default::default()
+    })?;
+    Ok(())
+}
+
+/// Initialize the FPU as `fninit` does.
+fn setup_fpu(fd: &VcpuFd) -> Result<()> {
+    fd.set_fpu(&kvm_fpu {
+        fcw: 0x37f,
+        mxcsr: 0x1f80,
+        ..Default::default()
+    })?;
+    Ok(())
+}
+
+/// Setup segments and page tables for long mode.
+fn setup_sregs(fd: &VcpuFd, memory: &GuestMemoryMmap) -> Result<()> {
+    let mut sregs = fd.get_sregs()?;
+    setup_segments(&mut sregs, memory)?;
+    setup_page_tables(&mut sregs, memory)?;
+    fd.set_sregs(&sregs)?;
+    Ok(())
+}
+
+/// Build a GDT entry from flags, base and limit.
+fn gdt_entry(flags: u16, base: u32, limit: u32) -> u64 {
+    ((base as u64 & 0xff00_0000) << (56 - 24))
+        | ((flags as u64 & 0x0000_f0ff) << 40)
+        | ((limit as u64 & 0x000f_0000) << (48 - 16))
+        | ((base as u64 & 0x00ff_ffff) << 16)
+        | (limit as u64 & 0x0000_ffff)
+}
+
+/// Convert a GDT entry at `index` to the segment KVM expects.
+fn kvm_segment(entry: u64, index: u8) -> kvm_segment {
+    let base = ((entry & 0xff00_0000_0000_0000) >> 32)
+        | ((entry & 0x0000_00ff_0000_0000) >> 16)
+        | ((entry & 0x0000_0000_ffff_0000) >> 16);
+    let limit = ((entry & 0x000f_0000_0000_0000) >> 32) | (entry & 0xffff);
+    let g = ((entry >> 55) & 0x1) as u8;
+    let present = ((entry >> 47) & 0x1) as u8;
+    kvm_segment {
+        base,
+        // Scale the limit manually when the granularity bit is set.
+        limit: match g {
+            0 => limit as u32,
+            _ => ((limit as u32) << 12) | 0xfff,
+        },
+        selector: (index as u16) * 8,
+        type_: ((entry >> 40) & 0xf) as u8,
+        present,
+        dpl: ((entry >> 45) & 0x3) as u8,
+        db: ((entry >> 54) & 0x1) as u8,
+        s: ((entry >> 44) & 0x1) as u8,
+        l: ((entry >> 53) & 0x1) as u8,
+        g,
+        avl: ((entry >> 52) & 0x1) as u8,
+        padding: 0,
+        unusable: (present == 0) as u8,
+    }
+}
+
+/// Write a flat GDT and load code, data and TSS segments from it.
+fn setup_segments(sregs: &mut kvm_sregs, memory: &GuestMemoryMmap) -> Result<()> {
+    let gdt = [
+        gdt_entry(0, 0, 0),             // NULL
+        gdt_entry(0xa09b, 0, 0xfffff),  // CODE
+        gdt_entry(0xc093, 0, 0xfffff),  // DATA
+        gdt_entry(0x808b, 0, 0xfffff),  // TSS
+    ];
+    for (i, entry) in gdt.iter().enumerate() {
+        memory.write_obj(*entry, GuestAddress(BOOT_GDT_START + (i as u64) * 8))
+            .map_err(|e| Error::BootError(e.to_string()))?;
+    }
+    memory.write_obj(0u64, GuestAddress(BOOT_IDT_START))
+        .map_err(|e| Error::BootError(e.to_string()))?;
+
+    let code = kvm_segment(gdt[1], 1);
+    let data = kvm_segment(gdt[2], 2);
+    let tss = kvm_segment(gdt[3], 3);
+    sregs.gdt.base = BOOT_GDT_START;
+    sregs.gdt.limit = (std::mem::size_of_val(&gdt) - 1) as u16;
+    sregs.idt.base = BOOT_IDT_START;
+    sregs.idt.limit = (std::mem::size_of::<u64>() - 1) as u16;
+    sregs.cs = code;
+    sregs.ds = data;
+    sregs.es = data;
+    sregs.fs = data;
+    sregs.gs = data;
+    sregs.ss = data;
+    sregs.tr = tss;
+
+    sregs.cr0 |= X86_CR0_PE;
+    sregs.efer |= EFER_LME | EFER_LMA;
+    Ok(())
+}
+
+/// Identity map the first 1 GiB with 2 MiB pages and enable paging.
+fn setup_page_tables(sregs: &mut kvm_sregs, memory: &GuestMemoryMmap) -> Result<()> {
+    let write = |value: u64, addr: u64| {
+        memory.write_obj(value, GuestAddress(addr))
+            .map_err(|e| Error::BootError(e.to_string()))
+    };
+    write(PDPTE_START | 0x03, PML4_START)?;
+    write(PDE_START | 0x03, PDPTE_START)?;
+    for i in 0..512u64 {
+        write((i << 21) | 0x83, PDE_START + i * 8)?;
+    }
+    sregs.cr3 = PML4_START;
+    sregs.cr4 |= X86_CR4_PAE;
+    sregs.cr0 |= X86_CR0_PG;
+    Ok(())
+}
+
+#[test]
+fn test_gdt_segment() {
+    let entry = gdt_entry(0xa09b, 0, 0xfffff);
+    assert_eq!(entry, 0x00af_9b00_0000_ffff);
+    let seg = kvm_segment(entry, 1);
+    assert_eq!(seg.selector, 0x8);
+    assert_eq!(seg.base, 0);
+    assert_eq!(seg.limit, 0xffff_ffff);
+    assert_eq!((seg.type_, seg.present, seg.s, seg.l, seg.g), (0xb, 1, 1, 1, 1));
+    assert_eq!(seg.unusable, 0);
+    assert_eq!(kvm_segment(gdt_entry(0, 0, 0), 0).unusable, 1);
+}
+
+#[test]
+fn test_e820_entries() {
+    use super::memory::{EBDA_START, MMIO_HOLE_END, RESERVED_HOLE_START};
+
+    let entries = e820_entries(&MemoryLayout::new(4096))
+        .iter()
+        .map(|e| (e.addr, e.size, e.type_))
+        .collect::<Vec<_>>();
+    assert_eq!(entries, vec![
+        (0, EBDA_START, E820_RAM),
+        (EBDA_START, HIGH_MEMORY_START - EBDA_START, E820_RESERVED),
+        (HIGH_MEMORY_START, 0xc000_0000 - HIGH_MEMORY_START, E820_RAM),
+        (RESERVED_HOLE_START, MMIO_HOLE_END - RESERVED_HOLE_START, E820_RESERVED),
+        (MMIO_HOLE_END, 0x4000_0000, E820_RAM),
+    ]);
+}
+
+#[test]
+fn test_load_elf() {
+    use std::io::Write;
+    use vm_memory::ByteValued;
+    use linux_loader::elf as elf_gen;
+
+    // A minimal ELF vmlinux with one loadable segment at 16 MiB.
+    let code = [0xf4u8; 16];
+    let ehdr_size = std::mem::size_of::<elf_gen::Elf64_Ehdr>();
+    let phdr_size = std::mem::size_of::<elf_gen::Elf64_Phdr>();
+    let mut ehdr = elf_gen::Elf64_Ehdr::default();
+    ehdr.e_ident[..4].copy_from_slice(b"\x7fELF");
+    ehdr.e_ident[4] = 2; // ELFCLASS64
+    ehdr.e_ident[5] = 1; // ELFDATA2LSB
+    ehdr.e_entry = 0x100_0000;
+    ehdr.e_phoff = ehdr_size as u64;
+    ehdr.e_phentsize = phdr_size as u16;
+    ehdr.e_phnum = 1;
+    let phdr = elf_gen::Elf64_Phdr {
+        p_type: elf_gen::PT_LOAD,
+        p_offset: (ehdr_size + phdr_size) as u64,
+        p_paddr: 0x100_0000,
+        p_filesz: code.len() as u64,
+        p_memsz: code.len() as u64,
+        ..Default::default()
+    };
+    let path = std::env::temp_dir().join(
+        format!("shuairan-test-vmlinux-{}", std::process::id())
+    );
+    let mut file = File::create(&path).unwrap();
+    file.write_all(ehdr.as_slice()).unwrap();
+    file.write_all(phdr.as_slice()).unwrap();
+    file.write_all(&code).unwrap();
+
+    let layout = MemoryLayout::new(32);
+    let memory = GuestMemoryMmap::from_ranges(&[
+        (GuestAddress(0), 0x200_0000)
+    ]).unwrap();
+    let config = OsConfig {
+        kernel: Some(path.to_str().unwrap().to_string()),
+        initrd: None,
+        rootfs: None,
+        cmdline: Some("console=ttyS0 panic=1".to_string()),
+    };
+    let entry = load_os(&memory, &layout, &config, &["pci=off".to_string()]);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(entry, Ok(KernelEntry { entry: GuestAddress(0x100_0000) }));
+
+    let mut buf = [0u8; 16];
+    memory.read_slice(&mut buf, GuestAddress(0x100_0000)).unwrap();
+    assert_eq!(buf, code);
+    let params: boot_params = memory.read_obj(GuestAddress(ZERO_PAGE_START)).unwrap();
+    assert_eq!({ params.hdr.cmd_line_ptr }, CMDLINE_START as u32);
+    assert_eq!({ params.hdr.cmdline_size }, 29);
+    assert_eq!(params.e820_entries, 4);
+    // Extra arguments are appended to the configured ones.
+    let mut cmdline = [0u8; 30];
+    memory.read_slice(&mut cmdline, GuestAddress(CMDLINE_START)).unwrap();
+    assert_eq!(&cmdline, b"console=ttyS0 panic=1 pci=off\0");
+
+    assert_eq!(
+        load_os(&memory, &layout, &OsConfig { kernel: None, ..config }, &[]),
+        Err(Error::InvalidConfig(vec![
+            ConfigIssue::new("os.kernel", ConfigIssueKind::Missing, "is required")
+        ]))
+    );
+}
+
+#[test]
+fn test_configure_vcpu() {
+    use kvm_bindings::KVM_MAX_CPUID_ENTRIES;
+    use kvm_ioctls::{Kvm, VcpuExit};
+
+    let kvm = Kvm::new().unwrap();
+    let fd = kvm.create_vm().unwrap();
+    let memory = GuestMemoryMmap::from_ranges(&[
+        (GuestAddress(0), 0x20_0000)
+    ]).unwrap();
+    super::memory::register_guest_memory(&fd, &memory).unwrap();
+    // The 64-bit kernel entry reads EFER and reports the LMA bit:
+    // mov ecx, 0xc0000080; rdmsr; shr eax, 10; and al, 1; mov dx, 0x3f8; 
+    // out dx, al; hlt
+    let code = [
+        0xb9, 0x80, 0x00, 0x00, 0xc0, 0x0f, 0x32, 0xc1, 0xe8, 0x0a, 0x24, 0x01,
+        0x66, 0xba, 0xf8, 0x03, 0xee, 0xf4,
+    ];
+    memory.write_slice(&code, GuestAddress(HIGH_MEMORY_START)).unwrap();
+
+    let vcpu = fd.create_vcpu(0).unwrap();
+    let cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
+    let entry = KernelEntry { entry: GuestAddress(HIGH_MEMORY_START) };
+    configure_vcpu(&vcpu, 0, entry, &memory, &cpuid).unwrap();
+    assert_eq!(vcpu.get_regs().unwrap().rsi, ZERO_PAGE_START);
+    match vcpu.run().unwrap() {
+        VcpuExit::IoOut(port, data) => assert_eq!((port, data), (0x3f8, &[1u8][..])),
+        exit => panic!("unexpected exit: {:?}", exit),
+    }
+    assert!(matches!(vcpu.run().unwrap(), VcpuExit::Hlt));
+}
@@ -0,0 +1,155 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+use super::error::{Error, Result};
+
+/// A device which claims ranges of a bus.
+///
+/// A single device is shared by all the vcpu threads of a VM, so
+/// implementations are responsible for their own synchronization.
+pub trait BusDevice: Send + Sync {
+    /// Handle a read at `offset` of the range starting at `base`, `data`
+    /// must be filled before the vcpu resumes.
+    fn read(&self, _base: u64, _offset: u64, data: &mut [u8]) {
+        data.fill(0xff);
+    }
+    /// Handle a write at `offset` of the range starting at `base`.
+    fn write(&self, _base: u64, _offset: u64, _data: &[u8]) {}
+}
+
+/// A range of a bus claimed by a device.
+#[derive(Clone)]
+struct Range {
+    len: u64,
+    device: Arc<dyn BusDevice>,
+}
+
+/// An address space where devices claim non-overlapping ranges.
+///
+/// Ranges are rarely changed, so accesses only take a read lock to find the
+/// device and call it without any lock of the bus.
+#[derive(Default)]
+pub struct Bus {
+    /// Ranges keyed by their base.
+    ranges: RwLock<BTreeMap<u64, Range>>,
+}
+
+impl Bus {
+    /// Create an empty bus.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Claim `len` bytes from `base` for the device.
+    pub fn insert(&self, device: Arc<dyn BusDevice>, base: u64, len: u64) -> Result<()> {
+        let end = base.checked_add(len)
+            .filter(|_| len > 0)
+            .ok_or_else(|| Error::BusError(format!("invalid range {:#x}+{:#x}", base, len)))?;
+        let mut ranges = self.ranges.write().unwrap();
+        let before = ranges.range(..end).next_back();
+        if let Some((&b, r)) = before.filter(|(&b, r)| b + r.len > base) {
+            return Err(Error::BusError(format!(
+                "range {:#x}-{:#x} overlaps {:#x}-{:#x}", base, end, b, b + r.len
+            )));
+        }
+        ranges.insert(base, Range { len, device });
+        Ok(())
+    }
+
+    /// Release the range starting at `base`.
+    pub fn remove(&self, base: u64) -> Result<()> {
+        self.ranges.write().unwrap()
+            .remove(&base)
+            .map(|_| ())
+            .ok_or_else(|| Error::BusError(format!("no range at {:#x}", base)))
+    }
+
+    /// Find the range containing `addr`, returns its base and device.
+    fn find(&self, addr: u64) -> Option<(u64, Arc<dyn BusDevice>)> {
+        let ranges = self.ranges.read().unwrap();
+        let (&base, range) = ranges.range(..=addr).next_back()?;
+        (addr - base < range.len).then(|| (base, range.device.clone()))
+    }
+
+    /// Read from the device at `addr`, returns false if it's unclaimed.
+    pub fn read(&self, addr: u64, data: &mut [u8]) -> bool {
+        match self.find(addr) {
+            Some((base, device)) => {
+                device.read(base, addr - base, data);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Write to the device at `addr`, returns false if it's unclaimed.
+    pub fn write(&self, addr: u64, data: &[u8]) -> bool {
+        match self.find(addr) {
+            Some((base, device)) => {
+                device.write(base, addr - base, data);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+/// The port I/O and MMIO buses of a VM.
+#[derive(Default)]
+pub struct Buses {
+    pub pio: Bus,
+    pub mmio: Bus,
+}
+
+impl Buses {
+    /// Create empty buses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[test]
+fn test_bus() {
+    use std::sync::Mutex;
+
+    /// Record the accesses as (base, offset).
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<(u64, u64)>>);
+
+    impl BusDevice for Recorder {
+        fn write(&self, base: u64, offset: u64, _data: &[u8]) {
+            self.0.lock().unwrap().push((base, offset));
+        }
+    }
+
+    let bus = Bus::new();
+    let device = Arc::new(Recorder::default());
+    assert_eq!(bus.insert(device.clone(), 0x3f8, 8), Ok(()));
+    assert_eq!(bus.insert(device.clone(), 0x400, 4), Ok(()));
+    // Ranges which are empty, wrap around or overlap are rejected.
+    assert!(bus.insert(device.clone(), 0x500, 0).is_err());
+    assert!(bus.insert(device.clone(), u64::MAX, 2).is_err());
+    assert!(bus.insert(device.clone(), 0x3f0, 9).is_err());
+    assert!(bus.insert(device.clone(), 0x3ff, 1).is_err());
+    assert!(bus.insert(device.clone(), 0x3f0, 0x20).is_err());
+    assert_eq!(bus.insert(device.clone(), 0x3f0, 8), Ok(()));
+
+    assert!(bus.write(0x3f8, &[0]));
+    assert!(bus.write(0x3ff, &[0]));
+    assert!(!bus.write(0x400 + 4, &[0]));
+    assert_eq!(*device.0.lock().unwrap(), vec![(0x3f8, 0), (0x3f8, 7)]);
+
+    // Unclaimed reads are left to the caller.
+    let mut data = [0u8; 2];
+    assert!(!bus.read(0x300, &mut data));
+    assert!(bus.read(0x3f0, &mut data));
+    assert_eq!(data, [0xff, 0xff]);
+
+    assert_eq!(bus.remove(0x3f8), Ok(()));
+    assert!(bus.remove(0x3f8).is_err());
+    assert!(!bus.write(0x3f8, &[0]));
+}
@@ -4,18 +4,181 @@
 use utils::{json::Json, log::LogLevel};
 #[allow(unused_imports)]
 use std::str::FromStr;
-use super::error::{Result, Error};
+use super::acpi::LOCAL_DISTANCE;
+use super::error::{ConfigIssue, ConfigIssueKind, Result, Error};
+use super::numa::{host_node_cpus, parse_cpu_list};
 
 // When kernel is configured with MAXSMP on, 8192 cpus are allowed.
 // So we use this value.
 const MAX_VCPU_DEFAULT: u32 = 8192;
+/// Queue pairs of a network device, as many as TAP interfaces allow.
+const MAX_NET_QUEUE_PAIRS: f64 = 256.0;
+/// Host NUMA nodes, as many as Linux supports.
+const MAX_HOST_NODES: f64 = 1024.0;
 
+/// Take the required `key` of the object at `path` as a `$type`, recording
+/// it in `issues` if missing or of another type. A `null` value is missing.
 macro_rules! required {
-    ($object:ident, $func:ident, $prefix:expr, $str:expr) => {
-        match $object.$func($str) {
-            Some(value) => Ok(value),
-            None => Err(Error::MissingConfig(format!("{}.{}", $prefix, $str))),
-        }?
+    ($issues:ident, $object:ident, $type:ty, $path:expr, $key:expr) => {
+        match $object.take($key) {
+            None | Some(Json::Null) => $issues.missing($path, $key),
+            Some(value) => $issues.typed::<$type>(value, &join($path, $key)),
+        }
+    }
+}
+
+/// Take the optional `key` of the object at `path` as a `$type`, recording
+/// it in `issues` if of another type. A `null` value is not set.
+macro_rules! optional {
+    ($issues:ident, $object:ident, $type:ty, $path:expr, $key:expr) => {
+        match $object.take($key) {
+            None | Some(Json::Null) => None,
+            Some(value) => $issues.typed::<$type>(value, &join($path, $key)),
+        }
+    }
+}
+
+/// How keys which aren't known to the hypervisor are treated when parsing
+/// a configuration.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum ConfigMode {
+    /// Reject them, e.g. misspelled keys.
+    #[default]
+    Strict,
+    /// Ignore them, e.g. keys of a newer version of the hypervisor.
+    Lenient,
+}
+
+/// Path to `key` of the object at `path`.
+fn join(path: &str, key: &str) -> String {
+    match path {
+        "" => key.to_string(),
+        _ => format!("{}.{}", path, key),
+    }
+}
+
+/// Types which values of a configuration are taken as.
+trait JsonType: Sized {
+    /// What a value of the type is, e.g. `a number`.
+    const NAME: &'static str;
+
+    /// Convert `json` to the type, `None` if it's of another type.
+    fn from_json(json: Json) -> Option<Self>;
+}
+
+impl JsonType for f64 {
+    const NAME: &'static str = "a number";
+
+    fn from_json(json: Json) -> Option<Self> {
+        match json {
+            Json::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+}
+
+impl JsonType for bool {
+    const NAME: &'static str = "a boolean";
+
+    fn from_json(json: Json) -> Option<Self> {
+        match json {
+            Json::Boolean(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+impl JsonType for String {
+    const NAME: &'static str = "a string";
+
+    fn from_json(json: Json) -> Option<Self> {
+        match json {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl JsonType for Vec<Json> {
+    const NAME: &'static str = "an array";
+
+    fn from_json(json: Json) -> Option<Self> {
+        match json {
+            Json::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+/// Objects are kept as they are, their keys are taken in turn.
+impl JsonType for Json {
+    const NAME: &'static str = "an object";
+
+    fn from_json(json: Json) -> Option<Self> {
+        match json {
+            Json::Object(_) => Some(json),
+            _ => None,
+        }
+    }
+}
+
+/// Problems found while parsing a configuration, so that all of them are
+/// reported at once rather than one per run.
+struct Issues {
+    mode: ConfigMode,
+    list: Vec<ConfigIssue>,
+}
+
+impl Issues {
+    fn new(mode: ConfigMode) -> Self {
+        Issues { mode, list: Vec::new() }
+    }
+
+    fn push(&mut self, path: String, kind: ConfigIssueKind, message: String) {
+        self.list.push(ConfigIssue { path, kind, message });
+    }
+
+    /// Record that the required `key` of the object at `path` is missing,
+    /// `None` is returned as its value.
+    fn missing<T>(&mut self, path: &str, key: &str) -> Option<T> {
+        self.push(join(path, key), ConfigIssueKind::Missing, "is required".to_string());
+        None
+    }
+
+    /// Convert the value at `path` to `T`, recording it as illegal if it's
+    /// of another type.
+    fn typed<T: JsonType>(&mut self, value: Json, path: &str) -> Option<T> {
+        match T::from_json(value) {
+            Some(value) => Some(value),
+            None => self.illegal(path, format!("expected {}", T::NAME)),
+        }
+    }
+
+    /// Record that the value at `path` is illegal because of `message`,
+    /// `None` is returned as its value.
+    fn illegal<T>(&mut self, path: &str, message: String) -> Option<T> {
+        self.push(path.to_string(), ConfigIssueKind::Illegal, message);
+        None
+    }
+
+    /// Record the keys of the JSON object at `path` left after parsing it,
+    /// which are unknown. They are ignored in lenient mode.
+    fn unknown(&mut self, json: &Json, path: &str) {
+        if let (Json::Object(map), ConfigMode::Strict) = (json, self.mode) {
+            let mut keys = map.keys().collect::<Vec<_>>();
+            keys.sort();
+            for key in keys {
+                self.push(join(path, key), ConfigIssueKind::Unknown, "is unknown".to_string());
+            }
+        }
+    }
+
+    /// `value` if no problem has been found.
+    fn result<T>(self, value: Option<T>) -> Result<T> {
+        match value {
+            Some(value) if self.list.is_empty() => Ok(value),
+            _ => Err(Error::InvalidConfig(self.list)),
+        }
     }
 }
 
@@ -24,16 +187,155 @@ macro_rules! required {
 pub struct CpuConfig {
     /// The number of vcpus.
     pub count: u32,
+    /// Host CPUs which vcpus are pinned to, vcpu `i` runs on the 
+    /// `i % len`th one of them.
+    pub affinity: Option<Vec<usize>>,
+    /// SCHED_FIFO priority for vcpu threads, the default policy is kept 
+    /// if not set.
+    pub priority: Option<i32>,
 }
 
 impl CpuConfig {
     /// Construct CpuConfig from a JSON object.
-    fn from(mut json: Json) -> Result<Self> {
-        let count = required!(json, take_number, "cpu", "count") as u32;
-        if count == 0 || count > MAX_VCPU_DEFAULT {
-            return Err(Error::IllegalConfig(format!("cpu.count={}", count)));
+    fn from(mut json: Json, issues: &mut Issues) -> Option<Self> {
+        let count = match required!(issues, json, f64, "cpu", "count") {
+            Some(n) if n >= 1.0 && n <= MAX_VCPU_DEFAULT as f64 && n.fract() == 0.0 => {
+                Some(n as u32)
+            },
+            Some(n) => issues.illegal("cpu.count", format!(
+                "{} isn't an integer in 1-{}", n, MAX_VCPU_DEFAULT
+            )),
+            None => None,
+        };
+        let affinity = match optional!(issues, json, String, "cpu", "affinity") {
+            Some(list) => match parse_cpu_list(&list) {
+                Some(cpus) if !cpus.is_empty() => Some(cpus),
+                _ => issues.illegal("cpu.affinity", format!(
+                    "`{}` isn't a list of CPUs below {}, e.g. `0-3,8`", list, libc::CPU_SETSIZE
+                )),
+            },
+            None => None,
+        };
+        let priority = match optional!(issues, json, f64, "cpu", "priority") {
+            Some(p) if (1.0..=99.0).contains(&p) => Some(p as i32),
+            Some(p) => issues.illegal("cpu.priority", format!("{} isn't in 1-99", p)),
+            None => None,
+        };
+        issues.unknown(&json, "cpu");
+        Some(CpuConfig { count: count?, affinity, priority })
+    }
+
+    /// Host CPU which the vcpu `id` is pinned to.
+    pub fn vcpu_affinity(&self, id: u32) -> Option<usize> {
+        self.affinity
+            .as_ref()
+            .map(|cpus| cpus[id as usize % cpus.len()])
+    }
+}
+
+/// Page sizes for backing the guest memory.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PageSize {
+    /// Normal 4 KiB pages.
+    Normal,
+    /// 2 MiB hugepages.
+    Huge2M,
+    /// 1 GiB hugepages.
+    Huge1G,
+}
+
+impl PageSize {
+    /// Size of the page in bytes.
+    pub fn bytes(&self) -> u64 {
+        match self {
+            PageSize::Normal => 4 << 10,
+            PageSize::Huge2M => 2 << 20,
+            PageSize::Huge1G => 1 << 30,
+        }
+    }
+
+    /// Whether the page is a hugepage.
+    pub fn is_huge(&self) -> bool {
+        *self != PageSize::Normal
+    }
+}
+
+/// Types of the guest memory backend.
+#[derive(Debug, PartialEq, Clone)]
+pub enum MemoryBackendType {
+    /// Anonymous memory mapping.
+    Anonymous,
+    /// A memfd file, which can be shared with other processes by its fd.
+    Memfd,
+    /// A file created under the given hugetlbfs mount point.
+    Hugetlbfs(String),
+}
+
+/// Configurations for the backend of the guest memory.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MemoryBackendConfig {
+    /// Type of the backend.
+    pub backend: MemoryBackendType,
+    /// Size of pages backing the memory.
+    pub page_size: PageSize,
+    /// Populate all the pages before the VM runs.
+    pub prefault: bool,
+    /// Map the memory as shared instead of private.
+    pub shared: bool,
+}
+
+impl Default for MemoryBackendConfig {
+    fn default() -> Self {
+        MemoryBackendConfig {
+            backend: MemoryBackendType::Anonymous,
+            page_size: PageSize::Normal,
+            prefault: false,
+            shared: false,
         }
-        Ok(CpuConfig { count })
+    }
+}
+
+impl MemoryBackendConfig {
+    /// Construct MemoryBackendConfig from a JSON object.
+    fn from(mut json: Json, issues: &mut Issues) -> Option<Self> {
+        let backend = optional!(issues, json, String, "memory.backend", "type");
+        let backend = match backend.as_deref() {
+            None | Some("anonymous") => Some(MemoryBackendType::Anonymous),
+            Some("memfd") => Some(MemoryBackendType::Memfd),
+            Some("hugetlbfs") => required!(issues, json, String, "memory.backend", "path")
+                .map(MemoryBackendType::Hugetlbfs),
+            Some(t) => issues.illegal("memory.backend.type", format!(
+                "`{}` isn't one of anonymous, memfd and hugetlbfs", t
+            )),
+        };
+        let hugetlbfs = matches!(backend, Some(MemoryBackendType::Hugetlbfs(_)));
+        let page_size = optional!(issues, json, String, "memory.backend", "page_size");
+        let page_size = match page_size.as_deref() {
+            // Files on hugetlbfs are always backed by hugepages.
+            None if hugetlbfs => Some(PageSize::Huge2M),
+            None | Some("4K") => Some(PageSize::Normal),
+            Some("2M") => Some(PageSize::Huge2M),
+            Some("1G") => Some(PageSize::Huge1G),
+            Some(s) => issues.illegal("memory.backend.page_size", format!(
+                "`{}` isn't one of 4K, 2M and 1G", s
+            )),
+        };
+        let page_size = match page_size {
+            Some(PageSize::Normal) if hugetlbfs => issues.illegal(
+                "memory.backend.page_size",
+                "4K pages can't back files on hugetlbfs".to_string()
+            ),
+            page_size => page_size,
+        };
+        let prefault = optional!(issues, json, bool, "memory.backend", "prefault");
+        let shared = optional!(issues, json, bool, "memory.backend", "shared");
+        issues.unknown(&json, "memory.backend");
+        Some(MemoryBackendConfig {
+            backend: backend?,
+            page_size: page_size?,
+            prefault: prefault.unwrap_or(false),
+            shared: shared.unwrap_or(false),
+        })
     }
 }
 
@@ -42,43 +344,566 @@ impl CpuConfig {
 pub struct MemoryConfig {
     /// The total size of VM's memory in MB.
     pub size_mib: u32,
+    /// Backend of VM's memory.
+    pub backend: MemoryBackendConfig,
 }
 
 impl MemoryConfig {
     /// Construct MemoryConfig form a JSON object.
-    fn from(mut json: Json) -> Result<Self> {
-        let size_mib = required!(
-            json, 
-            take_number, 
-            "memory", 
-            "size_mib"
-        ) as u32;
-        if size_mib == 0 {
-            return Err(Error::IllegalConfig("memory.size_mib".to_string()));
+    fn from(mut json: Json, issues: &mut Issues) -> Option<Self> {
+        let size_mib = match required!(issues, json, f64, "memory", "size_mib") {
+            Some(n) if n >= 1.0 && n <= u32::MAX as f64 && n.fract() == 0.0 => Some(n as u32),
+            Some(n) => issues.illegal("memory.size_mib", format!(
+                "{} isn't a positive integer", n
+            )),
+            None => None,
+        };
+        let backend = match optional!(issues, json, Json, "memory", "backend") {
+            Some(obj) => MemoryBackendConfig::from(obj, issues),
+            None => Some(MemoryBackendConfig::default()),
+        };
+        issues.unknown(&json, "memory");
+        let (size_mib, backend) = (size_mib?, backend?);
+        if !((size_mib as u64) << 20).is_multiple_of(backend.page_size.bytes()) {
+            return issues.illegal("memory.size_mib", format!(
+                "{} MiB isn't a multiple of the page size", size_mib
+            ));
         }
-        Ok(MemoryConfig { size_mib })
+        Some(MemoryConfig { size_mib, backend })
     }
 }
 
-/// Configurations of a virtual device for a VM.
+/// Configurations of a guest NUMA node.
 #[derive(Debug, PartialEq, Clone)]
-pub struct DeviceConfig {
-    /// The driver related to this device.
-    pub driver: String,
-    /// The physical source device or file related to this device.
+pub struct NumaNodeConfig {
+    /// Vcpus belonging to this node.
+    pub cpus: Vec<u32>,
+    /// Size of the memory belonging to this node in MB.
+    pub size_mib: u32,
+    /// Host NUMA node which the memory and vcpus are bound to.
+    pub host_node: Option<u32>,
+    /// Distances from this node to every node, including itself.
+    pub distances: Option<Vec<u8>>,
+}
+
+impl NumaNodeConfig {
+    /// Construct NumaNodeConfig from the JSON object at `numa[index]`.
+    fn from(mut json: Json, index: usize, issues: &mut Issues) -> Option<Self> {
+        let path = format!("numa[{}]", index);
+        let cpus = required!(issues, json, Vec<Json>, &path, "cpus")
+            .and_then(|array| numbers(array, &join(&path, "cpus"), issues));
+        let distances = match optional!(issues, json, Vec<Json>, &path, "distances") {
+            Some(array) => match numbers(array, &join(&path, "distances"), issues) {
+                Some(d) if d.iter().all(|d| *d <= u8::MAX as u32) => {
+                    Some(Some(d.into_iter().map(|d| d as u8).collect()))
+                },
+                Some(d) => issues.illegal(&join(&path, "distances"), format!(
+                    "{:?} has distances above {}", d, u8::MAX
+                )),
+                None => None,
+            },
+            None => Some(None),
+        };
+        let size_mib = match required!(issues, json, f64, &path, "size_mib") {
+            Some(n) if n >= 1.0 && n <= u32::MAX as f64 && n.fract() == 0.0 => Some(n as u32),
+            Some(n) => issues.illegal(&join(&path, "size_mib"), format!(
+                "{} isn't a positive integer", n
+            )),
+            None => None,
+        };
+        let host_node = match optional!(issues, json, f64, &path, "host_node") {
+            Some(n) if (0.0..MAX_HOST_NODES).contains(&n) && n.fract() == 0.0 => {
+                match host_node_cpus(n as u32) {
+                    Some(_) => Some(Some(n as u32)),
+                    None => issues.illegal(&join(&path, "host_node"), format!(
+                        "host node {} doesn't exist", n
+                    )),
+                }
+            },
+            Some(n) => issues.illegal(&join(&path, "host_node"), format!(
+                "{} isn't an integer in 0-{}", n, MAX_HOST_NODES - 1.0
+            )),
+            None => Some(None),
+        };
+        issues.unknown(&json, &path);
+        Some(NumaNodeConfig {
+            cpus: cpus?,
+            size_mib: size_mib?,
+            host_node: host_node?,
+            distances: distances?,
+        })
+    }
+
+    /// Check the nodes against the cpu and memory configurations.
+    fn validate(nodes: &[Self], cpu: &CpuConfig, memory: &MemoryConfig, issues: &mut Issues) {
+        let mut illegal = |path: &str, message: String| {
+            issues.push(path.to_string(), ConfigIssueKind::Illegal, message)
+        };
+        let size_mib: u64 = nodes.iter().map(|n| n.size_mib as u64).sum();
+        if nodes.is_empty() || size_mib != memory.size_mib as u64 {
+            illegal("numa", format!(
+                "the nodes have {} MiB rather than memory.size_mib", size_mib
+            ));
+        }
+        // Every vcpu belongs to exactly one node.
+        let mut seen = vec![false; cpu.count as usize];
+        for (i, node) in nodes.iter().enumerate() {
+            let path = format!("numa[{}].cpus", i);
+            for id in &node.cpus {
+                match seen.get_mut(*id as usize) {
+                    Some(s) if !*s => *s = true,
+                    Some(_) => illegal(&path, format!("vcpu {} is in more than one node", id)),
+                    None => illegal(&path, format!("vcpu {} doesn't exist", id)),
+                }
+            }
+        }
+        if let Some(id) = seen.iter().position(|s| !*s) {
+            illegal("numa", format!("vcpu {} isn't in any node", id));
+        }
+        for (i, node) in nodes.iter().enumerate() {
+            if let Some(distances) = &node.distances {
+                let valid = distances.len() == nodes.len()
+                    && distances.iter().enumerate().all(|(j, d)| {
+                        if i == j { *d == LOCAL_DISTANCE } else { *d > LOCAL_DISTANCE }
+                    });
+                if !valid {
+                    illegal(&format!("numa[{}].distances", i), format!(
+                        "{:?} isn't {} to the node itself and more to the others",
+                        distances, LOCAL_DISTANCE
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Convert a JSON array at `path` into non-negative integers.
+fn numbers(array: Vec<Json>, path: &str, issues: &mut Issues) -> Option<Vec<u32>> {
+    let numbers = array.into_iter()
+        .map(|v| match v {
+            Json::Number(n) if n >= 0.0 && n <= u32::MAX as f64 && n.fract() == 0.0 => {
+                Some(n as u32)
+            },
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>();
+    match numbers {
+        Some(numbers) => Some(numbers),
+        None => issues.illegal(path, "isn't an array of non-negative integers".to_string()),
+    }
+}
+
+/// Host backends of the serial console.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ConsoleBackend {
+    /// Stdin and stdout of the hypervisor, the terminal is put in raw mode.
+    Stdio,
+    /// A newly allocated pseudo terminal.
+    Pty,
+    /// A Unix socket listening at the given path.
+    Socket(String),
+    /// A file the output is appended to, there is no input.
+    File(String),
+}
+
+impl ConsoleBackend {
+    /// Construct ConsoleBackend from a `console` device object at `path`.
+    fn from(json: &mut Json, path: &str, issues: &mut Issues) -> Option<Self> {
+        match optional!(issues, json, String, path, "type").as_deref() {
+            None | Some("tty") | Some("stdio") => Some(ConsoleBackend::Stdio),
+            Some("pty") => Some(ConsoleBackend::Pty),
+            Some("socket") => required!(issues, json, String, path, "path")
+                .map(ConsoleBackend::Socket),
+            Some("file") => required!(issues, json, String, path, "path")
+                .map(ConsoleBackend::File),
+            Some(s) => issues.illegal(&join(path, "type"), format!(
+                "`{}` isn't one of tty, stdio, pty, socket and file", s
+            )),
+        }
+    }
+}
+
+/// How the host caches the data of a block device.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CacheMode {
+    /// Go through the host page cache.
+    Buffered,
+    /// Bypass the host page cache with `O_DIRECT`.
+    Direct,
+}
+
+/// How a block device submits I/O to the host.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum IoEngineKind {
+    /// io_uring, falling back to a thread pool if the host doesn't
+    /// support it.
+    IoUring,
+    /// A pool of threads doing blocking I/O.
+    ThreadPool,
+}
+
+/// Format of a disk image.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ImageFormat {
+    Raw,
+    Qcow2,
+}
+
+/// Options of a `virtio-blk` device.
+#[derive(Debug, PartialEq, Clone)]
+pub struct BlockConfig {
+    /// Path to the image file or the host block device.
+    pub source: String,
+    /// Format of the image, detected from its header if not given.
+    pub format: Option<ImageFormat>,
+    /// Reject writes from the guest, the image is opened read-only.
+    pub readonly: bool,
+    /// How the host caches the image, `Buffered` by default.
+    pub cache: CacheMode,
+    /// Serial number reported to the guest, at most 20 bytes.
+    pub serial: Option<String>,
+    /// Logical block size reported to the guest, 512 by default.
+    pub logical_block_size: u32,
+    /// How I/O is submitted to the host, `IoUring` by default.
+    pub io_engine: IoEngineKind,
+}
+
+impl Default for BlockConfig {
+    fn default() -> Self {
+        BlockConfig {
+            source: String::new(),
+            format: None,
+            readonly: false,
+            cache: CacheMode::Buffered,
+            serial: None,
+            logical_block_size: 512,
+            io_engine: IoEngineKind::IoUring,
+        }
+    }
+}
+
+impl BlockConfig {
+    /// Construct BlockConfig from a `virtio-blk` device object at `path`,
+    /// the image must exist.
+    fn from(json: &mut Json, path: &str, issues: &mut Issues) -> Option<Self> {
+        let source = match required!(issues, json, String, path, "source") {
+            Some(s) if !std::path::Path::new(&s).exists() => issues.illegal(
+                &join(path, "source"), format!("`{}` doesn't exist", s)
+            ),
+            source => source,
+        };
+        let format = match optional!(issues, json, String, path, "format").as_deref() {
+            None => Some(None),
+            Some("raw") => Some(Some(ImageFormat::Raw)),
+            Some("qcow2") => Some(Some(ImageFormat::Qcow2)),
+            Some(s) => issues.illegal(&join(path, "format"), format!(
+                "`{}` isn't one of raw and qcow2", s
+            )),
+        };
+        let cache = match optional!(issues, json, String, path, "cache").as_deref() {
+            None | Some("buffered") => Some(CacheMode::Buffered),
+            Some("direct") => Some(CacheMode::Direct),
+            Some(s) => issues.illegal(&join(path, "cache"), format!(
+                "`{}` isn't one of buffered and direct", s
+            )),
+        };
+        let serial = match optional!(issues, json, String, path, "serial") {
+            Some(s) if s.len() > 20 => issues.illegal(&join(path, "serial"), format!(
+                "`{}` is longer than 20 bytes", s
+            )),
+            serial => Some(serial),
+        };
+        let logical_block_size = match optional!(issues, json, f64, path, "logical_block_size") {
+            Some(n) if [512.0, 1024.0, 2048.0, 4096.0].contains(&n) => Some(n as u32),
+            Some(n) => issues.illegal(&join(path, "logical_block_size"), format!(
+                "{} isn't one of 512, 1024, 2048 and 4096", n
+            )),
+            None => Some(512),
+        };
+        let io_engine = match optional!(issues, json, String, path, "io_engine").as_deref() {
+            None | Some("io_uring") => Some(IoEngineKind::IoUring),
+            Some("thread_pool") => Some(IoEngineKind::ThreadPool),
+            Some(s) => issues.illegal(&join(path, "io_engine"), format!(
+                "`{}` isn't one of io_uring and thread_pool", s
+            )),
+        };
+        Some(BlockConfig {
+            source: source?,
+            format: format?,
+            readonly: optional!(issues, json, bool, path, "readonly").unwrap_or(false),
+            cache: cache?,
+            serial: serial?,
+            logical_block_size: logical_block_size?,
+            io_engine: io_engine?,
+        })
+    }
+}
+
+/// A unicast MAC address, e.g. `fa:16:3e:21:c0:c0`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    /// Parse 6 bytes in hex separated by colons, `None` unless it's a
+    /// legal unicast address.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut addr = [0u8; 6];
+        let mut parts = s.split(':');
+        for byte in addr.iter_mut() {
+            let part = parts.next()
+                .filter(|p| p.len() == 2 && p.chars().all(|c| c.is_ascii_hexdigit()))?;
+            *byte = u8::from_str_radix(part, 16).ok()?;
+        }
+        // Multicast and zero addresses can't be assigned to an interface.
+        if parts.next().is_some() || addr[0] & 1 != 0 || addr == [0; 6] {
+            return None;
+        }
+        Some(MacAddr(addr))
+    }
+}
+
+impl std::fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let a = &self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a[0], a[1], a[2], a[3], a[4], a[5])
+    }
+}
+
+/// Options of a `virtio-net` device.
+#[derive(Debug, PartialEq, Clone)]
+pub struct NetConfig {
+    /// Name of the host TAP interface, which is created if missing. The
+    /// kernel names it if neither this nor `fd` is given.
     pub source: Option<String>,
+    /// MAC address of the guest interface, chosen by the guest if not
+    /// given.
+    pub mac: Option<MacAddr>,
+    /// MTU reported to the guest, which should match the TAP interface.
+    pub mtu: Option<u16>,
+    /// Number of rx/tx queue pairs, each served by a queue of the TAP
+    /// interface, 1 by default.
+    pub queue_pairs: u16,
+    /// Offer checksum and TSO offloads to the guest, `true` by default.
+    pub offload: bool,
+    /// A TAP interface opened by the caller, instead of the one named by
+    /// `source`.
+    pub fd: Option<i32>,
 }
 
-impl DeviceConfig {
-    /// Construct DeviceConfig from a JSON object.
-    fn from(mut json: Json) -> Result<Self> {
-        Ok(DeviceConfig {
-            driver: required!(json, take_string, "device", "driver"),
-            source: json.take_string("source"),
+impl Default for NetConfig {
+    fn default() -> Self {
+        NetConfig { source: None, mac: None, mtu: None, queue_pairs: 1, offload: true, fd: None }
+    }
+}
+
+impl NetConfig {
+    /// Construct NetConfig from a `virtio-net` device object at `path`.
+    fn from(json: &mut Json, path: &str, issues: &mut Issues) -> Option<Self> {
+        let source = match optional!(issues, json, String, path, "source") {
+            Some(s) if s.len() >= libc::IFNAMSIZ => issues.illegal(&join(path, "source"), format!(
+                "`{}` is longer than {} bytes", s, libc::IFNAMSIZ - 1
+            )),
+            source => Some(source),
+        };
+        let mac = match optional!(issues, json, String, path, "mac") {
+            Some(s) => match MacAddr::parse(&s) {
+                Some(mac) => Some(Some(mac)),
+                None => issues.illegal(&join(path, "mac"), format!(
+                    "`{}` isn't a unicast MAC address, e.g. `fa:16:3e:21:c0:c0`", s
+                )),
+            },
+            None => Some(None),
+        };
+        let mtu = match optional!(issues, json, f64, path, "mtu") {
+            Some(n) if (68.0..=65535.0).contains(&n) && n.fract() == 0.0 => Some(Some(n as u16)),
+            Some(n) => issues.illegal(&join(path, "mtu"), format!(
+                "{} isn't an integer in 68-65535", n
+            )),
+            None => Some(None),
+        };
+        let queue_pairs = match optional!(issues, json, f64, path, "queue_pairs") {
+            Some(n) if (1.0..=MAX_NET_QUEUE_PAIRS).contains(&n) && n.fract() == 0.0 => {
+                Some(n as u16)
+            },
+            Some(n) => issues.illegal(&join(path, "queue_pairs"), format!(
+                "{} isn't an integer in 1-{}", n, MAX_NET_QUEUE_PAIRS
+            )),
+            None => Some(1),
+        };
+        let fd = match optional!(issues, json, f64, path, "fd") {
+            Some(n) if n >= 0.0 && n <= i32::MAX as f64 && n.fract() == 0.0 => {
+                Some(Some(n as i32))
+            },
+            Some(n) => issues.illegal(&join(path, "fd"), format!(
+                "{} isn't a file descriptor", n
+            )),
+            None => Some(None),
+        };
+        let offload = optional!(issues, json, bool, path, "offload").unwrap_or(true);
+        let (source, queue_pairs, fd) = (source?, queue_pairs?, fd?);
+        // An opened fd is a single queue of the interface.
+        if fd.is_some() && queue_pairs > 1 {
+            return issues.illegal(&join(path, "queue_pairs"), format!(
+                "{} queue pairs can't be served by a single fd", queue_pairs
+            ));
+        }
+        if let (Some(source), Some(_)) = (source.as_ref(), fd) {
+            return issues.illegal(&join(path, "source"), format!(
+                "`{}` can't be given along with fd", source
+            ));
+        }
+        Some(NetConfig { source, mac: mac?, mtu: mtu?, queue_pairs, offload, fd })
+    }
+}
+
+/// Device types served by vhost-user backends.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum VhostUserKind {
+    Block,
+    Net,
+}
+
+/// Options of a `vhost-user-blk` or `vhost-user-net` device.
+#[derive(Debug, PartialEq, Clone)]
+pub struct VhostUserConfig {
+    /// Device type served by the backend.
+    pub kind: VhostUserKind,
+    /// Path to the Unix socket the backend listens on.
+    pub socket: String,
+}
+
+impl VhostUserConfig {
+    /// Construct VhostUserConfig from a vhost-user device object at `path`.
+    fn from(
+        kind: VhostUserKind,
+        json: &mut Json,
+        path: &str,
+        issues: &mut Issues
+    ) -> Option<Self> {
+        Some(VhostUserConfig {
+            kind,
+            socket: required!(issues, json, String, path, "socket")?,
         })
     }
 }
 
+/// Address of a host PCI device, in the sysfs format
+/// `segment:bus:device.function`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PciBdf {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciBdf {
+    /// Parse `bus:device.function` in hex, e.g. `02:00.0`, optionally
+    /// preceded by the segment, e.g. `0000:02:00.0`. `None` unless the
+    /// device and function numbers are in range.
+    pub fn parse(s: &str) -> Option<Self> {
+        let hex = |s: &str, len: usize| {
+            (s.len() == len && s.chars().all(|c| c.is_ascii_hexdigit()))
+                .then(|| u16::from_str_radix(s, 16).ok())
+                .flatten()
+        };
+        let (s, function) = s.rsplit_once('.')?;
+        let mut parts = s.rsplit(':');
+        let device = hex(parts.next()?, 2).filter(|&d| d < 32)? as u8;
+        let bus = hex(parts.next()?, 2)? as u8;
+        let segment = match parts.next() {
+            Some(p) => hex(p, 4)?,
+            None => 0,
+        };
+        let function = hex(function, 1).filter(|&f| f < 8)? as u8;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(PciBdf { segment, bus, device, function })
+    }
+}
+
+impl std::fmt::Display for PciBdf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04x}:{:02x}:{:02x}.{:x}", self.segment, self.bus, self.device, self.function)
+    }
+}
+
+/// Options of a `vfio` device.
+#[derive(Debug, PartialEq, Clone)]
+pub struct VfioConfig {
+    /// Host PCI device passed through to the guest, given as `source`.
+    pub bdf: PciBdf,
+}
+
+impl VfioConfig {
+    /// Construct VfioConfig from a `vfio` device object at `path`.
+    fn from(json: &mut Json, path: &str, issues: &mut Issues) -> Option<Self> {
+        let source = required!(issues, json, String, path, "source")?;
+        match PciBdf::parse(&source) {
+            Some(bdf) => Some(VfioConfig { bdf }),
+            None => issues.illegal(&join(path, "source"), format!(
+                "`{}` isn't a PCI address, e.g. `02:00.0`", source
+            )),
+        }
+    }
+}
+
+/// Configurations of a virtual device for a VM, one variant per `driver`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum DeviceConfig {
+    /// `console`: connects the serial console to the host.
+    Console(ConsoleBackend),
+    /// `virtio-blk`: a block device backed by an image.
+    Block(BlockConfig),
+    /// `virtio-net`: a network device backed by a TAP interface.
+    Net(NetConfig),
+    /// `vhost-user-blk` or `vhost-user-net`: a device served by a
+    /// vhost-user backend.
+    VhostUser(VhostUserConfig),
+    /// `vfio`: a host PCI device passed through.
+    Vfio(VfioConfig),
+}
+
+impl DeviceConfig {
+    /// Construct DeviceConfig from the JSON object at `device[index]`.
+    fn from(mut json: Json, index: usize, issues: &mut Issues) -> Option<Self> {
+        let path = format!("device[{}]", index);
+        let driver = required!(issues, json, String, &path, "driver")?;
+        let json = &mut json;
+        let config = match driver.as_str() {
+            "console" => ConsoleBackend::from(json, &path, issues).map(DeviceConfig::Console),
+            "virtio-blk" => BlockConfig::from(json, &path, issues).map(DeviceConfig::Block),
+            "virtio-net" => NetConfig::from(json, &path, issues).map(DeviceConfig::Net),
+            "vhost-user-blk" => VhostUserConfig::from(VhostUserKind::Block, json, &path, issues)
+                .map(DeviceConfig::VhostUser),
+            "vhost-user-net" => VhostUserConfig::from(VhostUserKind::Net, json, &path, issues)
+                .map(DeviceConfig::VhostUser),
+            "vfio" => VfioConfig::from(json, &path, issues).map(DeviceConfig::Vfio),
+            _ => return issues.illegal(&join(&path, "driver"), format!(
+                "`{}` isn't one of console, virtio-blk, virtio-net, vhost-user-blk, \
+                vhost-user-net and vfio", driver
+            )),
+        };
+        issues.unknown(json, &path);
+        config
+    }
+
+    /// Name of the driver, as in the `driver` key.
+    pub fn driver(&self) -> &'static str {
+        match self {
+            DeviceConfig::Console(_) => "console",
+            DeviceConfig::Block(_) => "virtio-blk",
+            DeviceConfig::Net(_) => "virtio-net",
+            DeviceConfig::VhostUser(v) => match v.kind {
+                VhostUserKind::Block => "vhost-user-blk",
+                VhostUserKind::Net => "vhost-user-net",
+            },
+            DeviceConfig::Vfio(_) => "vfio",
+        }
+    }
+}
+
 /// Configurations related to the operating system.
 #[derive(Debug, PartialEq, Clone)]
 pub struct OsConfig {
@@ -94,13 +919,15 @@ pub struct OsConfig {
 
 impl OsConfig {
     /// Construct OsConfig from a JSON object.
-    fn from(mut json: Json) -> Result<Self> {
-        Ok(OsConfig{
-            kernel: json.take_string("kernel"),
-            initrd: json.take_string("initrd"),
-            rootfs: json.take_string("rootfs"),
-            cmdline: json.take_string("cmdline")
-        })
+    fn from(mut json: Json, issues: &mut Issues) -> Option<Self> {
+        let config = OsConfig {
+            kernel: optional!(issues, json, String, "os", "kernel"),
+            initrd: optional!(issues, json, String, "os", "initrd"),
+            rootfs: optional!(issues, json, String, "os", "rootfs"),
+            cmdline: optional!(issues, json, String, "os", "cmdline")
+        };
+        issues.unknown(&json, "os");
+        Some(config)
     }
 }
 
@@ -115,22 +942,22 @@ pub struct LogConfig {
 
 impl LogConfig {
     /// Construct LogConfig from a JSON object.
-    pub fn from(mut json: Json) -> Result<Self> {
-        Ok(
-            LogConfig {
-                level: json.take_string("level").map(
-                    |l| match &l[..] {
-                        "Debug" | "debug" => LogLevel::Debug,
-                        "Info" | "info" => LogLevel::Info,
-                        "Warn" | "warn" => LogLevel::Warn,
-                        "Error" | "error" => LogLevel::Error,
-                        // Unrecognized config will be amended to `Debug` level.
-                        _ => LogLevel::Debug
-                    }
-                ),
-                path: json.take_string("path")
-            }
-        )
+    fn from(mut json: Json, issues: &mut Issues) -> Option<Self> {
+        let config = LogConfig {
+            level: optional!(issues, json, String, "vmm.log", "level").map(
+                |l| match &l[..] {
+                    "Debug" | "debug" => LogLevel::Debug,
+                    "Info" | "info" => LogLevel::Info,
+                    "Warn" | "warn" => LogLevel::Warn,
+                    "Error" | "error" => LogLevel::Error,
+                    // Unrecognized config will be amended to `Debug` level.
+                    _ => LogLevel::Debug
+                }
+            ),
+            path: optional!(issues, json, String, "vmm.log", "path")
+        };
+        issues.unknown(&json, "vmm.log");
+        Some(config)
     }
 }
 
@@ -143,18 +970,25 @@ pub struct VmmConfig {
 
 impl VmmConfig {
     /// Construct VmmConfig from a JSON object.
-    pub fn from(mut json: Json) -> Result<Self> {
-        Ok(
-            VmmConfig {
-                log: match json.take_object("log") {
-                    Some(obj) => Some(LogConfig::from(obj)?),
-                    _ => None
-                }
-            }
-        )
+    fn from(mut json: Json, issues: &mut Issues) -> Option<Self> {
+        let log = match optional!(issues, json, Json, "vmm", "log") {
+            Some(obj) => Some(LogConfig::from(obj, issues)?),
+            _ => None
+        };
+        issues.unknown(&json, "vmm");
+        Some(VmmConfig { log })
     }
 }
 
+/// What to do when the guest reboots.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RebootAction {
+    /// Reset the vcpus, reload the OS and keep the VM running.
+    Restart,
+    /// Exit the VM, leaving the restart to the orchestration.
+    Exit,
+}
+
 /// Overall configurations for a virtual machine.
 #[derive(Debug, PartialEq, Clone)]
 pub struct VmConfig {
@@ -162,114 +996,639 @@ pub struct VmConfig {
     pub cpu: CpuConfig,
     /// Memory configurations for a VM.
     pub memory: MemoryConfig,
+    /// Guest NUMA topology, a single node is presented if not set.
+    pub numa: Option<Vec<NumaNodeConfig>>,
     /// Device configurations for a VM.
     pub device: Vec<DeviceConfig>,
     /// OS configurations for a VM.
     pub os: OsConfig,
+    /// What to do when the guest reboots, `Restart` by default.
+    pub on_reboot: RebootAction,
     /// Optional VMM configurations for a VM.
     pub vmm: Option<VmmConfig>
 }
 
 impl VmConfig {
-    /// Construct VmConfig form a JSON object.
-    pub fn from(mut json: Json) -> Result<Self> {
-        Ok(VmConfig { 
-            cpu: CpuConfig::from(required!(json, take_object, "", "cpu"))?, 
-            memory: MemoryConfig::from(required!(json, take_object, "", "memory"))?, 
-            device: {
-                let mut device = Vec::new();
-                for dev in required!(json, take_array, "", "device") {
-                    device.push(DeviceConfig::from(dev)?);
-                }
-                device
-            }, 
-            os: OsConfig::from(required!(json, take_object, "", "os"))?,
-            vmm: match json.take_object("vmm") {
-                Some(obj) => Some(VmmConfig::from(obj)?),
-                _ => None
+    /// Construct VmConfig form a JSON object, unknown keys are rejected.
+    pub fn from(json: Json) -> Result<Self> {
+        Self::from_with_mode(json, ConfigMode::default())
+    }
+
+    /// Construct VmConfig form a JSON object, unknown keys are treated
+    /// according to `mode`. All the problems found are reported together.
+    pub fn from_with_mode(json: Json, mode: ConfigMode) -> Result<Self> {
+        let mut issues = Issues::new(mode);
+        let config = Self::parse(json, &mut issues);
+        issues.result(config)
+    }
+
+    fn parse(mut json: Json, issues: &mut Issues) -> Option<Self> {
+        let cpu = required!(issues, json, Json, "", "cpu")
+            .and_then(|obj| CpuConfig::from(obj, issues));
+        let memory = required!(issues, json, Json, "", "memory")
+            .and_then(|obj| MemoryConfig::from(obj, issues));
+        let numa = optional!(issues, json, Vec<Json>, "", "numa").map(|array| {
+            array.into_iter()
+                .enumerate()
+                .map(|(index, node)| NumaNodeConfig::from(node, index, issues))
+                .collect::<Vec<_>>()
+        });
+        let device = required!(issues, json, Vec<Json>, "", "device").map(|array| {
+            array.into_iter()
+                .enumerate()
+                .map(|(index, dev)| DeviceConfig::from(dev, index, issues))
+                .collect::<Vec<_>>()
+        });
+        let os = required!(issues, json, Json, "", "os")
+            .and_then(|obj| OsConfig::from(obj, issues));
+        let on_reboot = match optional!(issues, json, String, "", "on_reboot").as_deref() {
+            None | Some("restart") => Some(RebootAction::Restart),
+            Some("exit") => Some(RebootAction::Exit),
+            Some(s) => issues.illegal("on_reboot", format!(
+                "`{}` isn't one of restart and exit", s
+            )),
+        };
+        let vmm = match optional!(issues, json, Json, "", "vmm") {
+            Some(obj) => VmmConfig::from(obj, issues).map(Some),
+            None => Some(None),
+        };
+        issues.unknown(&json, "");
+
+        let (cpu, memory) = (cpu?, memory?);
+        let numa = match numa {
+            Some(nodes) => {
+                let nodes = nodes.into_iter().collect::<Option<Vec<_>>>()?;
+                NumaNodeConfig::validate(&nodes, &cpu, &memory, issues);
+                Some(nodes)
+            },
+            None => None,
+        };
+        let device = device?.into_iter().collect::<Option<Vec<_>>>()?;
+        // vhost-user backends map the guest memory from its file.
+        if device.iter().any(|d| matches!(d, DeviceConfig::VhostUser(_))) {
+            if memory.backend.backend == MemoryBackendType::Anonymous {
+                issues.push(
+                    "memory.backend.type".to_string(),
+                    ConfigIssueKind::Illegal,
+                    "vhost-user devices need a memfd or hugetlbfs backend".to_string()
+                );
             }
-        }) 
+            if !memory.backend.shared {
+                issues.push(
+                    "memory.backend.shared".to_string(),
+                    ConfigIssueKind::Illegal,
+                    "vhost-user devices need the memory to be shared".to_string()
+                );
+            }
+        }
+        Some(VmConfig { cpu, memory, numa, device, os: os?, on_reboot: on_reboot?, vmm: vmm? })
+    }
+
+    /// Backend of the serial console, the first `console` device wins.
+    pub fn console(&self) -> Option<&ConsoleBackend> {
+        self.device.iter().find_map(|d| match d {
+            DeviceConfig::Console(backend) => Some(backend),
+            _ => None,
+        })
     }
+
     /// Construct VmConfig from loading a config file
     pub fn from_file(path: &str) -> Result<Self> {
-        Self::from(Json::from_file(path)?)
+        Self::from_file_with_mode(path, ConfigMode::default())
     }
+
+    /// Construct VmConfig from loading a config file, unknown keys are
+    /// treated according to `mode`.
+    pub fn from_file_with_mode(path: &str, mode: ConfigMode) -> Result<Self> {
+        Self::from_with_mode(Json::from_file(path)?, mode)
+    }
+}
+
+/// Parse the JSON object `s` by `from` in strict mode.
+#[cfg(test)]
+fn parse<T>(s: &str, from: impl FnOnce(Json, &mut Issues) -> Option<T>) -> Result<T> {
+    let mut issues = Issues::new(ConfigMode::Strict);
+    let value = from(Json::from_str(s).unwrap(), &mut issues);
+    issues.result(value)
+}
+
+/// The error of a single problem of `kind` at `path`.
+#[cfg(test)]
+fn issue(kind: ConfigIssueKind, path: &str, message: &str) -> Error {
+    Error::InvalidConfig(vec![
+        ConfigIssue { path: path.to_string(), kind, message: message.to_string() }
+    ])
+}
+
+#[cfg(test)]
+fn missing(path: &str) -> Error {
+    issue(ConfigIssueKind::Missing, path, "is required")
+}
+
+#[cfg(test)]
+fn illegal(path: &str, message: &str) -> Error {
+    issue(ConfigIssueKind::Illegal, path, message)
+}
+
+#[cfg(test)]
+fn unknown(path: &str) -> Error {
+    issue(ConfigIssueKind::Unknown, path, "is unknown")
 }
 
 #[test]
 fn test_cpu_config() {
     assert_eq!(
-        CpuConfig::from(Json::from_str(r#"{ "count": 4 }"#).unwrap()),
-        Ok(CpuConfig { count: 4 })
+        parse(r#"{ "count": 4 }"#, CpuConfig::from),
+        Ok(CpuConfig { count: 4, affinity: None, priority: None })
     );
+    let config = parse(
+        r#"{ "count": 4, "affinity": "2-3,6", "priority": 10 }"#
+    , CpuConfig::from).unwrap();
+    assert_eq!(config.affinity, Some(vec![2, 3, 6]));
+    assert_eq!(config.priority, Some(10));
     assert_eq!(
-        CpuConfig::from(Json::from_str("{}").unwrap()), 
-        Err(Error::MissingConfig("cpu.count".to_string()))
+        (0..4).map(|i| config.vcpu_affinity(i)).collect::<Vec<_>>(),
+        vec![Some(2), Some(3), Some(6), Some(2)]
     );
     assert_eq!(
-        CpuConfig::from(Json::from_str(r#"{ "count": 8197 }"#).unwrap()), 
-        Err(Error::IllegalConfig("cpu.count=8197".to_string()))
+        parse(r#"{ "count": 4, "affinity": "3-1" }"#, CpuConfig::from),
+        Err(illegal("cpu.affinity", "`3-1` isn't a list of CPUs below 1024, e.g. `0-3,8`"))
+    );
+    assert_eq!(
+        parse(r#"{ "count": 4, "affinity": "0-18446744073709551614" }"#, CpuConfig::from),
+        Err(illegal(
+            "cpu.affinity",
+            "`0-18446744073709551614` isn't a list of CPUs below 1024, e.g. `0-3,8`"
+        ))
+    );
+    assert_eq!(
+        parse(r#"{ "count": 4, "priority": 100 }"#, CpuConfig::from),
+        Err(illegal("cpu.priority", "100 isn't in 1-99"))
+    );
+    assert_eq!(
+        parse("{}", CpuConfig::from), 
+        Err(missing("cpu.count"))
+    );
+    assert_eq!(
+        parse(r#"{ "count": 8197 }"#, CpuConfig::from), 
+        Err(illegal("cpu.count", "8197 isn't an integer in 1-8192"))
     );
 }
 
 #[test]
 fn test_memconfig() {
     assert_eq!(
-        MemoryConfig::from(Json::from_str(r#"{"size_mib":1024}"#).unwrap()),
-        Ok(MemoryConfig { size_mib: 1024 })
+        parse(r#"{"size_mib":1024}"#, MemoryConfig::from),
+        Ok(MemoryConfig { 
+            size_mib: 1024, 
+            backend: MemoryBackendConfig::default() 
+        })
     );
     assert_eq!(
-        MemoryConfig::from(Json::from_str(r#"{"size_mib": 0}"#).unwrap()),
-        Err(Error::IllegalConfig("memory.size_mib".to_string()))
+        parse(r#"{"size_mib": 0}"#, MemoryConfig::from),
+        Err(illegal("memory.size_mib", "0 isn't a positive integer"))
     );
     assert_eq!(
-        MemoryConfig::from(Json::from_str(r#"{"size_mib": null}"#).unwrap()),
-        Err(Error::MissingConfig("memory.size_mib".to_string()))
+        parse(r#"{"size_mib": null}"#, MemoryConfig::from),
+        Err(missing("memory.size_mib"))
     );
     assert_eq!(
-        MemoryConfig::from(Json::from_str(r#"{}"#).unwrap()),
-        Err(Error::MissingConfig("memory.size_mib".to_string()))
+        parse(r#"{}"#, MemoryConfig::from),
+        Err(missing("memory.size_mib"))
     );
 }
 
 #[test]
-fn test_devconfig() {
+fn test_mem_backend_config() {
     assert_eq!(
-        DeviceConfig::from(Json::from_str(
-            r#"{"driver":"virtio-blk","source":"/xxx/disk.raw"}"#
-        ).unwrap()),
-        Ok(DeviceConfig { 
-            driver: "virtio-blk".to_string(),
-            source: Some("/xxx/disk.raw".to_string())
+        parse(concat!(
+            r#"{"size_mib":2048,"backend":{"type":"memfd","page_size":"1G","#,
+            r#""prefault":true,"shared":true}}"#
+        ), MemoryConfig::from),
+        Ok(MemoryConfig {
+            size_mib: 2048,
+            backend: MemoryBackendConfig {
+                backend: MemoryBackendType::Memfd,
+                page_size: PageSize::Huge1G,
+                prefault: true,
+                shared: true,
+            }
         })
     );
     assert_eq!(
-        DeviceConfig::from(Json::from_str(
-            r#"{ "driver":"virtio-blk" }"#
-        ).unwrap()),
-        Ok(DeviceConfig { 
-            driver: "virtio-blk".to_string(),
-            source: None
+        parse(
+            r#"{"type":"hugetlbfs","path":"/dev/hugepages"}"#
+        , MemoryBackendConfig::from),
+        Ok(MemoryBackendConfig {
+            backend: MemoryBackendType::Hugetlbfs("/dev/hugepages".to_string()),
+            page_size: PageSize::Huge2M,
+            prefault: false,
+            shared: false,
         })
     );
     assert_eq!(
-        DeviceConfig::from(Json::from_str(
-            r#"{}"#
-        ).unwrap()),
-        Err(Error::MissingConfig("device.driver".to_string()))
+        parse(
+            r#"{"type":"hugetlbfs"}"#
+        , MemoryBackendConfig::from),
+        Err(missing("memory.backend.path"))
+    );
+    assert_eq!(
+        parse(
+            r#"{"type":"hugetlbfs","path":"/dev/hugepages","page_size":"4K"}"#
+        , MemoryBackendConfig::from),
+        Err(illegal("memory.backend.page_size", "4K pages can't back files on hugetlbfs"))
+    );
+    assert_eq!(
+        parse(r#"{"type":"file"}"#, MemoryBackendConfig::from),
+        Err(illegal(
+            "memory.backend.type",
+            "`file` isn't one of anonymous, memfd and hugetlbfs"
+        ))
+    );
+    // The memory size must be a multiple of the page size.
+    assert_eq!(
+        parse(
+            r#"{"size_mib":1025,"backend":{"page_size":"2M"}}"#
+        , MemoryConfig::from),
+        Err(illegal("memory.size_mib", "1025 MiB isn't a multiple of the page size"))
+    );
+}
+
+#[test]
+fn test_numa_config() {
+    let config = |numa: &str| VmConfig::from(Json::from_str(&format!(
+        concat!(
+            r#"{{"cpu":{{"count":4}},"memory":{{"size_mib":2048}},"#,
+            r#""device":[],"os":{{}},"numa":{}}}"#
+        ),
+        numa
+    )).unwrap()).map(|c| c.numa);
+    assert_eq!(
+        config(concat!(
+            r#"[{"cpus":[0,1],"size_mib":1024,"host_node":0},"#,
+            r#"{"cpus":[2,3],"size_mib":1024,"distances":[21,10]}]"#
+        )),
+        Ok(Some(vec![
+            NumaNodeConfig {
+                cpus: vec![0, 1],
+                size_mib: 1024,
+                host_node: Some(0),
+                distances: None,
+            },
+            NumaNodeConfig {
+                cpus: vec![2, 3],
+                size_mib: 1024,
+                host_node: None,
+                distances: Some(vec![21, 10]),
+            },
+        ]))
+    );
+    assert_eq!(
+        config(r#"[{"cpus":[0,1,2,3],"size_mib":1024}]"#),
+        Err(illegal("numa", "the nodes have 1024 MiB rather than memory.size_mib"))
+    );
+    assert_eq!(
+        config(r#"[{"cpus":[0,1,2],"size_mib":1024},{"cpus":[2,3],"size_mib":1024}]"#),
+        Err(illegal("numa[1].cpus", "vcpu 2 is in more than one node"))
+    );
+    assert_eq!(
+        config(r#"[{"cpus":[0,1,2],"size_mib":2048}]"#),
+        Err(illegal("numa", "vcpu 3 isn't in any node"))
+    );
+    assert_eq!(
+        config(r#"[{"cpus":[0,1,2,3],"size_mib":2048,"distances":[20]}]"#),
+        Err(illegal(
+            "numa[0].distances",
+            "[20] isn't 10 to the node itself and more to the others"
+        ))
+    );
+    assert_eq!(
+        config(r#"[{"size_mib":2048}]"#),
+        Err(missing("numa[0].cpus"))
+    );
+    assert_eq!(
+        config(r#"[{"cpus":[0,1],"size_mib":1024},{"cpus":[2,3],"size_mib":1024.5}]"#),
+        Err(illegal("numa[1].size_mib", "1024.5 isn't a positive integer"))
+    );
+    assert_eq!(
+        config(r#"[{"cpus":[0,1,2,3],"size_mib":2048,"host_node":-1}]"#),
+        Err(illegal("numa[0].host_node", "-1 isn't an integer in 0-1023"))
+    );
+    assert_eq!(
+        config(r#"[{"cpus":[0,1,2,3],"size_mib":2048,"host_node":1023}]"#),
+        Err(illegal("numa[0].host_node", "host node 1023 doesn't exist"))
+    );
+    assert_eq!(
+        config(r#"[{"cpus":[0,1,2,3],"size_mib":2048,"host_node":"0"}]"#),
+        Err(illegal("numa[0].host_node", "expected a number"))
+    );
+}
+
+#[test]
+fn test_on_reboot_config() {
+    let config = |extra: &str| VmConfig::from(Json::from_str(&format!(
+        concat!(
+            r#"{{"cpu":{{"count":1}},"memory":{{"size_mib":128}},"#,
+            r#""device":[],"os":{{}}{}}}"#
+        ),
+        extra
+    )).unwrap()).map(|c| c.on_reboot);
+    assert_eq!(config(""), Ok(RebootAction::Restart));
+    assert_eq!(config(r#","on_reboot":"exit""#), Ok(RebootAction::Exit));
+    assert_eq!(
+        config(r#","on_reboot":"halt""#),
+        Err(illegal("on_reboot", "`halt` isn't one of restart and exit"))
     );
 }
 
+#[cfg(test)]
+fn device(s: &str) -> Result<DeviceConfig> {
+    parse(s, |json, issues| DeviceConfig::from(json, 0, issues))
+}
+
+#[test]
+fn test_devconfig() {
+    assert_eq!(
+        device(r#"{"driver":"virtio-blk","source":"/dev/null"}"#),
+        Ok(DeviceConfig::Block(BlockConfig {
+            source: "/dev/null".to_string(),
+            ..Default::default()
+        }))
+    );
+    assert_eq!(
+        device(r#"{ "driver":"virtio-blk" }"#),
+        Err(missing("device[0].source"))
+    );
+    assert_eq!(
+        device(r#"{"driver":"virtio-blk","source":"/xxx/disk.raw"}"#),
+        Err(illegal("device[0].source", "`/xxx/disk.raw` doesn't exist"))
+    );
+    assert_eq!(
+        device(r#"{"driver":"virtio-gpu"}"#),
+        Err(illegal(
+            "device[0].driver",
+            concat!(
+                "`virtio-gpu` isn't one of console, virtio-blk, virtio-net, vhost-user-blk, ",
+                "vhost-user-net and vfio"
+            )
+        ))
+    );
+    assert_eq!(
+        device(r#"{}"#),
+        Err(missing("device[0].driver"))
+    );
+    assert_eq!(
+        parse(r#"{"driver":"vfio"}"#, |json, issues| DeviceConfig::from(json, 3, issues)),
+        Err(missing("device[3].source"))
+    );
+    for driver in ["console", "virtio-net", "vhost-user-net"] {
+        let json = format!(r#"{{"driver":"{}","socket":"/run/s.sock"}}"#, driver);
+        let mut issues = Issues::new(ConfigMode::Lenient);
+        let config = DeviceConfig::from(Json::from_str(&json).unwrap(), 0, &mut issues);
+        assert_eq!(issues.result(config).map(|d| d.driver()), Ok(driver));
+    }
+}
+
+#[test]
+fn test_console_config() {
+    let console = |s: &str| device(s).map(|d| match d {
+        DeviceConfig::Console(backend) => Some(backend),
+        _ => None,
+    });
+    assert_eq!(
+        console(r#"{"driver":"console","type":"tty"}"#),
+        Ok(Some(ConsoleBackend::Stdio))
+    );
+    assert_eq!(
+        console(r#"{"driver":"console"}"#),
+        Ok(Some(ConsoleBackend::Stdio))
+    );
+    assert_eq!(
+        console(r#"{"driver":"console","type":"socket","path":"/tmp/s.sock"}"#),
+        Ok(Some(ConsoleBackend::Socket("/tmp/s.sock".to_string())))
+    );
+    assert_eq!(
+        console(r#"{"driver":"console","type":"file"}"#),
+        Err(missing("device[0].path"))
+    );
+    assert_eq!(
+        console(r#"{"driver":"console","type":"vga"}"#),
+        Err(illegal(
+            "device[0].type",
+            "`vga` isn't one of tty, stdio, pty, socket and file"
+        ))
+    );
+    assert_eq!(
+        console(r#"{"driver":"virtio-net","type":"pty"}"#),
+        Err(unknown("device[0].type"))
+    );
+}
+
+#[test]
+fn test_block_config() {
+    let block = |s: &str| device(s).map(|d| match d {
+        DeviceConfig::Block(block) => Some(block),
+        _ => None,
+    });
+    assert_eq!(
+        block(concat!(
+            r#"{"driver":"virtio-blk","source":"/dev/null","readonly":true,"#,
+            r#""cache":"direct","serial":"disk-0","logical_block_size":4096,"#,
+            r#""io_engine":"thread_pool","format":"qcow2"}"#
+        )),
+        Ok(Some(BlockConfig {
+            source: "/dev/null".to_string(),
+            format: Some(ImageFormat::Qcow2),
+            readonly: true,
+            cache: CacheMode::Direct,
+            serial: Some("disk-0".to_string()),
+            logical_block_size: 4096,
+            io_engine: IoEngineKind::ThreadPool,
+        }))
+    );
+    let invalid = |key: &str, value: &str| block(&format!(
+        r#"{{"driver":"virtio-blk","source":"/dev/null","{}":{}}}"#, key, value
+    ));
+    assert_eq!(
+        invalid("format", r#""vmdk""#),
+        Err(illegal("device[0].format", "`vmdk` isn't one of raw and qcow2"))
+    );
+    assert_eq!(
+        invalid("io_engine", r#""aio""#),
+        Err(illegal("device[0].io_engine", "`aio` isn't one of io_uring and thread_pool"))
+    );
+    assert_eq!(
+        invalid("cache", r#""writethrough""#),
+        Err(illegal(
+            "device[0].cache",
+            "`writethrough` isn't one of buffered and direct"
+        ))
+    );
+    assert_eq!(
+        invalid("logical_block_size", "520"),
+        Err(illegal(
+            "device[0].logical_block_size",
+            "520 isn't one of 512, 1024, 2048 and 4096"
+        ))
+    );
+    assert!(invalid("serial", r#""012345678901234567890""#).is_err());
+    assert_eq!(
+        block(r#"{"driver":"console","cache":"direct"}"#),
+        Err(unknown("device[0].cache"))
+    );
+}
+
+#[test]
+fn test_net_config() {
+    let net = |s: &str| device(s).map(|d| match d {
+        DeviceConfig::Net(net) => Some(net),
+        _ => None,
+    });
+    assert_eq!(
+        net(concat!(
+            r#"{"driver":"virtio-net","source":"tap0","mac":"FA:16:3e:21:c0:c0","#,
+            r#""mtu":9000,"queue_pairs":4,"offload":false}"#
+        )),
+        Ok(Some(NetConfig {
+            source: Some("tap0".to_string()),
+            mac: Some(MacAddr([0xfa, 0x16, 0x3e, 0x21, 0xc0, 0xc0])),
+            mtu: Some(9000),
+            queue_pairs: 4,
+            offload: false,
+            fd: None,
+        }))
+    );
+    assert_eq!(
+        net(r#"{"driver":"virtio-net","fd":3}"#),
+        Ok(Some(NetConfig { fd: Some(3), ..Default::default() }))
+    );
+    assert_eq!(MacAddr([0xfa, 0x16, 0x3e, 0x21, 0xc0, 0x0c]).to_string(), "fa:16:3e:21:c0:0c");
+    for mac in ["fa:16:3e:21:c0", "fa:16:3e:21:c0:c0:00", "fa-16-3e-21-c0-c0", "f:16:3e:21:c0:c0c",
+        "01:16:3e:21:c0:c0", "00:00:00:00:00:00", "fa:16:3e:21:c0:+c"] {
+        assert_eq!(
+            net(&format!(r#"{{"driver":"virtio-net","mac":"{}"}}"#, mac)),
+            Err(illegal("device[0].mac", &format!(
+                "`{}` isn't a unicast MAC address, e.g. `fa:16:3e:21:c0:c0`", mac
+            )))
+        );
+    }
+    assert_eq!(
+        net(r#"{"driver":"virtio-net","mtu":67}"#),
+        Err(illegal("device[0].mtu", "67 isn't an integer in 68-65535"))
+    );
+    assert_eq!(
+        net(r#"{"driver":"virtio-net","queue_pairs":0}"#),
+        Err(illegal("device[0].queue_pairs", "0 isn't an integer in 1-256"))
+    );
+    assert_eq!(
+        net(r#"{"driver":"virtio-net","fd":3,"queue_pairs":2}"#),
+        Err(illegal(
+            "device[0].queue_pairs",
+            "2 queue pairs can't be served by a single fd"
+        ))
+    );
+    assert_eq!(
+        net(r#"{"driver":"virtio-net","fd":3,"source":"tap0"}"#),
+        Err(illegal("device[0].source", "`tap0` can't be given along with fd"))
+    );
+    assert_eq!(
+        net(r#"{"driver":"virtio-net","source":"tap0123456789abcd"}"#),
+        Err(illegal(
+            "device[0].source",
+            "`tap0123456789abcd` is longer than 15 bytes"
+        ))
+    );
+    assert_eq!(
+        net(r#"{"driver":"console","mac":"fa:16:3e:21:c0:c0"}"#),
+        Err(unknown("device[0].mac"))
+    );
+}
+
+#[test]
+fn test_vhost_user_config() {
+    assert_eq!(
+        device(r#"{"driver":"vhost-user-net","socket":"/run/vhost-net.sock"}"#),
+        Ok(DeviceConfig::VhostUser(VhostUserConfig {
+            kind: VhostUserKind::Net,
+            socket: "/run/vhost-net.sock".to_string(),
+        }))
+    );
+    assert_eq!(
+        device(r#"{"driver":"vhost-user-blk"}"#),
+        Err(missing("device[0].socket"))
+    );
+
+    // The backend maps the guest memory, which must be a shared file.
+    let vm = |memory: &str| VmConfig::from(Json::from_str(&format!(
+        concat!(
+            r#"{{"cpu":{{"count":1}},"memory":{},"os":{{}},"#,
+            r#""device":[{{"driver":"vhost-user-blk","socket":"/run/vhost-blk.sock"}}]}}"#
+        ),
+        memory
+    )).unwrap()).map(|c| c.device[0].driver());
+    assert_eq!(
+        vm(r#"{"size_mib":1024,"backend":{"type":"memfd","shared":true}}"#),
+        Ok("vhost-user-blk")
+    );
+    assert_eq!(
+        vm(r#"{"size_mib":1024,"backend":{"type":"memfd"}}"#),
+        Err(illegal("memory.backend.shared", "vhost-user devices need the memory to be shared"))
+    );
+    assert_eq!(
+        vm(r#"{"size_mib":1024}"#),
+        Err(Error::InvalidConfig(vec![
+            ConfigIssue {
+                path: "memory.backend.type".to_string(),
+                kind: ConfigIssueKind::Illegal,
+                message: "vhost-user devices need a memfd or hugetlbfs backend".to_string(),
+            },
+            ConfigIssue {
+                path: "memory.backend.shared".to_string(),
+                kind: ConfigIssueKind::Illegal,
+                message: "vhost-user devices need the memory to be shared".to_string(),
+            },
+        ]))
+    );
+}
+
+#[test]
+fn test_vfio_config() {
+    let vfio = |source: &str| device(&format!(
+        r#"{{"driver":"vfio","source":"{}"}}"#, source
+    )).map(|d| match d {
+        DeviceConfig::Vfio(vfio) => Some(vfio.bdf),
+        _ => None,
+    });
+    assert_eq!(
+        vfio("02:1f.7"),
+        Ok(Some(PciBdf { segment: 0, bus: 2, device: 0x1f, function: 7 }))
+    );
+    assert_eq!(
+        vfio("000A:ff:00.1").map(|b| b.unwrap().to_string()),
+        Ok("000a:ff:00.1".to_string())
+    );
+    for source in ["02:20.0", "02:00.8", "2:00.0", "02:00", "0:02:00.0", "x0:00.0", "0000:02:00.0.0"] {
+        assert_eq!(
+            vfio(source),
+            Err(illegal(
+                "device[0].source",
+                &format!("`{}` isn't a PCI address, e.g. `02:00.0`", source)
+            ))
+        );
+    }
+}
+
 #[test]
 fn test_os_config() {
     assert_eq!(
-        OsConfig::from(Json::from_str(
+        parse(
             concat!(
                 r#"{ "kernel":"/xx/vmlinuz", "initrd":"/xx/initrd.img","#,
                 r#""rootfs":"/xx/xxx.raw", "#,
                 r#""cmdline":"console=ttyS0 reboot=k panic=1 pci=off" }"#
             ),
-        ).unwrap()),
+            OsConfig::from
+        ),
         Ok(OsConfig {
             kernel: Some("/xx/vmlinuz".to_string()),
             initrd: Some("/xx/initrd.img".to_string()),
@@ -279,7 +1638,7 @@ fn test_os_config() {
 
     );
     assert_eq!(
-        OsConfig::from(Json::from_str("{}").unwrap()),
+        parse("{}", OsConfig::from),
         Ok(OsConfig {
             kernel: None, 
             initrd: None, 
@@ -289,26 +1648,132 @@ fn test_os_config() {
     );
 }
 
+#[test]
+fn test_unknown_config() {
+    // Replace a section of a valid configuration.
+    let config = |section: &str, value: &str, mode: ConfigMode| {
+        let mut sections = vec![
+            ("cpu", r#"{"count":1}"#),
+            ("memory", r#"{"size_mib":1024,"backend":{}}"#),
+            ("device", r#"[{"driver":"console"}]"#),
+            ("os", "{}"),
+            ("vmm", r#"{"log":{}}"#),
+        ];
+        sections.retain(|(name, _)| *name != section);
+        sections.push((section, value));
+        let json = sections.iter()
+            .map(|(name, value)| format!(r#""{}":{}"#, name, value))
+            .collect::<Vec<_>>()
+            .join(",");
+        VmConfig::from_with_mode(Json::from_str(&format!("{{{}}}", json)).unwrap(), mode)
+            .map(|_| ())
+    };
+    assert_eq!(config("vmm", "{}", ConfigMode::Strict), Ok(()));
+    for (section, value, key) in [
+        ("cpus", "2", "cpus"),
+        ("cpu", r#"{"count":1,"cores":1}"#, "cpu.cores"),
+        ("memory", r#"{"size_mib":1024,"size_mb":1024}"#, "memory.size_mb"),
+        ("memory", r#"{"size_mib":1024,"backend":{"typ":"memfd"}}"#, "memory.backend.typ"),
+        ("numa", r#"[{"cpus":[0],"size_mib":1024,"host":0}]"#, "numa[0].host"),
+        ("device", r#"[{"driver":"console","kind":"pty"}]"#, "device[0].kind"),
+        ("os", r#"{"kernel_path":"/xx/vmlinuz"}"#, "os.kernel_path"),
+        ("vmm", r#"{"logger":{}}"#, "vmm.logger"),
+        ("vmm", r#"{"log":{"file":"/var/log/shuairan.log"}}"#, "vmm.log.file"),
+    ] {
+        assert_eq!(
+            config(section, value, ConfigMode::Strict),
+            Err(unknown(key))
+        );
+        assert_eq!(config(section, value, ConfigMode::Lenient), Ok(()));
+    }
+}
+
+#[test]
+fn test_config_issues() {
+    let result = VmConfig::from(Json::from_str(concat!(
+        r#"{"cpu":{"count":0},"memory":{},"os":{"kernel_path":"/xx/vmlinuz"},"#,
+        r#""device":[{"driver":"console"},{"driver":"virtio-net","mac":"ff","mtu":9000,"#,
+        r#""speed":10}],"on_reboot":"halt","vm":{}}"#
+    )).unwrap());
+    let issues = match &result {
+        Err(Error::InvalidConfig(issues)) => issues,
+        _ => panic!("{:?}", result),
+    };
+    assert_eq!(
+        issues.iter().map(|i| (i.path.as_str(), i.kind)).collect::<Vec<_>>(),
+        vec![
+            ("cpu.count", ConfigIssueKind::Illegal),
+            ("memory.size_mib", ConfigIssueKind::Missing),
+            ("device[1].mac", ConfigIssueKind::Illegal),
+            ("device[1].speed", ConfigIssueKind::Unknown),
+            ("os.kernel_path", ConfigIssueKind::Unknown),
+            ("on_reboot", ConfigIssueKind::Illegal),
+            ("vm", ConfigIssueKind::Unknown),
+        ]
+    );
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        concat!(
+            "The given configuration is invalid:\n",
+            "    cpu.count: 0 isn't an integer in 1-8192\n",
+            "    memory.size_mib: is required\n",
+            "    device[1].mac: `ff` isn't a unicast MAC address, e.g. `fa:16:3e:21:c0:c0`\n",
+            "    device[1].speed: is unknown\n",
+            "    os.kernel_path: is unknown\n",
+            "    on_reboot: `halt` isn't one of restart and exit\n",
+            "    vm: is unknown",
+        )
+    );
+}
+
+#[test]
+fn test_config_types() {
+    assert_eq!(
+        parse(r#"{"count":"2"}"#, CpuConfig::from),
+        Err(illegal("cpu.count", "expected a number"))
+    );
+    assert_eq!(
+        device(r#"{"driver":"virtio-blk","source":"/dev/null","readonly":"yes"}"#),
+        Err(illegal("device[0].readonly", "expected a boolean"))
+    );
+    assert_eq!(
+        device(r#"{"driver":"virtio-net","offload":0}"#),
+        Err(illegal("device[0].offload", "expected a boolean"))
+    );
+    assert_eq!(
+        parse(r#"{"size_mib":1024,"backend":{"shared":"true"}}"#, MemoryConfig::from),
+        Err(illegal("memory.backend.shared", "expected a boolean"))
+    );
+    assert_eq!(
+        parse(r#"{"size_mib":1024,"backend":[]}"#, MemoryConfig::from),
+        Err(illegal("memory.backend", "expected an object"))
+    );
+}
+
 #[test]
 fn test_vm_config() {
     assert_eq!(
         VmConfig::from(Json::from_str(
             concat!(
                 r#"{"cpu":{"count":4},"memory":{"size_mib":1024},"#,
-                r#""device":[{"driver":"virtio-blk","source":"/xxx/disk.raw"}],"#,
+                r#""device":[{"driver":"virtio-blk","source":"/dev/null"}],"#,
                 r#""os":{"kernel":"/xx/vmlinuz", "#,
-                r#""cmdline":"console=ttyS0 pci=off"},"#,
+                r#""cmdline":"console=ttyS0 pci=off"},"on_reboot":"exit","#,
                 r#""vmm":{"log":{"level":"Info","path":"/var/log/shuairan.log"}}}"#
             )
         ).unwrap()),
         Ok(VmConfig {
-            cpu: CpuConfig { count: 4 },
-            memory: MemoryConfig { size_mib: 1024 },
+            cpu: CpuConfig { count: 4, affinity: None, priority: None },
+            memory: MemoryConfig { 
+                size_mib: 1024, 
+                backend: MemoryBackendConfig::default() 
+            },
+            numa: None,
             device: vec![
-                DeviceConfig {
-                    driver: "virtio-blk".to_string(),
-                    source: Some("/xxx/disk.raw".to_string())
-                }
+                DeviceConfig::Block(BlockConfig {
+                    source: "/dev/null".to_string(),
+                    ..Default::default()
+                })
             ],            
             os: OsConfig {
                 kernel: Some("/xx/vmlinuz".to_string()),
@@ -316,6 +1781,7 @@ fn test_vm_config() {
                 rootfs: None,
                 cmdline: Some("console=ttyS0 pci=off".to_string())
             },
+            on_reboot: RebootAction::Exit,
             vmm: Some(VmmConfig {
                 log: Some(LogConfig{
                     level: Some(LogLevel::Info),
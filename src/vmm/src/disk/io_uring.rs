@@ -0,0 +1,355 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use vmm_sys_util::eventfd::EventFd;
+use super::{IoEngine, IoOp};
+
+/// Offsets to mmap the rings and the submission queue entries.
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+
+const IORING_OP_READV: u8 = 1;
+const IORING_OP_WRITEV: u8 = 2;
+const IORING_OP_FSYNC: u8 = 3;
+const IORING_FSYNC_DATASYNC: u32 = 1;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_REGISTER_EVENTFD: u32 = 4;
+
+/// struct io_sqring_offsets.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct SqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+/// struct io_cqring_offsets.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct CqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+/// struct io_uring_params.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqRingOffsets,
+    cq_off: CqRingOffsets,
+}
+
+/// struct io_uring_sqe.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+/// struct io_uring_cqe.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A region shared with the kernel.
+struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        // Safe because a new mapping is created and checked.
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap { ptr: ptr as *mut u8, len })
+    }
+
+    /// The u32 at `offset`, shared with the kernel.
+    fn atomic(&self, offset: u32) -> &AtomicU32 {
+        // Safe because the kernel puts aligned u32s at the offsets it gives.
+        unsafe { &*(self.ptr.add(offset as usize) as *const AtomicU32) }
+    }
+
+    fn at<T>(&self, offset: u32) -> *mut T {
+        // Safe because offsets are given by the kernel within the mapping.
+        unsafe { self.ptr.add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // Safe because the region is mapped by us.
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+/// An engine submitting I/O through io_uring, without any crate for it.
+pub struct IoUring {
+    // The mappings are dropped before the ring is closed.
+    sq_ring: Mmap,
+    cq_ring: Mmap,
+    sqes: Mmap,
+    ring: File,
+    params: Params,
+    /// Entries pushed but not yet submitted.
+    queued: u32,
+    /// Buffers of the operations in flight, keyed by their user data.
+    in_flight: HashMap<u64, Vec<libc::iovec>>,
+    /// Registered to be signaled on completions.
+    event: EventFd,
+}
+
+// Safe because the rings are only accessed through &mut self, and the
+// buffers stay valid until the operations complete, as IoEngine requires.
+unsafe impl Send for IoUring {}
+
+impl IoUring {
+    /// Set up a ring with `entries` submission entries.
+    pub fn new(entries: u32) -> io::Result<Self> {
+        let mut params = Params::default();
+        // Safe because the kernel only writes the params.
+        let fd = unsafe {
+            libc::syscall(libc::SYS_io_uring_setup, entries, &mut params as *mut Params)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because the fd is newly created and owned by the File.
+        let ring = unsafe { File::from_raw_fd(fd as RawFd) };
+        let (sq, cq) = (&params.sq_off, &params.cq_off);
+        let sq_len = sq.array as usize + params.sq_entries as usize * 4;
+        let cq_len = cq.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * std::mem::size_of::<Sqe>();
+        let event = EventFd::new(libc::EFD_NONBLOCK)?;
+        let raw_event = event.as_raw_fd();
+        // Safe because the kernel only reads the fd.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_register, fd, IORING_REGISTER_EVENTFD,
+                &raw_event as *const RawFd, 1
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(IoUring {
+            sq_ring: Mmap::new(ring.as_raw_fd(), sq_len, IORING_OFF_SQ_RING)?,
+            cq_ring: Mmap::new(ring.as_raw_fd(), cq_len, IORING_OFF_CQ_RING)?,
+            sqes: Mmap::new(ring.as_raw_fd(), sqes_len, IORING_OFF_SQES)?,
+            ring,
+            params,
+            queued: 0,
+            in_flight: HashMap::new(),
+            event,
+        })
+    }
+
+    fn enter(&self, to_submit: u32, min_complete: u32, flags: u32) -> io::Result<u32> {
+        loop {
+            // Safe because no memory is passed.
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter, self.ring.as_raw_fd(), to_submit,
+                    min_complete, flags, ptr::null::<libc::sigset_t>(), 0
+                )
+            };
+            if ret >= 0 {
+                return Ok(ret as u32);
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+}
+
+impl IoEngine for IoUring {
+    fn push(&mut self, fd: RawFd, op: IoOp, user_data: u64) -> io::Result<()> {
+        let sq = &self.params.sq_off;
+        let head = self.sq_ring.atomic(sq.head).load(Ordering::Acquire);
+        let tail = self.sq_ring.atomic(sq.tail).load(Ordering::Relaxed);
+        if tail.wrapping_sub(head) >= self.params.sq_entries {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+        let mut sqe = Sqe { fd, user_data, ..Default::default() };
+        let iovecs = match op {
+            IoOp::Read(offset, iovecs) => {
+                (sqe.opcode, sqe.off) = (IORING_OP_READV, offset);
+                iovecs
+            },
+            IoOp::Write(offset, iovecs) => {
+                (sqe.opcode, sqe.off) = (IORING_OP_WRITEV, offset);
+                iovecs
+            },
+            IoOp::Flush => {
+                (sqe.opcode, sqe.op_flags) = (IORING_OP_FSYNC, IORING_FSYNC_DATASYNC);
+                Vec::new()
+            },
+        };
+        // The iovec array is on the heap, so it doesn't move with the Vec.
+        // fsync requires the fields to be zero.
+        if !iovecs.is_empty() {
+            sqe.addr = iovecs.as_ptr() as u64;
+            sqe.len = iovecs.len() as u32;
+        }
+        self.in_flight.insert(user_data, iovecs);
+        let index = tail & self.sq_ring.atomic(sq.ring_mask).load(Ordering::Relaxed);
+        // Safe because the entry and the slot are within the mappings, and
+        // not used by the kernel until the tail passes them.
+        unsafe {
+            ptr::write_volatile(self.sqes.at::<Sqe>(0).add(index as usize), sqe);
+            ptr::write_volatile(self.sq_ring.at::<u32>(sq.array).add(index as usize), index);
+        }
+        self.sq_ring.atomic(sq.tail).store(tail.wrapping_add(1), Ordering::Release);
+        self.queued += 1;
+        Ok(())
+    }
+
+    fn submit(&mut self) -> io::Result<()> {
+        // The kernel stops at an entry it fails to prepare, after posting
+        // its completion, so the rest is submitted again.
+        while self.queued > 0 {
+            let submitted = self.enter(self.queued, 0, 0)?;
+            if submitted == 0 {
+                break;
+            }
+            self.queued -= submitted.min(self.queued);
+        }
+        Ok(())
+    }
+
+    fn event(&self) -> &EventFd {
+        &self.event
+    }
+
+    fn completions(&mut self) -> Vec<(u64, io::Result<usize>)> {
+        let cq = &self.params.cq_off;
+        let head_ref = self.cq_ring.atomic(cq.head);
+        let mut head = head_ref.load(Ordering::Relaxed);
+        let tail = self.cq_ring.atomic(cq.tail).load(Ordering::Acquire);
+        let mask = self.cq_ring.atomic(cq.ring_mask).load(Ordering::Relaxed);
+        let cqes = self.cq_ring.at::<Cqe>(cq.cqes);
+        let mut done = Vec::new();
+        while head != tail {
+            // Safe because the entry is within the ring and owned by us
+            // until the head passes it.
+            let cqe = unsafe { ptr::read_volatile(cqes.add((head & mask) as usize)) };
+            self.in_flight.remove(&cqe.user_data);
+            done.push((cqe.user_data, match cqe.res {
+                res if res < 0 => Err(io::Error::from_raw_os_error(-res)),
+                res => Ok(res as usize),
+            }));
+            head = head.wrapping_add(1);
+        }
+        head_ref.store(head, Ordering::Release);
+        done
+    }
+}
+
+impl Drop for IoUring {
+    /// Wait for the operations in flight, whose buffers may be freed
+    /// afterwards.
+    fn drop(&mut self) {
+        let _ = self.submit();
+        while !self.in_flight.is_empty() {
+            if self.enter(0, 1, IORING_ENTER_GETEVENTS).is_err() {
+                break;
+            }
+            self.completions();
+        }
+    }
+}
+
+#[test]
+fn test_io_uring() {
+    use std::io::Write;
+    use vmm_sys_util::tempfile::TempFile;
+    use super::{iovec, run_engine};
+
+    // Hosts may forbid io_uring, which is why there is a fallback.
+    let Ok(mut ring) = IoUring::new(4) else { return };
+    let temp = TempFile::new().unwrap();
+    temp.as_file().write_all(&[0xaa; 1024]).unwrap();
+    let (mut a, mut b, mut c) = ([0x55u8; 512], [0u8; 100], [0u8; 100]);
+    let done = run_engine(&mut ring, temp.as_file().as_raw_fd(), vec![
+        IoOp::Write(0, vec![iovec(a.as_mut_ptr(), 512)]),
+        IoOp::Flush,
+        IoOp::Read(1000, vec![iovec(b.as_mut_ptr(), 100)]),
+        IoOp::Read(-2i64 as u64, vec![iovec(c.as_mut_ptr(), 100)]),
+    ]);
+    assert_eq!(done[0].1.as_ref().ok(), Some(&512));
+    assert_eq!(done[1].1.as_ref().ok(), Some(&0));
+    // Short reads are left to the caller.
+    assert_eq!(done[2].1.as_ref().ok(), Some(&24));
+    assert!(done[3].1.is_err());
+    assert!(ring.in_flight.is_empty());
+
+    // The ring is full until submitted.
+    for i in 0..4 {
+        ring.push(temp.as_file().as_raw_fd(), IoOp::Flush, i).unwrap();
+    }
+    assert!(ring.push(temp.as_file().as_raw_fd(), IoOp::Flush, 4).is_err());
+    ring.submit().unwrap();
+    ring.push(temp.as_file().as_raw_fd(), IoOp::Flush, 4).unwrap();
+}
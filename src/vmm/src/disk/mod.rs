@@ -0,0 +1,281 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+pub mod io_uring;
+pub mod qcow2;
+pub mod raw;
+pub mod thread_pool;
+
+use std::{
+    alloc::{self, Layout},
+    io,
+    os::unix::io::RawFd,
+};
+use vmm_sys_util::eventfd::EventFd;
+use crate::config::{CacheMode, ImageFormat, IoEngineKind};
+use crate::error::{Error, Result};
+use raw::RawDisk;
+
+/// Size of a sector, the unit of block device addresses.
+pub const SECTOR_SIZE: u64 = 512;
+
+/// The data of a disk, whatever the format of its image.
+pub trait Disk: Send + Sync {
+    /// Size of the disk in bytes.
+    fn size(&self) -> u64;
+    /// The file if the disk is laid out as is in it, which I/O engines can
+    /// access directly.
+    fn raw(&self) -> Option<&RawDisk>;
+    /// Fill `buf` with the bytes at `offset`.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+    /// Write `buf` at `offset`.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<()>;
+    /// Make the written data durable.
+    fn flush(&self) -> io::Result<()>;
+    /// Tell the host the range is no longer used, the data is undefined
+    /// afterwards.
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()>;
+    /// Zero the range, deallocating it if `unmap` is allowed.
+    fn write_zeroes(&self, offset: u64, len: u64, unmap: bool) -> io::Result<()>;
+}
+
+/// Open the image at `path`, detecting its format if not given.
+pub fn open(
+    path: &str,
+    format: Option<ImageFormat>,
+    readonly: bool,
+    cache: CacheMode
+) -> Result<Box<dyn Disk>> {
+    open_image(path, format, readonly, cache, 0)
+}
+
+/// Open an image which is `depth` deep in a chain of backing files.
+fn open_image(
+    path: &str,
+    format: Option<ImageFormat>,
+    readonly: bool,
+    cache: CacheMode,
+    depth: usize
+) -> Result<Box<dyn Disk>> {
+    let file = RawDisk::open(path, readonly, cache)?;
+    let format = match format {
+        Some(format) => format,
+        None => {
+            let mut magic = [0u8; 4];
+            file.read_at(0, &mut magic)
+                .map_err(|e| Error::IOError(format!("{}: {}", path, e)))?;
+            match u32::from_be_bytes(magic) {
+                qcow2::MAGIC => ImageFormat::Qcow2,
+                _ => ImageFormat::Raw,
+            }
+        },
+    };
+    Ok(match format {
+        ImageFormat::Raw => Box::new(file),
+        ImageFormat::Qcow2 => Box::new(qcow2::Qcow2::open(file, path, readonly, cache, depth)?),
+    })
+}
+
+/// An operation submitted to an I/O engine.
+///
+/// The buffers must stay valid until the operation completes.
+#[derive(Debug, Clone)]
+pub enum IoOp {
+    /// Fill the buffers with the bytes at the offset.
+    Read(u64, Vec<libc::iovec>),
+    /// Write the bytes of the buffers at the offset.
+    Write(u64, Vec<libc::iovec>),
+    /// Make the written data durable.
+    Flush,
+}
+
+/// Completes I/O operations on a file asynchronously, and possibly out of
+/// order.
+pub trait IoEngine: Send {
+    /// Queue an operation on `fd`, tagged by `user_data`.
+    fn push(&mut self, fd: RawFd, op: IoOp, user_data: u64) -> io::Result<()>;
+    /// Start the queued operations.
+    fn submit(&mut self) -> io::Result<()>;
+    /// Signaled when operations complete.
+    fn event(&self) -> &EventFd;
+    /// Take the completed operations as (user_data, bytes transferred).
+    fn completions(&mut self) -> Vec<(u64, io::Result<usize>)>;
+}
+
+/// Create an engine completing up to `depth` operations at a time.
+///
+/// io_uring falls back to the thread pool if the host doesn't support it.
+pub fn create_engine(kind: IoEngineKind, depth: u32) -> io::Result<Box<dyn IoEngine>> {
+    if kind == IoEngineKind::IoUring {
+        if let Ok(ring) = io_uring::IoUring::new(depth) {
+            return Ok(Box::new(ring));
+        }
+    }
+    Ok(Box::new(thread_pool::ThreadPool::new()?))
+}
+
+/// A zeroed buffer aligned for `O_DIRECT`, used for bouncing guest buffers
+/// which aren't aligned.
+pub struct AlignedBuf {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+// Safe because the buffer is owned and only accessed through &self/&mut self.
+unsafe impl Send for AlignedBuf {}
+
+impl AlignedBuf {
+    /// Allocate `len` zeroed bytes aligned to `align`, a power of two.
+    pub fn new(len: usize, align: usize) -> Self {
+        let layout = Layout::from_size_align(len.max(1), align).unwrap();
+        // Safe because the layout isn't empty.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        AlignedBuf { ptr, layout }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        // Safe because the buffer is allocated with this size.
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        // Safe because the buffer is allocated with this size.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+
+    /// The first `len` bytes as a buffer for I/O.
+    pub fn iovec(&mut self, len: usize) -> libc::iovec {
+        iovec(self.ptr, len.min(self.layout.size()))
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        // Safe because the buffer is allocated with this layout.
+        unsafe { alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+/// Describe `len` bytes at `base` for I/O.
+pub fn iovec(base: *mut u8, len: usize) -> libc::iovec {
+    libc::iovec { iov_base: base as *mut libc::c_void, iov_len: len }
+}
+
+/// preadv/pwritev all the buffers, retrying short transfers.
+///
+/// The buffers must be valid, e.g. in the guest memory.
+pub fn transfer(fd: RawFd, mut offset: u64, iovecs: &[libc::iovec], write: bool) -> io::Result<usize> {
+    let mut iovecs = iovecs.to_vec();
+    let mut pending = &mut iovecs[..];
+    let mut done = 0;
+    while !pending.is_empty() {
+        let count = pending.len().min(libc::UIO_MAXIOV as usize) as libc::c_int;
+        // Safe because the buffers are valid as the caller promises.
+        let ret = unsafe {
+            match write {
+                true => libc::pwritev(fd, pending.as_ptr(), count, offset as i64),
+                false => libc::preadv(fd, pending.as_ptr(), count, offset as i64),
+            }
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        if ret == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        offset += ret as u64;
+        done += ret as usize;
+        pending = advance(pending, ret as usize);
+    }
+    Ok(done)
+}
+
+/// Skip `n` transferred bytes of the buffers.
+pub fn advance(iovecs: &mut [libc::iovec], mut n: usize) -> &mut [libc::iovec] {
+    let mut skip = 0;
+    for v in iovecs.iter_mut() {
+        if n < v.iov_len {
+            v.iov_base = (v.iov_base as usize + n) as *mut libc::c_void;
+            v.iov_len -= n;
+            break;
+        }
+        n -= v.iov_len;
+        skip += 1;
+    }
+    &mut iovecs[skip..]
+}
+
+/// Copy `src` into the buffers, which must be valid for writes.
+pub fn scatter(mut src: &[u8], iovecs: &[libc::iovec]) {
+    for v in iovecs {
+        let n = v.iov_len.min(src.len());
+        // Safe because the buffers are valid for writes of their length.
+        unsafe { std::ptr::copy_nonoverlapping(src.as_ptr(), v.iov_base as *mut u8, n) };
+        src = &src[n..];
+    }
+}
+
+/// Copy the buffers, which must be valid for reads, into `dst`.
+pub fn gather(iovecs: &[libc::iovec], mut dst: &mut [u8]) {
+    for v in iovecs {
+        let n = v.iov_len.min(dst.len());
+        // Safe because the buffers are valid for reads of their length.
+        unsafe { std::ptr::copy_nonoverlapping(v.iov_base as *const u8, dst.as_mut_ptr(), n) };
+        dst = &mut dst[n..];
+    }
+}
+
+/// Run the engine over `ops` on `fd` and wait for all of them.
+#[cfg(test)]
+pub fn run_engine(engine: &mut dyn IoEngine, fd: RawFd, ops: Vec<IoOp>) -> Vec<(u64, io::Result<usize>)> {
+    use std::os::unix::io::AsRawFd;
+
+    let count = ops.len();
+    for (i, op) in ops.into_iter().enumerate() {
+        engine.push(fd, op, i as u64).unwrap();
+    }
+    engine.submit().unwrap();
+    let mut done = Vec::new();
+    while done.len() < count {
+        let mut fds = [libc::pollfd { fd: engine.event().as_raw_fd(), events: libc::POLLIN, revents: 0 }];
+        // Safe because the pollfd is valid.
+        unsafe { libc::poll(fds.as_mut_ptr(), 1, 1000) };
+        let _ = engine.event().read();
+        done.extend(engine.completions());
+    }
+    done.sort_by_key(|(tag, _)| *tag);
+    done
+}
+
+#[test]
+fn test_transfer() {
+    use std::{io::Write, os::unix::io::AsRawFd};
+    use vmm_sys_util::tempfile::TempFile;
+
+    let temp = TempFile::new().unwrap();
+    temp.as_file().write_all(&[0xaa; 1024]).unwrap();
+    let fd = temp.as_file().as_raw_fd();
+    // Scattered buffers are read and written as a whole.
+    let (mut a, mut b) = ([0u8; 3], [0u8; 509]);
+    let iovecs = [iovec(a.as_mut_ptr(), 3), iovec(b.as_mut_ptr(), 509)];
+    assert_eq!(transfer(fd, 512, &iovecs, false).ok(), Some(512));
+    assert!(a.iter().chain(b.iter()).all(|&x| x == 0xaa));
+    let mut c = [1u8; 100];
+    assert_eq!(transfer(fd, 1000, &[iovec(c.as_mut_ptr(), 100)], true).ok(), Some(100));
+    // Reads beyond the end fail.
+    assert!(transfer(fd, 1100, &[iovec(c.as_mut_ptr(), 100)], false).is_err());
+
+    let mut buf = [0u8; 8];
+    scatter(&[1, 2, 3], &[iovec(buf.as_mut_ptr(), 2), iovec(buf[4..].as_mut_ptr(), 2)]);
+    assert_eq!(buf, [1, 2, 0, 0, 3, 0, 0, 0]);
+    let mut out = [0u8; 3];
+    gather(&[iovec(buf.as_mut_ptr(), 1), iovec(buf[4..].as_mut_ptr(), 2)], &mut out);
+    assert_eq!(out, [1, 3, 0]);
+}
@@ -0,0 +1,691 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    sync::Mutex,
+};
+use crate::config::{CacheMode, ImageFormat};
+use crate::error::{Error, Result};
+use super::{Disk, SECTOR_SIZE, open_image, raw::RawDisk};
+
+/// "QFI\xfb", the start of qcow2 images.
+pub const MAGIC: u32 = 0x5146_49fb;
+
+/// Length of the version 2 header, which version 3 extends.
+const HEADER_V2_LEN: usize = 72;
+const HEADER_V3_LEN: usize = 104;
+
+/// Incompatible features, the others aren't supported.
+const INCOMPAT_DIRTY: u64 = 1 << 0;
+const INCOMPAT_CORRUPT: u64 = 1 << 1;
+const INCOMPAT_COMPRESSION_TYPE: u64 = 1 << 3;
+
+/// Types of header extensions.
+const EXT_END: u32 = 0;
+const EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+/// Bits of L1 and L2 entries.
+const ENTRY_COPIED: u64 = 1 << 63;
+const ENTRY_COMPRESSED: u64 = 1 << 62;
+const ENTRY_ZERO: u64 = 1;
+const ENTRY_OFFSET: u64 = 0x00ff_ffff_ffff_fe00;
+
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+/// Limits of the tables kept in memory, the same as qemu's.
+const MAX_L1_BYTES: u64 = 32 << 20;
+const MAX_REFCOUNT_TABLE_BYTES: u64 = 8 << 20;
+/// Backing files chained deeper are refused, which also stops loops.
+const MAX_BACKING_DEPTH: usize = 16;
+const MAX_BACKING_NAME: usize = 1023;
+/// L2 tables cached in memory.
+const L2_CACHE_SIZE: usize = 64;
+
+fn be32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn be64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn corrupt(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("corrupt qcow2 image, {}", what))
+}
+
+/// Where the data of a guest cluster is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Cluster {
+    /// Not in the image, read from the backing file if any.
+    Unallocated,
+    /// Reads as zeros, with a host cluster kept for it unless the offset
+    /// is 0.
+    Zero(u64),
+    /// At the offset of a host cluster.
+    Data(u64),
+    /// Compressed in the bytes at the offset, of the length.
+    Compressed(u64, usize),
+}
+
+/// The state of an image, behind the lock of `Qcow2`.
+struct Inner {
+    file: RawDisk,
+    backing: Option<Box<dyn Disk>>,
+    version: u32,
+    cluster_bits: u32,
+    l1_offset: u64,
+    l1: Vec<u64>,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    refcount_order: u32,
+    /// L2 tables by their offset, written through.
+    l2_cache: HashMap<u64, Vec<u64>>,
+    /// Where clusters are allocated, the end of the file.
+    end: u64,
+}
+
+impl Inner {
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Indexes in the L1 and L2 tables of the guest cluster at `offset`.
+    fn indexes(&self, offset: u64) -> (usize, usize) {
+        let cluster = offset >> self.cluster_bits;
+        let l2_bits = self.cluster_bits - 3;
+        ((cluster >> l2_bits) as usize, (cluster & ((1 << l2_bits) - 1)) as usize)
+    }
+
+    /// Call `f` with the offset and the length of each piece of the range
+    /// within a guest cluster.
+    fn for_each_cluster<F>(&mut self, mut offset: u64, len: u64, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&mut Self, u64, usize) -> io::Result<()>,
+    {
+        let end = offset + len;
+        while offset < end {
+            let n = (self.cluster_size() - offset % self.cluster_size()).min(end - offset);
+            f(self, offset, n as usize)?;
+            offset += n;
+        }
+        Ok(())
+    }
+
+    /// The L2 table at `offset`.
+    fn l2_table(&mut self, offset: u64) -> io::Result<&mut Vec<u64>> {
+        if !offset.is_multiple_of(self.cluster_size()) {
+            return Err(corrupt("unaligned L2 table"));
+        }
+        if !self.l2_cache.contains_key(&offset) {
+            let mut buf = vec![0u8; self.cluster_size() as usize];
+            self.file.read_at(offset, &mut buf)?;
+            if self.l2_cache.len() >= L2_CACHE_SIZE {
+                let evicted = *self.l2_cache.keys().next().unwrap();
+                self.l2_cache.remove(&evicted);
+            }
+            let table = (0..buf.len()).step_by(8).map(|i| be64(&buf, i)).collect();
+            self.l2_cache.insert(offset, table);
+        }
+        Ok(self.l2_cache.get_mut(&offset).unwrap())
+    }
+
+    /// Find the data of the guest cluster at `offset`.
+    fn lookup(&mut self, offset: u64) -> io::Result<Cluster> {
+        let (l1_index, l2_index) = self.indexes(offset);
+        let l2_offset = self.l1[l1_index] & ENTRY_OFFSET;
+        if l2_offset == 0 {
+            return Ok(Cluster::Unallocated);
+        }
+        let entry = self.l2_table(l2_offset)?[l2_index];
+        if entry & ENTRY_COMPRESSED != 0 {
+            let shift = 62 - (self.cluster_bits - 8);
+            let host = entry & ((1 << shift) - 1);
+            let sectors = ((entry >> shift) & ((1 << (self.cluster_bits - 8)) - 1)) + 1;
+            let len = sectors * SECTOR_SIZE - host % SECTOR_SIZE;
+            return Ok(Cluster::Compressed(host, len as usize));
+        }
+        let host = entry & ENTRY_OFFSET;
+        if !host.is_multiple_of(self.cluster_size()) {
+            return Err(corrupt("unaligned data cluster"));
+        }
+        // Version 2 has no zero clusters, the bit is reserved.
+        Ok(match (self.version >= 3 && entry & ENTRY_ZERO != 0, host) {
+            (true, host) => Cluster::Zero(host),
+            (false, 0) => Cluster::Unallocated,
+            (false, host) => Cluster::Data(host),
+        })
+    }
+
+    /// Fill `buf` with the bytes at `offset`, within a guest cluster.
+    fn read_cluster(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let within = (offset % self.cluster_size()) as usize;
+        match self.lookup(offset)? {
+            Cluster::Unallocated => self.read_backing(offset, buf),
+            Cluster::Zero(_) => {
+                buf.fill(0);
+                Ok(())
+            },
+            Cluster::Data(host) => self.file.read_at(host + within as u64, buf),
+            Cluster::Compressed(host, len) => {
+                let data = self.decompress(host, len)?;
+                buf.copy_from_slice(&data[within..within + buf.len()]);
+                Ok(())
+            },
+        }
+    }
+
+    /// Read the backing file, which reads as zeros if missing or beyond
+    /// its end.
+    fn read_backing(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        buf.fill(0);
+        if let Some(backing) = self.backing.as_ref() {
+            let end = backing.size().min(offset + buf.len() as u64);
+            if offset < end {
+                backing.read_at(offset, &mut buf[..(end - offset) as usize])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Inflate a compressed cluster.
+    fn decompress(&self, host: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut compressed = vec![0u8; len];
+        self.file.read_at(host, &mut compressed)?;
+        // The data is padded to sectors, which inflating ignores.
+        let size = self.cluster_size() as usize;
+        match miniz_oxide::inflate::decompress_to_vec_with_limit(&compressed, size) {
+            Ok(data) if data.len() == size => Ok(data),
+            _ => Err(corrupt("bad compressed cluster")),
+        }
+    }
+
+    /// Write `buf` at `offset`, within a guest cluster, which is allocated
+    /// unless it's a data cluster already.
+    fn write_cluster(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let within = (offset % self.cluster_size()) as usize;
+        let old = self.lookup(offset)?;
+        if let Cluster::Data(host) = old {
+            return self.file.write_at(host + within as u64, buf);
+        }
+        // The rest of the cluster reads the same as before.
+        let start = offset - within as u64;
+        let mut data = vec![0u8; self.cluster_size() as usize];
+        if buf.len() < data.len() {
+            self.read_cluster(start, &mut data)?;
+        }
+        data[within..within + buf.len()].copy_from_slice(buf);
+        let host = match old {
+            Cluster::Zero(host) if host != 0 => host,
+            _ => self.allocate()?,
+        };
+        self.file.write_at(host, &data)?;
+        self.set_l2_entry(start, host | ENTRY_COPIED)?;
+        match old {
+            Cluster::Compressed(host, len) => self.free_compressed(host, len),
+            _ => Ok(()),
+        }
+    }
+
+    /// Make the whole guest cluster at `offset` read as zeros if `zero`,
+    /// otherwise unallocate it. The host cluster of a zero cluster is kept
+    /// for later writes if `keep`.
+    fn clear_cluster(&mut self, offset: u64, zero: bool, keep: bool) -> io::Result<()> {
+        let old = self.lookup(offset)?;
+        let host = match old {
+            Cluster::Data(host) | Cluster::Zero(host) => host,
+            _ => 0,
+        };
+        let keep = zero && keep && host != 0;
+        match old {
+            Cluster::Unallocated if !zero || self.backing.is_none() => return Ok(()),
+            Cluster::Zero(_) if zero && (keep || host == 0) => return Ok(()),
+            _ => {},
+        }
+        let entry = match (zero, keep) {
+            (true, true) => host | ENTRY_COPIED | ENTRY_ZERO,
+            (true, false) => ENTRY_ZERO,
+            (false, _) => 0,
+        };
+        self.set_l2_entry(offset, entry)?;
+        match old {
+            Cluster::Compressed(host, len) => self.free_compressed(host, len),
+            _ if host != 0 && !keep => self.free(host),
+            _ => Ok(()),
+        }
+    }
+
+    /// Point the guest cluster at `offset` to `entry`, allocating its L2
+    /// table if needed.
+    fn set_l2_entry(&mut self, offset: u64, entry: u64) -> io::Result<()> {
+        let (l1_index, l2_index) = self.indexes(offset);
+        let mut l2_offset = self.l1[l1_index] & ENTRY_OFFSET;
+        if l2_offset == 0 {
+            l2_offset = self.allocate()?;
+            self.file.write_at(l2_offset, &vec![0u8; self.cluster_size() as usize])?;
+            self.write_entry(self.l1_offset, l1_index, l2_offset | ENTRY_COPIED)?;
+            self.l1[l1_index] = l2_offset | ENTRY_COPIED;
+        }
+        self.write_entry(l2_offset, l2_index, entry)?;
+        self.l2_table(l2_offset)?[l2_index] = entry;
+        Ok(())
+    }
+
+    /// Write the entry at `index` of the table at `table`.
+    fn write_entry(&self, table: u64, index: usize, entry: u64) -> io::Result<()> {
+        self.file.write_at(table + index as u64 * 8, &entry.to_be_bytes())
+    }
+
+    /// Allocate a host cluster at the end of the file.
+    fn allocate(&mut self) -> io::Result<u64> {
+        let host = self.end;
+        self.end += self.cluster_size();
+        self.add_refcount(host, 1)?;
+        Ok(host)
+    }
+
+    /// Drop a reference to the host cluster at `host`, whose space is
+    /// returned to the host once unused.
+    fn free(&mut self, host: u64) -> io::Result<()> {
+        if self.add_refcount(host, -1)? == 0 {
+            self.file.discard(host, self.cluster_size())?;
+        }
+        Ok(())
+    }
+
+    /// Drop a reference to each host cluster holding compressed data.
+    fn free_compressed(&mut self, host: u64, len: usize) -> io::Result<()> {
+        let first = host >> self.cluster_bits;
+        let last = (host + len as u64 - 1) >> self.cluster_bits;
+        for cluster in first..=last {
+            self.free(cluster << self.cluster_bits)?;
+        }
+        Ok(())
+    }
+
+    /// Add `delta` to the refcount of the host cluster at `host`, returns
+    /// the new refcount. A delta of 0 only reads it.
+    fn add_refcount(&mut self, host: u64, delta: i64) -> io::Result<u64> {
+        let width = 1usize << (self.refcount_order - 3);
+        let per_block = self.cluster_size() / width as u64;
+        let cluster = host >> self.cluster_bits;
+        let table_index = (cluster / per_block) as usize;
+        if table_index >= self.refcount_table.len() {
+            return Err(io::Error::new(io::ErrorKind::StorageFull, "qcow2 refcount table is full"));
+        }
+        let mut block = self.refcount_table[table_index] & ENTRY_OFFSET;
+        if block == 0 {
+            // The new block is counted by itself, or by another block.
+            block = self.end;
+            self.end += self.cluster_size();
+            self.file.write_at(block, &vec![0u8; self.cluster_size() as usize])?;
+            self.write_entry(self.refcount_table_offset, table_index, block)?;
+            self.refcount_table[table_index] = block;
+            self.add_refcount(block, 1)?;
+        } else if !block.is_multiple_of(self.cluster_size()) {
+            return Err(corrupt("unaligned refcount block"));
+        }
+        let at = block + cluster % per_block * width as u64;
+        let mut bytes = [0u8; 8];
+        self.file.read_at(at, &mut bytes[8 - width..])?;
+        let max = u64::MAX >> (64 - width * 8);
+        let refcount = u64::from_be_bytes(bytes)
+            .checked_add_signed(delta)
+            .filter(|&v| v <= max)
+            .ok_or_else(|| corrupt("bad refcount"))?;
+        if delta != 0 {
+            self.file.write_at(at, &refcount.to_be_bytes()[8 - width..])?;
+        }
+        Ok(refcount)
+    }
+}
+
+/// A qcow2 image, versions 2 and 3.
+///
+/// Clusters are allocated at the end of the file, and the metadata is
+/// written through once the data is written, so a crash only leaks
+/// clusters. Compressed clusters are read, and rewritten uncompressed.
+/// Images with internal snapshots are only opened read-only.
+pub struct Qcow2 {
+    /// Size of the disk in bytes, rounded down to sectors.
+    size: u64,
+    readonly: bool,
+    inner: Mutex<Inner>,
+}
+
+impl Qcow2 {
+    /// Open the image in `file` at `path`, which is `depth` deep in a
+    /// chain of backing files.
+    pub fn open(
+        file: RawDisk,
+        path: &str,
+        readonly: bool,
+        cache: CacheMode,
+        depth: usize
+    ) -> Result<Self> {
+        let invalid = |s: &str| Error::ImageError(format!("{}: {}", path, s));
+        let io_error = |e: io::Error| Error::IOError(format!("{}: {}", path, e));
+        let mut header = vec![0u8; HEADER_V3_LEN];
+        file.read_at(0, &mut header).map_err(io_error)?;
+        if be32(&header, 0) != MAGIC {
+            return Err(invalid("not a qcow2 image"));
+        }
+        let version = be32(&header, 4);
+        if version != 2 && version != 3 {
+            return Err(invalid(&format!("unsupported version {}", version)));
+        }
+        let cluster_bits = be32(&header, 20);
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+            return Err(invalid(&format!("unsupported cluster bits {}", cluster_bits)));
+        }
+        let cluster_size = 1u64 << cluster_bits;
+        // The header and its extensions are within the first cluster.
+        let mut header = vec![0u8; cluster_size as usize];
+        file.read_at(0, &mut header).map_err(io_error)?;
+        let (incompatible, refcount_order, header_len) = match version {
+            2 => (0, 4, HEADER_V2_LEN),
+            _ => (be64(&header, 72), be32(&header, 96), be32(&header, 100) as usize),
+        };
+        if version == 3 && !(HEADER_V3_LEN..=cluster_size as usize).contains(&header_len) {
+            return Err(invalid(&format!("bad header length {}", header_len)));
+        }
+        let supported = INCOMPAT_DIRTY | INCOMPAT_CORRUPT | INCOMPAT_COMPRESSION_TYPE;
+        if incompatible & !supported != 0 {
+            return Err(invalid(&format!("unsupported features {:#x}", incompatible & !supported)));
+        }
+        if header_len > HEADER_V3_LEN && header[HEADER_V3_LEN] != 0 {
+            return Err(invalid("only zlib compression is supported"));
+        }
+        if be32(&header, 32) != 0 {
+            return Err(invalid("encryption is unsupported"));
+        }
+        if !(3..=6).contains(&refcount_order) {
+            return Err(invalid(&format!("unsupported refcount order {}", refcount_order)));
+        }
+        if !readonly && incompatible & (INCOMPAT_DIRTY | INCOMPAT_CORRUPT) != 0 {
+            return Err(invalid("the image needs repairing, e.g. by qemu-img check -r all"));
+        }
+        if !readonly && be32(&header, 60) != 0 {
+            return Err(invalid("images with internal snapshots can only be read-only"));
+        }
+
+        let size = be64(&header, 24);
+        let l1_size = be32(&header, 36) as u64;
+        let l1_offset = be64(&header, 40);
+        let refcount_table_offset = be64(&header, 48);
+        let refcount_table_bytes = be32(&header, 56) as u64 * cluster_size;
+        let l2_coverage = cluster_size / 8 * cluster_size;
+        if l1_size * 8 > MAX_L1_BYTES || size.div_ceil(l2_coverage) > l1_size {
+            return Err(invalid(&format!("bad L1 table size {}", l1_size)));
+        }
+        if refcount_table_bytes > MAX_REFCOUNT_TABLE_BYTES {
+            return Err(invalid("the refcount table is too large"));
+        }
+        if !l1_offset.is_multiple_of(cluster_size)
+            || !refcount_table_offset.is_multiple_of(cluster_size) {
+            return Err(invalid("unaligned tables"));
+        }
+        let read_table = |offset: u64, bytes: u64| -> Result<Vec<u64>> {
+            let mut buf = vec![0u8; bytes as usize];
+            file.read_at(offset, &mut buf).map_err(io_error)?;
+            Ok((0..buf.len()).step_by(8).map(|i| be64(&buf, i)).collect())
+        };
+        let l1 = read_table(l1_offset, l1_size * 8)?;
+        let refcount_table = read_table(refcount_table_offset, refcount_table_bytes)?;
+
+        let backing = match be64(&header, 8) {
+            0 => None,
+            offset => {
+                let len = be32(&header, 16) as usize;
+                let name = header.get(offset as usize..)
+                    .and_then(|name| name.get(..len))
+                    .filter(|_| len <= MAX_BACKING_NAME)
+                    .and_then(|name| std::str::from_utf8(name).ok())
+                    .ok_or_else(|| invalid("bad backing file name"))?;
+                if depth >= MAX_BACKING_DEPTH {
+                    return Err(invalid("too many backing files"));
+                }
+                // Relative names are relative to the image.
+                let backing_path = Path::new(path).parent().unwrap_or(Path::new("")).join(name);
+                let format = Self::backing_format(&header, header_len)
+                    .map_err(|s| invalid(&s))?;
+                Some(open_image(backing_path.to_str().unwrap(), format, true, cache, depth + 1)?)
+            },
+        };
+        let end = file.file_len().map_err(io_error)?.div_ceil(cluster_size) * cluster_size;
+        Ok(Qcow2 {
+            size: size / SECTOR_SIZE * SECTOR_SIZE,
+            readonly,
+            inner: Mutex::new(Inner {
+                file,
+                backing,
+                version,
+                cluster_bits,
+                l1_offset,
+                l1,
+                refcount_table_offset,
+                refcount_table,
+                refcount_order,
+                l2_cache: HashMap::new(),
+                end,
+            }),
+        })
+    }
+
+    /// The format of the backing file in the header extensions after
+    /// `offset`, `None` if it's to be detected.
+    fn backing_format(header: &[u8], mut offset: usize) -> std::result::Result<Option<ImageFormat>, String> {
+        while offset + 8 <= header.len() {
+            let (ext, len) = (be32(header, offset), be32(header, offset + 4) as usize);
+            let data = header.get(offset + 8..offset + 8 + len)
+                .ok_or_else(|| "bad header extension".to_string())?;
+            match ext {
+                EXT_END => break,
+                EXT_BACKING_FORMAT => return match data {
+                    b"raw" => Ok(Some(ImageFormat::Raw)),
+                    b"qcow2" => Ok(Some(ImageFormat::Qcow2)),
+                    _ => Err(format!("unsupported backing format {}", String::from_utf8_lossy(data))),
+                },
+                _ => {},
+            }
+            // Extensions are padded to 8 bytes.
+            offset += 8 + len.next_multiple_of(8);
+        }
+        Ok(None)
+    }
+
+    /// Fail unless `len` bytes at `offset` are within the disk.
+    fn check_range(&self, offset: u64, len: u64) -> io::Result<()> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(io::ErrorKind::InvalidInput.into()),
+        }
+    }
+
+    fn check_writable(&self, offset: u64, len: u64) -> io::Result<()> {
+        if self.readonly {
+            return Err(io::Error::from_raw_os_error(libc::EROFS));
+        }
+        self.check_range(offset, len)
+    }
+}
+
+impl Disk for Qcow2 {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn raw(&self) -> Option<&RawDisk> {
+        None
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.check_range(offset, buf.len() as u64)?;
+        let mut done = 0;
+        self.inner.lock().unwrap().for_each_cluster(offset, buf.len() as u64, |inner, pos, n| {
+            inner.read_cluster(pos, &mut buf[done..done + n])?;
+            done += n;
+            Ok(())
+        })
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.check_writable(offset, buf.len() as u64)?;
+        let mut done = 0;
+        self.inner.lock().unwrap().for_each_cluster(offset, buf.len() as u64, |inner, pos, n| {
+            inner.write_cluster(pos, &buf[done..done + n])?;
+            done += n;
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.lock().unwrap().file.flush()
+    }
+
+    /// Whole clusters are unallocated, the rest is left as is.
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.check_writable(offset, len)?;
+        self.inner.lock().unwrap().for_each_cluster(offset, len, |inner, pos, n| {
+            match n as u64 == inner.cluster_size() {
+                true => inner.clear_cluster(pos, false, false),
+                false => Ok(()),
+            }
+        })
+    }
+
+    /// Whole clusters are made zero clusters with version 3, the rest is
+    /// written with zeros.
+    fn write_zeroes(&self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        self.check_writable(offset, len)?;
+        let mut inner = self.inner.lock().unwrap();
+        let zeros = vec![0u8; inner.cluster_size() as usize];
+        inner.for_each_cluster(offset, len, |inner, pos, n| {
+            match n as u64 == inner.cluster_size() && inner.version >= 3 {
+                true => inner.clear_cluster(pos, true, !unmap),
+                false => inner.write_cluster(pos, &zeros[..n]),
+            }
+        })
+    }
+}
+
+/// Create an empty version 3 image of `size` bytes with 4 KiB clusters,
+/// as `qemu-img create` does.
+#[cfg(test)]
+pub fn create(path: &str, size: u64, backing: Option<&str>) {
+    let cluster_size = 4096;
+    let mut image = vec![0u8; 4 * cluster_size];
+    let mut put = |offset: usize, bytes: &[u8]| {
+        image[offset..offset + bytes.len()].copy_from_slice(bytes)
+    };
+    put(0, &MAGIC.to_be_bytes());
+    put(4, &3u32.to_be_bytes());
+    if let Some(backing) = backing {
+        // After the header and the end of the extensions.
+        put(8, &(HEADER_V3_LEN as u64 + 8).to_be_bytes());
+        put(16, &(backing.len() as u32).to_be_bytes());
+        put(HEADER_V3_LEN + 8, backing.as_bytes());
+    }
+    put(20, &12u32.to_be_bytes());
+    put(24, &size.to_be_bytes());
+    // The refcount table, a refcount block and the L1 table follow.
+    put(36, &(size.div_ceil(512 * 4096) as u32).to_be_bytes());
+    put(40, &(3 * cluster_size as u64).to_be_bytes());
+    put(48, &(cluster_size as u64).to_be_bytes());
+    put(56, &1u32.to_be_bytes());
+    put(96, &4u32.to_be_bytes());
+    put(100, &(HEADER_V3_LEN as u32).to_be_bytes());
+    put(cluster_size, &(2 * cluster_size as u64).to_be_bytes());
+    for i in 0..4 {
+        put(2 * cluster_size + i * 2, &1u16.to_be_bytes());
+    }
+    std::fs::write(path, image).unwrap();
+}
+
+#[test]
+fn test_qcow2() {
+    use std::{io::Write, os::unix::fs::FileExt};
+    use vmm_sys_util::tempfile::TempFile;
+    use super::open;
+
+    let backing = TempFile::new().unwrap();
+    backing.as_file().write_all(&[0xaa; 64 << 10]).unwrap();
+    let temp = TempFile::new().unwrap();
+    let path = temp.as_path().to_str().unwrap().to_string();
+    // The backing file is relative to the image.
+    let backing_name = backing.as_path().file_name().unwrap().to_str().unwrap();
+    create(&path, 1 << 20, Some(backing_name));
+
+    let disk = open(&path, None, false, CacheMode::Buffered).unwrap();
+    assert!(disk.raw().is_none());
+    assert_eq!(disk.size(), 1 << 20);
+    let mut buf = vec![0u8; 8192];
+    // Unallocated clusters read from the backing file, zeros beyond it.
+    disk.read_at((64 << 10) - 4096, &mut buf).unwrap();
+    assert!(buf[..4096].iter().all(|&b| b == 0xaa) && buf[4096..].iter().all(|&b| b == 0));
+
+    // Writing a part of a cluster keeps the rest of it.
+    disk.write_at(4096 + 512, &[0x55; 1024]).unwrap();
+    disk.read_at(4096, &mut buf[..4096]).unwrap();
+    assert_eq!((buf[511], buf[512], buf[1535], buf[1536]), (0xaa, 0x55, 0x55, 0xaa));
+    assert_eq!(disk.flush().ok(), Some(()));
+    // Zero clusters hide the backing file, discarded clusters don't.
+    disk.write_zeroes(4096, 4096, false).unwrap();
+    disk.read_at(4096, &mut buf[..4096]).unwrap();
+    assert!(buf[..4096].iter().all(|&b| b == 0));
+    disk.write_zeroes(8192, 4096, true).unwrap();
+    disk.discard(4096, 8192).unwrap();
+    disk.read_at(4096, &mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 0xaa));
+    disk.write_zeroes(0, 100, true).unwrap();
+    disk.write_at((1 << 20) - 512, &[0x11; 512]).unwrap();
+    assert!(disk.write_at(1 << 20, &[0x11; 512]).is_err());
+    drop(disk);
+
+    // The data is persisted, and the backing file is read-only.
+    let file = RawDisk::open(&path, true, CacheMode::Buffered).unwrap();
+    let image = Qcow2::open(file, &path, true, CacheMode::Buffered, 0).unwrap();
+    image.read_at(0, &mut buf).unwrap();
+    assert!(buf[..100].iter().all(|&b| b == 0) && buf[100..].iter().all(|&b| b == 0xaa));
+    image.read_at((1 << 20) - 512, &mut buf[..512]).unwrap();
+    assert!(buf[..512].iter().all(|&b| b == 0x11));
+    assert!(image.write_at(0, &[0; 512]).is_err());
+    let mut inner = image.inner.lock().unwrap();
+    assert!(inner.backing.as_ref().unwrap().raw().is_some());
+    // The header, the tables, an L2 table and 2 data clusters are used.
+    assert!(matches!(inner.lookup(0), Ok(Cluster::Data(_))));
+    assert_eq!(inner.lookup(4096).ok(), Some(Cluster::Unallocated));
+    let used = (0..inner.end).step_by(4096).filter(|&c| inner.add_refcount(c, 0).unwrap() > 0);
+    assert_eq!(used.count(), 7);
+    drop(inner);
+
+    // Compressed clusters are inflated, and rewritten uncompressed.
+    create(&path, 1 << 20, None);
+    let file = RawDisk::open(&path, false, CacheMode::Buffered).unwrap();
+    let image = Qcow2::open(file, &path, false, CacheMode::Buffered, 0).unwrap();
+    let data: Vec<u8> = (0..4096).map(|i| (i / 16) as u8).collect();
+    let compressed = miniz_oxide::deflate::compress_to_vec(&data, 6);
+    let mut inner = image.inner.lock().unwrap();
+    let host = inner.allocate().unwrap() + 100;
+    temp.as_file().write_all_at(&compressed, host).unwrap();
+    let sectors = (100 + compressed.len() as u64).div_ceil(512);
+    inner.set_l2_entry(0, ENTRY_COMPRESSED | (sectors - 1) << 58 | host).unwrap();
+    drop(inner);
+    image.read_at(0, &mut buf[..4096]).unwrap();
+    assert_eq!(&buf[..4096], &data[..]);
+    image.write_at(512, &[0; 512]).unwrap();
+    image.read_at(0, &mut buf[..4096]).unwrap();
+    assert_eq!((&buf[..512], &buf[1024..4096]), (&data[..512], &data[1024..]));
+    let mut inner = image.inner.lock().unwrap();
+    assert!(matches!(inner.lookup(0), Ok(Cluster::Data(_))));
+    assert_eq!(inner.add_refcount(host - 100, 0).ok(), Some(0));
+    drop(inner);
+
+    // Bad images are refused, and explicit formats aren't detected.
+    temp.as_file().write_all_at(&4u32.to_be_bytes(), 4).unwrap();
+    assert!(matches!(open(&path, None, true, CacheMode::Buffered), Err(Error::ImageError(_))));
+    let disk = open(&path, Some(ImageFormat::Raw), true, CacheMode::Buffered).unwrap();
+    assert!(disk.raw().is_some());
+}
@@ -0,0 +1,234 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Seek, SeekFrom},
+    os::unix::{fs::{FileExt, FileTypeExt, OpenOptionsExt}, io::{AsRawFd, RawFd}},
+};
+use crate::config::CacheMode;
+use crate::error::{Error, Result};
+use super::{AlignedBuf, Disk, SECTOR_SIZE, iovec, transfer};
+
+/// Buffers are aligned to a page for `O_DIRECT`, which satisfies any
+/// logical block size of the host.
+const DIRECT_BUF_ALIGN: usize = 4096;
+/// Size of the buffer for writing zeros when the file can't allocate them.
+const ZERO_CHUNK: usize = 1 << 20;
+
+/// A raw image file or a block device.
+pub struct RawDisk {
+    file: File,
+    /// Size in bytes, rounded down to sectors.
+    size: u64,
+    /// Opened with `O_DIRECT`.
+    direct: bool,
+    /// Alignment of offsets and lengths required by `O_DIRECT`.
+    align: u64,
+}
+
+impl RawDisk {
+    /// Open the image at `path`, read-only if `readonly`.
+    pub fn open(path: &str, readonly: bool, cache: CacheMode) -> Result<Self> {
+        let io_error = |e: io::Error| Error::IOError(format!("{}: {}", path, e));
+        let direct = cache == CacheMode::Direct;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(!readonly)
+            .custom_flags(if direct { libc::O_DIRECT } else { 0 })
+            .open(path)
+            .map_err(io_error)?;
+        let is_block = file.metadata().map_err(io_error)?.file_type().is_block_device();
+        // The size of a block device is only known by seeking to its end.
+        let size = file.seek(SeekFrom::End(0)).map_err(io_error)?;
+        let mut align = SECTOR_SIZE;
+        if is_block {
+            let mut ssz: libc::c_int = 0;
+            // Safe because the ioctl only writes an int.
+            if unsafe { libc::ioctl(file.as_raw_fd(), libc::BLKSSZGET, &mut ssz) } == 0 {
+                align = align.max(ssz as u64);
+            }
+        }
+        Ok(RawDisk { file, size: size / SECTOR_SIZE * SECTOR_SIZE, direct, align })
+    }
+
+    /// Length of the file, which isn't rounded like the size.
+    pub fn file_len(&self) -> io::Result<u64> {
+        (&self.file).seek(SeekFrom::End(0))
+    }
+
+    /// File descriptor for I/O engines.
+    pub fn fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+
+    /// Whether the buffers have to be bounced through an `AlignedBuf`,
+    /// which `O_DIRECT` accepts.
+    pub fn needs_bounce(&self, offset: u64, iovecs: &[libc::iovec]) -> bool {
+        let align = self.align as usize;
+        self.direct && !(offset.is_multiple_of(self.align) && iovecs.iter().all(|v| {
+            (v.iov_base as usize).is_multiple_of(align) && v.iov_len.is_multiple_of(align)
+        }))
+    }
+
+    /// A buffer of `len` bytes for bouncing.
+    pub fn bounce_buf(&self, len: usize) -> AlignedBuf {
+        AlignedBuf::new(len, DIRECT_BUF_ALIGN)
+    }
+
+    /// The blocks covering `len` bytes at `offset`, as (offset, length).
+    fn aligned(&self, offset: u64, len: usize) -> (u64, usize) {
+        let start = offset / self.align * self.align;
+        let end = (offset + len as u64).div_ceil(self.align) * self.align;
+        (start, (end - start) as usize)
+    }
+
+    /// pread until `buf` is full, the bytes beyond the end of the file
+    /// read as zeros.
+    fn pread(&self, mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.file.read_at(buf, offset) {
+                Ok(0) => {
+                    buf.fill(0);
+                    break;
+                },
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                },
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn fallocate(&self, mode: libc::c_int, offset: u64, len: u64) -> io::Result<()> {
+        // Safe because the fd is valid and no memory is involved.
+        let ret = unsafe {
+            libc::fallocate(self.file.as_raw_fd(), mode, offset as i64, len as i64)
+        };
+        match ret {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+impl Disk for RawDisk {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn raw(&self) -> Option<&RawDisk> {
+        Some(self)
+    }
+
+    /// The bytes beyond the end of the file read as zeros.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if !self.needs_bounce(offset, &[iovec(buf.as_mut_ptr(), buf.len())]) {
+            return self.pread(offset, buf);
+        }
+        let (start, len) = self.aligned(offset, buf.len());
+        let mut bounce = self.bounce_buf(len);
+        self.pread(start, bounce.as_mut_slice())?;
+        let skip = (offset - start) as usize;
+        buf.copy_from_slice(&bounce.as_slice()[skip..skip + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let iovecs = [iovec(buf.as_ptr() as *mut u8, buf.len())];
+        if !self.needs_bounce(offset, &iovecs) {
+            return transfer(self.fd(), offset, &iovecs, true).map(|_| ());
+        }
+        let (start, len) = self.aligned(offset, buf.len());
+        let mut bounce = self.bounce_buf(len);
+        let skip = (offset - start) as usize;
+        // Blocks partially written are read first.
+        if skip > 0 || buf.len() < len {
+            self.pread(start, bounce.as_mut_slice())?;
+        }
+        bounce.as_mut_slice()[skip..skip + buf.len()].copy_from_slice(buf);
+        transfer(self.fd(), start, &[bounce.iovec(len)], true).map(|_| ())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// It's a hint, so it's fine if the file can't do it.
+    fn discard(&self, offset: u64, len: u64) -> io::Result<()> {
+        match self.fallocate(libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE, offset, len) {
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+            result => result,
+        }
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64, unmap: bool) -> io::Result<()> {
+        let mut modes = vec![libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE];
+        if unmap {
+            // Holes read as zeros as well.
+            modes.insert(0, libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE);
+        }
+        for mode in modes {
+            match self.fallocate(mode, offset, len) {
+                Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => continue,
+                result => return result,
+            }
+        }
+        let mut buf = AlignedBuf::new(ZERO_CHUNK.min(len as usize), DIRECT_BUF_ALIGN);
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(ZERO_CHUNK as u64) as usize;
+            transfer(self.fd(), offset + done, &[buf.iovec(n)], true)?;
+            done += n as u64;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_raw_disk() {
+    use std::io::Write;
+    use vmm_sys_util::tempfile::TempFile;
+    use super::iovec;
+
+    let temp = TempFile::new().unwrap();
+    let path = temp.as_path().to_str().unwrap().to_string();
+    // The size is rounded down to sectors.
+    temp.as_file().write_all(&[0xaa; 4096 + 100]).unwrap();
+    let disk = RawDisk::open(&path, false, CacheMode::Buffered).unwrap();
+    assert_eq!(disk.size(), 4096);
+    let mut buf = [0u8; 512];
+    assert!(!disk.needs_bounce(1, &[iovec(buf.as_mut_ptr(), 3)]));
+    assert_eq!(disk.flush().ok(), Some(()));
+
+    assert_eq!(disk.write_zeroes(1024, 512, false).ok(), Some(()));
+    transfer(disk.fd(), 1024, &[iovec(buf.as_mut_ptr(), 512)], false).unwrap();
+    assert!(buf.iter().all(|&x| x == 0));
+    assert_eq!(disk.discard(0, 4096).ok(), Some(()));
+    assert_eq!(disk.size(), 4096);
+
+    // Unaligned buffers are bounced with O_DIRECT.
+    let mut aligned = disk.bounce_buf(512);
+    if let Ok(disk) = RawDisk::open(&path, false, CacheMode::Direct) {
+        assert!(!disk.needs_bounce(512, &[aligned.iovec(512)]));
+        assert!(disk.needs_bounce(512, &[iovec(aligned.iovec(512).iov_base as *mut u8, 100)]));
+        assert!(disk.needs_bounce(100, &[aligned.iovec(512)]));
+        assert_eq!(disk.write_at(510, &[1, 2, 3, 4]).ok(), Some(()));
+    } else {
+        disk.write_at(510, &[1, 2, 3, 4]).unwrap();
+    }
+    // Bytes are read anywhere, the ones beyond the end of the file as zeros.
+    let mut bytes = [0xffu8; 8];
+    disk.read_at(508, &mut bytes).unwrap();
+    assert_eq!(bytes, [0, 0, 1, 2, 3, 4, 0, 0]);
+    disk.read_at(4096 + 98, &mut bytes).unwrap();
+    assert_eq!(bytes, [0xaa, 0xaa, 0, 0, 0, 0, 0, 0]);
+
+    // A read-only disk can't be written.
+    let disk = RawDisk::open(&path, true, CacheMode::Buffered).unwrap();
+    assert!(transfer(disk.fd(), 0, &[iovec(buf.as_mut_ptr(), 512)], true).is_err());
+    assert!(RawDisk::open("/nonexistent/disk.raw", true, CacheMode::Buffered).is_err());
+}
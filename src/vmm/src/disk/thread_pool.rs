@@ -0,0 +1,152 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    io,
+    os::unix::io::RawFd,
+    sync::{Arc, Mutex, mpsc::{self, Receiver, Sender}},
+    thread::{self, JoinHandle},
+};
+use vmm_sys_util::eventfd::EventFd;
+use super::{IoEngine, IoOp, transfer};
+
+/// Number of threads doing blocking I/O for an engine.
+const WORKERS: usize = 4;
+
+/// An operation handed over to the workers.
+struct Job {
+    fd: RawFd,
+    op: IoOp,
+    user_data: u64,
+}
+
+// Safe because the buffers stay valid until the operation completes, as
+// IoEngine requires.
+unsafe impl Send for Job {}
+
+impl Job {
+    fn run(&self) -> io::Result<usize> {
+        match &self.op {
+            IoOp::Read(offset, iovecs) => transfer(self.fd, *offset, iovecs, false),
+            IoOp::Write(offset, iovecs) => transfer(self.fd, *offset, iovecs, true),
+            // Safe because the fd is owned by the caller.
+            IoOp::Flush => match unsafe { libc::fdatasync(self.fd) } {
+                0 => Ok(0),
+                _ => Err(io::Error::last_os_error()),
+            },
+        }
+    }
+}
+
+/// Completed operations shared with the workers.
+type Completions = Arc<Mutex<Vec<(u64, io::Result<usize>)>>>;
+
+/// An engine running blocking I/O on a pool of threads, for hosts without
+/// io_uring.
+pub struct ThreadPool {
+    /// Closed when the engine is dropped, which stops the workers.
+    sender: Option<Sender<Job>>,
+    /// Jobs pushed but not yet submitted.
+    pending: Vec<Job>,
+    completed: Completions,
+    event: Arc<EventFd>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    /// Start the workers.
+    pub fn new() -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let completed = Completions::default();
+        let event = Arc::new(EventFd::new(libc::EFD_NONBLOCK)?);
+        let mut pool = ThreadPool {
+            sender: Some(sender),
+            pending: Vec::new(),
+            completed,
+            event,
+            workers: Vec::new(),
+        };
+        for _ in 0..WORKERS {
+            let (receiver, completed, event) =
+                (receiver.clone(), pool.completed.clone(), pool.event.clone());
+            pool.workers.push(thread::Builder::new()
+                .name("io-worker".to_string())
+                .spawn(move || Self::work(&receiver, &completed, &event))?);
+        }
+        Ok(pool)
+    }
+
+    /// Run jobs until the engine is dropped.
+    fn work(receiver: &Mutex<Receiver<Job>>, completed: &Completions, event: &EventFd) {
+        loop {
+            // The lock is released once a job is received.
+            let job = match receiver.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            };
+            let result = job.run();
+            completed.lock().unwrap().push((job.user_data, result));
+            // The counter only overflows if nobody reaps, nothing to do.
+            let _ = event.write(1);
+        }
+    }
+}
+
+impl IoEngine for ThreadPool {
+    fn push(&mut self, fd: RawFd, op: IoOp, user_data: u64) -> io::Result<()> {
+        self.pending.push(Job { fd, op, user_data });
+        Ok(())
+    }
+
+    fn submit(&mut self) -> io::Result<()> {
+        let sender = self.sender.as_ref().unwrap();
+        for job in self.pending.drain(..) {
+            sender.send(job).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        }
+        Ok(())
+    }
+
+    fn event(&self) -> &EventFd {
+        &self.event
+    }
+
+    fn completions(&mut self) -> Vec<(u64, io::Result<usize>)> {
+        std::mem::take(&mut *self.completed.lock().unwrap())
+    }
+}
+
+impl Drop for ThreadPool {
+    /// Wait for the submitted jobs, whose buffers may be freed afterwards.
+    fn drop(&mut self) {
+        self.sender = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[test]
+fn test_thread_pool() {
+    use std::{io::Write, os::unix::io::AsRawFd};
+    use vmm_sys_util::tempfile::TempFile;
+    use super::{iovec, run_engine};
+
+    let temp = TempFile::new().unwrap();
+    temp.as_file().write_all(&[0xaa; 1024]).unwrap();
+    let mut pool = ThreadPool::new().unwrap();
+    let (mut a, mut b) = ([0x55u8; 512], [0u8; 512]);
+    let done = run_engine(&mut pool, temp.as_file().as_raw_fd(), vec![
+        IoOp::Write(0, vec![iovec(a.as_mut_ptr(), 512)]),
+        IoOp::Flush,
+        IoOp::Read(1000, vec![iovec(b.as_mut_ptr(), 512)]),
+    ]);
+    assert_eq!(done[0].1.as_ref().ok(), Some(&512));
+    assert_eq!(done[1].1.as_ref().ok(), Some(&0));
+    assert!(done[2].1.is_err());
+    let done = run_engine(&mut pool, temp.as_file().as_raw_fd(), vec![
+        IoOp::Read(256, vec![iovec(b.as_mut_ptr(), 512)]),
+    ]);
+    assert_eq!(done[0].1.as_ref().ok(), Some(&512));
+    assert_eq!((b[255], b[256]), (0x55, 0xaa));
+}
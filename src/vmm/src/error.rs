@@ -2,22 +2,48 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use utils::json;
+use super::vcpu::VcpuStatus;
+use super::vm::VmStatus;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 /// Errors   
 #[derive(Debug, PartialEq)]
 pub enum Error {
-        /// The required configuration is missing.
-    MissingConfig(String),
-    /// The configuration provided is illegal.
-    IllegalConfig(String),
+    /// All the problems found in the configuration of a VM.
+    InvalidConfig(Vec<ConfigIssue>),
     /// Errors generated when paring configurations from JSON strings.
     ParsingError(String),
     /// Errors generated when doing file operations.
     IOError(String),
     /// Error rasied by calling kvm ioctls, its format: (errno, info string).  
     IoctlError(i32, String),
+    /// The vcpu thread has gone and can't be communicated with.
+    VcpuGone,
+    /// The vcpu with the given id doesn't reply in time.
+    VcpuTimeout(u32),
+    /// Errors generated when loading or booting the guest OS.
+    BootError(String),
+    /// Errors generated when setting up the guest memory.
+    MemoryError(String),
+    /// There are not enough free hugepages for the guest memory.
+    HugePagesExhausted(String),
+    /// Errors generated when setting up a vcpu thread on the host.
+    ThreadError(String),
+    /// The VM or a vcpu is asked to transit between two states illegally.
+    InvalidStateTransition(IllegalTransition),
+    /// Errors generated when routing interrupts of devices.
+    InterruptError(String),
+    /// Errors generated when claiming ranges of a bus for devices.
+    BusError(String),
+    /// Errors generated by virtio devices or their transport.
+    VirtioError(String),
+    /// The disk image is malformed or uses unsupported features.
+    ImageError(String),
+    /// Errors generated when talking to a vhost-user backend.
+    VhostUserError(String),
+    /// Errors generated when passing a host device through VFIO.
+    VfioError(String),
 }
 
 impl std::fmt::Display for Error {
@@ -25,25 +51,97 @@ impl std::fmt::Display for Error {
         use Error::*;
 
         match self {
-            MissingConfig(s) => write!(
-                f, 
-                "The required configuration for {} is missing.", 
-                s
-            ),
-            IllegalConfig(s) => write!(
-                f, 
-                "The given configuration for {} is illegal.", 
-                s
-            ),
+            InvalidConfig(issues) => {
+                write!(f, "The given configuration is invalid:")?;
+                issues.iter().try_for_each(|i| write!(f, "\n    {}", i))
+            },
             ParsingError(s) => write!(f, "{}", s),
             IOError(s) => write!(f, "I/O error, error={}", s),
             IoctlError(errno, msg) => {
                 write!(f, "Failed kvm ioctl, error=({}, {})", errno, msg)
             },
+            VcpuGone => write!(f, "The vcpu thread has gone."),
+            VcpuTimeout(id) => write!(f, "The vcpu {} doesn't reply in time.", id),
+            BootError(s) => write!(f, "Failed to boot the guest, error={}", s),
+            MemoryError(s) => {
+                write!(f, "Failed to setup the guest memory, error={}", s)
+            },
+            HugePagesExhausted(s) => {
+                write!(f, "Hugepages are exhausted, {}", s)
+            },
+            ThreadError(s) => {
+                write!(f, "Failed to setup the vcpu thread, error={}", s)
+            },
+            InvalidStateTransition(transition) => {
+                write!(f, "Invalid state transition {}.", transition)
+            },
+            InterruptError(s) => {
+                write!(f, "Failed to route the interrupt, error={}", s)
+            },
+            BusError(s) => write!(f, "Failed to setup the bus, error={}", s),
+            VirtioError(s) => write!(f, "Virtio device error, error={}", s),
+            ImageError(s) => write!(f, "Invalid disk image, error={}", s),
+            VhostUserError(s) => write!(f, "vhost-user backend error, error={}", s),
+            VfioError(s) => write!(f, "VFIO error, error={}", s),
         }
     }
 }
 
+/// An illegal transition between two states of the VM or a vcpu, in the
+/// format of (from, to).
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum IllegalTransition {
+    Vm(VmStatus, VmStatus),
+    Vcpu(VcpuStatus, VcpuStatus),
+}
+
+impl std::fmt::Display for IllegalTransition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IllegalTransition::Vm(from, to) => {
+                write!(f, "of the VM from {:?} to {:?}", from, to)
+            },
+            IllegalTransition::Vcpu(from, to) => {
+                write!(f, "of the vcpu from {:?} to {:?}", from, to)
+            },
+        }
+    }
+}
+
+/// Kinds of problems in a configuration.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ConfigIssueKind {
+    /// A required key is missing.
+    Missing,
+    /// The value of a key is illegal.
+    Illegal,
+    /// The key isn't known, e.g. it's misspelled.
+    Unknown,
+}
+
+/// A problem in a configuration.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ConfigIssue {
+    /// Path to the key, e.g. `device[1].mac`.
+    pub path: String,
+    pub kind: ConfigIssueKind,
+    /// What is wrong with the key.
+    pub message: String,
+}
+
+impl ConfigIssue {
+    /// A problem of `kind` at `path`.
+    pub fn new(path: &str, kind: ConfigIssueKind, message: &str) -> Self {
+        ConfigIssue { path: path.to_string(), kind, message: message.to_string() }
+    }
+}
+
+impl std::fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
 impl From<kvm_ioctls::Error> for Error {
     fn from(e: kvm_ioctls::Error) -> Self {
         Error::IoctlError(e.errno(), e.to_string())
@@ -0,0 +1,239 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    sync::{Arc, Mutex},
+};
+use kvm_bindings::{
+    KVMIO, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
+    KVM_IRQ_ROUTING_IRQCHIP, KVM_IRQ_ROUTING_MSI, KVM_PIT_SPEAKER_DUMMY,
+    kvm_irq_routing, kvm_irq_routing_entry, kvm_pit_config,
+};
+use kvm_ioctls::VmFd;
+use vmm_sys_util::{eventfd::EventFd, ioctl::ioctl_with_ref, ioctl_iow_nr};
+use super::error::{Error, Result};
+use super::memory::{IDENTITY_MAP_START, TSS_START};
+
+/// Number of IOAPIC pins, GSIs below it are routed to the pins.
+pub const IOAPIC_PINS: u32 = 24;
+/// Number of GSIs routed to the PICs as well.
+const PIC_PINS: u32 = 16;
+/// GSIs KVM can route, i.e. KVM_MAX_IRQ_ROUTES.
+const MAX_GSI: u32 = 4096;
+/// Legacy IRQs free for devices, leaving out the ones of the PIT, PS/2,
+/// serial ports, RTC and SCI.
+const FREE_IRQS: [u32; 15] = [5, 6, 7, 10, 11, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22];
+
+ioctl_iow_nr!(KVM_SET_IDENTITY_MAP_ADDR, KVMIO, 0x48, u64);
+
+/// Create the in-kernel PIC, IOAPIC and LAPICs, and the PIT.
+///
+/// It must be done before any vcpu is created.
+pub fn create_irqchip(fd: &VmFd) -> Result<()> {
+    // Safe because the ioctl only reads the address.
+    let ret = unsafe { ioctl_with_ref(fd, KVM_SET_IDENTITY_MAP_ADDR(), &IDENTITY_MAP_START) };
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        return Err(Error::IoctlError(e.raw_os_error().unwrap_or(0), e.to_string()));
+    }
+    fd.set_tss_address(TSS_START as usize)?;
+    fd.create_irq_chip()?;
+    // The speaker port is left to user space, where nothing claims it.
+    fd.create_pit2(kvm_pit_config { flags: KVM_PIT_SPEAKER_DUMMY, ..Default::default() })?;
+    Ok(())
+}
+
+/// A message signaled interrupt.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+/// GSIs allocated to devices and their MSI routes.
+#[derive(Default)]
+struct Routes {
+    /// GSIs allocated and not yet freed.
+    allocated: BTreeMap<u32, Option<MsiMessage>>,
+    /// Legacy IRQs allocated to devices.
+    irqs: BTreeSet<u32>,
+}
+
+/// Routes interrupts of devices to the in-kernel irqchip.
+///
+/// GSIs below `IOAPIC_PINS` are the legacy IRQs wired to the IOAPIC (and
+/// the PICs for the first 16), the rest are allocated for MSIs.
+pub struct InterruptManager {
+    fd: Arc<VmFd>,
+    routes: Mutex<Routes>,
+}
+
+impl InterruptManager {
+    /// Create a manager for the VM, the irqchip must have been created.
+    pub fn new(fd: Arc<VmFd>) -> Result<Self> {
+        let manager = InterruptManager { fd, routes: Mutex::new(Routes::default()) };
+        manager.update(&manager.routes.lock().unwrap())?;
+        Ok(manager)
+    }
+
+    /// Allocate a GSI for MSIs, it isn't routed until `set_msi_route`.
+    pub fn allocate_gsi(&self) -> Result<u32> {
+        let mut routes = self.routes.lock().unwrap();
+        let gsi = (IOAPIC_PINS..MAX_GSI)
+            .find(|gsi| !routes.allocated.contains_key(gsi))
+            .ok_or_else(|| Error::InterruptError("GSIs are exhausted".to_string()))?;
+        routes.allocated.insert(gsi, None);
+        Ok(gsi)
+    }
+
+    /// Allocate a legacy IRQ for a device which can't signal MSIs, e.g. a
+    /// virtio-mmio device. It is routed to the IOAPIC already.
+    pub fn allocate_irq(&self) -> Result<u32> {
+        let mut routes = self.routes.lock().unwrap();
+        let irq = FREE_IRQS.into_iter()
+            .find(|irq| !routes.irqs.contains(irq))
+            .ok_or_else(|| Error::InterruptError("legacy IRQs are exhausted".to_string()))?;
+        routes.irqs.insert(irq);
+        Ok(irq)
+    }
+
+    /// Free an allocated GSI and remove its route.
+    pub fn free_gsi(&self, gsi: u32) -> Result<()> {
+        let mut routes = self.routes.lock().unwrap();
+        match routes.allocated.remove(&gsi) {
+            Some(Some(_)) => self.update(&routes),
+            Some(None) => Ok(()),
+            None => Err(Error::InterruptError(format!("GSI {} isn't allocated", gsi))),
+        }
+    }
+
+    /// Route an allocated GSI to the MSI `message`.
+    pub fn set_msi_route(&self, gsi: u32, message: MsiMessage) -> Result<()> {
+        let mut routes = self.routes.lock().unwrap();
+        match routes.allocated.get_mut(&gsi) {
+            Some(route) => *route = Some(message),
+            None => return Err(Error::InterruptError(format!("GSI {} isn't allocated", gsi))),
+        }
+        self.update(&routes)
+    }
+
+    /// Inject the GSI whenever `event` is written.
+    pub fn register_irqfd(&self, event: &EventFd, gsi: u32) -> Result<()> {
+        Ok(self.fd.register_irqfd(event, gsi)?)
+    }
+
+    /// Stop injecting the GSI when `event` is written.
+    pub fn unregister_irqfd(&self, event: &EventFd, gsi: u32) -> Result<()> {
+        Ok(self.fd.unregister_irqfd(event, gsi)?)
+    }
+
+    /// Create an eventfd injecting the GSI whenever it is written.
+    pub fn irqfd(&self, gsi: u32) -> Result<EventFd> {
+        let event = EventFd::new(libc::EFD_NONBLOCK)
+            .map_err(|e| Error::InterruptError(e.to_string()))?;
+        self.register_irqfd(&event, gsi)?;
+        Ok(event)
+    }
+
+    /// Replace the routing table of KVM with the legacy and MSI routes.
+    fn update(&self, routes: &Routes) -> Result<()> {
+        let mut entries = legacy_routes();
+        for (&gsi, message) in routes.allocated.iter() {
+            if let Some(message) = message {
+                entries.push(msi_route(gsi, message));
+            }
+        }
+        let table = routing_table(&entries);
+        Ok(self.fd.set_gsi_routing(&table[0])?)
+    }
+}
+
+fn irqchip_route(gsi: u32, irqchip: u32, pin: u32) -> kvm_irq_routing_entry {
+    let mut entry = kvm_irq_routing_entry {
+        gsi,
+        type_: KVM_IRQ_ROUTING_IRQCHIP,
+        ..Default::default()
+    };
+    entry.u.irqchip.irqchip = irqchip;
+    entry.u.irqchip.pin = pin;
+    entry
+}
+
+fn msi_route(gsi: u32, message: &MsiMessage) -> kvm_irq_routing_entry {
+    let mut entry = kvm_irq_routing_entry {
+        gsi,
+        type_: KVM_IRQ_ROUTING_MSI,
+        ..Default::default()
+    };
+    entry.u.msi.address_lo = message.address as u32;
+    entry.u.msi.address_hi = (message.address >> 32) as u32;
+    entry.u.msi.data = message.data;
+    entry
+}
+
+/// Routes of the legacy IRQs, matching the MADT: IRQ 0 is overridden to
+/// IOAPIC pin 2, which is the cascade on the PICs.
+fn legacy_routes() -> Vec<kvm_irq_routing_entry> {
+    let mut entries = Vec::new();
+    for gsi in (0..PIC_PINS).filter(|&gsi| gsi != 2) {
+        let chip = if gsi < 8 { KVM_IRQCHIP_PIC_MASTER } else { KVM_IRQCHIP_PIC_SLAVE };
+        entries.push(irqchip_route(gsi, chip, gsi % 8));
+    }
+    for gsi in (0..IOAPIC_PINS).filter(|&gsi| gsi != 2) {
+        let pin = if gsi == 0 { 2 } else { gsi };
+        entries.push(irqchip_route(gsi, KVM_IRQCHIP_IOAPIC, pin));
+    }
+    entries
+}
+
+/// Build a kvm_irq_routing followed by its entries, the first element is
+/// the one passed to KVM.
+fn routing_table(entries: &[kvm_irq_routing_entry]) -> Vec<kvm_irq_routing> {
+    let header = std::mem::size_of::<kvm_irq_routing>();
+    let bytes = std::mem::size_of_val(entries);
+    let mut table: Vec<kvm_irq_routing> = (0..1 + bytes.div_ceil(header))
+        .map(|_| kvm_irq_routing::default())
+        .collect();
+    table[0].nr = entries.len() as u32;
+    // Safe because the vector has room for all the entries after the header.
+    unsafe {
+        table[0].entries.as_mut_slice(entries.len()).copy_from_slice(entries);
+    }
+    table
+}
+
+#[test]
+fn test_interrupt_manager() {
+    let fd = Arc::new(kvm_ioctls::Kvm::new().unwrap().create_vm().unwrap());
+    // Routes can't be set without an irqchip.
+    assert!(InterruptManager::new(fd.clone()).is_err());
+    create_irqchip(&fd).unwrap();
+    let manager = InterruptManager::new(fd).unwrap();
+
+    let gsi = manager.allocate_gsi().unwrap();
+    assert_eq!(gsi, IOAPIC_PINS);
+    assert_eq!(manager.allocate_gsi(), Ok(IOAPIC_PINS + 1));
+    let message = MsiMessage { address: 0xfee0_0000, data: 0x4041 };
+    assert_eq!(manager.set_msi_route(gsi, message), Ok(()));
+    assert!(manager.set_msi_route(MAX_GSI, message).is_err());
+    let event = manager.irqfd(gsi).unwrap();
+    assert_eq!(manager.unregister_irqfd(&event, gsi), Ok(()));
+    assert_eq!(manager.irqfd(4).map(|_| ()), Ok(()));
+
+    // Freed GSIs are reused.
+    assert_eq!(manager.free_gsi(gsi), Ok(()));
+    assert!(manager.free_gsi(gsi).is_err());
+    assert_eq!(manager.allocate_gsi(), Ok(gsi));
+
+    // Legacy IRQs are allocated until exhausted.
+    assert_eq!(manager.allocate_irq(), Ok(5));
+    let irqs: Vec<_> = (1..FREE_IRQS.len()).map(|_| manager.allocate_irq().unwrap()).collect();
+    assert_eq!(irqs, FREE_IRQS[1..]);
+    assert!(manager.allocate_irq().is_err());
+
+    let routes = legacy_routes();
+    assert_eq!(routes.len(), 15 + 23);
+    assert!(routes.iter().all(|r| r.gsi != 2));
+}
@@ -1,15 +1,103 @@
 // Copyright 2022 Garry Xu
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod acpi;
+pub mod boot;
+pub mod bus;
 pub mod config;
+pub mod disk;
 pub mod error;
+pub mod interrupt;
+pub mod memory;
+pub mod numa;
+pub mod pci;
+pub mod power;
+pub mod serial;
+pub mod state;
+pub mod tap;
 pub mod vcpu;
+pub mod vfio;
+pub mod virtio;
 pub mod vm;
 
+use std::{
+    sync::{Arc, atomic::{AtomicBool, Ordering}},
+    thread,
+    time::{Duration, Instant},
+};
+use kvm_bindings::{
+    KVM_SYSTEM_EVENT_CRASH, KVM_SYSTEM_EVENT_RESET, KVM_SYSTEM_EVENT_SHUTDOWN,
+};
 use kvm_ioctls::Kvm;
-use config::{VmConfig, VmmConfig};
-use error::{Result};
-use vm::Vm;
+use config::{DeviceConfig, RebootAction, VmConfig, VmmConfig};
+use error::Result;
+use power::{PowerEvent, PowerManager, SCI_IRQ};
+use serial::{COM1_IRQ, Serial};
+use tap::Tap;
+use vcpu::VcpuStopReason;
+use virtio::{block::Block, net::Net, vhost_user::VhostUser};
+use vm::{Vm, VmStatus};
+
+/// Time for vcpus to respond to pause, resume or exit requests.
+const VCPU_TIMEOUT: Duration = Duration::from_secs(1);
+/// Time for the guest to power off after the power button is pressed.
+pub const GRACEFUL_TIMEOUT: Duration = Duration::from_secs(30);
+/// Interval for checking whether the VM should exit.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How to shut down a running VM.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShutdownMode {
+    /// Press the ACPI power button and wait for the guest to power off.
+    /// The VM is killed if the guest doesn't within the given time.
+    Graceful(Duration),
+    /// Kill the VM, waiting for vcpu threads no longer than the given time.
+    Force(Duration),
+}
+
+/// Reasons for which a VM exits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitReason {
+    /// The guest powered off.
+    Poweroff,
+    /// The guest rebooted, e.g. through the reset register or a triple fault.
+    Reboot,
+    /// The guest reported a crash.
+    Crash,
+    /// The VM was killed by the hypervisor.
+    Killed,
+    /// A vcpu stopped for an unrecoverable reason, its format: (id, reason).
+    VcpuFailure(u32, VcpuStopReason),
+}
+
+impl ExitReason {
+    /// Map the reason for which a vcpu stopped, `None` is returned if the
+    /// VM doesn't have to exit.
+    fn from_vcpu(id: u32, reason: VcpuStopReason) -> Option<Self> {
+        match reason {
+            // Not reported with the in-kernel irqchip, which handles HLT.
+            VcpuStopReason::Halted => None,
+            // A triple fault resets a real machine.
+            VcpuStopReason::Shutdown => Some(ExitReason::Reboot),
+            VcpuStopReason::SystemEvent(kind, _) => match kind {
+                KVM_SYSTEM_EVENT_SHUTDOWN => Some(ExitReason::Poweroff),
+                KVM_SYSTEM_EVENT_RESET => Some(ExitReason::Reboot),
+                KVM_SYSTEM_EVENT_CRASH => Some(ExitReason::Crash),
+                _ => Some(ExitReason::VcpuFailure(id, reason)),
+            },
+            _ => Some(ExitReason::VcpuFailure(id, reason)),
+        }
+    }
+}
+
+impl From<PowerEvent> for ExitReason {
+    fn from(e: PowerEvent) -> Self {
+        match e {
+            PowerEvent::Poweroff => ExitReason::Poweroff,
+            PowerEvent::Reset => ExitReason::Reboot,
+        }
+    }
+}
 
 /// Contains operations and metadata needed for the hypervisor.
 pub struct Vmm {
@@ -17,20 +105,187 @@ pub struct Vmm {
     config: Option<VmmConfig>,
     /// Used for KVM system level ioctls.
     kvm: Kvm,
+    /// Power manager of the VM, handling ACPI and reset requests.
+    power: Arc<PowerManager>,
+    /// Serial console on COM1.
+    serial: Arc<Serial>,
     /// Inside virtual machine.
     pub vm: Vm
 }
 
 impl Vmm {
-    fn new(mut config: VmConfig) -> Result<Self> {
+    pub fn new(mut config: VmConfig) -> Result<Self> {
         let kvm = Kvm::new()?;
         let fd = kvm.create_vm()?;
+        let vmm_config = config.vmm.take();
+        let mut vm = Vm::new(fd, config)?;
+        let interrupts = vm.interrupts();
+        let power = Arc::new(PowerManager::new(interrupts.irqfd(SCI_IRQ)?));
+        power.attach(&vm.buses().pio)?;
+        let serial = Serial::open(vm.config().console(), interrupts.irqfd(COM1_IRQ)?)?;
+        serial.attach(&vm.buses().pio)?;
+        for device in vm.config().device.clone() {
+            match device {
+                DeviceConfig::Block(block) => {
+                    vm.add_virtio_device(Box::new(Block::new(block)?))?;
+                },
+                DeviceConfig::Net(net) => {
+                    let taps = match net.fd {
+                        Some(fd) => vec![Tap::from_fd(fd)?],
+                        None => {
+                            let name = net.source.as_deref().unwrap_or("");
+                            Self::open_taps(name, net.queue_pairs)?
+                        },
+                    };
+                    vm.add_virtio_device(Box::new(Net::new(taps, net)?))?;
+                },
+                DeviceConfig::VhostUser(vhost_user) => {
+                    vm.add_virtio_device(Box::new(VhostUser::new(&vhost_user)?))?;
+                },
+                DeviceConfig::Vfio(vfio) => vm.add_vfio_device(&vfio.bdf)?,
+                DeviceConfig::Console(_) => {},
+            }
+        }
         Ok(
             Vmm {
-                config: config.vmm.take(),
+                config: vmm_config,
                 kvm,
-                vm: Vm::new(fd, config)?
+                power,
+                serial,
+                vm,
             }
         )
     }
-}
\ No newline at end of file
+
+    /// Open a queue of the TAP interface `name` per queue pair, the kernel
+    /// names the interface if `name` is empty.
+    fn open_taps(name: &str, pairs: u16) -> Result<Vec<Tap>> {
+        let multi_queue = pairs > 1;
+        let mut taps = vec![Tap::open(name, multi_queue)?];
+        for _ in 1..pairs {
+            let tap = Tap::open(taps[0].name(), multi_queue)?;
+            taps.push(tap);
+        }
+        Ok(taps)
+    }
+
+    /// Load the guest OS and start running the VM.
+    pub fn start(&mut self) -> Result<()> {
+        self.vm.load(&self.kvm)?;
+        self.vm.resume(VCPU_TIMEOUT)
+    }
+
+    /// Check whether the VM should exit because of the guest, without
+    /// blocking.
+    pub fn poll(&mut self) -> Option<ExitReason> {
+        if let Some(event) = self.power.take_event() {
+            return Some(event.into());
+        }
+        let stopped = self.vm.vcpus()
+            .map(|v| v.stopped())
+            .unwrap_or_default();
+        stopped.into_iter()
+            .find_map(|(id, reason)| ExitReason::from_vcpu(id, reason))
+    }
+
+    /// Start the VM and run it until the guest exits, or until `stop` is
+    /// set, in which case the VM is shut down gracefully.
+    ///
+    /// Guest reboots are handled in place unless `on_reboot` is `Exit`.
+    pub fn run(&mut self, stop: &AtomicBool) -> Result<ExitReason> {
+        self.start()?;
+        loop {
+            if stop.load(Ordering::Relaxed) {
+                return self.shutdown(ShutdownMode::Graceful(GRACEFUL_TIMEOUT));
+            }
+            match self.poll() {
+                Some(ExitReason::Reboot) 
+                    if self.vm.config().on_reboot == RebootAction::Restart => {
+                    self.reboot()?;
+                },
+                Some(reason) => {
+                    self.vm.exit(VCPU_TIMEOUT)?;
+                    return Ok(reason);
+                },
+                None => {},
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Reboot the guest without tearing down the VM.
+    pub fn reboot(&mut self) -> Result<()> {
+        self.power.reset();
+        self.serial.reset();
+        self.vm.reboot(&self.kvm, VCPU_TIMEOUT)
+    }
+
+    /// Shut down the VM and tear down its vcpus.
+    ///
+    /// A graceful shutdown falls back to killing the VM if the guest can't
+    /// be notified or doesn't power off in time.
+    pub fn shutdown(&mut self, mode: ShutdownMode) -> Result<ExitReason> {
+        let timeout = match mode {
+            ShutdownMode::Graceful(timeout) => {
+                if self.vm.status() == VmStatus::Running
+                    && self.power.press_power_button().is_ok() {
+                    let deadline = Instant::now() + timeout;
+                    while Instant::now() < deadline {
+                        if let Some(reason) = self.poll() {
+                            self.vm.exit(VCPU_TIMEOUT)?;
+                            return Ok(reason);
+                        }
+                        thread::sleep(POLL_INTERVAL);
+                    }
+                }
+                VCPU_TIMEOUT
+            },
+            ShutdownMode::Force(timeout) => timeout,
+        };
+        self.vm.exit(timeout)?;
+        Ok(ExitReason::Killed)
+    }
+
+    /// VMM configurations.
+    pub fn config(&self) -> Option<&VmmConfig> {
+        self.config.as_ref()
+    }
+
+    /// Handle for KVM system level ioctls.
+    pub fn kvm(&self) -> &Kvm {
+        &self.kvm
+    }
+
+    /// Serial console on COM1.
+    pub fn serial(&self) -> &Serial {
+        &self.serial
+    }
+}
+
+#[test]
+fn test_exit_reason() {
+    assert_eq!(ExitReason::from_vcpu(0, VcpuStopReason::Halted), None);
+    assert_eq!(
+        ExitReason::from_vcpu(0, VcpuStopReason::Shutdown),
+        Some(ExitReason::Reboot)
+    );
+    assert_eq!(
+        ExitReason::from_vcpu(1, VcpuStopReason::SystemEvent(KVM_SYSTEM_EVENT_SHUTDOWN, 0)),
+        Some(ExitReason::Poweroff)
+    );
+    assert_eq!(
+        ExitReason::from_vcpu(1, VcpuStopReason::SystemEvent(KVM_SYSTEM_EVENT_CRASH, 0)),
+        Some(ExitReason::Crash)
+    );
+    assert_eq!(
+        ExitReason::from_vcpu(2, VcpuStopReason::FailEntry),
+        Some(ExitReason::VcpuFailure(2, VcpuStopReason::FailEntry))
+    );
+    use vcpu::VcpuStatus::Paused;
+    let transition = VcpuStopReason::InvalidStateTransition(Paused, Paused);
+    assert_eq!(
+        ExitReason::from_vcpu(0, transition),
+        Some(ExitReason::VcpuFailure(0, transition))
+    );
+    assert_eq!(ExitReason::from(PowerEvent::Reset), ExitReason::Reboot);
+}
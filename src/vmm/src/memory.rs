@@ -0,0 +1,379 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    os::unix::io::FromRawFd,
+    path::Path,
+    sync::Arc,
+};
+use kvm_bindings::kvm_userspace_memory_region;
+use kvm_ioctls::VmFd;
+use vm_memory::{
+    Address, FileOffset, GuestAddress, GuestMemory, GuestMemoryMmap, 
+    GuestMemoryRegion, GuestRegionMmap, MmapRegion,
+    mmap::MmapRegionError,
+};
+use super::config::{MemoryBackendConfig, MemoryBackendType, MemoryConfig, PageSize};
+use super::error::{Error, Result};
+
+/// Start of the EBDA, memory from here up to 1 MiB is reserved for legacy usage.
+pub const EBDA_START: u64 = 0x9fc00;
+/// Start of the high memory where the kernel is loaded.
+pub const HIGH_MEMORY_START: u64 = 0x100000;
+/// Start of the 32-bit MMIO hole below 4 GiB.
+pub const MMIO_HOLE_START: u64 = 0xc000_0000;
+/// Start of the window in the MMIO hole where BARs of PCI devices are
+/// placed, virtio-mmio devices take the hole below it.
+pub const PCI_MMIO_START: u64 = 0xe000_0000;
+/// Start of the range reserved for IOAPIC, LAPIC and firmware.
+pub const RESERVED_HOLE_START: u64 = 0xfec0_0000;
+/// Page used by KVM for the identity map on Intel, in the reserved range.
+pub const IDENTITY_MAP_START: u64 = 0xfffb_c000;
+/// Three pages used by KVM for the TSS on Intel, right after the identity map.
+pub const TSS_START: u64 = 0xfffb_d000;
+/// End of the 32-bit address space, RAM beyond the MMIO hole restarts here.
+pub const MMIO_HOLE_END: u64 = 0x1_0000_0000;
+
+/// Kinds of guest physical address ranges.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegionKind {
+    /// Ordinary RAM usable by the guest OS.
+    Ram,
+    /// Backed by RAM but reserved for legacy usage, e.g. EBDA and BIOS.
+    Reserved,
+    /// Free for device models to place their MMIO ranges.
+    Mmio,
+    /// Used by emulated interrupt controllers and firmware, no RAM behind.
+    System,
+}
+
+/// A range of guest physical addresses with the same kind.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    /// Start address of the range.
+    pub start: GuestAddress,
+    /// Size of the range in bytes.
+    pub size: u64,
+    /// Kind of the range.
+    pub kind: RegionKind,
+}
+
+impl Region {
+    /// The first address after the range.
+    pub fn end(&self) -> GuestAddress {
+        self.start.unchecked_add(self.size)
+    }
+
+    /// Whether the range contains `addr`.
+    pub fn contains(&self, addr: GuestAddress) -> bool {
+        addr >= self.start && addr < self.end()
+    }
+}
+
+/// Layout of the guest physical address space.
+///
+/// Guest RAM is split around the 32-bit MMIO hole: RAM fills the address
+/// space from 0 up to `MMIO_HOLE_START`, and the rest of it starts at 4 GiB.
+/// Device models and the OS loader should query this map instead of
+/// hardcoding addresses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryLayout {
+    /// Sorted and non-overlapping regions.
+    regions: Vec<Region>,
+}
+
+impl MemoryLayout {
+    /// Create the layout for a VM with `size_mib` MiB RAM.
+    pub fn new(size_mib: u32) -> Self {
+        let size = (size_mib as u64) << 20;
+        let mut regions = Vec::new();
+        let mut add = |start: u64, end: u64, kind: RegionKind| {
+            if end > start {
+                regions.push(Region { start: GuestAddress(start), size: end - start, kind });
+            }
+        };
+        let low_end = std::cmp::min(size, MMIO_HOLE_START);
+        add(0, std::cmp::min(low_end, EBDA_START), RegionKind::Ram);
+        add(EBDA_START, std::cmp::min(low_end, HIGH_MEMORY_START), RegionKind::Reserved);
+        add(HIGH_MEMORY_START, low_end, RegionKind::Ram);
+        add(MMIO_HOLE_START, RESERVED_HOLE_START, RegionKind::Mmio);
+        add(RESERVED_HOLE_START, MMIO_HOLE_END, RegionKind::System);
+        add(MMIO_HOLE_END, MMIO_HOLE_END + size - low_end, RegionKind::Ram);
+        MemoryLayout { regions }
+    }
+
+    /// All the regions sorted by their start addresses.
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// Find the region containing `addr`.
+    pub fn find(&self, addr: GuestAddress) -> Option<&Region> {
+        self.regions.iter().find(|r| r.contains(addr))
+    }
+
+    /// Regions of the given kind.
+    pub fn regions_of(&self, kind: RegionKind) -> impl Iterator<Item = &Region> {
+        self.regions.iter().filter(move |r| r.kind == kind)
+    }
+
+    /// Contiguous ranges which need RAM behind them, in the format of
+    /// (start, size). Adjacent RAM and reserved regions are merged.
+    pub fn ram_ranges(&self) -> Vec<(GuestAddress, u64)> {
+        let mut ranges: Vec<(GuestAddress, u64)> = Vec::new();
+        for r in self.regions.iter() {
+            if r.kind != RegionKind::Ram && r.kind != RegionKind::Reserved {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some((start, size)) if start.unchecked_add(*size) == r.start => {
+                    *size += r.size;
+                },
+                _ => ranges.push((r.start, r.size)),
+            }
+        }
+        ranges
+    }
+
+    /// The first address after the RAM below the MMIO hole.
+    pub fn low_ram_end(&self) -> GuestAddress {
+        self.regions_of(RegionKind::Ram)
+            .filter(|r| r.start.raw_value() < MMIO_HOLE_START)
+            .last()
+            .map(|r| r.end())
+            .unwrap_or(GuestAddress(0))
+    }
+}
+
+/// Allocate the guest RAM for the given configuration.
+///
+/// With a file backend, all the RAM ranges are mapped from a single file 
+/// one after another, so the file can be shared with other processes.
+pub fn create_guest_memory(
+    config: &MemoryConfig,
+    layout: &MemoryLayout
+) -> Result<GuestMemoryMmap> {
+    let backend = &config.backend;
+    let total = (config.size_mib as u64) << 20;
+    check_hugepages(backend.page_size, total)?;
+
+    let file = match &backend.backend {
+        MemoryBackendType::Anonymous => None,
+        MemoryBackendType::Memfd => Some(create_memfd(backend.page_size)?),
+        MemoryBackendType::Hugetlbfs(path) => Some(create_hugetlbfs_file(path)?),
+    };
+    if let Some(file) = file.as_ref() {
+        file.set_len(total).map_err(
+            |e| Error::MemoryError(format!("Failed to resize the backend file: {}", e))
+        )?;
+    }
+
+    let mut flags = if backend.shared { libc::MAP_SHARED } else { libc::MAP_PRIVATE };
+    if backend.prefault {
+        flags |= libc::MAP_POPULATE;
+    }
+    if file.is_none() {
+        flags |= libc::MAP_ANONYMOUS | match backend.page_size {
+            // Hugepages are reserved at mmap time, so a shortage is reported
+            // here instead of killing the VM by SIGBUS at run time.
+            PageSize::Normal => libc::MAP_NORESERVE,
+            PageSize::Huge2M => libc::MAP_HUGETLB | libc::MAP_HUGE_2MB,
+            PageSize::Huge1G => libc::MAP_HUGETLB | libc::MAP_HUGE_1GB,
+        };
+    }
+
+    let mut regions = Vec::new();
+    let mut offset = 0;
+    for (start, size) in layout.ram_ranges() {
+        let file_offset = file.as_ref().map(
+            |f| FileOffset::from_arc(f.clone(), offset)
+        );
+        let region = MmapRegion::build(
+            file_offset,
+            size as usize,
+            libc::PROT_READ | libc::PROT_WRITE,
+            flags
+        ).map_err(|e| mmap_error(e, backend, size))?;
+        regions.push(
+            GuestRegionMmap::new(region, start)
+                .map_err(|e| Error::MemoryError(e.to_string()))?
+        );
+        offset += size;
+    }
+    GuestMemoryMmap::from_regions(regions)
+        .map_err(|e| Error::MemoryError(e.to_string()))
+}
+
+/// Convert a failed mmap to an error, hugepage shortage is reported 
+/// separately.
+fn mmap_error(e: MmapRegionError, backend: &MemoryBackendConfig, size: u64) -> Error {
+    match e {
+        MmapRegionError::Mmap(e) 
+            if backend.page_size.is_huge() && e.raw_os_error() == Some(libc::ENOMEM) => {
+            Error::HugePagesExhausted(format!(
+                "{} bytes with {:?} pages", size, backend.page_size
+            ))
+        },
+        e => Error::MemoryError(e.to_string()),
+    }
+}
+
+/// Make sure there are enough free hugepages for `size` bytes of memory.
+fn check_hugepages(page_size: PageSize, size: u64) -> Result<()> {
+    let dir = match page_size {
+        PageSize::Normal => return Ok(()),
+        PageSize::Huge2M => "hugepages-2048kB",
+        PageSize::Huge1G => "hugepages-1048576kB",
+    };
+    let path = Path::new("/sys/kernel/mm/hugepages").join(dir).join("free_hugepages");
+    let free = fs::read_to_string(&path)
+        .ok()
+        .and_then(|s| s.trim().parse::<u64>().ok())
+        .unwrap_or(0);
+    let needed = size / page_size.bytes();
+    if free < needed {
+        return Err(Error::HugePagesExhausted(format!(
+            "{} {:?} pages needed but only {} free", needed, page_size, free
+        )));
+    }
+    Ok(())
+}
+
+/// Create an anonymous memfd file backed by pages of `page_size`.
+fn create_memfd(page_size: PageSize) -> Result<Arc<File>> {
+    let name = CString::new("shuairan-guest-ram").unwrap();
+    let flags = libc::MFD_CLOEXEC | match page_size {
+        PageSize::Normal => 0,
+        PageSize::Huge2M => libc::MFD_HUGETLB | libc::MFD_HUGE_2MB,
+        PageSize::Huge1G => libc::MFD_HUGETLB | libc::MFD_HUGE_1GB,
+    };
+    // Safe because the name is a valid C string and the result is checked.
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(Error::MemoryError(format!(
+            "Failed to create memfd: {}", std::io::Error::last_os_error()
+        )));
+    }
+    // Safe because the fd is just created and owned by nobody else.
+    Ok(Arc::new(unsafe { File::from_raw_fd(fd) }))
+}
+
+/// Create a file under the hugetlbfs mount point `dir`.
+/// 
+/// The file is unlinked at once, so it goes away with the VM.
+fn create_hugetlbfs_file(dir: &str) -> Result<Arc<File>> {
+    let path = Path::new(dir).join(format!("shuairan-{}", std::process::id()));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(|e| Error::MemoryError(
+            format!("Failed to create {}: {}", path.display(), e)
+        ))?;
+    fs::remove_file(&path).map_err(|e| Error::MemoryError(
+        format!("Failed to unlink {}: {}", path.display(), e)
+    ))?;
+    Ok(Arc::new(file))
+}
+
+/// Register all the guest memory regions to KVM, one slot for each.
+pub fn register_guest_memory(fd: &VmFd, memory: &GuestMemoryMmap) -> Result<()> {
+    for (slot, region) in memory.iter().enumerate() {
+        let host_addr = memory.get_host_address(region.start_addr())
+            .map_err(|e| Error::MemoryError(e.to_string()))?;
+        let region = kvm_userspace_memory_region {
+            slot: slot as u32,
+            guest_phys_addr: region.start_addr().raw_value(),
+            memory_size: region.len(),
+            userspace_addr: host_addr as u64,
+            flags: 0,
+        };
+        // Safe because the mapping lives as long as the VM which owns both
+        // the memory and the fd.
+        unsafe { fd.set_user_memory_region(region) }?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_memory_layout() {
+    let layout = MemoryLayout::new(1024);
+    assert_eq!(
+        layout.regions().iter().map(|r| (r.start.0, r.size, r.kind)).collect::<Vec<_>>(),
+        vec![
+            (0, EBDA_START, RegionKind::Ram),
+            (EBDA_START, HIGH_MEMORY_START - EBDA_START, RegionKind::Reserved),
+            (HIGH_MEMORY_START, (1 << 30) - HIGH_MEMORY_START, RegionKind::Ram),
+            (MMIO_HOLE_START, RESERVED_HOLE_START - MMIO_HOLE_START, RegionKind::Mmio),
+            (RESERVED_HOLE_START, MMIO_HOLE_END - RESERVED_HOLE_START, RegionKind::System),
+        ]
+    );
+    assert_eq!(layout.ram_ranges(), vec![(GuestAddress(0), 1 << 30)]);
+    assert_eq!(layout.low_ram_end(), GuestAddress(1 << 30));
+
+    // RAM beyond the hole is moved above 4 GiB.
+    let layout = MemoryLayout::new(4096);
+    assert_eq!(
+        layout.ram_ranges(),
+        vec![
+            (GuestAddress(0), MMIO_HOLE_START),
+            (GuestAddress(MMIO_HOLE_END), (4 << 30) - MMIO_HOLE_START),
+        ]
+    );
+    assert_eq!(layout.low_ram_end(), GuestAddress(MMIO_HOLE_START));
+    assert_eq!(
+        layout.find(GuestAddress(0xd000_0000)).map(|r| r.kind),
+        Some(RegionKind::Mmio)
+    );
+    assert_eq!(
+        layout.find(GuestAddress(0xfee0_0000)).map(|r| r.kind),
+        Some(RegionKind::System)
+    );
+    assert_eq!(
+        layout.find(GuestAddress(MMIO_HOLE_END)).map(|r| r.kind),
+        Some(RegionKind::Ram)
+    );
+    assert_eq!(layout.find(GuestAddress(MMIO_HOLE_END + (1 << 30))), None);
+}
+
+#[test]
+fn test_guest_memory_backend() {
+    use vm_memory::Bytes;
+    use super::config::MemoryBackendConfig;
+
+    let config = MemoryConfig {
+        size_mib: 16,
+        backend: MemoryBackendConfig {
+            backend: MemoryBackendType::Memfd,
+            shared: true,
+            ..Default::default()
+        }
+    };
+    let memory = create_guest_memory(&config, &MemoryLayout::new(16)).unwrap();
+    assert_eq!(memory.num_regions(), 1);
+    let region = memory.find_region(GuestAddress(0)).unwrap();
+    let file_offset = region.file_offset().unwrap();
+    assert_eq!(file_offset.start(), 0);
+    assert_eq!(file_offset.file().metadata().unwrap().len(), 16 << 20);
+    // Writes to a shared mapping go through the file.
+    memory.write_obj(0x5a5au16, GuestAddress(0x1000)).unwrap();
+    let mut buf = [0u8; 2];
+    std::os::unix::fs::FileExt::read_exact_at(file_offset.file(), &mut buf, 0x1000).unwrap();
+    assert_eq!(u16::from_ne_bytes(buf), 0x5a5a);
+
+    // No host can spare 1 TiB of 1 GiB hugepages.
+    let config = MemoryConfig {
+        size_mib: 1 << 20,
+        backend: MemoryBackendConfig {
+            page_size: PageSize::Huge1G,
+            ..Default::default()
+        }
+    };
+    assert!(matches!(
+        create_guest_memory(&config, &MemoryLayout::new(1 << 20)),
+        Err(Error::HugePagesExhausted(_))
+    ));
+}
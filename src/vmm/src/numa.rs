@@ -0,0 +1,192 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs;
+use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap};
+use super::acpi::NumaNode;
+use super::config::NumaNodeConfig;
+use super::error::{Error, Result};
+use super::memory::MemoryLayout;
+
+/// Memory policy and flags for mbind(2).
+const MPOL_BIND: libc::c_int = 2;
+const MPOL_MF_STRICT: libc::c_uint = 1 << 0;
+const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+
+/// Parse a CPU or node list like "0-3,8,10-11", ids must be below
+/// CPU_SETSIZE.
+pub fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for item in list.trim().split(',').filter(|s| !s.is_empty()) {
+        let (first, last) = match item.split_once('-') {
+            Some((first, last)) => (first.parse::<usize>().ok()?, last.parse::<usize>().ok()?),
+            None => {
+                let id = item.parse::<usize>().ok()?;
+                (id, id)
+            },
+        };
+        if first > last || last >= libc::CPU_SETSIZE as usize {
+            return None;
+        }
+        cpus.extend(first..=last);
+    }
+    Some(cpus)
+}
+
+/// Host CPUs belonging to the host NUMA node `node`, None if the node
+/// doesn't exist.
+pub fn host_node_cpus(node: u32) -> Option<Vec<usize>> {
+    let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+    fs::read_to_string(&path).ok().and_then(|s| parse_cpu_list(&s))
+}
+
+/// Split the guest RAM into consecutive ranges for each node, in the format
+/// of (start, size).
+pub fn node_ranges(
+    layout: &MemoryLayout,
+    nodes: &[NumaNodeConfig]
+) -> Vec<Vec<(GuestAddress, u64)>> {
+    let mut ranges = layout.ram_ranges().into_iter();
+    let mut current = ranges.next();
+    let mut result = Vec::new();
+    for node in nodes.iter() {
+        let mut left = (node.size_mib as u64) << 20;
+        let mut node_ranges = Vec::new();
+        while left > 0 {
+            let (start, size) = match current {
+                Some(range) => range,
+                None => break,
+            };
+            let taken = std::cmp::min(left, size);
+            node_ranges.push((start, taken));
+            left -= taken;
+            current = if taken == size {
+                ranges.next()
+            } else {
+                Some((start.unchecked_add(taken), size - taken))
+            };
+        }
+        result.push(node_ranges);
+    }
+    result
+}
+
+/// Describe the guest NUMA nodes for the ACPI tables.
+pub fn guest_nodes(layout: &MemoryLayout, nodes: &[NumaNodeConfig]) -> Vec<NumaNode> {
+    node_ranges(layout, nodes)
+        .into_iter()
+        .zip(nodes.iter())
+        .enumerate()
+        .map(|(i, (memory, node))| NumaNode {
+            cpus: node.cpus.clone(),
+            memory,
+            distances: node.distances.clone().unwrap_or_else(|| {
+                default_distances(i, nodes.len())
+            }),
+        })
+        .collect()
+}
+
+/// Distances from node `i` to all the `count` nodes by default.
+pub fn default_distances(i: usize, count: usize) -> Vec<u8> {
+    (0..count)
+        .map(|j| if i == j { super::acpi::LOCAL_DISTANCE } else { super::acpi::REMOTE_DISTANCE })
+        .collect()
+}
+
+/// Bind the guest memory of each node to its host NUMA node.
+pub fn bind_memory(
+    memory: &GuestMemoryMmap,
+    layout: &MemoryLayout,
+    nodes: &[NumaNodeConfig]
+) -> Result<()> {
+    for (ranges, node) in node_ranges(layout, nodes).iter().zip(nodes.iter()) {
+        let host_node = match node.host_node {
+            Some(n) => n as usize,
+            None => continue,
+        };
+        let mut mask = vec![0u64; host_node / 64 + 1];
+        mask[host_node / 64] |= 1 << (host_node % 64);
+        for (start, size) in ranges.iter() {
+            let addr = memory.get_host_address(*start)
+                .map_err(|e| Error::MemoryError(e.to_string()))?;
+            // Safe because the range is inside a mapping owned by `memory`
+            // and the mask outlives the call.
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_mbind,
+                    addr as *mut libc::c_void,
+                    *size as libc::c_ulong,
+                    MPOL_BIND,
+                    mask.as_ptr(),
+                    (mask.len() * 64 + 1) as libc::c_ulong,
+                    MPOL_MF_STRICT | MPOL_MF_MOVE,
+                )
+            };
+            if ret < 0 {
+                return Err(Error::MemoryError(format!(
+                    "Failed to bind 0x{:x}+0x{:x} to host node {}: {}",
+                    start.raw_value(), size, host_node, std::io::Error::last_os_error()
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Host CPUs each vcpu should be pinned to, empty for no pinning.
+pub fn vcpu_affinity(vcpus: u32, nodes: &[NumaNodeConfig]) -> Vec<Vec<usize>> {
+    let mut affinity = vec![Vec::new(); vcpus as usize];
+    for node in nodes.iter() {
+        if let Some(host_node) = node.host_node {
+            // The host node is checked when the configuration is parsed.
+            let cpus = host_node_cpus(host_node).unwrap_or_default();
+            for id in node.cpus.iter() {
+                affinity[*id as usize] = cpus.clone();
+            }
+        }
+    }
+    affinity
+}
+
+#[test]
+fn test_parse_cpu_list() {
+    assert_eq!(parse_cpu_list("0-3,8,10-11\n"), Some(vec![0, 1, 2, 3, 8, 10, 11]));
+    assert_eq!(parse_cpu_list("5"), Some(vec![5]));
+    assert_eq!(parse_cpu_list(""), Some(vec![]));
+    assert_eq!(parse_cpu_list("3-1"), None);
+    assert_eq!(parse_cpu_list("a"), None);
+    assert_eq!(parse_cpu_list("1023"), Some(vec![1023]));
+    assert_eq!(parse_cpu_list("1024"), None);
+    assert_eq!(parse_cpu_list("0-4000000000"), None);
+    assert_eq!(parse_cpu_list("0-18446744073709551614"), None);
+}
+
+#[test]
+fn test_node_ranges() {
+    use super::memory::MMIO_HOLE_END;
+
+    let node = |size_mib| NumaNodeConfig {
+        cpus: vec![],
+        size_mib,
+        host_node: None,
+        distances: None,
+    };
+    // The second node spans the MMIO hole.
+    let layout = MemoryLayout::new(4096);
+    let nodes = vec![node(2048), node(2048)];
+    assert_eq!(
+        node_ranges(&layout, &nodes),
+        vec![
+            vec![(GuestAddress(0), 2 << 30)],
+            vec![
+                (GuestAddress(2 << 30), 1 << 30),
+                (GuestAddress(MMIO_HOLE_END), 1 << 30)
+            ],
+        ]
+    );
+    assert_eq!(
+        guest_nodes(&layout, &nodes)[1].distances,
+        vec![super::acpi::REMOTE_DISTANCE, super::acpi::LOCAL_DISTANCE]
+    );
+}
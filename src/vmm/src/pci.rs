@@ -0,0 +1,288 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::{Arc, Mutex};
+use super::bus::{Bus, BusDevice};
+use super::error::{Error, Result};
+use super::memory::{PCI_MMIO_START, RESERVED_HOLE_START};
+
+/// Port of the address register of the configuration mechanism #1, the
+/// data register follows it.
+pub const CONFIG_ADDRESS: u16 = 0xcf8;
+/// Ports taken by the address and data registers.
+pub const CONFIG_LEN: u16 = 8;
+/// Offset of the data register from the address register.
+const CONFIG_DATA: u64 = 4;
+/// Enable bit of the address register.
+const ADDRESS_ENABLE: u32 = 0x8000_0000;
+/// Devices on a PCI bus, each of them only has function 0 here.
+const MAX_DEVICES: usize = 32;
+
+/// Offsets in the configuration space header.
+pub const VENDOR_ID: usize = 0x00;
+pub const COMMAND: usize = 0x04;
+pub const CLASS_REVISION: usize = 0x08;
+pub const HEADER_TYPE: usize = 0x0e;
+pub const BAR0: usize = 0x10;
+pub const ROM_ADDRESS: usize = 0x30;
+pub const CAPABILITY_LIST: usize = 0x34;
+pub const INTERRUPT_LINE: usize = 0x3c;
+/// Number of BARs of a type 0 header.
+pub const BAR_COUNT: usize = 6;
+/// Size of the configuration space of a conventional PCI device.
+pub const CONFIG_SPACE_LEN: usize = 256;
+
+/// Memory space bit of the command register.
+pub const COMMAND_MEMORY: u16 = 0x2;
+/// Capability list bit of the status register.
+pub const STATUS_CAPABILITIES: u16 = 0x10;
+/// Multi-function bit of the header type.
+pub const HEADER_MULTI_FUNCTION: u8 = 0x80;
+/// I/O space bit of a BAR.
+pub const BAR_IO: u32 = 0x1;
+/// 64-bit bit of a memory BAR.
+pub const BAR_MEM_64: u32 = 0x4;
+/// Low bits of a memory BAR which aren't part of the address.
+pub const BAR_MEM_FLAGS: u32 = 0xf;
+
+/// Capability IDs.
+pub const CAP_ID_MSI: u8 = 0x05;
+pub const CAP_ID_MSIX: u8 = 0x11;
+
+/// IDs and class of the host bridge, reported as the one of a generic
+/// virtual machine.
+const HOST_BRIDGE_VENDOR: u32 = 0x8086;
+const HOST_BRIDGE_DEVICE: u32 = 0x0d57;
+const CLASS_HOST_BRIDGE: u32 = 0x0600;
+
+/// Write `data` into the 32-bit register `value` at `offset`, only the low
+/// 2 bits of `offset` are used.
+pub fn merge(value: u32, offset: usize, data: &[u8]) -> u32 {
+    let mut bytes = value.to_le_bytes();
+    let start = offset & 3;
+    let end = std::cmp::min(start + data.len(), 4);
+    bytes[start..end].copy_from_slice(&data[..end - start]);
+    u32::from_le_bytes(bytes)
+}
+
+/// Fill `data` from the 32-bit register `value` at `offset`, only the low
+/// 2 bits of `offset` are used.
+pub fn extract(value: u32, offset: usize, data: &mut [u8]) {
+    let bytes = value.to_le_bytes();
+    let start = offset & 3;
+    let end = std::cmp::min(start + data.len(), 4);
+    data[..end - start].copy_from_slice(&bytes[start..end]);
+}
+
+/// A device on the PCI bus, accessed by the guest through its
+/// configuration space.
+pub trait PciDevice: Send {
+    /// Read the 32-bit register at `offset` of the configuration space,
+    /// `offset` is aligned to 4.
+    fn read_config(&mut self, offset: usize) -> u32;
+    /// Write `data` at `offset` of the configuration space, the access
+    /// doesn't cross a 32-bit register.
+    fn write_config(&mut self, offset: usize, data: &[u8]);
+    /// Bring the device back to its state at power on, e.g. when the guest
+    /// reboots.
+    fn reset(&mut self) {}
+}
+
+/// The host bridge at 00.0, which lets the guest know a PCI bus is there.
+struct HostBridge;
+
+impl PciDevice for HostBridge {
+    fn read_config(&mut self, offset: usize) -> u32 {
+        match offset {
+            VENDOR_ID => HOST_BRIDGE_DEVICE << 16 | HOST_BRIDGE_VENDOR,
+            CLASS_REVISION => CLASS_HOST_BRIDGE << 16,
+            _ => 0,
+        }
+    }
+
+    fn write_config(&mut self, _offset: usize, _data: &[u8]) {}
+}
+
+struct PciBusState {
+    /// Value of the address register.
+    address: u32,
+    /// Devices indexed by their device numbers.
+    devices: Vec<Box<dyn PciDevice>>,
+    /// The first free address of the MMIO window for BARs.
+    next_bar: u64,
+}
+
+/// PCI bus 0 behind the host bridge, its configuration space is accessed
+/// through the configuration mechanism #1 on ports `0xcf8-0xcff`.
+///
+/// BARs of the devices are placed in the window from `PCI_MMIO_START` to
+/// the reserved range below 4 GiB, which is described to the guest by the
+/// ACPI DSDT.
+pub struct PciBus {
+    state: Mutex<PciBusState>,
+}
+
+impl Default for PciBus {
+    fn default() -> Self {
+        let state = PciBusState {
+            address: 0,
+            devices: vec![Box::new(HostBridge)],
+            next_bar: PCI_MMIO_START,
+        };
+        PciBus { state: Mutex::new(state) }
+    }
+}
+
+impl PciBus {
+    /// Create a bus with only the host bridge.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Claim the configuration ports on the bus.
+    pub fn attach(self: &Arc<Self>, pio: &Bus) -> Result<()> {
+        pio.insert(self.clone(), CONFIG_ADDRESS as u64, CONFIG_LEN as u64)
+    }
+
+    /// Plug a device into the next free slot, returns its device number.
+    pub fn add(&self, device: Box<dyn PciDevice>) -> Result<u8> {
+        let mut state = self.state.lock().unwrap();
+        if state.devices.len() == MAX_DEVICES {
+            return Err(Error::BusError("PCI bus is full".to_string()));
+        }
+        state.devices.push(device);
+        Ok(state.devices.len() as u8 - 1)
+    }
+
+    /// Allocate `size` bytes of the MMIO window for a BAR, aligned to its
+    /// size and to a page so that it can be mapped to the guest alone.
+    pub fn allocate_bar(&self, size: u64) -> Result<u64> {
+        let align = std::cmp::max(size.next_power_of_two(), 0x1000);
+        let mut state = self.state.lock().unwrap();
+        let start = state.next_bar.next_multiple_of(align);
+        if size == 0 || start + size > RESERVED_HOLE_START {
+            return Err(Error::BusError(format!(
+                "a BAR of {:#x} bytes doesn't fit the PCI MMIO window", size
+            )));
+        }
+        state.next_bar = start + align;
+        Ok(start)
+    }
+
+    /// Reset all the devices.
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.address = 0;
+        state.devices.iter_mut().for_each(|d| d.reset());
+    }
+}
+
+impl PciBusState {
+    /// The device and the register offset selected by the address
+    /// register, only function 0 of bus 0 exists.
+    fn selected(&mut self) -> Option<(&mut Box<dyn PciDevice>, usize)> {
+        let bus = (self.address >> 16) & 0xff;
+        let device = (self.address >> 11) & 0x1f;
+        let function = (self.address >> 8) & 0x7;
+        if self.address & ADDRESS_ENABLE == 0 || bus != 0 || function != 0 {
+            return None;
+        }
+        let offset = (self.address & 0xfc) as usize;
+        self.devices.get_mut(device as usize).map(|d| (d, offset))
+    }
+}
+
+impl BusDevice for PciBus {
+    fn read(&self, _base: u64, offset: u64, data: &mut [u8]) {
+        let mut state = self.state.lock().unwrap();
+        if offset < CONFIG_DATA {
+            return extract(state.address, offset as usize, data);
+        }
+        let value = match state.selected() {
+            Some((device, register)) => device.read_config(register),
+            None => 0xffff_ffff,
+        };
+        extract(value, offset as usize, data);
+    }
+
+    fn write(&self, _base: u64, offset: u64, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        if offset < CONFIG_DATA {
+            state.address = merge(state.address, offset as usize, data) & !0x3;
+            return;
+        }
+        if let Some((device, register)) = state.selected() {
+            device.write_config(register + (offset as usize & 3), data);
+        }
+    }
+}
+
+#[test]
+fn test_pci_bus() {
+    /// Writes as (offset, data).
+    type Writes = Arc<Mutex<Vec<(usize, Vec<u8>)>>>;
+    struct Recorder(Writes);
+
+    impl PciDevice for Recorder {
+        fn read_config(&mut self, offset: usize) -> u32 {
+            0x1234_0000 | offset as u32
+        }
+
+        fn write_config(&mut self, offset: usize, data: &[u8]) {
+            self.0.lock().unwrap().push((offset, data.to_vec()));
+        }
+    }
+
+    let bus = Arc::new(PciBus::new());
+    let pio = Bus::new();
+    bus.attach(&pio).unwrap();
+    let writes = Arc::new(Mutex::new(Vec::new()));
+    assert_eq!(bus.add(Box::new(Recorder(writes.clone()))), Ok(1));
+
+    let select = |device: u32, offset: u32| {
+        let address = ADDRESS_ENABLE | device << 11 | offset;
+        assert!(pio.write(0xcf8, &address.to_le_bytes()));
+    };
+    let read = |port: u64, len: usize| {
+        let mut data = [0u8; 4];
+        assert!(pio.read(port, &mut data[..len]));
+        u32::from_le_bytes(data)
+    };
+    // Probe of the mechanism by Linux, and the host bridge it looks for.
+    select(0, 0);
+    assert_eq!(read(0xcf8, 4), ADDRESS_ENABLE);
+    assert_eq!(read(0xcfc, 4), 0x0d57_8086);
+    select(0, CLASS_REVISION as u32);
+    assert_eq!(read(0xcfe, 2), 0x0600);
+
+    select(1, 0x44);
+    assert_eq!(read(0xcfc, 4), 0x1234_0044);
+    assert_eq!(read(0xcfd, 1), 0x00);
+    assert_eq!(read(0xcfe, 2), 0x1234);
+    assert!(pio.write(0xcfe, &[0xab, 0xcd]));
+    assert_eq!(*writes.lock().unwrap(), vec![(0x46, vec![0xab, 0xcd])]);
+
+    // Absent devices and functions read as all ones.
+    select(2, 0);
+    assert_eq!(read(0xcfc, 4), 0xffff_ffff);
+    select(1, 1 << 8);
+    assert_eq!(read(0xcfc, 4), 0xffff_ffff);
+    assert!(pio.write(0xcf8, &0x0800u32.to_le_bytes()));
+    assert_eq!(read(0xcfc, 4), 0xffff_ffff);
+
+    for _ in 2..MAX_DEVICES {
+        bus.add(Box::new(HostBridge)).unwrap();
+    }
+    assert!(bus.add(Box::new(HostBridge)).is_err());
+}
+
+#[test]
+fn test_allocate_bar() {
+    let bus = PciBus::new();
+    assert_eq!(bus.allocate_bar(0x10), Ok(PCI_MMIO_START));
+    assert_eq!(bus.allocate_bar(0x4000), Ok(PCI_MMIO_START + 0x4000));
+    assert_eq!(bus.allocate_bar(0x1000), Ok(PCI_MMIO_START + 0x8000));
+    assert_eq!(bus.allocate_bar(0x100_0000), Ok(PCI_MMIO_START + 0x100_0000));
+    assert!(bus.allocate_bar(0x4000_0000).is_err());
+    assert!(bus.allocate_bar(0).is_err());
+}
@@ -0,0 +1,206 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::eventfd::EventFd;
+use super::bus::{Bus, BusDevice};
+use super::error::{Error, Result};
+
+/// Port of the PM1a event block, i.e. PM1_STS followed by PM1_EN.
+pub const PM1_EVT_BLK: u16 = 0x600;
+/// Length of the PM1a event block.
+pub const PM1_EVT_LEN: u8 = 4;
+/// Port of the PM1a control block.
+pub const PM1_CNT_BLK: u16 = 0x604;
+/// Length of the PM1a control block.
+pub const PM1_CNT_LEN: u8 = 2;
+/// Port of the ACPI reset register, i.e. the reset control register.
+pub const RESET_PORT: u16 = 0xcf9;
+/// Value written to the reset register to reset the system.
+pub const RESET_VALUE: u8 = 0x6;
+/// IRQ of the system control interrupt.
+pub const SCI_IRQ: u32 = 9;
+/// SLP_TYP of the S5 (soft off) sleep state.
+pub const S5_SLP_TYP: u8 = 5;
+
+/// Port of the i8042 command register.
+const I8042_COMMAND_PORT: u16 = 0x64;
+/// i8042 command which pulses the CPU reset line.
+const I8042_RESET_CMD: u8 = 0xfe;
+
+/// PM1 status and enable bits.
+const PWRBTN_STS: u16 = 1 << 8;
+const PWRBTN_EN: u16 = 1 << 8;
+/// PM1 control bits.
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0x7 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+
+/// Power requests made by the guest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerEvent {
+    /// The guest entered S5 through the PM1 control register.
+    Poweroff,
+    /// The guest reset the system through the reset register or i8042.
+    Reset,
+}
+
+/// Registers of the power manager.
+#[derive(Default)]
+struct Registers {
+    /// PM1_STS.
+    status: u16,
+    /// PM1_EN.
+    enable: u16,
+    /// PM1_CNT.
+    control: u16,
+    /// The first power request not yet collected.
+    event: Option<PowerEvent>,
+}
+
+/// A minimal ACPI fixed-feature power manager.
+///
+/// It provides the power button, the S5 sleep state and the reset register
+/// described by the FADT, plus the i8042 reset used by `reboot=k`.
+pub struct PowerManager {
+    regs: Mutex<Registers>,
+    /// Irqfd of the SCI, which is edge triggered as described by the MADT.
+    sci: EventFd,
+}
+
+impl PowerManager {
+    /// Create a power manager with all the registers cleared.
+    pub fn new(sci: EventFd) -> Self {
+        PowerManager { regs: Mutex::new(Registers::default()), sci }
+    }
+
+    /// Press the power button, the SCI is raised if the guest enabled it.
+    pub fn press_power_button(&self) -> Result<()> {
+        let mut regs = self.regs.lock().unwrap();
+        regs.status |= PWRBTN_STS;
+        if regs.enable & PWRBTN_EN != 0 {
+            self.sci.write(1).map_err(|e| Error::InterruptError(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Clear all the registers, e.g. when the guest reboots.
+    pub fn reset(&self) {
+        *self.regs.lock().unwrap() = Registers::default();
+    }
+
+    /// Collect the first power request since the last call.
+    pub fn take_event(&self) -> Option<PowerEvent> {
+        self.regs.lock().unwrap().event.take()
+    }
+
+    /// Record a power request, the earlier one wins.
+    fn request(regs: &mut Registers, event: PowerEvent) {
+        regs.event.get_or_insert(event);
+    }
+}
+
+impl PowerManager {
+    /// Claim the ports of the registers on the bus.
+    pub fn attach(self: &Arc<Self>, pio: &Bus) -> Result<()> {
+        pio.insert(self.clone(), PM1_EVT_BLK as u64, PM1_EVT_LEN as u64)?;
+        pio.insert(self.clone(), PM1_CNT_BLK as u64, PM1_CNT_LEN as u64)?;
+        pio.insert(self.clone(), RESET_PORT as u64, 1)?;
+        pio.insert(self.clone(), I8042_COMMAND_PORT as u64, 1)
+    }
+}
+
+impl BusDevice for PowerManager {
+    fn read(&self, base: u64, offset: u64, data: &mut [u8]) {
+        let port = (base + offset) as u16;
+        let regs = self.regs.lock().unwrap();
+        let value = match port {
+            PM1_EVT_BLK => regs.status,
+            p if p == PM1_EVT_BLK + 2 => regs.enable,
+            // The platform is always in ACPI mode.
+            PM1_CNT_BLK => regs.control | SCI_EN,
+            // The i8042 status register, nothing to read and ready to write.
+            I8042_COMMAND_PORT => 0,
+            _ => {
+                data.fill(0xff);
+                return;
+            },
+        };
+        let bytes = value.to_le_bytes();
+        let len = data.len().min(2);
+        data.fill(0);
+        data[..len].copy_from_slice(&bytes[..len]);
+    }
+
+    fn write(&self, base: u64, offset: u64, data: &[u8]) {
+        let port = (base + offset) as u16;
+        let mut value = [0u8; 2];
+        let len = data.len().min(2);
+        value[..len].copy_from_slice(&data[..len]);
+        let value = u16::from_le_bytes(value);
+        let mut regs = self.regs.lock().unwrap();
+        match port {
+            // Status bits are cleared by writing 1s.
+            PM1_EVT_BLK => regs.status &= !value,
+            p if p == PM1_EVT_BLK + 2 => regs.enable = value,
+            PM1_CNT_BLK => {
+                regs.control = value & !SLP_EN;
+                let slp_typ = (value & SLP_TYP_MASK) >> SLP_TYP_SHIFT;
+                if value & SLP_EN != 0 && slp_typ == S5_SLP_TYP as u16 {
+                    Self::request(&mut regs, PowerEvent::Poweroff);
+                }
+            },
+            RESET_PORT if data.len() == 1 && data[0] == RESET_VALUE => {
+                Self::request(&mut regs, PowerEvent::Reset);
+            },
+            I8042_COMMAND_PORT if data.first() == Some(&I8042_RESET_CMD) => {
+                Self::request(&mut regs, PowerEvent::Reset);
+            },
+            _ => {},
+        }
+    }
+}
+
+#[test]
+fn test_power_manager() {
+    let sci = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+    let pm = Arc::new(PowerManager::new(sci.try_clone().unwrap()));
+    let pio = Bus::new();
+    pm.attach(&pio).unwrap();
+    let mut data = [0u8; 2];
+    pio.read(PM1_CNT_BLK as u64, &mut data);
+    assert_eq!(u16::from_le_bytes(data), SCI_EN);
+
+    // Sleep states other than S5 are ignored.
+    pio.write(PM1_CNT_BLK as u64, &(SLP_EN | (1 << SLP_TYP_SHIFT)).to_le_bytes());
+    assert_eq!(pm.take_event(), None);
+    pio.write(
+        PM1_CNT_BLK as u64,
+        &(SLP_EN | ((S5_SLP_TYP as u16) << SLP_TYP_SHIFT)).to_le_bytes()
+    );
+    pio.write(I8042_COMMAND_PORT as u64, &[I8042_RESET_CMD]);
+    assert_eq!(pm.take_event(), Some(PowerEvent::Poweroff));
+    assert_eq!(pm.take_event(), None);
+
+    // Only byte writes hit the reset register.
+    pio.write(RESET_PORT as u64, &[RESET_VALUE, 0, 0, 0]);
+    assert_eq!(pm.take_event(), None);
+    pio.write(RESET_PORT as u64, &[RESET_VALUE]);
+    assert_eq!(pm.take_event(), Some(PowerEvent::Reset));
+
+    // The power button status is set regardless of PWRBTN_EN, and is
+    // cleared by writing 1.
+    assert_eq!(pm.press_power_button(), Ok(()));
+    assert!(sci.read().is_err());
+    pio.read(PM1_EVT_BLK as u64, &mut data);
+    assert_eq!(u16::from_le_bytes(data), PWRBTN_STS);
+    pio.write(PM1_EVT_BLK as u64, &PWRBTN_STS.to_le_bytes());
+    pio.read(PM1_EVT_BLK as u64, &mut data);
+    assert_eq!(u16::from_le_bytes(data), 0);
+
+    // The SCI is raised once the guest enables it.
+    pio.write((PM1_EVT_BLK + 2) as u64, &PWRBTN_EN.to_le_bytes());
+    assert_eq!(pm.press_power_button(), Ok(()));
+    assert_eq!(sci.read().unwrap(), 1);
+}
@@ -0,0 +1,698 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::VecDeque,
+    ffi::CStr,
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Read, Write},
+    mem,
+    os::unix::{
+        fs::{FileTypeExt, OpenOptionsExt},
+        io::{AsRawFd, FromRawFd, RawFd},
+        net::{UnixListener, UnixStream},
+    },
+    sync::{Arc, Mutex, Weak},
+    thread,
+    time::Duration,
+};
+use vmm_sys_util::eventfd::EventFd;
+use super::bus::{Bus, BusDevice};
+use super::config::ConsoleBackend;
+use super::error::{Error, Result};
+
+/// First port of COM1.
+pub const COM1_BASE: u16 = 0x3f8;
+/// Number of ports taken by a 16550A.
+pub const COM1_LEN: u16 = 8;
+/// IRQ of COM1.
+pub const COM1_IRQ: u32 = 4;
+
+/// Register offsets, the first two are the divisor latch when DLAB is set.
+const DATA: u16 = 0;
+const IER: u16 = 1;
+const IIR: u16 = 2;
+const LCR: u16 = 3;
+const MCR: u16 = 4;
+const LSR: u16 = 5;
+const MSR: u16 = 6;
+const SCR: u16 = 7;
+
+/// Interrupt enable bits.
+const IER_RDI: u8 = 0x01;
+const IER_THRI: u8 = 0x02;
+const IER_MASK: u8 = 0x0f;
+/// Interrupt identification values.
+const IIR_NO_INT: u8 = 0x01;
+const IIR_THRI: u8 = 0x02;
+const IIR_RDI: u8 = 0x04;
+const IIR_FIFO: u8 = 0xc0;
+/// FIFO control bits.
+const FCR_ENABLE: u8 = 0x01;
+const FCR_CLEAR_RCVR: u8 = 0x02;
+/// Line control bits.
+const LCR_DLAB: u8 = 0x80;
+const LCR_8N1: u8 = 0x03;
+/// Modem control bits.
+const MCR_DTR: u8 = 0x01;
+const MCR_RTS: u8 = 0x02;
+const MCR_OUT1: u8 = 0x04;
+const MCR_OUT2: u8 = 0x08;
+const MCR_LOOP: u8 = 0x10;
+/// Modem status bits.
+const MSR_CTS: u8 = 0x10;
+const MSR_DSR: u8 = 0x20;
+const MSR_RI: u8 = 0x40;
+const MSR_DCD: u8 = 0x80;
+/// Line status bits.
+const LSR_DR: u8 = 0x01;
+const LSR_THRE: u8 = 0x20;
+const LSR_TEMT: u8 = 0x40;
+
+/// Size of the receiver FIFO.
+const FIFO_SIZE: usize = 64;
+/// Divisor of 9600 baud with the 1.8432 MHz clock.
+const DEFAULT_DIVISOR: u16 = 12;
+/// Interval for retrying input which doesn't fit in the receiver FIFO.
+const INPUT_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Registers of the UART.
+struct Registers {
+    /// IER.
+    enable: u8,
+    /// FCR, only the enable bit is kept.
+    fifo: u8,
+    /// LCR.
+    line_control: u8,
+    /// MCR.
+    modem_control: u8,
+    /// SCR.
+    scratch: u8,
+    /// DLL and DLM.
+    divisor: u16,
+    /// The THR empty interrupt is pending until IIR is read or THR written.
+    thr_pending: bool,
+    /// Receiver FIFO, LSR_DR is set if it isn't empty.
+    input: VecDeque<u8>,
+}
+
+impl Default for Registers {
+    fn default() -> Self {
+        Registers {
+            enable: 0,
+            fifo: 0,
+            line_control: LCR_8N1,
+            modem_control: MCR_OUT2,
+            scratch: 0,
+            divisor: DEFAULT_DIVISOR,
+            thr_pending: false,
+            input: VecDeque::new(),
+        }
+    }
+}
+
+impl Registers {
+    fn dlab(&self) -> bool {
+        self.line_control & LCR_DLAB != 0
+    }
+
+    /// The pending interrupt with the highest priority.
+    fn identification(&self) -> u8 {
+        if self.enable & IER_RDI != 0 && !self.input.is_empty() {
+            IIR_RDI
+        } else if self.enable & IER_THRI != 0 && self.thr_pending {
+            IIR_THRI
+        } else {
+            IIR_NO_INT
+        }
+    }
+
+    /// In loopback mode, modem control outputs are wired to status inputs.
+    fn modem_status(&self) -> u8 {
+        if self.modem_control & MCR_LOOP == 0 {
+            return MSR_DCD | MSR_DSR | MSR_CTS;
+        }
+        let mcr = self.modem_control;
+        let mut msr = 0;
+        for (out, input) in [
+            (MCR_DTR, MSR_DSR), (MCR_RTS, MSR_CTS),
+            (MCR_OUT1, MSR_RI), (MCR_OUT2, MSR_DCD)
+        ] {
+            if mcr & out != 0 {
+                msr |= input;
+            }
+        }
+        msr
+    }
+}
+
+/// An emulated 16550A UART on COM1.
+///
+/// Characters are transmitted to the backend as soon as they are written,
+/// so the transmitter is always empty. Interrupts are edges written to an
+/// eventfd, which is registered as the irqfd of `COM1_IRQ`.
+pub struct Serial {
+    regs: Mutex<Registers>,
+    output: Mutex<Box<dyn Write + Send>>,
+    interrupt: EventFd,
+    /// Path to the pseudo terminal backing the UART, if any.
+    pty: Option<String>,
+}
+
+impl Serial {
+    /// Create a UART transmitting to `output`.
+    pub fn new(output: Box<dyn Write + Send>, interrupt: EventFd) -> Self {
+        Serial {
+            regs: Mutex::new(Registers::default()),
+            output: Mutex::new(output),
+            interrupt,
+            pty: None,
+        }
+    }
+
+    /// Create a UART connected to the host backend, it transmits to nowhere
+    /// if there is no backend.
+    ///
+    /// Input from the backend is received by a thread named `serial-input`,
+    /// which exits once the UART is dropped and the input ends.
+    pub fn open(
+        backend: Option<&ConsoleBackend>,
+        interrupt: EventFd
+    ) -> Result<Arc<Self>> {
+        let mut pty = None;
+        let (output, input): (Box<dyn Write + Send>, Option<Box<dyn Read + Send>>) =
+            match backend {
+                None => (Box::new(io::sink()), None),
+                Some(ConsoleBackend::Stdio) => (
+                    Box::new(StdioOutput::new()?),
+                    Some(Box::new(io::stdin()))
+                ),
+                Some(ConsoleBackend::Pty) => {
+                    let (master, slave, path) = open_pty()?;
+                    pty = Some(path);
+                    (
+                        Box::new(LossyWriter(master.try_clone().map_err(io_error)?)),
+                        Some(Box::new(PollReader { inner: master, _peer: Some(slave) })),
+                    )
+                },
+                Some(ConsoleBackend::Socket(path)) => {
+                    let input = SocketInput::bind(path)?;
+                    (Box::new(SocketOutput(input.client.clone())), Some(Box::new(input)))
+                },
+                Some(ConsoleBackend::File(path)) => {
+                    let file = OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .map_err(|e| Error::IOError(format!("{}: {}", path, e)))?;
+                    (Box::new(file), None)
+                },
+            };
+        let serial = Arc::new(Serial { pty, ..Serial::new(output, interrupt) });
+        if let Some(input) = input {
+            spawn_input(Arc::downgrade(&serial), input)?;
+        }
+        Ok(serial)
+    }
+
+    /// Path to the pseudo terminal of the `pty` backend, for the user to
+    /// connect to.
+    pub fn pty(&self) -> Option<&str> {
+        self.pty.as_deref()
+    }
+
+    /// Put received characters into the FIFO, returns how many of them fit.
+    pub fn enqueue(&self, data: &[u8]) -> usize {
+        let mut regs = self.regs.lock().unwrap();
+        let len = data.len().min(FIFO_SIZE - regs.input.len());
+        regs.input.extend(&data[..len]);
+        if len > 0 && regs.enable & IER_RDI != 0 {
+            self.raise();
+        }
+        len
+    }
+
+    /// Claim the ports of COM1 on the bus.
+    pub fn attach(self: &Arc<Self>, pio: &Bus) -> Result<()> {
+        pio.insert(self.clone(), COM1_BASE as u64, COM1_LEN as u64)
+    }
+
+    /// Reset all the registers and drop the received characters, e.g. when
+    /// the guest reboots.
+    pub fn reset(&self) {
+        *self.regs.lock().unwrap() = Registers::default();
+    }
+
+    /// Signal an interrupt edge to the irqchip.
+    fn raise(&self) {
+        // The counter only overflows if the irqfd is gone, nothing to do.
+        let _ = self.interrupt.write(1);
+    }
+
+    /// Read the register at `offset`.
+    fn read_register(&self, offset: u16) -> u8 {
+        let mut regs = self.regs.lock().unwrap();
+        match offset {
+            DATA if regs.dlab() => regs.divisor as u8,
+            IER if regs.dlab() => (regs.divisor >> 8) as u8,
+            DATA => regs.input.pop_front().unwrap_or(0),
+            IER => regs.enable,
+            IIR => {
+                let iir = regs.identification();
+                if iir == IIR_THRI {
+                    regs.thr_pending = false;
+                }
+                let fifo = if regs.fifo & FCR_ENABLE != 0 { IIR_FIFO } else { 0 };
+                iir | fifo
+            },
+            LCR => regs.line_control,
+            MCR => regs.modem_control,
+            LSR => {
+                let ready = if regs.input.is_empty() { 0 } else { LSR_DR };
+                LSR_THRE | LSR_TEMT | ready
+            },
+            MSR => regs.modem_status(),
+            SCR => regs.scratch,
+            _ => 0xff,
+        }
+    }
+
+    /// Write `value` to the register at `offset`.
+    fn write_register(&self, offset: u16, value: u8) {
+        let mut regs = self.regs.lock().unwrap();
+        match offset {
+            DATA if regs.dlab() => {
+                regs.divisor = (regs.divisor & 0xff00) | value as u16;
+            },
+            IER if regs.dlab() => {
+                regs.divisor = (regs.divisor & 0x00ff) | ((value as u16) << 8);
+            },
+            DATA => {
+                if regs.modem_control & MCR_LOOP != 0 {
+                    if regs.input.len() < FIFO_SIZE {
+                        regs.input.push_back(value);
+                    }
+                } else {
+                    // Output is best effort, the guest can't do anything
+                    // about a broken backend.
+                    let mut output = self.output.lock().unwrap();
+                    let _ = output.write_all(&[value]).and_then(|_| output.flush());
+                }
+                regs.thr_pending = true;
+                if regs.identification() != IIR_NO_INT {
+                    self.raise();
+                }
+            },
+            IER => {
+                let enabled = value & !regs.enable;
+                regs.enable = value & IER_MASK;
+                // The transmitter is always empty, so enabling its interrupt
+                // raises one at once.
+                if enabled & IER_THRI != 0 {
+                    regs.thr_pending = true;
+                }
+                if enabled & (IER_THRI | IER_RDI) != 0
+                    && regs.identification() != IIR_NO_INT {
+                    self.raise();
+                }
+            },
+            IIR => {
+                regs.fifo = value & FCR_ENABLE;
+                if value & FCR_CLEAR_RCVR != 0 {
+                    regs.input.clear();
+                }
+            },
+            LCR => regs.line_control = value,
+            MCR => regs.modem_control = value,
+            SCR => regs.scratch = value,
+            // LSR and MSR are read-only.
+            _ => {},
+        }
+    }
+}
+
+impl BusDevice for Serial {
+    fn read(&self, _base: u64, offset: u64, data: &mut [u8]) {
+        data.fill(0);
+        if let Some(byte) = data.first_mut() {
+            *byte = self.read_register(offset as u16);
+        }
+    }
+
+    fn write(&self, _base: u64, offset: u64, data: &[u8]) {
+        if let Some(&value) = data.first() {
+            self.write_register(offset as u16, value);
+        }
+    }
+}
+
+/// Feed the input into the UART until either of them is gone.
+fn spawn_input(serial: Weak<Serial>, mut input: Box<dyn Read + Send>) -> Result<()> {
+    thread::Builder::new()
+        .name("serial-input".to_string())
+        .spawn(move || {
+            let mut buf = [0u8; FIFO_SIZE];
+            loop {
+                let len = match input.read(&mut buf) {
+                    Ok(0) => return,
+                    Ok(len) => len,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(_) => return,
+                };
+                let mut data = &buf[..len];
+                while let Some(serial) = serial.upgrade() {
+                    data = &data[serial.enqueue(data)..];
+                    if data.is_empty() {
+                        break;
+                    }
+                    // Wait for the guest to drain the FIFO.
+                    drop(serial);
+                    thread::sleep(INPUT_RETRY_INTERVAL);
+                }
+                if !data.is_empty() {
+                    return;
+                }
+            }
+        })
+        .map(|_| ())
+        .map_err(|e| Error::IOError(format!("serial-input: {}", e)))
+}
+
+fn io_error(e: io::Error) -> Error {
+    Error::IOError(e.to_string())
+}
+
+/// Put the terminal in raw mode, returns the previous attributes.
+fn set_raw_mode(fd: RawFd) -> io::Result<libc::termios> {
+    // Safe because termios is plain data filled by tcgetattr, and both
+    // calls only access the given struct.
+    unsafe {
+        let mut termios: libc::termios = mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let saved = termios;
+        libc::cfmakeraw(&mut termios);
+        if libc::tcsetattr(fd, libc::TCSANOW, &termios) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(saved)
+    }
+}
+
+/// Saves the attributes of stdin if it's a terminal, and restores them when
+/// dropped.
+///
+/// The serial console may leave the terminal in raw mode if the process
+/// exits while a vcpu thread still holds it, so the caller of `Vmm` should
+/// own a guard for the whole run.
+pub struct TerminalGuard {
+    /// Terminal attributes to restore, `None` if stdin isn't a terminal.
+    saved: Option<libc::termios>,
+}
+
+impl TerminalGuard {
+    pub fn new() -> Self {
+        let stdin = io::stdin().as_raw_fd();
+        // Safe because isatty only checks the fd, and termios is plain data
+        // filled by tcgetattr.
+        let saved = unsafe {
+            let mut termios: libc::termios = mem::zeroed();
+            if libc::isatty(stdin) == 1 && libc::tcgetattr(stdin, &mut termios) == 0 {
+                Some(termios)
+            } else {
+                None
+            }
+        };
+        TerminalGuard { saved }
+    }
+}
+
+impl Default for TerminalGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        if let Some(termios) = self.saved.as_ref() {
+            // Safe because termios was filled by tcgetattr.
+            unsafe { libc::tcsetattr(io::stdin().as_raw_fd(), libc::TCSANOW, termios) };
+        }
+    }
+}
+
+/// Stdout of the hypervisor, stdin is kept in raw mode while it lives so
+/// that keys like Ctrl-C go to the guest.
+struct StdioOutput {
+    /// Restores stdin when the output is dropped.
+    _terminal: TerminalGuard,
+}
+
+impl StdioOutput {
+    fn new() -> Result<Self> {
+        let terminal = TerminalGuard::new();
+        if terminal.saved.is_some() {
+            set_raw_mode(io::stdin().as_raw_fd()).map_err(io_error)?;
+        }
+        Ok(StdioOutput { _terminal: terminal })
+    }
+}
+
+impl Write for StdioOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stdout().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+/// Allocate a pseudo terminal, returns its nonblocking master, its slave
+/// in raw mode and the path to the slave.
+fn open_pty() -> Result<(File, File, String)> {
+    let flags = libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK | libc::O_CLOEXEC;
+    // Safe because the fd is checked and owned by the File right away.
+    let fd = unsafe { libc::posix_openpt(flags) };
+    if fd < 0 {
+        return Err(io_error(io::Error::last_os_error()));
+    }
+    let master = unsafe { File::from_raw_fd(fd) };
+    let mut name = [0 as libc::c_char; 64];
+    // Safe because the fd is valid and ptsname_r writes no more than the
+    // given length.
+    let path = unsafe {
+        if libc::grantpt(fd) < 0 || libc::unlockpt(fd) < 0
+            || libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0 {
+            return Err(io_error(io::Error::last_os_error()));
+        }
+        CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned()
+    };
+    // Keeping the slave open stops the master from failing with EIO before
+    // anyone connects.
+    let slave = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(&path)
+        .map_err(|e| Error::IOError(format!("{}: {}", path, e)))?;
+    set_raw_mode(slave.as_raw_fd()).map_err(io_error)?;
+    Ok((master, slave, path))
+}
+
+/// Wait until the fd is readable or hung up.
+fn wait_readable(fd: RawFd) -> io::Result<()> {
+    let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    // Safe because pollfd lives across the call.
+    if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Blocking reads from a nonblocking file.
+struct PollReader {
+    inner: File,
+    /// The other end which has to be kept open, e.g. the pty slave.
+    _peer: Option<File>,
+}
+
+impl Read for PollReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.inner.read(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    wait_readable(self.inner.as_raw_fd())?;
+                },
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Writes to a nonblocking file, output which doesn't fit is dropped
+/// instead of stalling the vcpu.
+struct LossyWriter<T: Write>(T);
+
+impl<T: Write> Write for LossyWriter<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0.write(buf) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(buf.len()),
+            result => result,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The client currently connected to the console socket.
+type Client = Arc<Mutex<Option<UnixStream>>>;
+
+/// Input from the console socket, one client is served at a time and the
+/// next one is accepted once it disconnects.
+struct SocketInput {
+    listener: UnixListener,
+    client: Client,
+}
+
+impl SocketInput {
+    fn bind(path: &str) -> Result<Self> {
+        // Only a stale socket is removed, any other file is left alone.
+        if fs::symlink_metadata(path).map(|m| m.file_type().is_socket()).unwrap_or(false) {
+            fs::remove_file(path).map_err(|e| Error::IOError(format!("{}: {}", path, e)))?;
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|e| Error::IOError(format!("{}: {}", path, e)))?;
+        Ok(SocketInput { listener, client: Arc::new(Mutex::new(None)) })
+    }
+
+    /// The connected client, or the next one to connect.
+    fn connect(&self) -> io::Result<UnixStream> {
+        if let Some(stream) = self.client.lock().unwrap().as_ref() {
+            return stream.try_clone();
+        }
+        let (stream, _) = self.listener.accept()?;
+        stream.set_nonblocking(true)?;
+        *self.client.lock().unwrap() = Some(stream.try_clone()?);
+        Ok(stream)
+    }
+}
+
+impl Read for SocketInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut stream = self.connect()?;
+            match stream.read(buf) {
+                Ok(len) if len > 0 => return Ok(len),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    wait_readable(stream.as_raw_fd())?;
+                },
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                // The client has gone.
+                _ => *self.client.lock().unwrap() = None,
+            }
+        }
+    }
+}
+
+/// Output to the console socket, dropped if no client is connected.
+struct SocketOutput(Client);
+
+impl Write for SocketOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut client = self.0.lock().unwrap();
+        if let Some(stream) = client.as_mut() {
+            match stream.write(buf) {
+                Ok(_) => {},
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {},
+                Err(_) => *client = None,
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_serial_registers() {
+    use std::sync::mpsc::{Sender, channel};
+
+    struct Output(Sender<u8>);
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            buf.iter().for_each(|&b| self.0.send(b).unwrap());
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let (send, recv) = channel();
+    let interrupt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+    let serial = Arc::new(Serial::new(Box::new(Output(send)), interrupt.try_clone().unwrap()));
+    let pio = Bus::new();
+    serial.attach(&pio).unwrap();
+    let read = |offset| {
+        let mut data = [0u8];
+        pio.read((COM1_BASE + offset) as u64, &mut data);
+        data[0]
+    };
+    let write = |offset, data: &[u8]| pio.write((COM1_BASE + offset) as u64, data);
+
+    // The transmitter is always empty and the output goes to the backend.
+    assert_eq!(read(LSR), LSR_THRE | LSR_TEMT);
+    write(DATA, b"k");
+    assert_eq!(recv.try_recv(), Ok(b'k'));
+    assert!(interrupt.read().is_err());
+
+    // Divisor latch.
+    write(LCR, &[LCR_DLAB | LCR_8N1]);
+    write(DATA, &[0x01]);
+    write(IER, &[0x00]);
+    assert_eq!(read(DATA), 0x01);
+    assert!(recv.try_recv().is_err());
+    write(LCR, &[LCR_8N1]);
+
+    // Enabling the THR empty interrupt raises one, reading IIR clears it.
+    write(IER, &[IER_THRI | IER_RDI]);
+    assert_eq!(interrupt.read().unwrap(), 1);
+    assert_eq!(read(IIR), IIR_THRI);
+    assert_eq!(read(IIR), IIR_NO_INT);
+
+    // Received data has a higher priority and is drained by reading RBR.
+    write(IIR, &[FCR_ENABLE]);
+    assert_eq!(serial.enqueue(&[b'a'; FIFO_SIZE + 1]), FIFO_SIZE);
+    assert_eq!(interrupt.read().unwrap(), 1);
+    assert_eq!(read(IIR), IIR_FIFO | IIR_RDI);
+    assert_eq!(read(LSR) & LSR_DR, LSR_DR);
+    (0..FIFO_SIZE).for_each(|_| assert_eq!(read(DATA), b'a'));
+    assert_eq!(read(LSR) & LSR_DR, 0);
+
+    // Loopback as probed by the Linux 8250 driver.
+    write(MCR, &[MCR_LOOP | MCR_OUT2 | MCR_RTS]);
+    assert_eq!(read(MSR) & 0xf0, MSR_DCD | MSR_CTS);
+    write(DATA, b"x");
+    assert!(recv.try_recv().is_err());
+    assert_eq!(read(DATA), b'x');
+
+    serial.reset();
+    assert_eq!(read(IER), 0);
+    assert_eq!(read(MCR), MCR_OUT2);
+}
+
+#[test]
+fn test_serial_pty() {
+    let interrupt = || EventFd::new(libc::EFD_NONBLOCK).unwrap();
+    let serial = Serial::open(Some(&ConsoleBackend::Pty), interrupt()).unwrap();
+    assert!(serial.pty().unwrap().starts_with("/dev/pts/"));
+    let serial = Serial::open(None, interrupt()).unwrap();
+    assert_eq!(serial.pty(), None);
+}
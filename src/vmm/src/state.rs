@@ -0,0 +1,129 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex, mpsc::{Receiver, Sender, channel}},
+};
+use super::error::{Error, IllegalTransition, Result};
+
+/// A state of some lifecycle, e.g. `VmStatus` or `VcpuStatus`.
+pub trait State: Debug + Clone + Copy + PartialEq + Send + 'static {
+    /// Whether the transition from `self` to `to` is legal.
+    fn can_transit(&self, to: Self) -> bool;
+
+    /// Describe the illegal transition from `self` to `to` for errors.
+    fn illegal(&self, to: Self) -> IllegalTransition;
+}
+
+/// Event emitted when a state machine transits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transition<S: State> {
+    /// ID of the object owning the state machine, e.g. the vcpu id.
+    pub id: u32,
+    /// State before the transition.
+    pub from: S,
+    /// State after the transition.
+    pub to: S,
+}
+
+/// Receivers of transition events, it can be shared by state machines
+/// living in different threads.
+#[derive(Clone)]
+pub struct Observers<S: State>(Arc<Mutex<Vec<Sender<Transition<S>>>>>);
+
+impl<S: State> Default for Observers<S> {
+    fn default() -> Self {
+        Observers(Arc::new(Mutex::new(Vec::new())))
+    }
+}
+
+impl<S: State> Observers<S> {
+    /// Subscribe to all the following transitions.
+    pub fn subscribe(&self) -> Receiver<Transition<S>> {
+        let (send, recv) = channel();
+        self.0.lock().unwrap().push(send);
+        recv
+    }
+
+    /// Send the event to all the observers, dropping those which have gone.
+    fn notify(&self, event: Transition<S>) {
+        self.0.lock().unwrap().retain(|s| s.send(event).is_ok());
+    }
+}
+
+/// A state machine which only takes legal transitions.
+pub struct StateMachine<S: State> {
+    /// ID of the object owning the state machine.
+    id: u32,
+    /// Current state.
+    current: S,
+    /// Receivers of transition events.
+    observers: Observers<S>,
+}
+
+impl<S: State> StateMachine<S> {
+    /// Create a state machine at the `initial` state.
+    pub fn new(id: u32, initial: S, observers: Observers<S>) -> Self {
+        StateMachine { id, current: initial, observers }
+    }
+
+    /// Current state.
+    pub fn current(&self) -> S {
+        self.current
+    }
+
+    /// Check whether the transition to `to` is legal without taking it.
+    pub fn check(&self, to: S) -> Result<()> {
+        if !self.current.can_transit(to) {
+            return Err(self.invalid(to));
+        }
+        Ok(())
+    }
+
+    /// Error for transiting to `to` from the current state, which is legal 
+    /// in general but not for the specific operation.
+    pub fn invalid(&self, to: S) -> Error {
+        Error::InvalidStateTransition(self.current.illegal(to))
+    }
+
+    /// Transit to `to` and notify the observers.
+    pub fn transit(&mut self, to: S) -> Result<()> {
+        self.check(to)?;
+        let event = Transition { id: self.id, from: self.current, to };
+        self.current = to;
+        self.observers.notify(event);
+        Ok(())
+    }
+
+    /// Subscribe to all the following transitions.
+    pub fn subscribe(&self) -> Receiver<Transition<S>> {
+        self.observers.subscribe()
+    }
+}
+
+#[test]
+fn test_state_machine() {
+    use super::vm::VmStatus;
+
+    let mut machine = StateMachine::new(1, VmStatus::Epoch, Observers::default());
+    let events = machine.subscribe();
+    assert_eq!(machine.transit(VmStatus::Paused), Ok(()));
+    assert_eq!(
+        machine.transit(VmStatus::Paused),
+        Err(Error::InvalidStateTransition(
+            IllegalTransition::Vm(VmStatus::Paused, VmStatus::Paused)
+        ))
+    );
+    assert_eq!(machine.current(), VmStatus::Paused);
+    // Only the legal transition is emitted.
+    assert_eq!(
+        events.try_recv(),
+        Ok(Transition { id: 1, from: VmStatus::Epoch, to: VmStatus::Paused })
+    );
+    assert!(events.try_recv().is_err());
+    // Observers which have gone are dropped silently.
+    drop(events);
+    assert_eq!(machine.transit(VmStatus::Running), Ok(()));
+    assert_eq!(machine.current(), VmStatus::Running);
+}
@@ -0,0 +1,285 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::{fs::OpenOptionsExt, io::{AsRawFd, FromRawFd, RawFd}},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use crate::error::{Error, Result};
+
+/// Length of struct virtio_net_hdr_v1, which prefixes the packets.
+pub const VNET_HDR_LEN: usize = 12;
+
+/// A queue of a host TAP interface, whose packets are prefixed by a
+/// virtio-net header.
+pub struct Tap {
+    file: File,
+    name: String,
+    /// Whether the queue is attached to the interface.
+    attached: AtomicBool,
+}
+
+impl Tap {
+    /// Open a queue of the TAP interface `name`, which is created if
+    /// missing. The kernel names it if `name` is empty.
+    pub fn open(name: &str, multi_queue: bool) -> Result<Self> {
+        // The configured names are checked when parsing the configuration.
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(Error::IOError(format!("{}: the TAP name is too long", name)));
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open("/dev/net/tun")
+            .map_err(|e| Error::IOError(format!("/dev/net/tun: {}", e)))?;
+        let mut flags = libc::IFF_TAP | libc::IFF_NO_PI | libc::IFF_VNET_HDR;
+        if multi_queue {
+            flags |= libc::IFF_MULTI_QUEUE;
+        }
+        let mut ifreq = Self::ifreq(name, flags);
+        // Safe because the kernel only accesses the ifreq.
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF, &mut ifreq) } < 0 {
+            return Err(Self::error(name, io::Error::last_os_error()));
+        }
+        let tap = Tap { file, name: Self::ifreq_name(&ifreq), attached: AtomicBool::new(true) };
+        tap.set_vnet_hdr_len()?;
+        Ok(tap)
+    }
+
+    /// Take over a TAP interface opened by the caller, with `IFF_VNET_HDR`.
+    pub fn from_fd(fd: RawFd) -> Result<Self> {
+        let error = |e: io::Error| Error::IOError(format!("fd {}: {}", fd, e));
+        // Safe because the fd isn't used, only checked.
+        if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+            return Err(error(io::Error::last_os_error()));
+        }
+        // Safe because the caller hands the fd over.
+        let file = unsafe { File::from_raw_fd(fd) };
+        let mut ifreq = Self::ifreq("", 0);
+        // Safe because the kernel only writes the ifreq.
+        if unsafe { libc::ioctl(fd, libc::TUNGETIFF, &mut ifreq) } < 0 {
+            return Err(error(io::Error::last_os_error()));
+        }
+        // Safe because TUNGETIFF sets the flags.
+        let flags = unsafe { ifreq.ifr_ifru.ifru_flags } as libc::c_int;
+        let required = libc::IFF_TAP | libc::IFF_NO_PI | libc::IFF_VNET_HDR;
+        if flags & required != required {
+            return Err(error(io::Error::other("not a TAP with IFF_NO_PI and IFF_VNET_HDR")));
+        }
+        // Safe because no memory is involved.
+        let status = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if status < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, status | libc::O_NONBLOCK) } < 0 {
+            return Err(error(io::Error::last_os_error()));
+        }
+        let tap = Tap { file, name: Self::ifreq_name(&ifreq), attached: AtomicBool::new(true) };
+        tap.set_vnet_hdr_len()?;
+        Ok(tap)
+    }
+
+    /// Name of the interface.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Let the kernel hand over packets with the offloads in `TUN_F_*`
+    /// flags, which the guest accepts.
+    pub fn set_offload(&self, flags: libc::c_uint) -> Result<()> {
+        // Safe because the argument is passed by value.
+        let ret = unsafe {
+            libc::ioctl(self.file.as_raw_fd(), libc::TUNSETOFFLOAD, flags as libc::c_ulong)
+        };
+        match ret {
+            0 => Ok(()),
+            _ => Err(Self::error(&self.name, io::Error::last_os_error())),
+        }
+    }
+
+    /// Attach or detach the queue of a multi-queue interface, packets are
+    /// only steered to attached queues.
+    pub fn set_queue_enabled(&self, enabled: bool) -> Result<()> {
+        if self.attached.load(Ordering::SeqCst) == enabled {
+            return Ok(());
+        }
+        let flags = match enabled {
+            true => libc::IFF_ATTACH_QUEUE,
+            false => libc::IFF_DETACH_QUEUE,
+        };
+        let mut ifreq = Self::ifreq("", flags);
+        // Safe because the kernel only reads the ifreq.
+        match unsafe { libc::ioctl(self.file.as_raw_fd(), libc::TUNSETQUEUE, &mut ifreq) } {
+            0 => {
+                self.attached.store(enabled, Ordering::SeqCst);
+                Ok(())
+            },
+            _ => Err(Self::error(&self.name, io::Error::last_os_error())),
+        }
+    }
+
+    /// Read a packet into the buffers, which must be valid for writes.
+    pub fn read_vectored(&self, iovecs: &[libc::iovec]) -> io::Result<usize> {
+        // Safe because the buffers are valid as the caller promises.
+        let ret = unsafe {
+            libc::readv(self.file.as_raw_fd(), iovecs.as_ptr(), iovecs.len() as libc::c_int)
+        };
+        match ret {
+            n if n < 0 => Err(io::Error::last_os_error()),
+            n => Ok(n as usize),
+        }
+    }
+
+    /// Write a packet from the buffers, which must be valid for reads.
+    pub fn write_vectored(&self, iovecs: &[libc::iovec]) -> io::Result<usize> {
+        // Safe because the buffers are valid as the caller promises.
+        let ret = unsafe {
+            libc::writev(self.file.as_raw_fd(), iovecs.as_ptr(), iovecs.len() as libc::c_int)
+        };
+        match ret {
+            n if n < 0 => Err(io::Error::last_os_error()),
+            n => Ok(n as usize),
+        }
+    }
+
+    fn set_vnet_hdr_len(&self) -> Result<()> {
+        let len = VNET_HDR_LEN as libc::c_int;
+        // Safe because the kernel only reads the int.
+        match unsafe { libc::ioctl(self.file.as_raw_fd(), libc::TUNSETVNETHDRSZ, &len) } {
+            0 => Ok(()),
+            _ => Err(Self::error(&self.name, io::Error::last_os_error())),
+        }
+    }
+
+    fn ifreq(name: &str, flags: libc::c_int) -> libc::ifreq {
+        // Safe because ifreq is plain data.
+        let mut ifreq: libc::ifreq = unsafe { std::mem::zeroed() };
+        for (dst, &src) in ifreq.ifr_name.iter_mut().zip(name.as_bytes()) {
+            *dst = src as libc::c_char;
+        }
+        ifreq.ifr_ifru.ifru_flags = flags as libc::c_short;
+        ifreq
+    }
+
+    fn ifreq_name(ifreq: &libc::ifreq) -> String {
+        ifreq.ifr_name.iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as u8 as char)
+            .collect()
+    }
+
+    fn error(name: &str, e: io::Error) -> Error {
+        Error::IOError(format!("tap {}: {}", name, e))
+    }
+}
+
+impl AsRawFd for Tap {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// Bring the interface `name` up, and return a packet socket bound to it,
+/// which reads the packets written to the TAP and writes the ones read
+/// from it.
+#[cfg(test)]
+pub fn test_socket(name: &str) -> File {
+    // Keep the host from sending its own packets, e.g. for IPv6 neighbor
+    // discovery.
+    let _ = std::fs::write(format!("/proc/sys/net/ipv6/conf/{}/disable_ipv6", name), "1");
+    // Safe because the sockets are checked and owned by the Files.
+    let inet = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    assert!(inet >= 0);
+    let inet = unsafe { File::from_raw_fd(inet) };
+    let mut ifreq = Tap::ifreq(name, (libc::IFF_UP | libc::IFF_BROADCAST) as libc::c_int);
+    assert_eq!(unsafe { libc::ioctl(inet.as_raw_fd(), libc::SIOCSIFFLAGS, &mut ifreq) }, 0);
+    assert_eq!(unsafe { libc::ioctl(inet.as_raw_fd(), libc::SIOCGIFINDEX, &mut ifreq) }, 0);
+
+    let protocol = (libc::ETH_P_ALL as u16).to_be();
+    let packet = unsafe {
+        libc::socket(libc::AF_PACKET, libc::SOCK_RAW | libc::SOCK_NONBLOCK, protocol as i32)
+    };
+    assert!(packet >= 0);
+    let packet = unsafe { File::from_raw_fd(packet) };
+    let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as u16;
+    addr.sll_protocol = protocol;
+    addr.sll_ifindex = unsafe { ifreq.ifr_ifru.ifru_ifindex };
+    let ret = unsafe {
+        libc::bind(
+            packet.as_raw_fd(),
+            &addr as *const _ as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_ll>() as u32
+        )
+    };
+    assert_eq!(ret, 0);
+    packet
+}
+
+#[test]
+fn test_tap() {
+    use std::{io::{Read, Write}, thread, time::Duration};
+
+    // Creating interfaces needs CAP_NET_ADMIN.
+    let Ok(tap) = Tap::open("", false) else { return };
+    assert!(tap.name().starts_with("tap"));
+    assert!(Tap::open(&"x".repeat(libc::IFNAMSIZ), false).is_err());
+    assert!(tap.set_offload(libc::TUN_F_CSUM | libc::TUN_F_TSO4).is_ok());
+    // A single queue interface can't detach its queue.
+    assert!(tap.set_queue_enabled(true).is_ok());
+    assert!(tap.set_queue_enabled(false).is_err());
+    let mut socket = test_socket(tap.name());
+
+    // Packets written to the TAP come with a virtio-net header.
+    let mut frame = vec![0xffu8; 6];
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 1, 0x88, 0xb5]);
+    frame.extend_from_slice(b"hello");
+    socket.write_all(&frame).unwrap();
+    let (mut header, mut buf) = ([0xaau8; VNET_HDR_LEN], [0u8; 64]);
+    let iovecs = [
+        libc::iovec { iov_base: header.as_mut_ptr() as *mut libc::c_void, iov_len: header.len() },
+        libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() },
+    ];
+    let mut n = 0;
+    for _ in 0..100 {
+        match tap.read_vectored(&iovecs) {
+            Ok(len) => {
+                n = len;
+                break;
+            },
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+    assert_eq!(n, VNET_HDR_LEN + frame.len());
+    assert_eq!(&header[..10], &[0; 10]);
+    assert_eq!(&buf[..frame.len()], &frame[..]);
+
+    // Packets read from the TAP have their header stripped.
+    frame[11] = 2;
+    let mut packet = [0u8; VNET_HDR_LEN].to_vec();
+    packet.extend_from_slice(&frame);
+    let iovecs = [libc::iovec { iov_base: packet.as_mut_ptr() as *mut libc::c_void, iov_len: packet.len() }];
+    assert_eq!(tap.write_vectored(&iovecs).ok(), Some(packet.len()));
+    let mut buf = [0u8; 64];
+    let mut n = 0;
+    for _ in 0..100 {
+        match socket.read(&mut buf) {
+            // The host may send its own packets, e.g. for IPv6.
+            Ok(len) if buf[..len] == frame[..] => {
+                n = len;
+                break;
+            },
+            Ok(_) => {},
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+    assert_eq!(n, frame.len());
+
+    // The fd of an opened TAP can be handed over, but not other fds.
+    let fd = unsafe { libc::dup(tap.as_raw_fd()) };
+    assert_eq!(Tap::from_fd(fd).map(|t| t.name).ok(), Some(tap.name().to_string()));
+    let file = File::open("/dev/null").unwrap();
+    let fd = unsafe { libc::dup(file.as_raw_fd()) };
+    assert!(Tap::from_fd(fd).is_err());
+    assert!(Tap::from_fd(-1).is_err());
+}
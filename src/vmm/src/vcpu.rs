@@ -2,50 +2,132 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::{
+    cell::Cell,
+    os::unix::thread::JoinHandleExt,
+    ptr,
     thread::{self, JoinHandle},
-    sync::mpsc::{Receiver, Sender, channel},
+    time::{Duration, Instant},
+    sync::{Arc, Once, mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError, channel}},
 };
-use kvm_ioctls::{VcpuFd, VmFd};
+use kvm_bindings::{kvm_fpu, kvm_lapic_state, kvm_mp_state, kvm_regs, kvm_sregs};
+use kvm_ioctls::{VcpuExit, VcpuFd, VmFd};
+use super::bus::Buses;
 use super::config::CpuConfig;
-use super::error::Result;
+use super::error::{Error, IllegalTransition, Result};
+use super::state::{Observers, State, StateMachine, Transition};
 
 /// Status for the current vcpu. 
 /// 
 /// The lifecycle of a normal vcpu will be: epoch -> paused -> running ->| exit  
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum VcpuStatus {
     /// Vcpu is created and needs further initialization.  
     Epoch,
     /// Vcpu is ready to run.  
     Paused,
     /// Vcpu is now running. 
-    Running
+    Running,
+    /// Vcpu thread has exited.
+    Exit
+}
+
+impl State for VcpuStatus {
+    fn can_transit(&self, to: Self) -> bool {
+        use VcpuStatus::*;
+
+        matches!(
+            (self, to),
+            (Epoch, Paused) | (Paused, Running) | (Running, Paused) 
+                | (Paused, Exit) | (Running, Exit)
+        )
+    }
+
+    fn illegal(&self, to: Self) -> IllegalTransition {
+        IllegalTransition::Vcpu(*self, to)
+    }
 }
 
 /// Input & output message for manipulating or querying information for the vcpu.
 /// 
 ///  Note: messages can only be consumed by vcpus before VM enter or after VM exit. 
 pub enum VcpuMsg {
-    /// Tell vcpu to run. If the vcpu is not in 'epoch' or 'paused' status, 
-    /// this message will be ignored.  
+    /// Tell vcpu to run. If the vcpu is not in 'paused' status, this message 
+    /// will be ignored.  
     Run,
     /// Reply for 'Run' message, current status of the vcpu is returned. 
     RunReply(VcpuStatus),
+    /// Tell vcpu to leave the guest and stay 'paused' until it is resumed.
+    Pause,
+    /// Reply for 'Pause' message, current status of the vcpu is returned.
+    PauseReply(VcpuStatus),
+    /// Tell a 'paused' vcpu to continue running the guest.
+    Resume,
+    /// Reply for 'Resume' message, current status of the vcpu is returned.
+    ResumeReply(VcpuStatus),
+    /// Sent by the vcpu when it stops running on its own, e.g. the guest 
+    /// halts or shuts down. The vcpu is 'paused' afterwards.
+    Stopped(VcpuStopReason),
     /// Tell vcpu to exit.  
     Exit
 }
 
+/// Reasons for which a vcpu stops running the guest without being told to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VcpuStopReason {
+    /// The guest executed 'hlt' and the halt is not handled in kernel.
+    Halted,
+    /// The guest triggered a shutdown, usually a triple fault.
+    Shutdown,
+    /// The guest requested a system event, its format: (type, flags).
+    SystemEvent(u32, u64),
+    /// KVM failed to emulate an instruction.
+    InternalError,
+    /// KVM failed to enter the guest.
+    FailEntry,
+    /// An exit which is not expected by the hypervisor occured.
+    Unhandled,
+    /// `KVM_RUN` failed with the given errno.
+    RunError(i32),
+    /// The vcpu loop took an illegal status transition and the vcpu thread
+    /// exited, its format: (from, to).
+    InvalidStateTransition(VcpuStatus, VcpuStatus),
+}
+
+/// Register state of a vcpu right after it is created, i.e. its reset state.
+struct ResetState {
+    regs: kvm_regs,
+    sregs: kvm_sregs,
+    fpu: kvm_fpu,
+    /// APs wait for INIT/SIPI when there is an in-kernel irqchip.
+    mp_state: kvm_mp_state,
+    /// Only available with an in-kernel irqchip.
+    lapic: Option<kvm_lapic_state>,
+}
+
 /// VcpuManager contains operations and metadata for all the vcpus for a vm.  
 /// 
 /// Instance of VcpuManager should be owned by the control thread.  
 pub struct VcpuManager {
     /// Configuration for VM's vcpus.
     config: CpuConfig,
-    /// List of vcpu thread handles.  
-    threads: Vec<JoinHandle<()>>,
+    /// Handler for exits emulated in user space.
+    buses: Arc<Buses>,
+    /// Host CPUs each vcpu thread is pinned to.
+    affinity: Vec<Vec<usize>>,
+    /// Reset state of each vcpu.
+    reset_states: Vec<ResetState>,
+    /// List of vcpu thread handles, the vcpu fd is returned on exit.  
+    threads: Vec<JoinHandle<VcpuFd>>,
     /// Vcpus' input channels.
     chs_in_send: Vec<Sender<VcpuMsg>>,
     /// Vcpus' output channels.
     chs_out_recv: Vec<Receiver<VcpuMsg>>,
+    /// Stop reasons reported by vcpus but not yet collected.
+    stopped: Vec<(u32, VcpuStopReason)>,
+    /// Number of requests each vcpu hasn't replied yet.
+    pending: Vec<u32>,
+    /// Observers of vcpus' status transitions.
+    observers: Observers<VcpuStatus>,
 }
 
 impl VcpuManager {
@@ -55,29 +137,361 @@ impl VcpuManager {
     /// 
     /// * `fd` - File discriptor for VM ioctls.
     /// * `config` - Configuration for VM's vcpus.
-    pub fn new(fd: VmFd, config: CpuConfig) -> Result<Self> {
-        let mut threads = Vec::new();
-        let mut chs_in_send = Vec::new();
-        let mut chs_out_recv = Vec::new();
+    /// * `buses` - Buses of devices emulated in user space.
+    /// * `setup` - Called with the id and fd of each vcpu before its thread 
+    ///   is spawned, e.g. to set up the boot registers.
+    /// * `affinity` - Host CPUs each vcpu thread is pinned to, indexed by 
+    ///   vcpu id. Vcpus without an entry or with an empty one are not pinned.
+    ///   `CpuConfig::affinity` takes precedence over it.
+    pub fn new(
+        fd: &VmFd, 
+        config: CpuConfig, 
+        buses: Arc<Buses>,
+        setup: &dyn Fn(u32, &VcpuFd) -> Result<()>,
+        affinity: &[Vec<usize>]
+    ) -> Result<Self> {
+        register_kick_handler()?;
+        let mut fds = Vec::new();
+        let mut reset_states = Vec::new();
         for i in 0..config.count {
             let fd = fd.create_vcpu(i as u64)?;
+            reset_states.push(ResetState {
+                regs: fd.get_regs()?,
+                sregs: fd.get_sregs()?,
+                fpu: fd.get_fpu()?,
+                mp_state: fd.get_mp_state()?,
+                lapic: fd.get_lapic().ok(),
+            });
+            setup(i, &fd)?;
+            fds.push(fd);
+        }
+        let mut manager = VcpuManager {
+            config,
+            buses,
+            affinity: affinity.to_vec(),
+            reset_states,
+            threads: Vec::new(),
+            chs_in_send: Vec::new(),
+            chs_out_recv: Vec::new(),
+            stopped: Vec::new(),
+            pending: Vec::new(),
+            observers: Observers::default(),
+        };
+        manager.spawn(fds)?;
+        Ok(manager)
+    }
+
+    /// Spawn a thread for each vcpu, the vcpus are 'paused' afterwards.
+    fn spawn(&mut self, fds: Vec<VcpuFd>) -> Result<()> {
+        for (i, fd) in (0..).zip(fds) {
             let (ch_in_send, ch_in_recv) = channel();
-            let (vcpu, ch_out_recv) = Vcpu::new(i, fd, ch_in_recv);
-            threads.push(thread::spawn(move || {
-                Vcpu::run(vcpu);
-            }));
-            chs_in_send.push(ch_in_send);
-            chs_out_recv.push(ch_out_recv);
-        }
-        Ok(
-            VcpuManager {
-                config,
-                threads,
-                chs_in_send,
-                chs_out_recv,
+            let (vcpu, ch_out_recv) = Vcpu::new(
+                i, 
+                fd, 
+                self.buses.clone(), 
+                ch_in_recv,
+                self.observers.clone()
+            );
+            let handle = thread::Builder::new()
+                .name(format!("vcpu-{}", i))
+                .spawn(move || Vcpu::run(vcpu))
+                .map_err(|e| Error::ThreadError(e.to_string()))?;
+            // The vcpu thread waits for a 'Run' message, so it is set up 
+            // before entering the guest.
+            match self.config.vcpu_affinity(i) {
+                Some(cpu) => set_affinity(&handle, &[cpu])?,
+                None => match self.affinity.get(i as usize) {
+                    Some(cpus) if !cpus.is_empty() => {
+                        set_affinity(&handle, cpus)?
+                    },
+                    _ => {},
+                },
+            }
+            if let Some(priority) = self.config.priority {
+                set_fifo_priority(&handle, priority)?;
+            }
+            self.threads.push(handle);
+            self.chs_in_send.push(ch_in_send);
+            self.chs_out_recv.push(ch_out_recv);
+            self.pending.push(0);
+        }
+        Ok(())
+    }
+
+    /// Reset all the vcpus to their power-on state and run `setup` on them 
+    /// again, e.g. for rebooting the guest.
+    ///
+    /// Vcpu threads are restarted and 'paused' afterwards. Stop reasons not 
+    /// yet collected are dropped.
+    pub fn reset(
+        &mut self, 
+        setup: &dyn Fn(u32, &VcpuFd) -> Result<()>,
+        timeout: Duration
+    ) -> Result<()> {
+        let fds = self.join(timeout)?;
+        for ((i, fd), state) in (0..).zip(fds.iter()).zip(self.reset_states.iter()) {
+            fd.set_regs(&state.regs)?;
+            fd.set_sregs(&state.sregs)?;
+            fd.set_fpu(&state.fpu)?;
+            fd.set_mp_state(state.mp_state)?;
+            if let Some(lapic) = state.lapic.as_ref() {
+                fd.set_lapic(lapic)?;
+            }
+            setup(i, fd)?;
+        }
+        self.chs_in_send.clear();
+        self.chs_out_recv.clear();
+        self.stopped.clear();
+        self.pending.clear();
+        self.spawn(fds)
+    }
+
+    /// Tell all the vcpus to run and return their status after that.
+    pub fn run(&mut self) -> Result<Vec<VcpuStatus>> {
+        self.request(|| VcpuMsg::Run, None)
+    }
+
+    /// Tell all the vcpus to pause and return their status after that.
+    ///
+    /// `Error::VcpuTimeout` is returned for the first vcpu not replying 
+    /// within `timeout`.
+    pub fn pause(&mut self, timeout: Duration) -> Result<Vec<VcpuStatus>> {
+        self.request(|| VcpuMsg::Pause, Some(timeout))
+    }
+
+    /// Tell all the paused vcpus to continue and return their status after 
+    /// that, waiting no longer than `timeout`.
+    pub fn resume(&mut self, timeout: Duration) -> Result<Vec<VcpuStatus>> {
+        self.request(|| VcpuMsg::Resume, Some(timeout))
+    }
+
+    /// Send a request to all the vcpus and collect their replies.
+    ///
+    /// Vcpus inside the guest are kicked out of it to see the request.
+    /// Replies to requests which timed out before are discarded.
+    fn request(
+        &mut self, 
+        msg: fn() -> VcpuMsg, 
+        timeout: Option<Duration>
+    ) -> Result<Vec<VcpuStatus>> {
+        for (id, ch) in self.chs_in_send.iter().enumerate() {
+            ch.send(msg()).map_err(|_| Error::VcpuGone)?;
+            self.pending[id] += 1;
+        }
+        self.kick();
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut status = Vec::new();
+        for id in 0..self.chs_out_recv.len() {
+            loop {
+                let msg = match deadline {
+                    Some(deadline) => {
+                        let left = deadline.saturating_duration_since(Instant::now());
+                        match self.chs_out_recv[id].recv_timeout(left) {
+                            Ok(msg) => msg,
+                            Err(RecvTimeoutError::Timeout) => {
+                                return Err(Error::VcpuTimeout(id as u32));
+                            },
+                            Err(RecvTimeoutError::Disconnected) => {
+                                return Err(Error::VcpuGone);
+                            },
+                        }
+                    },
+                    None => self.chs_out_recv[id].recv()
+                        .map_err(|_| Error::VcpuGone)?,
+                };
+                match msg {
+                    VcpuMsg::RunReply(s) 
+                    | VcpuMsg::PauseReply(s) 
+                    | VcpuMsg::ResumeReply(s) => {
+                        self.pending[id] -= 1;
+                        if self.pending[id] == 0 {
+                            status.push(s);
+                            break;
+                        }
+                    },
+                    VcpuMsg::Stopped(reason) => {
+                        self.stopped.push((id as u32, reason))
+                    },
+                    _ => {},
+                }
             }
+        }
+        Ok(status)
+    }
+
+    /// Kick all the vcpus out of the guest, so that they can see messages.
+    fn kick(&self) {
+        for handle in self.threads.iter() {
+            // Safe because the thread is still joinable. The signal is 
+            // handled by `handle_kick` and does nothing else.
+            unsafe { libc::pthread_kill(handle.as_pthread_t(), kick_signal()) };
+        }
+    }
+
+    /// Collect vcpus which stopped on their own since the last call, 
+    /// in the format of (vcpu id, reason).
+    pub fn stopped(&mut self) -> Vec<(u32, VcpuStopReason)> {
+        for (id, ch) in self.chs_out_recv.iter().enumerate() {
+            while let Ok(msg) = ch.try_recv() {
+                if let VcpuMsg::Stopped(reason) = msg {
+                    self.stopped.push((id as u32, reason));
+                }
+            }
+        }
+        std::mem::take(&mut self.stopped)
+    }
+
+    /// Tell all the vcpus to exit and wait for their threads.
+    ///
+    /// Threads not exiting within `timeout` are detached, and 
+    /// `Error::VcpuTimeout` is returned for the first of them.
+    pub fn exit(&mut self, timeout: Duration) -> Result<()> {
+        self.join(timeout).map(|_| ())
+    }
+
+    /// Tell all the vcpus to exit and collect their fds from the threads.
+    fn join(&mut self, timeout: Duration) -> Result<Vec<VcpuFd>> {
+        for ch in self.chs_in_send.iter() {
+            // The vcpu thread may have already gone.
+            let _ = ch.send(VcpuMsg::Exit);
+        }
+        self.kick();
+        let deadline = Instant::now() + timeout;
+        while self.threads.iter().any(|h| !h.is_finished()) 
+            && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut fds = Vec::new();
+        let mut result = Ok(());
+        for (id, handle) in self.threads.drain(..).enumerate() {
+            if !handle.is_finished() {
+                if result.is_ok() {
+                    result = Err(Error::VcpuTimeout(id as u32));
+                }
+                continue;
+            }
+            match handle.join() {
+                Ok(fd) => fds.push(fd),
+                Err(_) if result.is_ok() => result = Err(Error::VcpuGone),
+                Err(_) => {},
+            }
+        }
+        result.map(|_| fds)
+    }
+
+    /// Subscribe to the status transitions of all the vcpus, the vcpu id 
+    /// is carried in each event.
+    pub fn subscribe(&self) -> Receiver<Transition<VcpuStatus>> {
+        self.observers.subscribe()
+    }
+
+    /// Configuration for VM's vcpus.
+    pub fn config(&self) -> &CpuConfig {
+        &self.config
+    }
+}
+
+/// Pin a thread to the given host CPUs.
+fn set_affinity<T>(handle: &JoinHandle<T>, cpus: &[usize]) -> Result<()> {
+    // Safe because cpu_set_t is a plain bitmap and all zeros is a valid value.
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for cpu in cpus.iter() {
+        if *cpu >= libc::CPU_SETSIZE as usize {
+            return Err(Error::ThreadError(format!("Invalid host cpu {}", cpu)));
+        }
+        // Safe because the cpu is checked against the size of the set.
+        unsafe { libc::CPU_SET(*cpu, &mut set) };
+    }
+    // Safe because the thread is still joinable and `set` is a valid cpu set.
+    let ret = unsafe {
+        libc::pthread_setaffinity_np(
+            handle.as_pthread_t(),
+            std::mem::size_of::<libc::cpu_set_t>(),
+            &set
         )
+    };
+    if ret != 0 {
+        return Err(Error::ThreadError(format!(
+            "Failed to pin the thread to {:?}: {}",
+            cpus, std::io::Error::from_raw_os_error(ret)
+        )));
+    }
+    Ok(())
+}
+
+/// Run a thread with SCHED_FIFO policy at the given priority.
+fn set_fifo_priority<T>(handle: &JoinHandle<T>, priority: i32) -> Result<()> {
+    let param = libc::sched_param { sched_priority: priority };
+    // Safe because the thread is still joinable and `param` is valid.
+    let ret = unsafe {
+        libc::pthread_setschedparam(handle.as_pthread_t(), libc::SCHED_FIFO, &param)
+    };
+    if ret != 0 {
+        return Err(Error::ThreadError(format!(
+            "Failed to set SCHED_FIFO priority {}: {}",
+            priority, std::io::Error::from_raw_os_error(ret)
+        )));
     }
+    Ok(())
+}
+
+thread_local! {
+    /// Fd of the vcpu running on the current thread, used by `handle_kick`.
+    static VCPU_FD: Cell<*const VcpuFd> = const { Cell::new(ptr::null()) };
+}
+
+/// Signal used for kicking vcpus out of the guest.
+fn kick_signal() -> libc::c_int {
+    libc::SIGRTMIN()
+}
+
+/// Handler for the kick signal.
+///
+/// `KVM_RUN` is interrupted if the signal arrives inside the guest. Setting 
+/// `immediate_exit` covers the case where it arrives right before entering 
+/// the guest, after the vcpu has checked its messages.
+extern "C" fn handle_kick(_: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    VCPU_FD.with(|fd| {
+        let fd = fd.get();
+        if !fd.is_null() {
+            // Safe because the pointer is only set while the vcpu is alive 
+            // on this thread, and writing `immediate_exit` is signal safe.
+            unsafe { (*fd).set_kvm_immediate_exit(1) };
+        }
+    });
+}
+
+/// Register `handle_kick` for the kick signal once per process.
+fn register_kick_handler() -> Result<()> {
+    static REGISTER: Once = Once::new();
+    let mut ret = 0;
+    REGISTER.call_once(|| {
+        // Safe because the handler only touches its thread local variable.
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle_kick as *const () as usize;
+            action.sa_flags = libc::SA_SIGINFO;
+            libc::sigemptyset(&mut action.sa_mask);
+            ret = libc::sigaction(kick_signal(), &action, ptr::null_mut());
+        }
+    });
+    if ret != 0 {
+        return Err(Error::ThreadError(format!(
+            "Failed to register the kick signal: {}",
+            std::io::Error::last_os_error()
+        )));
+    }
+    Ok(())
+}
+
+/// What the vcpu loop should do after the guest stops running.
+enum Emulation {
+    /// The vcpu stopped on its own.
+    Stopped(VcpuStopReason),
+    /// The vcpu is told to pause.
+    Paused,
+    /// The vcpu is told to exit.
+    Exit,
 }
 
 /// Vcpu contains operations and metadata for a specific vcpu.
@@ -95,6 +509,10 @@ pub struct Vcpu {
     id: u32,
     /// File descriptor for vcpu ioctls.
     fd: VcpuFd,
+    /// Status of the vcpu.
+    status: StateMachine<VcpuStatus>,
+    /// Buses of devices emulated in user space.
+    buses: Arc<Buses>,
 
     /// Receiver for input channel.
     ch_in_recv: Receiver<VcpuMsg>,
@@ -108,13 +526,23 @@ impl Vcpu {
     /// # Arguments
     /// - `id` - Vcpu id.
     /// - `fd` - File descriptor for vcpu ioctls.
+    /// - `buses` - Buses of devices emulated in user space.
     /// - `ch_in_recv` - The receiver for the input channel.
-    fn new(id: u32, fd: VcpuFd, ch_in_recv: Receiver<VcpuMsg>) -> (Self, Receiver<VcpuMsg>) {
+    /// - `observers` - Observers of the vcpu's status transitions.
+    fn new(
+        id: u32, 
+        fd: VcpuFd, 
+        buses: Arc<Buses>,
+        ch_in_recv: Receiver<VcpuMsg>,
+        observers: Observers<VcpuStatus>
+    ) -> (Self, Receiver<VcpuMsg>) {
         let (ch_out_send, ch_out_recv) = channel();
         (
             Vcpu {
                 id,
                 fd,
+                status: StateMachine::new(id, VcpuStatus::Epoch, observers),
+                buses,
                 ch_in_recv,
                 ch_out_send
             },
@@ -123,8 +551,320 @@ impl Vcpu {
         
     }
 
+    /// ID for the vcpu.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Current status of the vcpu.
+    pub fn status(&self) -> VcpuStatus {
+        self.status.current()
+    }
+
+    /// Transit to the given status, an illegal transition stops the vcpu
+    /// loop with the reason.
+    fn transit(&mut self, to: VcpuStatus) -> std::result::Result<(), VcpuStopReason> {
+        let from = self.status();
+        self.status.transit(to)
+            .map_err(|_| VcpuStopReason::InvalidStateTransition(from, to))
+    }
+
     /// Vcpu's main loop.
-    fn run(vcpu: Vcpu) {
-        todo!()
+    /// 
+    /// The vcpu waits for control messages while it is not running, and 
+    /// returns its fd once it is told to exit or the main thread has gone.
+    fn run(mut vcpu: Vcpu) -> VcpuFd {
+        VCPU_FD.with(|fd| fd.set(&vcpu.fd));
+        // The main thread learns that the vcpu failed from the stop reason.
+        if let Err(reason) = vcpu.serve() {
+            let _ = vcpu.reply(VcpuMsg::Stopped(reason));
+        }
+        VCPU_FD.with(|fd| fd.set(ptr::null()));
+        vcpu.fd
+    }
+
+    /// Serve control messages until the vcpu is told to exit or the main
+    /// thread has gone.
+    fn serve(&mut self) -> std::result::Result<(), VcpuStopReason> {
+        // The vcpu has been set up before its thread is spawned.
+        self.transit(VcpuStatus::Paused)?;
+        while let Ok(msg) = self.ch_in_recv.recv() {
+            let reply = match msg {
+                VcpuMsg::Run | VcpuMsg::Resume => {
+                    self.transit(VcpuStatus::Running)?;
+                    match msg {
+                        VcpuMsg::Run => VcpuMsg::RunReply(self.status()),
+                        _ => VcpuMsg::ResumeReply(self.status()),
+                    }
+                },
+                // The vcpu is not inside the guest, nothing to do.
+                VcpuMsg::Pause => {
+                    if self.reply(VcpuMsg::PauseReply(self.status())).is_err() {
+                        break;
+                    }
+                    continue;
+                },
+                VcpuMsg::Exit => break,
+                // Replies are never sent to vcpus.
+                _ => continue,
+            };
+            if self.reply(reply).is_err() {
+                break;
+            }
+            match self.run_guest()? {
+                Emulation::Stopped(reason) => {
+                    self.transit(VcpuStatus::Paused)?;
+                    if self.reply(VcpuMsg::Stopped(reason)).is_err() {
+                        break;
+                    }
+                },
+                Emulation::Paused => {},
+                Emulation::Exit => break,
+            }
+        }
+        self.transit(VcpuStatus::Exit)
+    }
+
+    /// Send a message to the main thread.
+    fn reply(&self, msg: VcpuMsg) -> std::result::Result<(), ()> {
+        self.ch_out_send.send(msg).map_err(|_| ())
+    }
+
+    /// Keep running the guest until it stops or the vcpu is told to pause 
+    /// or exit.
+    fn run_guest(&mut self) -> std::result::Result<Emulation, VcpuStopReason> {
+        loop {
+            if let Some(reason) = self.run_once() {
+                return Ok(Emulation::Stopped(reason));
+            }
+            // Control messages are consumed between two VM entries.
+            match self.ch_in_recv.try_recv() {
+                Ok(VcpuMsg::Run) => {
+                    if self.reply(VcpuMsg::RunReply(self.status())).is_err() {
+                        return Ok(Emulation::Exit);
+                    }
+                },
+                Ok(VcpuMsg::Resume) => {
+                    if self.reply(VcpuMsg::ResumeReply(self.status())).is_err() {
+                        return Ok(Emulation::Exit);
+                    }
+                },
+                Ok(VcpuMsg::Pause) => {
+                    self.transit(VcpuStatus::Paused)?;
+                    if self.reply(VcpuMsg::PauseReply(self.status())).is_err() {
+                        return Ok(Emulation::Exit);
+                    }
+                    return Ok(Emulation::Paused);
+                },
+                Ok(VcpuMsg::Exit) | Err(TryRecvError::Disconnected) => {
+                    return Ok(Emulation::Exit);
+                },
+                Ok(_) | Err(TryRecvError::Empty) => {},
+            }
+        }
     }
+
+    /// Enter the guest once and dispatch the VM exit.
+    /// 
+    /// `None` is returned if the guest can be resumed.
+    fn run_once(&mut self) -> Option<VcpuStopReason> {
+        match self.fd.run() {
+            Ok(exit) => match exit {
+                // Unclaimed addresses read as all ones like an empty ISA bus,
+                // and writes to them are dropped.
+                VcpuExit::IoIn(port, data) => {
+                    if !self.buses.pio.read(port as u64, data) {
+                        data.fill(0xff);
+                    }
+                    None
+                },
+                VcpuExit::IoOut(port, data) => {
+                    self.buses.pio.write(port as u64, data);
+                    None
+                },
+                VcpuExit::MmioRead(addr, data) => {
+                    if !self.buses.mmio.read(addr, data) {
+                        data.fill(0xff);
+                    }
+                    None
+                },
+                VcpuExit::MmioWrite(addr, data) => {
+                    self.buses.mmio.write(addr, data);
+                    None
+                },
+                VcpuExit::Hlt => Some(VcpuStopReason::Halted),
+                VcpuExit::Shutdown => Some(VcpuStopReason::Shutdown),
+                VcpuExit::SystemEvent(kind, flags) => {
+                    Some(VcpuStopReason::SystemEvent(kind, flags))
+                },
+                VcpuExit::InternalError => Some(VcpuStopReason::InternalError),
+                VcpuExit::FailEntry => Some(VcpuStopReason::FailEntry),
+                // Nothing to emulate, the guest can be resumed directly.
+                VcpuExit::Intr | VcpuExit::IrqWindowOpen => None,
+                _ => Some(VcpuStopReason::Unhandled),
+            },
+            Err(e) => match e.errno() {
+                // KVM_RUN is interrupted by a signal, resume the guest 
+                // after checking control messages.
+                libc::EINTR | libc::EAGAIN => {
+                    self.fd.set_kvm_immediate_exit(0);
+                    None
+                },
+                errno => Some(VcpuStopReason::RunError(errno)),
+            }
+        }
+    }
+}
+
+/// A VM with 64 KiB of memory below 4 GiB, where `code` is at the reset
+/// vector 0xfffffff0 which a vcpu starts from in real mode. The memory has
+/// to outlive the vcpus.
+#[cfg(test)]
+fn test_vm(code: &[u8]) -> (kvm_ioctls::VmFd, vm_memory::GuestMemoryMmap<()>) {
+    use kvm_bindings::kvm_userspace_memory_region;
+    use kvm_ioctls::Kvm;
+    use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+
+    let fd = Kvm::new().unwrap().create_vm().unwrap();
+    let base = GuestAddress(0xffff_0000);
+    let memory = GuestMemoryMmap::<()>::from_ranges(&[(base, 0x10000)]).unwrap();
+    memory.write_slice(code, GuestAddress(0xffff_fff0)).unwrap();
+    let region = kvm_userspace_memory_region {
+        slot: 0,
+        guest_phys_addr: base.0,
+        memory_size: 0x10000,
+        userspace_addr: memory.get_host_address(base).unwrap() as u64,
+        flags: 0,
+    };
+    unsafe { fd.set_user_memory_region(region).unwrap() };
+    (fd, memory)
+}
+
+#[test]
+fn test_vcpu_run() {
+    use std::sync::Mutex;
+    use super::bus::BusDevice;
+
+    /// Record all the port writes from the guest.
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<(u64, Vec<u8>)>>);
+
+    impl BusDevice for Recorder {
+        fn write(&self, base: u64, offset: u64, data: &[u8]) {
+            self.0.lock().unwrap().push((base + offset, data.to_vec()));
+        }
+    }
+
+    // mov dx, 0x3f8; mov al, 'a'; out dx, al; hlt
+    let (fd, _memory) = test_vm(&[0xba, 0xf8, 0x03, 0xb0, 0x61, 0xee, 0xf4]);
+
+    let recorder = Arc::new(Recorder::default());
+    let buses = Buses::new();
+    buses.pio.insert(recorder.clone(), 0x3f8, 8).unwrap();
+    let mut manager = VcpuManager::new(
+        &fd, 
+        CpuConfig { count: 1, affinity: None, priority: None }, 
+        Arc::new(buses),
+        &|_, _| Ok(()),
+        &[vec![0]]
+    ).unwrap();
+    assert_eq!(manager.threads[0].thread().name(), Some("vcpu-0"));
+    assert_eq!(manager.run(), Ok(vec![VcpuStatus::Running]));
+    // Wait for the guest to halt.
+    let stopped = loop {
+        let stopped = manager.stopped();
+        if !stopped.is_empty() {
+            break stopped;
+        }
+        thread::sleep(std::time::Duration::from_millis(10));
+    };
+    assert_eq!(stopped, vec![(0, VcpuStopReason::Halted)]);
+    assert_eq!(*recorder.0.lock().unwrap(), vec![(0x3f8, vec![0x61])]);
+    manager.exit(std::time::Duration::from_secs(1)).unwrap();
+}
+
+#[test]
+fn test_vcpu_pause() {
+
+    // A busy loop without any exit: jmp $
+    let (fd, _memory) = test_vm(&[0xeb, 0xfe]);
+
+    let timeout = std::time::Duration::from_secs(1);
+    let mut manager = VcpuManager::new(
+        &fd, 
+        CpuConfig { count: 2, affinity: None, priority: None }, 
+        Arc::new(Buses::new()),
+        &|_, _| Ok(()),
+        &[]
+    ).unwrap();
+    let events = manager.subscribe();
+    // Vcpus are started by 'Resume' as well.
+    assert_eq!(manager.resume(timeout), Ok(vec![VcpuStatus::Running; 2]));
+    // The guest never exits on its own, so vcpus have to be kicked.
+    assert_eq!(manager.pause(timeout), Ok(vec![VcpuStatus::Paused; 2]));
+    assert_eq!(manager.pause(timeout), Ok(vec![VcpuStatus::Paused; 2]));
+    assert_eq!(manager.resume(timeout), Ok(vec![VcpuStatus::Running; 2]));
+    assert_eq!(manager.run(), Ok(vec![VcpuStatus::Running; 2]));
+    assert_eq!(manager.stopped(), vec![]);
+    manager.exit(timeout).unwrap();
+    // The vcpu may enter 'paused' before the subscription.
+    let transitions: Vec<_> = events.try_iter()
+        .filter(|e| e.id == 0 && e.from != VcpuStatus::Epoch)
+        .map(|e| (e.from, e.to))
+        .collect();
+    use VcpuStatus::*;
+    assert_eq!(
+        transitions,
+        vec![(Paused, Running), (Running, Paused), (Paused, Running), (Running, Exit)]
+    );
+}
+
+#[test]
+fn test_vcpu_reset() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::bus::BusDevice;
+
+    /// Count the port writes from the guest.
+    #[derive(Default)]
+    struct Counter(AtomicUsize);
+
+    impl BusDevice for Counter {
+        fn write(&self, _base: u64, _offset: u64, _data: &[u8]) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // out 0x80, al; hlt
+    let (fd, _memory) = test_vm(&[0xe6, 0x80, 0xf4]);
+
+    let wait_halted = |manager: &mut VcpuManager| loop {
+        if manager.stopped() == vec![(0, VcpuStopReason::Halted)] {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    let timeout = Duration::from_secs(1);
+    let counter = Arc::new(Counter::default());
+    let buses = Buses::new();
+    buses.pio.insert(counter.clone(), 0x80, 1).unwrap();
+    let mut manager = VcpuManager::new(
+        &fd, 
+        CpuConfig { count: 1, affinity: None, priority: None }, 
+        Arc::new(buses),
+        &|_, _| Ok(()),
+        &[]
+    ).unwrap();
+    manager.run().unwrap();
+    wait_halted(&mut manager);
+    // The vcpu starts from the reset vector again.
+    let setups = AtomicUsize::new(0);
+    manager.reset(&|_, _| {
+        setups.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }, timeout).unwrap();
+    assert_eq!(setups.load(Ordering::SeqCst), 1);
+    assert_eq!(manager.run(), Ok(vec![VcpuStatus::Running]));
+    wait_halted(&mut manager);
+    assert_eq!(counter.0.load(Ordering::SeqCst), 2);
+    manager.exit(timeout).unwrap();
 }
@@ -0,0 +1,429 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+pub mod pci;
+
+use std::{
+    collections::BTreeMap,
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    io,
+    os::unix::{fs::FileExt, io::{AsRawFd, FromRawFd}},
+    sync::{Arc, Mutex},
+};
+use vm_memory::{Address, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vmm_sys_util::{
+    eventfd::EventFd,
+    ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ptr, ioctl_with_ref, ioctl_with_val},
+    ioctl_io_nr,
+};
+use crate::config::PciBdf;
+use crate::error::{Error, Result};
+
+/// Type and base number of the VFIO ioctls, none of which encodes the size
+/// of its argument.
+const VFIO_TYPE: u32 = b';' as u32;
+const VFIO_BASE: u32 = 100;
+
+ioctl_io_nr!(VFIO_GET_API_VERSION, VFIO_TYPE, VFIO_BASE);
+ioctl_io_nr!(VFIO_CHECK_EXTENSION, VFIO_TYPE, VFIO_BASE + 1);
+ioctl_io_nr!(VFIO_SET_IOMMU, VFIO_TYPE, VFIO_BASE + 2);
+ioctl_io_nr!(VFIO_GROUP_GET_STATUS, VFIO_TYPE, VFIO_BASE + 3);
+ioctl_io_nr!(VFIO_GROUP_SET_CONTAINER, VFIO_TYPE, VFIO_BASE + 4);
+ioctl_io_nr!(VFIO_GROUP_GET_DEVICE_FD, VFIO_TYPE, VFIO_BASE + 6);
+ioctl_io_nr!(VFIO_DEVICE_GET_INFO, VFIO_TYPE, VFIO_BASE + 7);
+ioctl_io_nr!(VFIO_DEVICE_GET_REGION_INFO, VFIO_TYPE, VFIO_BASE + 8);
+ioctl_io_nr!(VFIO_DEVICE_GET_IRQ_INFO, VFIO_TYPE, VFIO_BASE + 9);
+ioctl_io_nr!(VFIO_DEVICE_SET_IRQS, VFIO_TYPE, VFIO_BASE + 10);
+ioctl_io_nr!(VFIO_DEVICE_RESET, VFIO_TYPE, VFIO_BASE + 11);
+ioctl_io_nr!(VFIO_IOMMU_MAP_DMA, VFIO_TYPE, VFIO_BASE + 13);
+
+/// Version of the VFIO API this implementation speaks.
+const VFIO_API_VERSION: i32 = 0;
+/// The type 1 IOMMU, v2 which allows unmapping parts of mappings.
+const VFIO_TYPE1V2_IOMMU: u64 = 3;
+/// The group has all its devices bound to VFIO drivers.
+const VFIO_GROUP_FLAGS_VIABLE: u32 = 1;
+/// Device flags.
+const VFIO_DEVICE_FLAGS_RESET: u32 = 1;
+const VFIO_DEVICE_FLAGS_PCI: u32 = 1 << 1;
+/// Region flags.
+pub const VFIO_REGION_INFO_FLAG_MMAP: u32 = 1 << 2;
+/// IRQ set flags.
+const VFIO_IRQ_SET_DATA_NONE: u32 = 1;
+const VFIO_IRQ_SET_DATA_EVENTFD: u32 = 1 << 2;
+const VFIO_IRQ_SET_ACTION_TRIGGER: u32 = 1 << 5;
+/// DMA mapping flags.
+const VFIO_DMA_MAP_FLAG_READ: u32 = 1;
+const VFIO_DMA_MAP_FLAG_WRITE: u32 = 1 << 1;
+
+/// Region indexes of a PCI device, BARs are 0 to 5.
+pub const VFIO_PCI_CONFIG_REGION_INDEX: u32 = 7;
+/// IRQ indexes of a PCI device.
+pub const VFIO_PCI_MSI_IRQ_INDEX: u32 = 1;
+pub const VFIO_PCI_MSIX_IRQ_INDEX: u32 = 2;
+
+/// Directory of the VFIO character devices.
+const VFIO_DIR: &str = "/dev/vfio";
+/// Directory of the host PCI devices.
+const PCI_DEVICES_DIR: &str = "/sys/bus/pci/devices";
+
+/// struct vfio_group_status
+#[repr(C)]
+#[derive(Default)]
+struct GroupStatus {
+    argsz: u32,
+    flags: u32,
+}
+
+/// struct vfio_device_info, without the capability offset of newer
+/// kernels.
+#[repr(C)]
+#[derive(Default)]
+struct DeviceInfo {
+    argsz: u32,
+    flags: u32,
+    num_regions: u32,
+    num_irqs: u32,
+}
+
+/// struct vfio_region_info
+#[repr(C)]
+#[derive(Default)]
+struct RegionInfo {
+    argsz: u32,
+    flags: u32,
+    index: u32,
+    cap_offset: u32,
+    size: u64,
+    offset: u64,
+}
+
+/// struct vfio_irq_info
+#[repr(C)]
+#[derive(Default)]
+struct IrqInfo {
+    argsz: u32,
+    flags: u32,
+    index: u32,
+    count: u32,
+}
+
+/// struct vfio_iommu_type1_dma_map
+#[repr(C)]
+#[derive(Default)]
+struct DmaMap {
+    argsz: u32,
+    flags: u32,
+    vaddr: u64,
+    iova: u64,
+    size: u64,
+}
+
+/// Size of an ioctl argument, as its `argsz`.
+fn argsz<T>() -> u32 {
+    std::mem::size_of::<T>() as u32
+}
+
+/// Map the result of an ioctl, which fails with a negative value.
+fn check(ret: i32) -> io::Result<i32> {
+    match ret {
+        ret if ret < 0 => Err(io::Error::last_os_error()),
+        ret => Ok(ret),
+    }
+}
+
+/// An IOMMU context shared by all the passed through devices of a VM, the
+/// whole guest memory is mapped for their DMA at guest physical addresses.
+pub struct VfioContainer {
+    file: File,
+    memory: GuestMemoryMmap,
+    /// Groups added to the container, keyed by their IDs.
+    groups: Mutex<BTreeMap<u32, Arc<File>>>,
+}
+
+impl VfioContainer {
+    /// Open a container for the guest `memory`, it's mapped once the first
+    /// group is added.
+    pub fn open(memory: GuestMemoryMmap) -> Result<Self> {
+        let path = format!("{}/vfio", VFIO_DIR);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .map_err(|e| Error::VfioError(format!("{}: {}", path, e)))?;
+        let error = |e: io::Error| Error::VfioError(format!("{}: {}", path, e));
+        // Safe because the ioctls take no argument.
+        let version = check(unsafe { ioctl(&file, VFIO_GET_API_VERSION()) }).map_err(error)?;
+        if version != VFIO_API_VERSION {
+            return Err(Error::VfioError(format!("unsupported API version {}", version)));
+        }
+        let supported = check(unsafe {
+            ioctl_with_val(&file, VFIO_CHECK_EXTENSION(), VFIO_TYPE1V2_IOMMU)
+        }).map_err(error)?;
+        if supported != 1 {
+            return Err(Error::VfioError("the type 1 v2 IOMMU is unsupported".to_string()));
+        }
+        Ok(VfioContainer { file, memory, groups: Mutex::new(BTreeMap::new()) })
+    }
+
+    /// Open the host PCI device `bdf` bound to `vfio-pci`, adding its group
+    /// to the container if it's not yet.
+    pub fn device(&self, bdf: &PciBdf) -> Result<VfioDevice> {
+        let name = bdf.to_string();
+        let error = |e: io::Error| Error::VfioError(format!("{}: {}", name, e));
+        let link = fs::read_link(format!("{}/{}/iommu_group", PCI_DEVICES_DIR, name))
+            .map_err(error)?;
+        let id = link.file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.parse::<u32>().ok())
+            .ok_or_else(|| Error::VfioError(format!("{}: invalid IOMMU group", name)))?;
+        let group = self.group(id)?;
+        let name_c = CString::new(name.as_str()).unwrap();
+        // Safe because the kernel only reads the name, and the fd returned
+        // is owned by the file.
+        let fd = check(unsafe {
+            ioctl_with_ptr(group.as_ref(), VFIO_GROUP_GET_DEVICE_FD(), name_c.as_ptr())
+        }).map_err(error)?;
+        let file = unsafe { File::from_raw_fd(fd) };
+        VfioDevice::new(file, name, group)
+    }
+
+    /// Open the group `id` and add it to the container, the IOMMU is set up
+    /// along with the first group.
+    fn group(&self, id: u32) -> Result<Arc<File>> {
+        let mut groups = self.groups.lock().unwrap();
+        if let Some(group) = groups.get(&id) {
+            return Ok(group.clone());
+        }
+        let path = format!("{}/{}", VFIO_DIR, id);
+        let error = |e: io::Error| Error::VfioError(format!("{}: {}", path, e));
+        let group = OpenOptions::new().read(true).write(true).open(&path).map_err(error)?;
+        let mut status = GroupStatus { argsz: argsz::<GroupStatus>(), flags: 0 };
+        // Safe because the kernel only writes the status.
+        check(unsafe { ioctl_with_mut_ref(&group, VFIO_GROUP_GET_STATUS(), &mut status) })
+            .map_err(error)?;
+        if status.flags & VFIO_GROUP_FLAGS_VIABLE == 0 {
+            return Err(Error::VfioError(format!(
+                "{}: not all devices in the group are bound to vfio-pci", path
+            )));
+        }
+        // Safe because the kernel only reads the fd.
+        check(unsafe {
+            ioctl_with_ref(&group, VFIO_GROUP_SET_CONTAINER(), &self.file.as_raw_fd())
+        }).map_err(error)?;
+        if groups.is_empty() {
+            // Safe because no memory is involved.
+            check(unsafe { ioctl_with_val(&self.file, VFIO_SET_IOMMU(), VFIO_TYPE1V2_IOMMU) })
+                .map_err(error)?;
+            self.map_memory()?;
+        }
+        let group = Arc::new(group);
+        groups.insert(id, group.clone());
+        Ok(group)
+    }
+
+    /// Map all the guest memory for DMA at guest physical addresses, the
+    /// pages are pinned by the kernel.
+    fn map_memory(&self) -> Result<()> {
+        for region in self.memory.iter() {
+            let host_addr = self.memory.get_host_address(region.start_addr())
+                .map_err(|e| Error::MemoryError(e.to_string()))?;
+            let map = DmaMap {
+                argsz: argsz::<DmaMap>(),
+                flags: VFIO_DMA_MAP_FLAG_READ | VFIO_DMA_MAP_FLAG_WRITE,
+                vaddr: host_addr as u64,
+                iova: region.start_addr().raw_value(),
+                size: region.len(),
+            };
+            // Safe because the memory stays mapped while the container lives.
+            check(unsafe { ioctl_with_ref(&self.file, VFIO_IOMMU_MAP_DMA(), &map) })
+                .map_err(|e| Error::VfioError(format!(
+                    "map {:#x}+{:#x} for DMA: {}", map.iova, map.size, e
+                )))?;
+        }
+        Ok(())
+    }
+}
+
+/// A region of a VFIO device, e.g. a BAR or the configuration space.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VfioRegion {
+    pub flags: u32,
+    pub size: u64,
+    /// Offset of the region in the device file.
+    pub offset: u64,
+}
+
+/// Part of a region mapped into the VMM.
+pub struct VfioMapping {
+    addr: *mut u8,
+    len: usize,
+}
+
+// Safe because the mapping is only unmapped when it's dropped.
+unsafe impl Send for VfioMapping {}
+unsafe impl Sync for VfioMapping {}
+
+impl VfioMapping {
+    /// Host address of the mapping.
+    pub fn addr(&self) -> u64 {
+        self.addr as u64
+    }
+}
+
+impl Drop for VfioMapping {
+    fn drop(&mut self) {
+        // Safe because the range was mapped by mmap.
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, self.len) };
+    }
+}
+
+/// A host PCI device opened through VFIO.
+pub struct VfioDevice {
+    file: File,
+    name: String,
+    flags: u32,
+    regions: Vec<VfioRegion>,
+    irqs: Vec<u32>,
+    /// Keeps the device's group in the container.
+    _group: Arc<File>,
+}
+
+impl VfioDevice {
+    fn new(file: File, name: String, group: Arc<File>) -> Result<Self> {
+        let error = |e: io::Error| Error::VfioError(format!("{}: {}", name, e));
+        let mut info = DeviceInfo { argsz: argsz::<DeviceInfo>(), ..Default::default() };
+        // Safe because the kernel only writes the info.
+        check(unsafe { ioctl_with_mut_ref(&file, VFIO_DEVICE_GET_INFO(), &mut info) })
+            .map_err(error)?;
+        if info.flags & VFIO_DEVICE_FLAGS_PCI == 0
+            || info.num_regions <= VFIO_PCI_CONFIG_REGION_INDEX
+        {
+            return Err(Error::VfioError(format!("{}: not a PCI device", name)));
+        }
+        let mut regions = Vec::new();
+        for index in 0..info.num_regions {
+            let mut region = RegionInfo { argsz: argsz::<RegionInfo>(), index, ..Default::default() };
+            // Safe because the kernel only writes the info.
+            check(unsafe {
+                ioctl_with_mut_ref(&file, VFIO_DEVICE_GET_REGION_INFO(), &mut region)
+            }).map_err(error)?;
+            regions.push(VfioRegion { flags: region.flags, size: region.size, offset: region.offset });
+        }
+        let mut irqs = Vec::new();
+        for index in 0..info.num_irqs {
+            let mut irq = IrqInfo { argsz: argsz::<IrqInfo>(), index, ..Default::default() };
+            // Safe because the kernel only writes the info.
+            check(unsafe { ioctl_with_mut_ref(&file, VFIO_DEVICE_GET_IRQ_INFO(), &mut irq) })
+                .map_err(error)?;
+            irqs.push(irq.count);
+        }
+        Ok(VfioDevice { file, name, flags: info.flags, regions, irqs, _group: group })
+    }
+
+    /// Address of the device, e.g. `0000:02:00.0`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn error(&self, e: io::Error) -> Error {
+        Error::VfioError(format!("{}: {}", self.name, e))
+    }
+
+    /// The region at `index`, empty if the device doesn't have it.
+    pub fn region(&self, index: u32) -> VfioRegion {
+        self.regions.get(index as usize).copied().unwrap_or_default()
+    }
+
+    /// Number of vectors of the IRQ at `index`.
+    pub fn irq_count(&self, index: u32) -> u32 {
+        self.irqs.get(index as usize).copied().unwrap_or(0)
+    }
+
+    /// Read from `offset` of a region.
+    pub fn read_region(&self, index: u32, offset: u64, data: &mut [u8]) -> Result<()> {
+        let region = self.region(index);
+        if offset + data.len() as u64 > region.size {
+            return Err(self.error(io::Error::from(io::ErrorKind::InvalidInput)));
+        }
+        self.file.read_exact_at(data, region.offset + offset).map_err(|e| self.error(e))
+    }
+
+    /// Write to `offset` of a region.
+    pub fn write_region(&self, index: u32, offset: u64, data: &[u8]) -> Result<()> {
+        let region = self.region(index);
+        if offset + data.len() as u64 > region.size {
+            return Err(self.error(io::Error::from(io::ErrorKind::InvalidInput)));
+        }
+        self.file.write_all_at(data, region.offset + offset).map_err(|e| self.error(e))
+    }
+
+    /// Map `len` bytes from `offset` of a region into the VMM.
+    pub fn mmap_region(&self, index: u32, offset: u64, len: u64) -> Result<VfioMapping> {
+        let region = self.region(index);
+        if region.flags & VFIO_REGION_INFO_FLAG_MMAP == 0 || offset + len > region.size {
+            return Err(self.error(io::Error::from(io::ErrorKind::InvalidInput)));
+        }
+        // Safe because a new mapping is created, owned by VfioMapping.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                self.file.as_raw_fd(),
+                (region.offset + offset) as libc::off_t,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(self.error(io::Error::last_os_error()));
+        }
+        Ok(VfioMapping { addr: addr as *mut u8, len: len as usize })
+    }
+
+    /// Signal the eventfds when the vectors of the IRQ at `index` fire,
+    /// enabling them in the device.
+    pub fn enable_irqs(&self, index: u32, events: &[&EventFd]) -> Result<()> {
+        let fds: Vec<u32> = events.iter().map(|e| e.as_raw_fd() as u32).collect();
+        self.set_irqs(index, VFIO_IRQ_SET_DATA_EVENTFD, &fds)
+    }
+
+    /// Disable all the vectors of the IRQ at `index`.
+    pub fn disable_irqs(&self, index: u32) -> Result<()> {
+        self.set_irqs(index, VFIO_IRQ_SET_DATA_NONE, &[])
+    }
+
+    fn set_irqs(&self, index: u32, data: u32, fds: &[u32]) -> Result<()> {
+        // struct vfio_irq_set followed by the fds.
+        let mut irq_set = vec![0, data | VFIO_IRQ_SET_ACTION_TRIGGER, index, 0, fds.len() as u32];
+        irq_set.extend_from_slice(fds);
+        irq_set[0] = (irq_set.len() * 4) as u32;
+        // Safe because the kernel only reads `argsz` bytes.
+        check(unsafe { ioctl_with_ptr(&self.file, VFIO_DEVICE_SET_IRQS(), irq_set.as_ptr()) })
+            .map(|_| ())
+            .map_err(|e| self.error(e))
+    }
+
+    /// Reset the device if it supports any kind of reset.
+    pub fn reset(&self) -> Result<()> {
+        if self.flags & VFIO_DEVICE_FLAGS_RESET == 0 {
+            return Ok(());
+        }
+        // Safe because the ioctl takes no argument.
+        check(unsafe { ioctl(&self.file, VFIO_DEVICE_RESET()) })
+            .map(|_| ())
+            .map_err(|e| self.error(e))
+    }
+}
+
+#[test]
+fn test_vfio_ioctls() {
+    // Numbers from the kernel headers.
+    assert_eq!(VFIO_GET_API_VERSION(), 0x3b64);
+    assert_eq!(VFIO_GROUP_GET_DEVICE_FD(), 0x3b6a);
+    assert_eq!(VFIO_DEVICE_SET_IRQS(), 0x3b6e);
+    assert_eq!(VFIO_IOMMU_MAP_DMA(), 0x3b71);
+    assert_eq!(std::mem::size_of::<RegionInfo>(), 32);
+    assert_eq!(std::mem::size_of::<DmaMap>(), 32);
+}
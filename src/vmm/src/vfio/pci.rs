@@ -0,0 +1,779 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    ops::{Range, RangeFrom},
+    sync::{Arc, Mutex},
+};
+use kvm_bindings::kvm_userspace_memory_region;
+use kvm_ioctls::VmFd;
+use vmm_sys_util::eventfd::EventFd;
+use crate::bus::{BusDevice, Buses};
+use crate::error::{Error, Result};
+use crate::interrupt::{InterruptManager, MsiMessage};
+use crate::memory::{MMIO_HOLE_START, RESERVED_HOLE_START};
+use crate::pci::{
+    self, BAR0, BAR_COUNT, BAR_IO, BAR_MEM_64, BAR_MEM_FLAGS, CAPABILITY_LIST, CAP_ID_MSI,
+    CAP_ID_MSIX, COMMAND, COMMAND_MEMORY, CONFIG_SPACE_LEN, HEADER_MULTI_FUNCTION, HEADER_TYPE,
+    INTERRUPT_LINE, PciBus, PciDevice, ROM_ADDRESS, STATUS_CAPABILITIES,
+};
+use super::{
+    VFIO_PCI_CONFIG_REGION_INDEX, VFIO_PCI_MSIX_IRQ_INDEX, VFIO_PCI_MSI_IRQ_INDEX,
+    VFIO_REGION_INFO_FLAG_MMAP, VfioDevice, VfioMapping,
+};
+
+/// Granularity of the ranges mapped into the guest.
+const PAGE_SIZE: u64 = 0x1000;
+
+/// MSI control bits.
+const MSI_CONTROL_ENABLE: u16 = 0x1;
+const MSI_CONTROL_MMC_SHIFT: u16 = 1;
+const MSI_CONTROL_MME_SHIFT: u16 = 4;
+const MSI_CONTROL_MME: u16 = 0x7 << MSI_CONTROL_MME_SHIFT;
+const MSI_CONTROL_64: u16 = 0x80;
+const MSI_CONTROL_MASKABLE: u16 = 0x100;
+
+/// MSI-X control bits.
+const MSIX_CONTROL_TABLE_SIZE: u16 = 0x7ff;
+const MSIX_CONTROL_MASK: u16 = 0x4000;
+const MSIX_CONTROL_ENABLE: u16 = 0x8000;
+/// Offsets in the MSI-X capability.
+const MSIX_TABLE: usize = 4;
+/// BAR indicator in the table offset register.
+const MSIX_BIR: u32 = 0x7;
+/// Size of an MSI-X table entry, and its vector control mask bit.
+const MSIX_ENTRY_LEN: u64 = 16;
+const MSIX_ENTRY_MASKED: u32 = 0x1;
+
+/// Read the 32-bit register at `offset` of the device's configuration
+/// space, all ones if it fails.
+fn read_config(device: &VfioDevice, offset: usize) -> u32 {
+    let mut data = [0xffu8; 4];
+    let _ = device.read_region(VFIO_PCI_CONFIG_REGION_INDEX, offset as u64, &mut data);
+    u32::from_le_bytes(data)
+}
+
+/// Vectors of MSI or MSI-X enabled by the guest. The device signals the
+/// eventfd of a vector, which injects its GSI while it's registered as an
+/// irqfd, i.e. while the vector is unmasked.
+struct Vectors {
+    /// VFIO IRQ index of the vectors.
+    index: u32,
+    device: Arc<VfioDevice>,
+    interrupts: Arc<InterruptManager>,
+    /// GSI, eventfd and message of each vector, the message is `None`
+    /// while the vector is masked.
+    vectors: Vec<(u32, EventFd, Option<MsiMessage>)>,
+}
+
+impl Vectors {
+    fn new(index: u32, device: Arc<VfioDevice>, interrupts: Arc<InterruptManager>) -> Self {
+        Vectors { index, device, interrupts, vectors: Vec::new() }
+    }
+
+    /// Enable `count` vectors in the device, all of them masked.
+    fn enable(&mut self, count: usize) -> Result<()> {
+        self.disable();
+        for _ in 0..count {
+            let gsi = self.interrupts.allocate_gsi()?;
+            match EventFd::new(libc::EFD_NONBLOCK) {
+                Ok(event) => self.vectors.push((gsi, event, None)),
+                Err(e) => {
+                    let _ = self.interrupts.free_gsi(gsi);
+                    self.disable();
+                    return Err(Error::InterruptError(e.to_string()));
+                },
+            }
+        }
+        let events: Vec<&EventFd> = self.vectors.iter().map(|v| &v.1).collect();
+        let result = self.device.enable_irqs(self.index, &events);
+        if result.is_err() {
+            self.disable();
+        }
+        result
+    }
+
+    /// Disable all the vectors in the device and free their GSIs.
+    fn disable(&mut self) {
+        if self.vectors.is_empty() {
+            return;
+        }
+        let _ = self.device.disable_irqs(self.index);
+        for (gsi, event, message) in self.vectors.drain(..) {
+            if message.is_some() {
+                let _ = self.interrupts.unregister_irqfd(&event, gsi);
+            }
+            let _ = self.interrupts.free_gsi(gsi);
+        }
+    }
+
+    /// Route the vector to `message`, or mask it if `None`. Interrupts
+    /// signaled while it's masked are injected once it's unmasked.
+    fn set(&mut self, vector: usize, message: Option<MsiMessage>) -> Result<()> {
+        let Some((gsi, event, current)) = self.vectors.get_mut(vector) else {
+            return Ok(());
+        };
+        if *current == message {
+            return Ok(());
+        }
+        match message {
+            Some(m) => {
+                self.interrupts.set_msi_route(*gsi, m)?;
+                if current.is_none() {
+                    self.interrupts.register_irqfd(event, *gsi)?;
+                }
+            },
+            None => self.interrupts.unregister_irqfd(event, *gsi)?,
+        }
+        *current = message;
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.vectors.len()
+    }
+}
+
+impl Drop for Vectors {
+    fn drop(&mut self) {
+        self.disable();
+    }
+}
+
+/// The MSI capability, emulated on top of the vectors. Per-vector masking
+/// is hidden from the guest.
+struct Msi {
+    /// Offset of the capability.
+    cap: usize,
+    /// Message control, as seen by the guest.
+    control: u16,
+    address: u64,
+    data: u16,
+    vectors: Vectors,
+}
+
+impl Msi {
+    fn new(cap: usize, device: Arc<VfioDevice>, interrupts: Arc<InterruptManager>) -> Self {
+        let control = (read_config(&device, cap) >> 16) as u16;
+        Msi {
+            cap,
+            control: control & !(MSI_CONTROL_ENABLE | MSI_CONTROL_MME | MSI_CONTROL_MASKABLE),
+            address: 0,
+            data: 0,
+            vectors: Vectors::new(VFIO_PCI_MSI_IRQ_INDEX, device, interrupts),
+        }
+    }
+
+    fn is_64(&self) -> bool {
+        self.control & MSI_CONTROL_64 != 0
+    }
+
+    /// Whether the register at `offset` is emulated.
+    fn contains(&self, offset: usize) -> bool {
+        let data = if self.is_64() { 0xc } else { 0x8 };
+        offset >= self.cap && offset <= self.cap + data
+    }
+
+    fn read(&self, offset: usize, value: u32) -> u32 {
+        match offset - self.cap {
+            0 => value & 0xffff | (self.control as u32) << 16,
+            4 => self.address as u32,
+            8 if self.is_64() => (self.address >> 32) as u32,
+            _ => self.data as u32,
+        }
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) {
+        let register = offset & !3;
+        let value = pci::merge(self.read(register, 0), offset, data);
+        match register - self.cap {
+            0 => {
+                let writable = MSI_CONTROL_ENABLE | MSI_CONTROL_MME;
+                self.control = self.control & !writable | (value >> 16) as u16 & writable;
+            },
+            4 => self.address = self.address & !0xffff_ffff | (value & !0x3) as u64,
+            8 if self.is_64() => self.address = self.address & 0xffff_ffff | (value as u64) << 32,
+            _ => self.data = value as u16,
+        }
+        self.update();
+    }
+
+    /// Enable, disable or reroute the vectors as the guest programmed.
+    fn update(&mut self) {
+        if self.control & MSI_CONTROL_ENABLE == 0 {
+            return self.vectors.disable();
+        }
+        let capable = (self.control >> MSI_CONTROL_MMC_SHIFT) & 0x7;
+        let enabled = (self.control & MSI_CONTROL_MME) >> MSI_CONTROL_MME_SHIFT;
+        let count = 1usize << std::cmp::min(capable, enabled);
+        if self.vectors.len() != count && self.vectors.enable(count).is_err() {
+            return;
+        }
+        // Multiple vectors are told apart by the low bits of the data.
+        for vector in 0..count {
+            let data = self.data as u32 & !(count as u32 - 1) | vector as u32;
+            let message = MsiMessage { address: self.address, data };
+            let _ = self.vectors.set(vector, Some(message));
+        }
+    }
+
+    fn reset(&mut self) {
+        self.control &= !(MSI_CONTROL_ENABLE | MSI_CONTROL_MME);
+        self.address = 0;
+        self.data = 0;
+        self.vectors.disable();
+    }
+}
+
+/// The MSI-X capability and its vector table, emulated on top of the
+/// vectors. The pending bit array is left to the device.
+struct Msix {
+    /// Offset of the capability.
+    cap: usize,
+    /// Enable and function mask bits of the message control.
+    control: u16,
+    /// Entries of the table: message address low and high, message data
+    /// and vector control.
+    table: Vec<[u32; 4]>,
+    vectors: Vectors,
+}
+
+impl Msix {
+    fn new(cap: usize, size: usize, device: Arc<VfioDevice>, interrupts: Arc<InterruptManager>) -> Self {
+        Msix {
+            cap,
+            control: 0,
+            table: vec![[0, 0, 0, MSIX_ENTRY_MASKED]; size],
+            vectors: Vectors::new(VFIO_PCI_MSIX_IRQ_INDEX, device, interrupts),
+        }
+    }
+
+    fn read_control(&self, value: u32) -> u32 {
+        let control = value >> 16 & !(MSIX_CONTROL_ENABLE | MSIX_CONTROL_MASK) as u32;
+        value & 0xffff | (control | self.control as u32) << 16
+    }
+
+    fn write_control(&mut self, offset: usize, data: &[u8]) {
+        let value = pci::merge((self.control as u32) << 16, offset, data);
+        self.control = (value >> 16) as u16 & (MSIX_CONTROL_ENABLE | MSIX_CONTROL_MASK);
+        self.update();
+    }
+
+    /// The message of a vector, `None` if it's masked.
+    fn message(&self, vector: usize) -> Option<MsiMessage> {
+        let [low, high, data, control] = self.table[vector];
+        if self.control & MSIX_CONTROL_MASK != 0 || control & MSIX_ENTRY_MASKED != 0 {
+            return None;
+        }
+        Some(MsiMessage { address: (high as u64) << 32 | low as u64, data })
+    }
+
+    /// Enable, disable or reroute the vectors as the guest programmed.
+    fn update(&mut self) {
+        if self.control & MSIX_CONTROL_ENABLE == 0 {
+            return self.vectors.disable();
+        }
+        if self.vectors.len() != self.table.len() && self.vectors.enable(self.table.len()).is_err() {
+            return;
+        }
+        for vector in 0..self.table.len() {
+            let _ = self.vectors.set(vector, self.message(vector));
+        }
+    }
+
+    /// Access the table at `offset` in 32-bit pieces, calling `f` with the
+    /// entry, the register in it and the bytes of the access in the register.
+    fn access_table(
+        &mut self,
+        offset: u64,
+        len: usize,
+        mut f: impl FnMut(&mut Self, usize, usize, usize, Range<usize>),
+    ) {
+        let mut done = 0;
+        while done < len {
+            let offset = offset + done as u64;
+            let n = std::cmp::min(4 - (offset & 3) as usize, len - done);
+            let vector = (offset / MSIX_ENTRY_LEN) as usize;
+            let register = (offset % MSIX_ENTRY_LEN / 4) as usize;
+            if vector < self.table.len() {
+                f(self, vector, register, offset as usize, done..done + n);
+            }
+            done += n;
+        }
+    }
+
+    fn read_table(&mut self, offset: u64, data: &mut [u8]) {
+        data.fill(0);
+        self.access_table(offset, data.len(), |msix, vector, register, offset, range| {
+            pci::extract(msix.table[vector][register], offset, &mut data[range]);
+        });
+    }
+
+    fn write_table(&mut self, offset: u64, data: &[u8]) {
+        self.access_table(offset, data.len(), |msix, vector, register, offset, range| {
+            let entry = &mut msix.table[vector][register];
+            *entry = pci::merge(*entry, offset, &data[range]);
+            if msix.control & MSIX_CONTROL_ENABLE != 0 {
+                let message = msix.message(vector);
+                let _ = msix.vectors.set(vector, message);
+            }
+        });
+    }
+
+    fn reset(&mut self) {
+        self.control = 0;
+        self.table.iter_mut().for_each(|e| *e = [0, 0, 0, MSIX_ENTRY_MASKED]);
+        self.vectors.disable();
+    }
+}
+
+/// Ranges of a BAR as (offset, length).
+type BarRanges = Vec<(u64, u64)>;
+
+/// Split a BAR of `size` bytes into the ranges mapped into the guest and
+/// the ones trapped by the MMIO bus. `emulated` is a range which must be
+/// trapped, e.g. the MSI-X table.
+fn split_bar(size: u64, mmap: bool, emulated: Option<(u64, u64)>) -> (BarRanges, BarRanges) {
+    if !mmap || size < PAGE_SIZE {
+        return (Vec::new(), vec![(0, size)]);
+    }
+    let Some((offset, len)) = emulated else {
+        return (vec![(0, size)], Vec::new());
+    };
+    let start = offset / PAGE_SIZE * PAGE_SIZE;
+    let end = std::cmp::min((offset + len).next_multiple_of(PAGE_SIZE), size);
+    let direct = [(0, start), (end, size - end)]
+        .into_iter()
+        .filter(|&(_, len)| len > 0)
+        .collect();
+    (direct, vec![(start, end - start)])
+}
+
+/// A range of a BAR trapped by the MMIO bus, where accesses are forwarded
+/// to the device, except for the ones to the emulated MSI-X table.
+struct BarTrap {
+    device: Arc<VfioDevice>,
+    /// Region index of the BAR.
+    index: u32,
+    /// Offset of the range in the BAR.
+    start: u64,
+    /// The MSI-X table, if it's in the range, as (offset in the BAR,
+    /// length, state).
+    msix: Option<(u64, u64, Arc<Mutex<Msix>>)>,
+}
+
+impl BarTrap {
+    /// The MSI-X table and the offset in it, if `offset` is in the table.
+    fn msix(&self, offset: u64) -> Option<(&Mutex<Msix>, u64)> {
+        self.msix.as_ref()
+            .filter(|(table, len, _)| offset >= *table && offset < table + len)
+            .map(|(table, _, msix)| (msix.as_ref(), offset - table))
+    }
+}
+
+impl BusDevice for BarTrap {
+    fn read(&self, _base: u64, offset: u64, data: &mut [u8]) {
+        let offset = self.start + offset;
+        match self.msix(offset) {
+            Some((msix, offset)) => msix.lock().unwrap().read_table(offset, data),
+            None => {
+                if self.device.read_region(self.index, offset, data).is_err() {
+                    data.fill(0xff);
+                }
+            },
+        }
+    }
+
+    fn write(&self, _base: u64, offset: u64, data: &[u8]) {
+        let offset = self.start + offset;
+        match self.msix(offset) {
+            Some((msix, offset)) => msix.lock().unwrap().write_table(offset, data),
+            None => {
+                let _ = self.device.write_region(self.index, offset, data);
+            },
+        }
+    }
+}
+
+/// A range of a BAR mapped from the device into the guest, accessed by the
+/// guest without exits.
+struct DirectRange {
+    offset: u64,
+    len: u64,
+    mapping: VfioMapping,
+    /// KVM memory slot of the range.
+    slot: u32,
+}
+
+/// A memory BAR of the device.
+struct Bar {
+    /// Region index of the BAR, i.e. its number.
+    index: u32,
+    size: u64,
+    /// Low bits of the BAR register: type and prefetchable.
+    flags: u32,
+    /// Address programmed by the guest.
+    addr: u64,
+    /// Address assigned before the guest boots.
+    initial: u64,
+    /// Address where the BAR is mapped now.
+    mapped: Option<u64>,
+    direct: Vec<DirectRange>,
+    /// Trapped ranges as (offset, length, trap).
+    traps: Vec<(u64, u64, Arc<BarTrap>)>,
+}
+
+impl Bar {
+    fn is_64(&self) -> bool {
+        self.flags & BAR_MEM_64 != 0
+    }
+
+    /// Value of the BAR register at `index`, which is its upper half if
+    /// `index` follows a 64-bit BAR.
+    fn read(&self, index: u32) -> Option<u32> {
+        match index {
+            i if i == self.index => Some(self.addr as u32 | self.flags),
+            i if i == self.index + 1 && self.is_64() => Some((self.addr >> 32) as u32),
+            _ => None,
+        }
+    }
+
+    /// Write the BAR register at `index`, returns false if it isn't of this
+    /// BAR. Writing all ones reads back the size.
+    fn write(&mut self, index: u32, value: u32) -> bool {
+        let addr = match index {
+            i if i == self.index => self.addr & !0xffff_ffff | (value & !BAR_MEM_FLAGS) as u64,
+            i if i == self.index + 1 && self.is_64() => {
+                (value as u64) << 32 | self.addr & 0xffff_ffff
+            },
+            _ => return false,
+        };
+        let mask = if self.is_64() { u64::MAX } else { 0xffff_ffff };
+        self.addr = addr & !(self.size - 1) & mask;
+        true
+    }
+
+    /// Map the BAR at `addr` of the guest, nothing is left mapped if it
+    /// fails.
+    fn map(&mut self, addr: u64, vm: &VmFd, buses: &Buses) -> Result<()> {
+        for (i, (offset, len, trap)) in self.traps.iter().enumerate() {
+            if let Err(e) = buses.mmio.insert(trap.clone(), addr + offset, *len) {
+                for (offset, _, _) in self.traps[..i].iter() {
+                    let _ = buses.mmio.remove(addr + offset);
+                }
+                return Err(e);
+            }
+        }
+        self.mapped = Some(addr);
+        for range in self.direct.iter() {
+            let region = kvm_userspace_memory_region {
+                slot: range.slot,
+                flags: 0,
+                guest_phys_addr: addr + range.offset,
+                memory_size: range.len,
+                userspace_addr: range.mapping.addr(),
+            };
+            // Safe because the mapping lives as long as the BAR, and it's
+            // removed from the guest before being unmapped.
+            if let Err(e) = unsafe { vm.set_user_memory_region(region) } {
+                self.unmap(vm, buses);
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the BAR from the guest.
+    fn unmap(&mut self, vm: &VmFd, buses: &Buses) {
+        let Some(addr) = self.mapped.take() else {
+            return;
+        };
+        for range in self.direct.iter() {
+            let region = kvm_userspace_memory_region { slot: range.slot, ..Default::default() };
+            // Safe because a slot is deleted.
+            let _ = unsafe { vm.set_user_memory_region(region) };
+        }
+        for (offset, _, _) in self.traps.iter() {
+            let _ = buses.mmio.remove(addr + offset);
+        }
+    }
+}
+
+/// A host PCI device passed through to the guest over VFIO.
+///
+/// The configuration space is read from and written to the device, except
+/// for the BARs, MSI and MSI-X capabilities which are emulated. Memory BARs
+/// are placed in the PCI MMIO window and mapped from the device, only the
+/// pages of the MSI-X table are trapped. I/O BARs, the expansion ROM and
+/// INTx are hidden from the guest.
+pub struct VfioPciDevice {
+    device: Arc<VfioDevice>,
+    vm: Arc<VmFd>,
+    buses: Arc<Buses>,
+    bars: Vec<Bar>,
+    msi: Option<Msi>,
+    msix: Option<Arc<Mutex<Msix>>>,
+}
+
+impl VfioPciDevice {
+    /// Assign BARs of `device` from the window of `pci`, and KVM memory
+    /// slots from `slots` for the ranges mapped into the guest.
+    pub fn new(
+        device: VfioDevice,
+        vm: Arc<VmFd>,
+        buses: Arc<Buses>,
+        interrupts: Arc<InterruptManager>,
+        pci: &PciBus,
+        slots: &mut RangeFrom<u32>,
+    ) -> Result<Self> {
+        device.reset()?;
+        let device = Arc::new(device);
+        let mut msi = None;
+        let mut msix = None;
+        let mut msix_table = None;
+        for (id, cap) in Self::capabilities(&device) {
+            if id == CAP_ID_MSI && device.irq_count(VFIO_PCI_MSI_IRQ_INDEX) > 0 {
+                msi = Some(Msi::new(cap, device.clone(), interrupts.clone()));
+            }
+            if id == CAP_ID_MSIX && device.irq_count(VFIO_PCI_MSIX_IRQ_INDEX) > 0 {
+                let control = (read_config(&device, cap) >> 16) as u16;
+                let size = (control & MSIX_CONTROL_TABLE_SIZE) as usize + 1;
+                let table = read_config(&device, cap + MSIX_TABLE);
+                let state = Arc::new(Mutex::new(
+                    Msix::new(cap, size, device.clone(), interrupts.clone())
+                ));
+                msix_table = Some((
+                    table & MSIX_BIR,
+                    (table & !MSIX_BIR) as u64,
+                    size as u64 * MSIX_ENTRY_LEN,
+                    state.clone(),
+                ));
+                msix = Some(state);
+            }
+        }
+
+        let mut bars = Vec::new();
+        let mut index = 0;
+        while index < BAR_COUNT as u32 {
+            let value = read_config(&device, BAR0 + index as usize * 4);
+            let region = device.region(index);
+            let flags = value & BAR_MEM_FLAGS;
+            let next = if flags & BAR_MEM_64 != 0 { index + 2 } else { index + 1 };
+            if region.size == 0 || value & BAR_IO != 0 {
+                index += 1;
+                continue;
+            }
+            let size = region.size.next_power_of_two();
+            let addr = pci.allocate_bar(size)?;
+            let table = msix_table.as_ref().filter(|t| t.0 == index);
+            let mmap = region.flags & VFIO_REGION_INFO_FLAG_MMAP != 0;
+            let (direct, trapped) = split_bar(region.size, mmap, table.map(|t| (t.1, t.2)));
+            let mut bar = Bar {
+                index,
+                size,
+                flags,
+                addr,
+                initial: addr,
+                mapped: None,
+                direct: Vec::new(),
+                traps: Vec::new(),
+            };
+            for (offset, len) in direct {
+                bar.direct.push(DirectRange {
+                    offset,
+                    len,
+                    mapping: device.mmap_region(index, offset, len)?,
+                    slot: slots.next().unwrap(),
+                });
+            }
+            for (offset, len) in trapped {
+                let trap = BarTrap {
+                    device: device.clone(),
+                    index,
+                    start: offset,
+                    msix: table.map(|t| (t.1, t.2, t.3.clone())),
+                };
+                bar.traps.push((offset, len, Arc::new(trap)));
+            }
+            bars.push(bar);
+            index = next;
+        }
+        let mut device = VfioPciDevice { device, vm, buses, bars, msi, msix };
+        device.update_mappings()?;
+        Ok(device)
+    }
+
+    /// Walk through the capability list, returns (ID, offset) of each
+    /// capability.
+    fn capabilities(device: &VfioDevice) -> Vec<(u8, usize)> {
+        let mut caps = Vec::new();
+        if (read_config(device, COMMAND) >> 16) as u16 & STATUS_CAPABILITIES == 0 {
+            return caps;
+        }
+        let mut next = read_config(device, CAPABILITY_LIST) as usize & 0xfc;
+        // Bounded in case the list is looped.
+        while (0x40..CONFIG_SPACE_LEN).contains(&next) && caps.len() < 48 {
+            let header = read_config(device, next);
+            caps.push((header as u8, next));
+            next = (header >> 8) as usize & 0xfc;
+        }
+        caps
+    }
+
+    /// Map the BARs at their addresses if the memory space is enabled, a
+    /// BAR is only mapped when it's in the MMIO hole.
+    fn update_mappings(&mut self) -> Result<()> {
+        let command = read_config(&self.device, COMMAND) as u16;
+        if command & COMMAND_MEMORY == 0 {
+            return Ok(());
+        }
+        for bar in self.bars.iter_mut() {
+            if bar.mapped == Some(bar.addr) {
+                continue;
+            }
+            bar.unmap(&self.vm, &self.buses);
+            if bar.addr >= MMIO_HOLE_START && bar.addr + bar.size <= RESERVED_HOLE_START {
+                bar.map(bar.addr, &self.vm, &self.buses)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Value of the BAR register at `offset`, zero for the hidden ones.
+    fn read_bar(&self, offset: usize) -> u32 {
+        let index = ((offset - BAR0) / 4) as u32;
+        self.bars.iter().find_map(|b| b.read(index)).unwrap_or(0)
+    }
+}
+
+impl PciDevice for VfioPciDevice {
+    fn read_config(&mut self, offset: usize) -> u32 {
+        if (BAR0..BAR0 + BAR_COUNT * 4).contains(&offset) {
+            return self.read_bar(offset);
+        }
+        let value = read_config(&self.device, offset);
+        match offset {
+            ROM_ADDRESS => 0,
+            // A single function, without INTx.
+            o if o == HEADER_TYPE & !3 => value & !((HEADER_MULTI_FUNCTION as u32) << 16),
+            INTERRUPT_LINE => value & !0xff00,
+            o if self.msi.as_ref().is_some_and(|m| m.contains(o)) => {
+                self.msi.as_ref().unwrap().read(o, value)
+            },
+            o => match self.msix.as_ref() {
+                Some(msix) if msix.lock().unwrap().cap == o => {
+                    msix.lock().unwrap().read_control(value)
+                },
+                _ => value,
+            },
+        }
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        let register = offset & !3;
+        if (BAR0..BAR0 + BAR_COUNT * 4).contains(&register) {
+            let value = pci::merge(self.read_bar(register), offset, data);
+            let index = ((register - BAR0) / 4) as u32;
+            if self.bars.iter_mut().any(|b| b.write(index, value)) {
+                let _ = self.update_mappings();
+            }
+            return;
+        }
+        if register == ROM_ADDRESS {
+            return;
+        }
+        if let Some(msi) = self.msi.as_mut().filter(|m| m.contains(register)) {
+            return msi.write(offset, data);
+        }
+        if let Some(msix) = self.msix.as_ref() {
+            let mut msix = msix.lock().unwrap();
+            if msix.cap == register {
+                if offset - register + data.len() > 2 {
+                    msix.write_control(offset, data);
+                }
+                return;
+            }
+        }
+        let _ = self.device.write_region(VFIO_PCI_CONFIG_REGION_INDEX, offset as u64, data);
+        if register == COMMAND {
+            let _ = self.update_mappings();
+        }
+    }
+
+    fn reset(&mut self) {
+        if let Some(msi) = self.msi.as_mut() {
+            msi.reset();
+        }
+        if let Some(msix) = self.msix.as_ref() {
+            msix.lock().unwrap().reset();
+        }
+        let _ = self.device.reset();
+        for bar in self.bars.iter_mut() {
+            bar.unmap(&self.vm, &self.buses);
+            bar.addr = bar.initial;
+        }
+        let _ = self.update_mappings();
+    }
+}
+
+impl Drop for VfioPciDevice {
+    fn drop(&mut self) {
+        // Slots are deleted before their mappings are unmapped.
+        for bar in self.bars.iter_mut() {
+            bar.unmap(&self.vm, &self.buses);
+        }
+    }
+}
+
+#[test]
+fn test_split_bar() {
+    // Small or unmappable BARs are trapped as a whole.
+    assert_eq!(split_bar(0x100, true, None), (vec![], vec![(0, 0x100)]));
+    assert_eq!(split_bar(0x4000, false, Some((0, 0x10))), (vec![], vec![(0, 0x4000)]));
+    assert_eq!(split_bar(0x4000, true, None), (vec![(0, 0x4000)], vec![]));
+    // Only the pages of the MSI-X table are trapped.
+    assert_eq!(
+        split_bar(0x4000, true, Some((0x2010, 0x20))),
+        (vec![(0, 0x2000), (0x3000, 0x1000)], vec![(0x2000, 0x1000)])
+    );
+    assert_eq!(
+        split_bar(0x4000, true, Some((0, 0x1010))),
+        (vec![(0x2000, 0x2000)], vec![(0, 0x2000)])
+    );
+    assert_eq!(
+        split_bar(0x4000, true, Some((0x3000, 0x800))),
+        (vec![(0, 0x3000)], vec![(0x3000, 0x1000)])
+    );
+}
+
+#[test]
+fn test_bar_register() {
+    let bar = |flags: u32| Bar {
+        index: 2,
+        size: 0x4000,
+        flags,
+        addr: 0xe000_4000,
+        initial: 0xe000_4000,
+        mapped: None,
+        direct: Vec::new(),
+        traps: Vec::new(),
+    };
+    let mut bar32 = bar(0x8);
+    assert_eq!(bar32.read(2), Some(0xe000_4008));
+    assert_eq!(bar32.read(3), None);
+    // Sizing by writing all ones.
+    assert!(bar32.write(2, 0xffff_ffff));
+    assert_eq!(bar32.read(2), Some(0xffff_c008));
+    assert!(bar32.write(2, 0xe001_0000));
+    assert_eq!(bar32.addr, 0xe001_0000);
+    assert!(!bar32.write(3, 0));
+
+    let mut bar64 = bar(BAR_MEM_64 | 0x8);
+    assert_eq!(bar64.read(3), Some(0));
+    assert!(bar64.write(2, 0xffff_ffff));
+    assert!(bar64.write(3, 0xffff_ffff));
+    assert_eq!((bar64.read(2), bar64.read(3)), (Some(0xffff_c00c), Some(0xffff_ffff)));
+    assert!(bar64.write(2, 0x4000));
+    assert!(bar64.write(3, 0x1));
+    assert_eq!(bar64.addr, 0x1_0000_4000);
+}
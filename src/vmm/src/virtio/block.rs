@@ -0,0 +1,730 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    collections::HashMap,
+    io,
+    os::unix::io::AsRawFd,
+    sync::Arc,
+};
+use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+use vmm_sys_util::{
+    epoll::{ControlOperation, Epoll, EpollEvent, EventSet},
+    eventfd::EventFd,
+};
+use crate::config::BlockConfig;
+use crate::disk::{self, AlignedBuf, Disk, IoEngine, IoOp, SECTOR_SIZE, gather, iovec, scatter};
+use crate::error::{Error, Result};
+use super::{
+    TYPE_BLOCK, VIRTIO_F_VERSION_1, VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC,
+    IoThread, VirtioDevice, VirtioInterrupt, read_config_space,
+};
+use super::queue::{DescriptorChain, Queue};
+
+/// Feature bits of block devices.
+const VIRTIO_BLK_F_SEG_MAX: u32 = 2;
+const VIRTIO_BLK_F_RO: u32 = 5;
+const VIRTIO_BLK_F_BLK_SIZE: u32 = 6;
+const VIRTIO_BLK_F_FLUSH: u32 = 9;
+const VIRTIO_BLK_F_DISCARD: u32 = 13;
+const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 14;
+
+/// Request types.
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+
+/// Request status.
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// Unmap the range while zeroing it.
+const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 0x1;
+/// Length of the serial number returned by GET_ID.
+const VIRTIO_BLK_ID_BYTES: usize = 20;
+
+const QUEUE_SIZE: u16 = 256;
+/// Segments of a discard or write zeroes request.
+const MAX_DISCARD_SEG: u32 = 32;
+
+/// Header of a request, as laid out in guest memory.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct RequestHeader {
+    request_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+// Safe because RequestHeader only contains plain data.
+unsafe impl ByteValued for RequestHeader {}
+
+/// A segment of a discard or write zeroes request.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct DiscardSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+// Safe because DiscardSegment only contains plain data.
+unsafe impl ByteValued for DiscardSegment {}
+
+/// Buffers in guest memory, as (address, length) pairs.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Buffers(Vec<(GuestAddress, usize)>);
+
+impl Buffers {
+    fn len(&self) -> usize {
+        self.0.iter().map(|&(_, len)| len).sum()
+    }
+
+    /// Split off the first `n` bytes, `None` if there are fewer.
+    fn split_front(&mut self, mut n: usize) -> Option<Buffers> {
+        let mut front = Vec::new();
+        while n > 0 {
+            let (addr, len) = *self.0.first()?;
+            let take = n.min(len);
+            front.push((addr, take));
+            if take == len {
+                self.0.remove(0);
+            } else {
+                self.0[0] = (addr.unchecked_add(take as u64), len - take);
+            }
+            n -= take;
+        }
+        Some(Buffers(front))
+    }
+
+    /// Split off the last `n` bytes, `None` if there are fewer.
+    fn split_back(&mut self, n: usize) -> Option<Buffers> {
+        let len = self.len().checked_sub(n)?;
+        let front = self.split_front(len)?;
+        Some(std::mem::replace(self, front))
+    }
+
+    /// Copy the bytes of the buffers into `data`.
+    fn read(&self, memory: &GuestMemoryMmap, data: &mut [u8]) -> Result<()> {
+        let mut done = 0;
+        for &(addr, len) in self.0.iter() {
+            let n = len.min(data.len() - done);
+            memory.read_slice(&mut data[done..done + n], addr)
+                .map_err(|e| Error::VirtioError(e.to_string()))?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Copy `data` into the buffers.
+    fn write(&self, memory: &GuestMemoryMmap, data: &[u8]) -> Result<()> {
+        let mut done = 0;
+        for &(addr, len) in self.0.iter() {
+            let n = len.min(data.len() - done);
+            memory.write_slice(&data[done..done + n], addr)
+                .map_err(|e| Error::VirtioError(e.to_string()))?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Host addresses of the buffers, which must be in guest RAM.
+    fn iovecs(&self, memory: &GuestMemoryMmap) -> Result<Vec<libc::iovec>> {
+        self.0.iter()
+            .map(|&(addr, len)| {
+                memory.get_slice(addr, len)
+                    .map(|s| iovec(s.as_ptr(), len))
+                    .map_err(|e| Error::VirtioError(e.to_string()))
+            })
+            .collect()
+    }
+}
+
+/// A request parsed from a descriptor chain.
+struct Request {
+    header: RequestHeader,
+    /// Data read or written by the device, depending on the type.
+    data: Buffers,
+    /// Where the status byte is written.
+    status: GuestAddress,
+}
+
+impl Request {
+    /// Parse a chain made of the header, the data and the status byte.
+    fn parse(memory: &GuestMemoryMmap, chain: &DescriptorChain) -> Result<Self> {
+        let mut readable = Buffers(
+            chain.readable().map(|d| (d.addr(), d.len() as usize)).collect()
+        );
+        let mut writable = Buffers(
+            chain.writable().map(|d| (d.addr(), d.len() as usize)).collect()
+        );
+        let malformed = |s: &str| Error::VirtioError(format!("malformed request: {}", s));
+        let header_len = std::mem::size_of::<RequestHeader>();
+        let mut header = RequestHeader::default();
+        readable.split_front(header_len)
+            .ok_or_else(|| malformed("no header"))?
+            .read(memory, header.as_mut_slice())?;
+        let status = writable.split_back(1)
+            .ok_or_else(|| malformed("no status"))?
+            .0[0].0;
+        let data = match u32::from_le(header.request_type) {
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_GET_ID => writable,
+            _ => readable,
+        };
+        Ok(Request { header, data, status })
+    }
+
+    fn sector(&self) -> u64 {
+        u64::from_le(self.header.sector)
+    }
+}
+
+/// Epoll tokens of the I/O thread.
+const TOKEN_QUEUE: u64 = 0;
+const TOKEN_ENGINE: u64 = 1;
+const TOKEN_KILL: u64 = 2;
+
+/// A request submitted to the I/O engine, keyed by its chain head.
+struct InFlight {
+    /// Where the status byte is written.
+    status: GuestAddress,
+    /// Bytes the operation must transfer to succeed.
+    expected: usize,
+    /// Bytes written to the data of the request on success.
+    written: u32,
+    /// Buffer standing for unaligned guest buffers with `O_DIRECT`, and
+    /// the guest buffers of a read to copy it into.
+    bounce: Option<(AlignedBuf, Option<Vec<libc::iovec>>)>,
+    /// The operation submitted, to finish a short transfer synchronously.
+    op: IoOp,
+}
+
+// Safe because the guest buffers outlive the request.
+unsafe impl Send for InFlight {}
+
+/// Handles the queue of an activated device in its I/O thread.
+struct Worker {
+    disk: Arc<dyn Disk>,
+    config: BlockConfig,
+    memory: GuestMemoryMmap,
+    interrupt: Arc<VirtioInterrupt>,
+    queue: Queue,
+    // The engine waits for the operations in flight when it's dropped, so
+    // it must be dropped before their buffers.
+    engine: Box<dyn IoEngine>,
+    in_flight: HashMap<u16, InFlight>,
+}
+
+impl Worker {
+    /// Handle the queue until `kill` is signaled.
+    fn run(&mut self, queue_event: &EventFd, kill: &EventFd) -> Result<()> {
+        let error = |e: io::Error| Error::VirtioError(e.to_string());
+        let epoll = Epoll::new().map_err(error)?;
+        for (fd, token) in [
+            (queue_event.as_raw_fd(), TOKEN_QUEUE),
+            (self.engine.event().as_raw_fd(), TOKEN_ENGINE),
+            (kill.as_raw_fd(), TOKEN_KILL),
+        ] {
+            epoll.ctl(ControlOperation::Add, fd, EpollEvent::new(EventSet::IN, token))
+                .map_err(error)?;
+        }
+        let mut events = [EpollEvent::default(); 3];
+        loop {
+            let count = match epoll.wait(-1, &mut events) {
+                Ok(count) => count,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(error(e)),
+            };
+            for event in &events[..count] {
+                match event.data() {
+                    TOKEN_QUEUE => {
+                        let _ = queue_event.read();
+                        self.process_queue()?;
+                    },
+                    TOKEN_ENGINE => {
+                        let _ = self.engine.event().read();
+                        self.complete()?;
+                    },
+                    _ => return Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Start the requests made available in the queue.
+    fn process_queue(&mut self) -> Result<()> {
+        let mut used = false;
+        while let Some(chain) = self.queue.pop(&self.memory)? {
+            let request = match Request::parse(&self.memory, &chain) {
+                Ok(request) => request,
+                Err(_) => {
+                    // Nowhere to write the status, return the chain untouched.
+                    self.queue.add_used(&self.memory, chain.head, 0)?;
+                    used = true;
+                    continue;
+                },
+            };
+            match self.start(chain.head, &request) {
+                Some(in_flight) => {
+                    self.in_flight.insert(chain.head, in_flight);
+                },
+                None => {
+                    let (status, len) = self.execute(&request);
+                    self.finish(chain.head, request.status, status, len)?;
+                    used = true;
+                },
+            }
+        }
+        self.engine.submit().map_err(|e| Error::VirtioError(e.to_string()))?;
+        self.notify(used)
+    }
+
+    /// Submit a read, write or flush of a raw image to the engine, other
+    /// requests and the ones failing to start are left to `execute`.
+    fn start(&mut self, head: u16, request: &Request) -> Option<InFlight> {
+        let raw = self.disk.raw()?;
+        let request_type = u32::from_le(request.header.request_type);
+        let len = request.data.len();
+        let op = match request_type {
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => {
+                let write = request_type == VIRTIO_BLK_T_OUT;
+                let offset = self.rw_offset(request, write)?;
+                let iovecs = request.data.iovecs(&self.memory).ok()?;
+                let mut bounce = None;
+                let iovecs = match raw.needs_bounce(offset, &iovecs) {
+                    true => {
+                        let mut buf = raw.bounce_buf(len);
+                        if write {
+                            gather(&iovecs, buf.as_mut_slice());
+                        }
+                        let bounced = vec![buf.iovec(len)];
+                        bounce = Some((buf, (!write).then_some(iovecs)));
+                        bounced
+                    },
+                    false => iovecs,
+                };
+                let written = if write { 0 } else { len as u32 };
+                let op = match write {
+                    true => IoOp::Write(offset, iovecs),
+                    false => IoOp::Read(offset, iovecs),
+                };
+                (op.clone(), InFlight { status: request.status, expected: len, written, bounce, op })
+            },
+            VIRTIO_BLK_T_FLUSH => (
+                IoOp::Flush,
+                InFlight {
+                    status: request.status,
+                    expected: 0,
+                    written: 0,
+                    bounce: None,
+                    op: IoOp::Flush,
+                }
+            ),
+            _ => return None,
+        };
+        self.engine.push(raw.fd(), op.0, head as u64).ok()?;
+        Some(op.1)
+    }
+
+    /// Offset of a read or write request, `None` if it's illegal.
+    fn rw_offset(&self, request: &Request, write: bool) -> Option<u64> {
+        let len = request.data.len() as u64;
+        if (write && self.config.readonly)
+            || !len.is_multiple_of(SECTOR_SIZE)
+            || !self.in_range(request.sector(), len) {
+            return None;
+        }
+        Some(request.sector() * SECTOR_SIZE)
+    }
+
+    /// Return the requests completed by the engine.
+    fn complete(&mut self) -> Result<()> {
+        let mut used = false;
+        for (tag, result) in self.engine.completions() {
+            let Some(request) = self.in_flight.remove(&(tag as u16)) else { continue };
+            let (status, len) = match result {
+                Ok(n) if self.transferred(&request, n) => {
+                    if let Some((buf, Some(iovecs))) = request.bounce.as_ref() {
+                        scatter(buf.as_slice(), iovecs);
+                    }
+                    (VIRTIO_BLK_S_OK, request.written)
+                },
+                _ => (VIRTIO_BLK_S_IOERR, 0),
+            };
+            self.finish(tag as u16, request.status, status, len)?;
+            used = true;
+        }
+        self.notify(used)
+    }
+
+    /// Whether all the bytes of a request are transferred after the engine
+    /// did `n` bytes, the rest of a short read or write is done here.
+    fn transferred(&self, request: &InFlight, n: usize) -> bool {
+        if n >= request.expected {
+            return n == request.expected;
+        }
+        let (offset, iovecs, write) = match &request.op {
+            IoOp::Read(offset, iovecs) => (*offset, iovecs, false),
+            IoOp::Write(offset, iovecs) => (*offset, iovecs, true),
+            IoOp::Flush => return false,
+        };
+        let Some(raw) = self.disk.raw() else { return false };
+        let mut iovecs = iovecs.clone();
+        let rest = disk::advance(&mut iovecs, n);
+        disk::transfer(raw.fd(), offset + n as u64, rest, write).is_ok()
+    }
+
+    /// Write the status of a request and return its chain, with `len`
+    /// bytes written to its data.
+    fn finish(&mut self, head: u16, addr: GuestAddress, status: u8, len: u32) -> Result<()> {
+        self.memory.write_obj(status, addr)
+            .map_err(|e| Error::VirtioError(e.to_string()))?;
+        self.queue.add_used(&self.memory, head, len + 1)
+    }
+
+    /// Interrupt the driver for the returned chains if it wants.
+    fn notify(&mut self, used: bool) -> Result<()> {
+        if used && self.queue.needs_notification(&self.memory)? {
+            self.interrupt.signal_used();
+        }
+        Ok(())
+    }
+
+    /// Whether `len` bytes at `sector` are within the disk.
+    fn in_range(&self, sector: u64, len: u64) -> bool {
+        sector.checked_mul(SECTOR_SIZE)
+            .and_then(|offset| offset.checked_add(len))
+            .map(|end| end <= self.disk.size())
+            .unwrap_or(false)
+    }
+
+    /// Execute a request synchronously, returns its status and the bytes
+    /// written to its data.
+    fn execute(&self, request: &Request) -> (u8, u32) {
+        let len = request.data.len() as u64;
+        let request_type = u32::from_le(request.header.request_type);
+        match request_type {
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => {
+                let write = request_type == VIRTIO_BLK_T_OUT;
+                let Some(offset) = self.rw_offset(request, write) else {
+                    return (VIRTIO_BLK_S_IOERR, 0);
+                };
+                let mut data = vec![0u8; len as usize];
+                let result = match write {
+                    true => request.data.read(&self.memory, &mut data).ok()
+                        .and_then(|_| self.disk.write_at(offset, &data).ok()),
+                    false => self.disk.read_at(offset, &mut data).ok()
+                        .and_then(|_| request.data.write(&self.memory, &data).ok()),
+                };
+                match (result, write) {
+                    (Some(()), true) => (VIRTIO_BLK_S_OK, 0),
+                    (Some(()), false) => (VIRTIO_BLK_S_OK, len as u32),
+                    (None, _) => (VIRTIO_BLK_S_IOERR, 0),
+                }
+            },
+            VIRTIO_BLK_T_FLUSH => match self.disk.flush() {
+                Ok(()) => (VIRTIO_BLK_S_OK, 0),
+                Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                let Some(serial) = self.config.serial.as_ref() else {
+                    return (VIRTIO_BLK_S_UNSUPP, 0);
+                };
+                // NUL terminated unless it takes all the bytes.
+                let mut id = [0u8; VIRTIO_BLK_ID_BYTES];
+                id[..serial.len()].copy_from_slice(serial.as_bytes());
+                let n = id.len().min(len as usize);
+                match request.data.write(&self.memory, &id[..n]) {
+                    Ok(()) => (VIRTIO_BLK_S_OK, n as u32),
+                    Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+                }
+            },
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES if self.config.readonly => {
+                (VIRTIO_BLK_S_IOERR, 0)
+            },
+            t @ (VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES) => {
+                (self.discard(request, t == VIRTIO_BLK_T_WRITE_ZEROES), 0)
+            },
+            _ => (VIRTIO_BLK_S_UNSUPP, 0),
+        }
+    }
+
+    /// Discard or zero the segments of a request.
+    fn discard(&self, request: &Request, zero: bool) -> u8 {
+        let seg_len = std::mem::size_of::<DiscardSegment>();
+        let count = request.data.len() / seg_len;
+        if !request.data.len().is_multiple_of(seg_len) || count == 0
+            || count > MAX_DISCARD_SEG as usize {
+            return VIRTIO_BLK_S_UNSUPP;
+        }
+        let mut data = vec![0u8; count * seg_len];
+        if request.data.read(&self.memory, &mut data).is_err() {
+            return VIRTIO_BLK_S_IOERR;
+        }
+        let alignment = (self.config.logical_block_size as u64) / SECTOR_SIZE;
+        for chunk in data.chunks_exact(seg_len) {
+            let mut s = DiscardSegment::default();
+            s.as_mut_slice().copy_from_slice(chunk);
+            let (sector, flags) = (u64::from_le(s.sector), u32::from_le(s.flags));
+            let len = u32::from_le(s.num_sectors) as u64 * SECTOR_SIZE;
+            let unmap = flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0;
+            // Discards take no flags, and unknown flags are unsupported.
+            if flags & !VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0 || (!zero && unmap) {
+                return VIRTIO_BLK_S_UNSUPP;
+            }
+            if !self.in_range(sector, len) || (!zero && !sector.is_multiple_of(alignment)) {
+                return VIRTIO_BLK_S_IOERR;
+            }
+            let offset = sector * SECTOR_SIZE;
+            let result = match zero {
+                true => self.disk.write_zeroes(offset, len, unmap),
+                false => self.disk.discard(offset, len),
+            };
+            if result.is_err() {
+                return VIRTIO_BLK_S_IOERR;
+            }
+        }
+        VIRTIO_BLK_S_OK
+    }
+}
+
+/// A virtio-blk device backed by an image or a block device.
+///
+/// Requests are handled in an I/O thread, which is woken up by the queue
+/// notifications and the completions of the I/O engine. Only raw images
+/// go through the engine, requests to other formats are executed in the
+/// thread.
+pub struct Block {
+    disk: Arc<dyn Disk>,
+    config: BlockConfig,
+    /// Configuration space, as laid out for the driver.
+    config_space: Vec<u8>,
+    features: u64,
+    io_thread: Option<IoThread>,
+}
+
+impl Block {
+    /// Create a device for the image at `config.source`.
+    pub fn new(config: BlockConfig) -> Result<Self> {
+        let disk = disk::open(&config.source, config.format, config.readonly, config.cache)?;
+        let mut features = 1 << VIRTIO_F_VERSION_1
+            | 1 << VIRTIO_RING_F_EVENT_IDX
+            | 1 << VIRTIO_RING_F_INDIRECT_DESC
+            | 1 << VIRTIO_BLK_F_SEG_MAX
+            | 1 << VIRTIO_BLK_F_BLK_SIZE
+            | 1 << VIRTIO_BLK_F_FLUSH;
+        features |= match config.readonly {
+            true => 1 << VIRTIO_BLK_F_RO,
+            false => 1 << VIRTIO_BLK_F_DISCARD | 1 << VIRTIO_BLK_F_WRITE_ZEROES,
+        };
+        let config_space = Self::config_space(disk.size(), config.logical_block_size);
+        Ok(Block { disk: Arc::from(disk), config, config_space, features, io_thread: None })
+    }
+
+    /// Lay out struct virtio_blk_config.
+    fn config_space(size: u64, block_size: u32) -> Vec<u8> {
+        let alignment = block_size / SECTOR_SIZE as u32;
+        let mut space = Vec::with_capacity(60);
+        // capacity, size_max
+        space.extend_from_slice(&(size / SECTOR_SIZE).to_le_bytes());
+        space.extend_from_slice(&0u32.to_le_bytes());
+        // seg_max, leaving room for the header and the status.
+        space.extend_from_slice(&(QUEUE_SIZE as u32 - 2).to_le_bytes());
+        // geometry
+        space.extend_from_slice(&[0; 4]);
+        space.extend_from_slice(&block_size.to_le_bytes());
+        // topology, writeback, unused0, num_queues
+        space.extend_from_slice(&[0; 8]);
+        space.extend_from_slice(&[0, 0]);
+        space.extend_from_slice(&1u16.to_le_bytes());
+        // max_discard_sectors, max_discard_seg, discard_sector_alignment
+        for v in [u32::MAX, MAX_DISCARD_SEG, alignment] {
+            space.extend_from_slice(&v.to_le_bytes());
+        }
+        // max_write_zeroes_sectors, max_write_zeroes_seg,
+        // write_zeroes_may_unmap, unused1
+        for v in [u32::MAX, MAX_DISCARD_SEG] {
+            space.extend_from_slice(&v.to_le_bytes());
+        }
+        space.extend_from_slice(&[1, 0, 0, 0]);
+        space
+    }
+}
+
+impl VirtioDevice for Block {
+    fn device_type(&self) -> u32 {
+        TYPE_BLOCK
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &[QUEUE_SIZE]
+    }
+
+    fn features(&self) -> u64 {
+        self.features
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        read_config_space(&self.config_space, offset, data)
+    }
+
+    fn activate(
+        &mut self,
+        memory: GuestMemoryMmap,
+        interrupt: Arc<VirtioInterrupt>,
+        queues: Vec<(Queue, EventFd)>
+    ) -> Result<()> {
+        let error = |e: io::Error| Error::VirtioError(e.to_string());
+        let Ok([(queue, queue_event)]) = <[_; 1]>::try_from(queues) else {
+            return Err(Error::VirtioError("virtio-blk takes a single queue".to_string()));
+        };
+        let engine = disk::create_engine(self.config.io_engine, QUEUE_SIZE as u32)
+            .map_err(error)?;
+        let mut worker = Worker {
+            disk: self.disk.clone(),
+            config: self.config.clone(),
+            memory,
+            interrupt,
+            queue,
+            engine,
+            in_flight: HashMap::new(),
+        };
+        self.io_thread = Some(IoThread::spawn("virtio-blk", move |kill| {
+            if worker.run(&queue_event, kill).is_err() {
+                // The queue is unusable until the driver resets the device.
+                worker.interrupt.signal_needs_reset();
+            }
+        })?);
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        if let Some(io_thread) = self.io_thread.take() {
+            io_thread.stop();
+        }
+    }
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        self.reset();
+    }
+}
+
+#[test]
+fn test_block() {
+    use std::{io::Write, thread, time::{Duration, Instant}};
+    use vmm_sys_util::tempfile::TempFile;
+    use crate::config::{ImageFormat, IoEngineKind};
+    use super::queue::{TestQueue, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE, desc};
+
+    // The same requests to raw images through each engine, and to a qcow2
+    // image backed by a raw one.
+    let qcow2 = TempFile::new().unwrap();
+    for (io_engine, format) in [
+        (IoEngineKind::IoUring, None),
+        (IoEngineKind::ThreadPool, None),
+        (IoEngineKind::IoUring, Some(ImageFormat::Qcow2)),
+    ] {
+        let temp = TempFile::new().unwrap();
+        let mut path = temp.as_path().to_str().unwrap().to_string();
+        temp.as_file().write_all(&[0xaa; 8 * 512]).unwrap();
+        if format.is_some() {
+            disk::qcow2::create(qcow2.as_path().to_str().unwrap(), 8 * 512, Some(&path));
+            path = qcow2.as_path().to_str().unwrap().to_string();
+        }
+        let config = BlockConfig {
+            source: path,
+            format,
+            serial: Some("disk-0".to_string()),
+            logical_block_size: 1024,
+            io_engine,
+            ..Default::default()
+        };
+        let mut block = Block::new(config).unwrap();
+        assert_eq!(block.features() & (1 << VIRTIO_BLK_F_RO), 0);
+        let mut data = [0u8; 8];
+        block.read_config(0, &mut data);
+        assert_eq!(u64::from_le_bytes(data), 8);
+        block.read_config(20, &mut data[..4]);
+        assert_eq!(u32::from_le_bytes(data[..4].try_into().unwrap()), 1024);
+        assert_eq!(block.config_space.len(), 60);
+
+        let memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let irqfd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let interrupt = Arc::new(VirtioInterrupt::new(irqfd.try_clone().unwrap()));
+        let queue_event = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let mut q = TestQueue::new(16, 0x1000);
+        let queues = vec![(q.queue.clone(), queue_event.try_clone().unwrap())];
+        block.activate(memory.clone(), interrupt.clone(), queues).unwrap();
+
+        // Submit a request of the header at 0x4000, the data at 0x5000 and
+        // the status at 0x6000, returns the status and the used length.
+        let mut submit = |request_type: u32, sector: u64, len: u32, write: bool| {
+            let header = RequestHeader { request_type, reserved: 0, sector };
+            memory.write_obj(header, GuestAddress(0x4000)).unwrap();
+            let data_flags = VIRTQ_DESC_F_NEXT | if write { VIRTQ_DESC_F_WRITE } else { 0 };
+            q.desc(&memory, 0, desc(0x4000, 16, VIRTQ_DESC_F_NEXT, 1));
+            q.desc(&memory, 1, desc(0x5000, len, data_flags, 2));
+            q.desc(&memory, 2, desc(0x6000, 1, VIRTQ_DESC_F_WRITE, 0));
+            let used_idx = q.used_idx(&memory);
+            q.offer(&memory, 0);
+            queue_event.write(1).unwrap();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while q.used_idx(&memory) == used_idx {
+                assert!(Instant::now() < deadline);
+                thread::sleep(Duration::from_millis(1));
+            }
+            let used = q.used(&memory, used_idx);
+            (memory.read_obj::<u8>(GuestAddress(0x6000)).unwrap(), used.1)
+        };
+
+        assert_eq!(submit(VIRTIO_BLK_T_IN, 1, 512, true), (VIRTIO_BLK_S_OK, 513));
+        assert_eq!(memory.read_obj::<u8>(GuestAddress(0x51ff)).unwrap(), 0xaa);
+        assert!(irqfd.read().unwrap() >= 1);
+        memory.write_slice(&[0x55; 1024], GuestAddress(0x5000)).unwrap();
+        assert_eq!(submit(VIRTIO_BLK_T_OUT, 6, 1024, false), (VIRTIO_BLK_S_OK, 1));
+        assert_eq!(submit(VIRTIO_BLK_T_OUT, 7, 1024, false), (VIRTIO_BLK_S_IOERR, 1));
+        assert_eq!(submit(VIRTIO_BLK_T_IN, 0, 100, true), (VIRTIO_BLK_S_IOERR, 1));
+        assert_eq!(submit(VIRTIO_BLK_T_FLUSH, 0, 0, false), (VIRTIO_BLK_S_OK, 1));
+        assert_eq!(submit(VIRTIO_BLK_T_GET_ID, 0, 20, true), (VIRTIO_BLK_S_OK, 21));
+        let mut id = [0u8; 7];
+        memory.read_slice(&mut id, GuestAddress(0x5000)).unwrap();
+        assert_eq!(&id, b"disk-0\0");
+        assert_eq!(submit(42, 0, 512, false), (VIRTIO_BLK_S_UNSUPP, 1));
+
+        // Zero sectors 6-7 written above, then discard them.
+        let segment = DiscardSegment { sector: 6, num_sectors: 2, flags: 0 };
+        memory.write_obj(segment, GuestAddress(0x5000)).unwrap();
+        assert_eq!(submit(VIRTIO_BLK_T_WRITE_ZEROES, 0, 16, false), (VIRTIO_BLK_S_OK, 1));
+        assert_eq!(submit(VIRTIO_BLK_T_IN, 6, 1024, true), (VIRTIO_BLK_S_OK, 1025));
+        let mut buf = [0xffu8; 1024];
+        memory.read_slice(&mut buf, GuestAddress(0x5000)).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+        memory.write_obj(segment, GuestAddress(0x5000)).unwrap();
+        assert_eq!(submit(VIRTIO_BLK_T_DISCARD, 0, 16, false), (VIRTIO_BLK_S_OK, 1));
+        // Discards are aligned to the logical block size.
+        let segment = DiscardSegment { sector: 1, num_sectors: 1, flags: 0 };
+        memory.write_obj(segment, GuestAddress(0x5000)).unwrap();
+        assert_eq!(submit(VIRTIO_BLK_T_DISCARD, 0, 16, false), (VIRTIO_BLK_S_IOERR, 1));
+
+        // The I/O thread stops on reset.
+        block.reset();
+        assert!(block.io_thread.is_none());
+    }
+
+    // A read-only device rejects writes.
+    let temp = TempFile::new().unwrap();
+    let config = BlockConfig {
+        source: temp.as_path().to_str().unwrap().to_string(),
+        readonly: true,
+        ..Default::default()
+    };
+    let block = Block::new(config).unwrap();
+    assert_ne!(block.features() & (1 << VIRTIO_BLK_F_RO), 0);
+    assert_eq!(block.features() & (1 << VIRTIO_BLK_F_DISCARD), 0);
+}
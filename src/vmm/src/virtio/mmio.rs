@@ -0,0 +1,447 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::{Arc, Mutex};
+use vm_memory::{GuestAddress, GuestMemoryMmap};
+use vmm_sys_util::eventfd::EventFd;
+use crate::bus::BusDevice;
+use crate::error::{Error, Result};
+use super::{
+    STATUS_DEVICE_NEEDS_RESET, STATUS_DRIVER, STATUS_DRIVER_OK, STATUS_FEATURES_OK, VIRTIO_F_VERSION_1,
+    VIRTIO_RING_F_EVENT_IDX, VirtioDevice, VirtioInterrupt,
+};
+use super::queue::Queue;
+
+/// Size of the MMIO range taken by each device.
+pub const MMIO_LEN: u64 = 0x1000;
+
+/// "virt" in little endian.
+const MAGIC_VALUE: u32 = 0x7472_6976;
+/// The modern (non-legacy) interface.
+const VERSION: u32 = 2;
+/// Vendor ID reported to the driver.
+const VENDOR_ID: u32 = 0;
+
+/// Register offsets.
+const MAGIC_VALUE_REG: u64 = 0x000;
+const VERSION_REG: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR_ID_REG: u64 = 0x00c;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+/// Notifications are usually caught by ioeventfds registered at it.
+pub const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const CONFIG_GENERATION: u64 = 0x0fc;
+const CONFIG: u64 = 0x100;
+
+/// State of the transport, which is driven by the driver.
+struct MmioState {
+    device: Box<dyn VirtioDevice>,
+    memory: GuestMemoryMmap,
+    interrupt: Arc<VirtioInterrupt>,
+    /// Queues set up by the driver, copies are handed over on activation.
+    queues: Vec<Queue>,
+    /// Signaled when the driver notifies the queue of the same index.
+    events: Vec<EventFd>,
+    queue_select: u32,
+    device_features_select: u32,
+    driver_features_select: u32,
+    driver_features: u64,
+    status: u32,
+    activated: bool,
+}
+
+impl MmioState {
+    fn queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_select as usize)
+    }
+
+    /// Set the low or high half of a queue address.
+    fn set_queue_addr(&mut self, offset: u64, value: u32) {
+        // Queues can't be changed once the driver is ready.
+        if self.status & STATUS_DRIVER_OK != 0 {
+            return;
+        }
+        let Some(queue) = self.queue() else { return };
+        let addr = match offset {
+            QUEUE_DESC_LOW | QUEUE_DESC_HIGH => &mut queue.desc_table,
+            QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH => &mut queue.avail_ring,
+            _ => &mut queue.used_ring,
+        };
+        let (shift, mask) = if offset & 0x4 == 0 { (0, !0xffff_ffff) } else { (32, 0xffff_ffff) };
+        *addr = GuestAddress((addr.0 & mask) | ((value as u64) << shift));
+    }
+
+    /// Handle the driver writing the device status.
+    fn set_status(&mut self, status: u32) {
+        if status == 0 {
+            self.reset();
+            return;
+        }
+        // Bits can only be added, and FEATURES_OK only if the features
+        // are acceptable.
+        let added = status & !self.status;
+        if status & self.status != self.status {
+            return;
+        }
+        if added & STATUS_FEATURES_OK != 0 {
+            let offered = self.device.features();
+            if self.driver_features & !offered != 0
+                || self.driver_features & (1 << VIRTIO_F_VERSION_1) == 0 {
+                self.status = status & !STATUS_FEATURES_OK;
+                return;
+            }
+            self.device.ack_features(self.driver_features);
+        }
+        // The device can't run without negotiated features.
+        if added & STATUS_DRIVER_OK != 0 && status & STATUS_FEATURES_OK == 0 {
+            return;
+        }
+        self.status = status;
+        if added & STATUS_DRIVER_OK != 0 && !self.activated {
+            self.activate();
+        }
+    }
+
+    /// Hand the queues over to the device.
+    fn activate(&mut self) {
+        let event_idx = self.driver_features & (1 << VIRTIO_RING_F_EVENT_IDX) != 0;
+        let mut queues = Vec::new();
+        for (queue, event) in self.queues.iter().zip(self.events.iter()).filter(|(q, _)| q.ready) {
+            let Ok(event) = event.try_clone() else {
+                return self.interrupt.signal_needs_reset();
+            };
+            if queue.validate(&self.memory).is_err() {
+                return self.interrupt.signal_needs_reset();
+            }
+            let mut queue = queue.clone();
+            queue.event_idx = event_idx;
+            queues.push((queue, event));
+        }
+        let result = self.device.activate(
+            self.memory.clone(),
+            self.interrupt.clone(),
+            queues
+        );
+        match result {
+            Ok(()) => self.activated = true,
+            Err(_) => self.interrupt.signal_needs_reset(),
+        }
+    }
+
+    /// Device status, including the broken state reported by the device.
+    fn status(&self) -> u32 {
+        match self.interrupt.needs_reset() {
+            true => self.status | STATUS_DEVICE_NEEDS_RESET,
+            false => self.status,
+        }
+    }
+
+    /// Restore the state before the driver probed the device.
+    fn reset(&mut self) {
+        if self.activated {
+            self.device.reset();
+            self.activated = false;
+        }
+        self.queues.iter_mut().for_each(Queue::reset);
+        self.queue_select = 0;
+        self.device_features_select = 0;
+        self.driver_features_select = 0;
+        self.driver_features = 0;
+        self.status = 0;
+        self.interrupt.reset();
+    }
+}
+
+/// virtio-mmio transport of a device, as defined in the virtio 1.1 spec.
+///
+/// The guest finds it by `virtio_mmio.device=<size>@<base>:<irq>` on the
+/// kernel command line.
+pub struct MmioTransport {
+    state: Mutex<MmioState>,
+}
+
+impl MmioTransport {
+    /// Create a transport for the device.
+    pub fn new(
+        device: Box<dyn VirtioDevice>,
+        memory: GuestMemoryMmap,
+        interrupt: Arc<VirtioInterrupt>
+    ) -> Result<Self> {
+        let queues: Vec<_> = device.queue_max_sizes().iter().map(|&s| Queue::new(s)).collect();
+        let events = queues.iter()
+            .map(|_| EventFd::new(libc::EFD_NONBLOCK))
+            .collect::<std::io::Result<_>>()
+            .map_err(|e| Error::VirtioError(e.to_string()))?;
+        Ok(MmioTransport {
+            state: Mutex::new(MmioState {
+                device,
+                memory,
+                interrupt,
+                queues,
+                events,
+                queue_select: 0,
+                device_features_select: 0,
+                driver_features_select: 0,
+                driver_features: 0,
+                status: 0,
+                activated: false,
+            }),
+        })
+    }
+
+    /// Eventfds to be signaled when the driver notifies the queue of the
+    /// same index, e.g. by ioeventfds at `QUEUE_NOTIFY`.
+    pub fn queue_events(&self) -> Result<Vec<EventFd>> {
+        self.state.lock().unwrap().events.iter()
+            .map(|e| e.try_clone())
+            .collect::<std::io::Result<_>>()
+            .map_err(|e| Error::VirtioError(e.to_string()))
+    }
+
+    /// Reset the device as if the driver did, e.g. when the guest reboots.
+    pub fn reset(&self) {
+        self.state.lock().unwrap().reset();
+    }
+
+    /// The device status set by the driver.
+    pub fn status(&self) -> u32 {
+        self.state.lock().unwrap().status()
+    }
+
+    /// Kernel command line parameter for the guest to find the device.
+    pub fn cmdline(base: u64, irq: u32) -> String {
+        format!("virtio_mmio.device={}K@{:#x}:{}", MMIO_LEN >> 10, base, irq)
+    }
+}
+
+impl BusDevice for MmioTransport {
+    fn read(&self, _base: u64, offset: u64, data: &mut [u8]) {
+        let mut state = self.state.lock().unwrap();
+        if offset >= CONFIG {
+            state.device.read_config(offset - CONFIG, data);
+            return;
+        }
+        // Registers are only accessed as 32-bit words.
+        if data.len() != 4 {
+            data.fill(0);
+            return;
+        }
+        let value = match offset {
+            MAGIC_VALUE_REG => MAGIC_VALUE,
+            VERSION_REG => VERSION,
+            DEVICE_ID => state.device.device_type(),
+            VENDOR_ID_REG => VENDOR_ID,
+            DEVICE_FEATURES => match state.device_features_select {
+                0 => state.device.features() as u32,
+                1 => (state.device.features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => state.queue().map(|q| q.max_size as u32).unwrap_or(0),
+            QUEUE_READY => state.queue().map(|q| q.ready as u32).unwrap_or(0),
+            INTERRUPT_STATUS => state.interrupt.status(),
+            STATUS => state.status(),
+            CONFIG_GENERATION => state.interrupt.config_generation(),
+            _ => 0,
+        };
+        data.copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn write(&self, _base: u64, offset: u64, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        if offset >= CONFIG {
+            state.device.write_config(offset - CONFIG, data);
+            return;
+        }
+        let Ok(bytes) = <[u8; 4]>::try_from(data) else { return };
+        let value = u32::from_le_bytes(bytes);
+        let negotiating = state.status & STATUS_DRIVER != 0
+            && state.status & STATUS_FEATURES_OK == 0;
+        match offset {
+            DEVICE_FEATURES_SEL => state.device_features_select = value,
+            DRIVER_FEATURES_SEL => state.driver_features_select = value,
+            DRIVER_FEATURES if negotiating => match state.driver_features_select {
+                0 => state.driver_features = (state.driver_features & !0xffff_ffff) | value as u64,
+                1 => state.driver_features = (state.driver_features & 0xffff_ffff) | ((value as u64) << 32),
+                _ => {},
+            },
+            QUEUE_SEL => state.queue_select = value,
+            QUEUE_NUM => if let Some(q) = state.queue() {
+                q.size = value as u16;
+            },
+            QUEUE_READY => if let Some(q) = state.queue() {
+                q.ready = value == 1;
+            },
+            // Only reached if the ioeventfd isn't registered.
+            QUEUE_NOTIFY => if let Some(event) = state.events.get(value as usize) {
+                let _ = event.write(1);
+            },
+            INTERRUPT_ACK => state.interrupt.ack(value),
+            STATUS => state.set_status(value),
+            QUEUE_DESC_LOW | QUEUE_DESC_HIGH | QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH
+                | QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => state.set_queue_addr(offset, value),
+            _ => {},
+        }
+    }
+}
+
+#[test]
+fn test_mmio_transport() {
+    use vmm_sys_util::eventfd::EventFd;
+    use crate::error::{Error, Result};
+    use super::{INTERRUPT_CONFIG, INTERRUPT_VRING, STATUS_ACKNOWLEDGE, read_config_space};
+
+    type Activated = Option<Vec<(Queue, EventFd)>>;
+
+    /// Record what the transport asks the device to do.
+    #[derive(Default)]
+    struct Dummy {
+        acked: Arc<Mutex<u64>>,
+        queues: Arc<Mutex<Activated>>,
+    }
+
+    impl VirtioDevice for Dummy {
+        fn device_type(&self) -> u32 { 0x42 }
+        fn queue_max_sizes(&self) -> &[u16] { &[16, 8] }
+        fn features(&self) -> u64 { 1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_RING_F_EVENT_IDX | 1 }
+        fn ack_features(&mut self, features: u64) {
+            *self.acked.lock().unwrap() = features;
+        }
+        fn read_config(&self, offset: u64, data: &mut [u8]) {
+            read_config_space(&[1, 2, 3, 4], offset, data)
+        }
+        fn activate(
+            &mut self,
+            _memory: GuestMemoryMmap,
+            _interrupt: Arc<VirtioInterrupt>,
+            queues: Vec<(Queue, EventFd)>
+        ) -> Result<()> {
+            if queues.len() != 1 {
+                return Err(Error::VirtioError("expect a single queue".to_string()));
+            }
+            *self.queues.lock().unwrap() = Some(queues);
+            Ok(())
+        }
+        fn reset(&mut self) {
+            *self.queues.lock().unwrap() = None;
+        }
+    }
+
+    let memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let irqfd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+    let interrupt = Arc::new(VirtioInterrupt::new(irqfd.try_clone().unwrap()));
+    let dummy = Dummy::default();
+    let (acked, queues) = (dummy.acked.clone(), dummy.queues.clone());
+    let transport = MmioTransport::new(Box::new(dummy), memory, interrupt.clone()).unwrap();
+    assert_eq!(transport.queue_events().map(|e| e.len()), Ok(2));
+    let read = |offset| {
+        let mut data = [0u8; 4];
+        transport.read(0, offset, &mut data);
+        u32::from_le_bytes(data)
+    };
+    let write = |offset, value: u32| transport.write(0, offset, &value.to_le_bytes());
+
+    assert_eq!(read(MAGIC_VALUE_REG), MAGIC_VALUE);
+    assert_eq!(read(VERSION_REG), VERSION);
+    assert_eq!(read(DEVICE_ID), 0x42);
+    let mut config = [0u8; 2];
+    transport.read(0, CONFIG + 3, &mut config);
+    assert_eq!(config, [4, 0]);
+
+    // Feature negotiation, unknown features are rejected.
+    write(STATUS, STATUS_ACKNOWLEDGE);
+    write(STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+    write(DEVICE_FEATURES_SEL, 1);
+    assert_eq!(read(DEVICE_FEATURES), 1);
+    write(DRIVER_FEATURES_SEL, 0);
+    write(DRIVER_FEATURES, 0x3);
+    write(DRIVER_FEATURES_SEL, 1);
+    write(DRIVER_FEATURES, 1);
+    write(STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+    assert_eq!(read(STATUS) & STATUS_FEATURES_OK, 0);
+    write(DRIVER_FEATURES_SEL, 0);
+    write(DRIVER_FEATURES, 0x1);
+    let features_ok = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK;
+    write(STATUS, features_ok);
+    assert_eq!(read(STATUS), features_ok);
+    assert_eq!(*acked.lock().unwrap(), 1 << VIRTIO_F_VERSION_1 | 1);
+
+    // Set up the first queue only.
+    write(QUEUE_SEL, 0);
+    assert_eq!(read(QUEUE_NUM_MAX), 16);
+    write(QUEUE_NUM, 8);
+    write(QUEUE_DESC_LOW, 0x1000);
+    write(QUEUE_DRIVER_LOW, 0x1080);
+    write(QUEUE_DEVICE_LOW, 0x2000);
+    write(QUEUE_READY, 1);
+    write(QUEUE_SEL, 2);
+    assert_eq!(read(QUEUE_NUM_MAX), 0);
+    write(STATUS, features_ok | STATUS_DRIVER_OK);
+    let queue = queues.lock().unwrap().as_ref().unwrap()[0].0.clone();
+    assert_eq!(
+        (queue.size, queue.desc_table, queue.avail_ring, queue.used_ring),
+        (8, GuestAddress(0x1000), GuestAddress(0x1080), GuestAddress(0x2000))
+    );
+    assert!(!queue.event_idx);
+
+    // Notifications reach the device through the eventfd of the queue.
+    write(QUEUE_NOTIFY, 0);
+    write(QUEUE_NOTIFY, 7);
+    assert_eq!(queues.lock().unwrap().as_ref().unwrap()[0].1.read().ok(), Some(1));
+
+    // Interrupts are kept until acknowledged.
+    interrupt.signal_used();
+    assert_eq!(irqfd.read().unwrap(), 1);
+    assert_eq!(read(INTERRUPT_STATUS), INTERRUPT_VRING);
+    write(INTERRUPT_ACK, INTERRUPT_VRING);
+    assert_eq!(read(INTERRUPT_STATUS), 0);
+
+    // Only changes made by the device start a new config generation.
+    transport.write(0, CONFIG, &[5]);
+    assert_eq!(read(CONFIG_GENERATION), 0);
+    interrupt.signal_config();
+    assert_eq!(read(CONFIG_GENERATION), 1);
+    assert_eq!(read(INTERRUPT_STATUS), INTERRUPT_CONFIG);
+    write(INTERRUPT_ACK, INTERRUPT_CONFIG);
+
+    // Reset by the driver.
+    write(STATUS, 0);
+    assert_eq!(read(STATUS), 0);
+    assert!(queues.lock().unwrap().is_none());
+    write(QUEUE_SEL, 0);
+    assert_eq!(read(QUEUE_READY), 0);
+
+    // A broken queue needs a reset, which is told by a config interrupt.
+    write(STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+    write(DRIVER_FEATURES_SEL, 1);
+    write(DRIVER_FEATURES, 1);
+    write(STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK);
+    assert_eq!(read(STATUS), STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+    write(STATUS, features_ok);
+    write(QUEUE_NUM, 3);
+    write(QUEUE_READY, 1);
+    write(STATUS, features_ok | STATUS_DRIVER_OK);
+    assert_eq!(read(STATUS) & STATUS_DEVICE_NEEDS_RESET, STATUS_DEVICE_NEEDS_RESET);
+    assert_eq!(read(INTERRUPT_STATUS), INTERRUPT_CONFIG);
+    assert!(queues.lock().unwrap().is_none());
+
+    assert_eq!(
+        MmioTransport::cmdline(0xc000_0000, 5),
+        "virtio_mmio.device=4K@0xc0000000:5"
+    );
+}
@@ -0,0 +1,185 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+pub mod block;
+pub mod mmio;
+pub mod net;
+pub mod queue;
+pub mod vhost_user;
+
+use std::{
+    sync::{Arc, atomic::{AtomicBool, AtomicU32, Ordering}},
+    thread::{self, JoinHandle},
+};
+use vm_memory::GuestMemoryMmap;
+use vmm_sys_util::eventfd::EventFd;
+use crate::error::{Error, Result};
+use queue::Queue;
+
+/// Device IDs.
+pub const TYPE_NET: u32 = 1;
+pub const TYPE_BLOCK: u32 = 2;
+
+/// Device status bits.
+pub const STATUS_ACKNOWLEDGE: u32 = 0x01;
+pub const STATUS_DRIVER: u32 = 0x02;
+pub const STATUS_DRIVER_OK: u32 = 0x04;
+pub const STATUS_FEATURES_OK: u32 = 0x08;
+pub const STATUS_DEVICE_NEEDS_RESET: u32 = 0x40;
+pub const STATUS_FAILED: u32 = 0x80;
+
+/// Feature bits shared by all devices.
+pub const VIRTIO_RING_F_INDIRECT_DESC: u32 = 28;
+pub const VIRTIO_RING_F_EVENT_IDX: u32 = 29;
+pub const VIRTIO_F_VERSION_1: u32 = 32;
+
+/// Interrupt status bits.
+pub const INTERRUPT_VRING: u32 = 0x1;
+pub const INTERRUPT_CONFIG: u32 = 0x2;
+
+/// Interrupt of a virtio device, the cause is kept in the status until the
+/// driver acknowledges it.
+pub struct VirtioInterrupt {
+    status: AtomicU32,
+    /// The device is broken until the driver resets it.
+    needs_reset: AtomicBool,
+    /// Bumped each time the device changes its configuration space.
+    config_generation: AtomicU32,
+    irqfd: EventFd,
+}
+
+impl VirtioInterrupt {
+    /// Create an interrupt raised by writing `irqfd`.
+    pub fn new(irqfd: EventFd) -> Self {
+        VirtioInterrupt {
+            status: AtomicU32::new(0),
+            needs_reset: AtomicBool::new(false),
+            config_generation: AtomicU32::new(0),
+            irqfd,
+        }
+    }
+
+    /// Tell the driver the device is broken, e.g. by a malformed queue,
+    /// and has to be reset.
+    pub fn signal_needs_reset(&self) {
+        self.needs_reset.store(true, Ordering::SeqCst);
+        self.signal(INTERRUPT_CONFIG);
+    }
+
+    /// Whether the device is broken until the driver resets it.
+    pub fn needs_reset(&self) -> bool {
+        self.needs_reset.load(Ordering::SeqCst)
+    }
+
+    /// Interrupt the driver for used buffers.
+    pub fn signal_used(&self) {
+        self.signal(INTERRUPT_VRING);
+    }
+
+    /// Interrupt the driver for a change of the configuration space made
+    /// by the device, which also starts a new config generation.
+    pub fn signal_config(&self) {
+        self.config_generation.fetch_add(1, Ordering::SeqCst);
+        self.signal(INTERRUPT_CONFIG);
+    }
+
+    /// Generation of the configuration space, so that the driver can tell
+    /// whether it changed while being read.
+    pub fn config_generation(&self) -> u32 {
+        self.config_generation.load(Ordering::SeqCst)
+    }
+
+    fn signal(&self, cause: u32) {
+        self.status.fetch_or(cause, Ordering::SeqCst);
+        // The counter only overflows if the irqfd is gone, nothing to do.
+        let _ = self.irqfd.write(1);
+    }
+
+    /// Causes not yet acknowledged.
+    pub fn status(&self) -> u32 {
+        self.status.load(Ordering::SeqCst)
+    }
+
+    /// Clear the acknowledged causes.
+    pub fn ack(&self, causes: u32) {
+        self.status.fetch_and(!causes, Ordering::SeqCst);
+    }
+
+    /// Clear the causes and the broken state when the device is reset.
+    pub fn reset(&self) {
+        self.needs_reset.store(false, Ordering::SeqCst);
+        self.ack(!0);
+    }
+}
+
+/// A virtio device backend, independent of the transport.
+///
+/// The transport negotiates features and sets up the queues with the
+/// driver, then hands the queues over to the device on activation. Devices
+/// handle the queues in their own threads.
+pub trait VirtioDevice: Send {
+    /// Device ID, e.g. `TYPE_BLOCK`.
+    fn device_type(&self) -> u32;
+    /// Maximum size of each queue.
+    fn queue_max_sizes(&self) -> &[u16];
+    /// Features offered to the driver.
+    fn features(&self) -> u64;
+    /// Features accepted by the driver, which are a subset of the offered
+    /// ones.
+    fn ack_features(&mut self, _features: u64) {}
+    /// Read the configuration space at `offset`.
+    fn read_config(&self, offset: u64, data: &mut [u8]);
+    /// Write the configuration space at `offset`, ignored by default.
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+    /// Start the device with the queues set up by the driver, each comes
+    /// with the eventfd signaled when the driver notifies it.
+    fn activate(
+        &mut self,
+        memory: GuestMemoryMmap,
+        interrupt: Arc<VirtioInterrupt>,
+        queues: Vec<(Queue, EventFd)>
+    ) -> Result<()>;
+    /// Stop the device and drop its queues, e.g. when the driver resets it.
+    fn reset(&mut self);
+}
+
+/// A thread handling queues of an activated device.
+pub struct IoThread {
+    kill: EventFd,
+    handle: JoinHandle<()>,
+}
+
+impl IoThread {
+    /// Spawn a thread running `f`, which must return once the eventfd it's
+    /// given is signaled.
+    pub fn spawn<F>(name: &str, f: F) -> Result<Self>
+    where
+        F: FnOnce(&EventFd) + Send + 'static
+    {
+        let kill = EventFd::new(libc::EFD_NONBLOCK)
+            .map_err(|e| Error::VirtioError(e.to_string()))?;
+        let thread_kill = kill.try_clone()
+            .map_err(|e| Error::VirtioError(e.to_string()))?;
+        let handle = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || f(&thread_kill))
+            .map_err(|e| Error::ThreadError(e.to_string()))?;
+        Ok(IoThread { kill, handle })
+    }
+
+    /// Tell the thread to return and wait for it.
+    pub fn stop(self) {
+        let _ = self.kill.write(1);
+        let _ = self.handle.join();
+    }
+}
+
+/// Read the bytes of a configuration space at `offset`, bytes out of it
+/// read as zeros.
+pub fn read_config_space(config: &[u8], offset: u64, data: &mut [u8]) {
+    data.fill(0);
+    if let Some(src) = config.get(offset as usize..) {
+        let len = src.len().min(data.len());
+        data[..len].copy_from_slice(&src[..len]);
+    }
+}
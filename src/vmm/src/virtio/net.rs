@@ -0,0 +1,613 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    io,
+    os::unix::io::AsRawFd,
+    sync::Arc,
+};
+use vm_memory::{Bytes, GuestMemory, GuestMemoryMmap};
+use vmm_sys_util::{
+    epoll::{ControlOperation, Epoll, EpollEvent, EventSet},
+    eventfd::EventFd,
+};
+use crate::config::NetConfig;
+use crate::disk::{gather, iovec, scatter};
+use crate::error::{Error, Result};
+use crate::tap::{Tap, VNET_HDR_LEN};
+use super::{
+    TYPE_NET, VIRTIO_F_VERSION_1, VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC,
+    IoThread, VirtioDevice, VirtioInterrupt, read_config_space,
+};
+use super::queue::{Descriptor, Queue};
+
+/// Feature bits of network devices.
+const VIRTIO_NET_F_CSUM: u32 = 0;
+const VIRTIO_NET_F_GUEST_CSUM: u32 = 1;
+const VIRTIO_NET_F_MTU: u32 = 3;
+const VIRTIO_NET_F_MAC: u32 = 5;
+const VIRTIO_NET_F_GUEST_TSO4: u32 = 7;
+const VIRTIO_NET_F_GUEST_TSO6: u32 = 8;
+const VIRTIO_NET_F_GUEST_ECN: u32 = 9;
+const VIRTIO_NET_F_HOST_TSO4: u32 = 11;
+const VIRTIO_NET_F_HOST_TSO6: u32 = 12;
+const VIRTIO_NET_F_HOST_ECN: u32 = 13;
+const VIRTIO_NET_F_STATUS: u32 = 16;
+const VIRTIO_NET_F_CTRL_VQ: u32 = 17;
+const VIRTIO_NET_F_MQ: u32 = 22;
+
+/// Link status in the configuration space.
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+/// Control commands, as (class, command).
+const VIRTIO_NET_CTRL_MQ: u8 = 4;
+const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+
+/// Acks of control commands.
+const VIRTIO_NET_OK: u8 = 0;
+const VIRTIO_NET_ERR: u8 = 1;
+
+const QUEUE_SIZE: u16 = 256;
+const CTRL_QUEUE_SIZE: u16 = 64;
+/// Longest control command accepted, far more than the supported ones.
+const MAX_CTRL_LEN: usize = 64;
+
+/// Epoll tokens of the I/O threads.
+const TOKEN_RX: u64 = 0;
+const TOKEN_TX: u64 = 1;
+const TOKEN_TAP: u64 = 2;
+const TOKEN_CTRL: u64 = 3;
+const TOKEN_KILL: u64 = 4;
+
+/// Host addresses of the buffers, `None` if any is out of guest RAM or
+/// there are more than a syscall takes.
+fn iovecs<'a>(
+    memory: &GuestMemoryMmap,
+    descriptors: impl Iterator<Item = &'a Descriptor>
+) -> Option<Vec<libc::iovec>> {
+    let iovecs = descriptors
+        .map(|d| {
+            memory.get_slice(d.addr(), d.len() as usize).ok()
+                .map(|s| iovec(s.as_ptr(), s.len()))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    (iovecs.len() <= libc::UIO_MAXIOV as usize).then_some(iovecs)
+}
+
+fn len(iovecs: &[libc::iovec]) -> usize {
+    iovecs.iter().map(|v| v.iov_len).sum()
+}
+
+/// Handles a queue pair of an activated device in its I/O thread, and the
+/// control queue for the first pair.
+struct Worker {
+    /// Index of the pair, which is also the index of its TAP queue.
+    index: usize,
+    taps: Arc<Vec<Tap>>,
+    memory: GuestMemoryMmap,
+    interrupt: Arc<VirtioInterrupt>,
+    rx: (Queue, EventFd),
+    tx: (Queue, EventFd),
+    ctrl: Option<(Queue, EventFd)>,
+    /// Notifications of the rx queues of the other pairs, signaled for them
+    /// to poll their TAP queues once attached.
+    rx_events: Vec<EventFd>,
+    /// The TAP queue isn't polled while the rx queue has no buffers.
+    polling_tap: bool,
+}
+
+impl Worker {
+    /// Handle the queues until `kill` is signaled.
+    fn run(&mut self, kill: &EventFd) -> Result<()> {
+        let error = |e: io::Error| Error::VirtioError(e.to_string());
+        let epoll = Epoll::new().map_err(error)?;
+        let mut fds = vec![
+            (self.rx.1.as_raw_fd(), TOKEN_RX),
+            (self.tx.1.as_raw_fd(), TOKEN_TX),
+            (kill.as_raw_fd(), TOKEN_KILL),
+        ];
+        if let Some((_, event)) = self.ctrl.as_ref() {
+            fds.push((event.as_raw_fd(), TOKEN_CTRL));
+        }
+        for (fd, token) in fds {
+            epoll.ctl(ControlOperation::Add, fd, EpollEvent::new(EventSet::IN, token))
+                .map_err(error)?;
+        }
+        self.poll_tap(&epoll, true)?;
+        let mut events = [EpollEvent::default(); 5];
+        loop {
+            let count = match epoll.wait(-1, &mut events) {
+                Ok(count) => count,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(error(e)),
+            };
+            for event in &events[..count] {
+                match event.data() {
+                    TOKEN_RX => {
+                        let _ = self.rx.1.read();
+                        self.poll_tap(&epoll, true)?;
+                        self.process_rx(&epoll)?;
+                    },
+                    TOKEN_TAP => self.process_rx(&epoll)?,
+                    TOKEN_TX => {
+                        let _ = self.tx.1.read();
+                        self.process_tx()?;
+                    },
+                    TOKEN_CTRL => self.process_ctrl()?,
+                    _ => return Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Start or stop polling the TAP queue.
+    fn poll_tap(&mut self, epoll: &Epoll, poll: bool) -> Result<()> {
+        if self.polling_tap == poll {
+            return Ok(());
+        }
+        let op = match poll {
+            true => ControlOperation::Add,
+            false => ControlOperation::Delete,
+        };
+        let fd = self.taps[self.index].as_raw_fd();
+        epoll.ctl(op, fd, EpollEvent::new(EventSet::IN, TOKEN_TAP))
+            .map_err(|e| Error::VirtioError(e.to_string()))?;
+        self.polling_tap = poll;
+        Ok(())
+    }
+
+    /// Receive the packets of the TAP queue into the rx buffers.
+    fn process_rx(&mut self, epoll: &Epoll) -> Result<()> {
+        let taps = self.taps.clone();
+        let tap = &taps[self.index];
+        let mut used = false;
+        loop {
+            let Some(chain) = self.rx.0.pop(&self.memory)? else {
+                // Packets wait in the TAP queue until buffers are added.
+                self.poll_tap(epoll, false)?;
+                break;
+            };
+            let iovecs = match iovecs(&self.memory, chain.writable()) {
+                Some(iovecs) if len(&iovecs) >= VNET_HDR_LEN => iovecs,
+                _ => {
+                    self.rx.0.add_used(&self.memory, chain.head, 0)?;
+                    used = true;
+                    continue;
+                },
+            };
+            match tap.read_vectored(&iovecs) {
+                Ok(n) => {
+                    // The TAP leaves num_buffers, which is 1 without
+                    // VIRTIO_NET_F_MRG_RXBUF.
+                    let mut header = [0u8; VNET_HDR_LEN];
+                    gather(&iovecs, &mut header);
+                    header[10..].copy_from_slice(&1u16.to_le_bytes());
+                    scatter(&header, &iovecs);
+                    self.rx.0.add_used(&self.memory, chain.head, n as u32)?;
+                    used = true;
+                },
+                Err(e) => {
+                    self.rx.0.undo_pop();
+                    // Don't spin on a broken TAP queue, the next buffers
+                    // added to the rx queue try again.
+                    if e.kind() != io::ErrorKind::WouldBlock {
+                        self.poll_tap(epoll, false)?;
+                    }
+                    break;
+                },
+            }
+        }
+        Self::notify(&self.memory, &self.interrupt, &mut self.rx.0, used)
+    }
+
+    /// Send the packets of the tx queue to the TAP queue.
+    fn process_tx(&mut self) -> Result<()> {
+        let mut used = false;
+        while let Some(chain) = self.tx.0.pop(&self.memory)? {
+            let iovecs = iovecs(&self.memory, chain.readable())
+                .filter(|iovecs| len(iovecs) >= VNET_HDR_LEN);
+            if let Some(iovecs) = iovecs {
+                // Packets the TAP can't take are dropped, as on a wire.
+                let _ = self.taps[self.index].write_vectored(&iovecs);
+            }
+            self.tx.0.add_used(&self.memory, chain.head, 0)?;
+            used = true;
+        }
+        Self::notify(&self.memory, &self.interrupt, &mut self.tx.0, used)
+    }
+
+    /// Execute the commands of the control queue.
+    fn process_ctrl(&mut self) -> Result<()> {
+        let Some((queue, event)) = self.ctrl.as_mut() else { return Ok(()) };
+        let _ = event.read();
+        let mut used = false;
+        while let Some(chain) = queue.pop(&self.memory)? {
+            let mut command = Vec::new();
+            for d in chain.readable() {
+                let start = command.len();
+                if start + d.len() as usize > MAX_CTRL_LEN {
+                    command.clear();
+                    break;
+                }
+                command.resize(start + d.len() as usize, 0);
+                if self.memory.read_slice(&mut command[start..], d.addr()).is_err() {
+                    command.clear();
+                    break;
+                }
+            }
+            let ack = match command[..] {
+                [VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET, lo, hi] => {
+                    let pairs = u16::from_le_bytes([lo, hi]) as usize;
+                    match Self::set_queue_pairs(&self.taps, &self.rx_events, pairs) {
+                        Ok(()) => VIRTIO_NET_OK,
+                        Err(_) => VIRTIO_NET_ERR,
+                    }
+                },
+                _ => VIRTIO_NET_ERR,
+            };
+            let len = match chain.writable().next() {
+                Some(d) if !d.is_empty() => {
+                    self.memory.write_obj(ack, d.addr())
+                        .map_err(|e| Error::VirtioError(e.to_string()))?;
+                    1
+                },
+                _ => 0,
+            };
+            queue.add_used(&self.memory, chain.head, len)?;
+            used = true;
+        }
+        Self::notify(&self.memory, &self.interrupt, queue, used)
+    }
+
+    /// Steer packets to the TAP queues of the first `pairs` queue pairs.
+    fn set_queue_pairs(taps: &[Tap], rx_events: &[EventFd], pairs: usize) -> Result<()> {
+        // Only the pairs with workers can be enabled.
+        if pairs == 0 || pairs > rx_events.len() + 1 {
+            return Err(Error::VirtioError(format!("{} queue pairs", pairs)));
+        }
+        for (i, tap) in taps.iter().enumerate() {
+            tap.set_queue_enabled(i < pairs)?;
+        }
+        for event in rx_events {
+            let _ = event.write(1);
+        }
+        Ok(())
+    }
+
+    /// Interrupt the driver for the returned chains if it wants.
+    fn notify(
+        memory: &GuestMemoryMmap,
+        interrupt: &VirtioInterrupt,
+        queue: &mut Queue,
+        used: bool
+    ) -> Result<()> {
+        if used && queue.needs_notification(memory)? {
+            interrupt.signal_used();
+        }
+        Ok(())
+    }
+}
+
+/// A virtio-net device backed by a TAP interface.
+///
+/// Each rx/tx queue pair is served by a queue of the interface in its own
+/// I/O thread. The guest starts with a single pair, and enables more
+/// through the control queue.
+pub struct Net {
+    taps: Arc<Vec<Tap>>,
+    /// Configuration space, as laid out for the driver.
+    config_space: Vec<u8>,
+    queue_sizes: Vec<u16>,
+    features: u64,
+    acked_features: u64,
+    io_threads: Vec<IoThread>,
+}
+
+impl Net {
+    /// Create a device for the queues of a TAP interface, one per queue
+    /// pair.
+    pub fn new(taps: Vec<Tap>, config: NetConfig) -> Result<Self> {
+        let pairs = taps.len();
+        if pairs == 0 || pairs > u16::MAX as usize / 2 {
+            return Err(Error::VirtioError(format!("{} queue pairs", pairs)));
+        }
+        let mut features = 1 << VIRTIO_F_VERSION_1
+            | 1 << VIRTIO_RING_F_EVENT_IDX
+            | 1 << VIRTIO_RING_F_INDIRECT_DESC
+            | 1 << VIRTIO_NET_F_STATUS;
+        if config.mac.is_some() {
+            features |= 1 << VIRTIO_NET_F_MAC;
+        }
+        if config.mtu.is_some() {
+            features |= 1 << VIRTIO_NET_F_MTU;
+        }
+        if config.offload {
+            for f in [
+                VIRTIO_NET_F_CSUM, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_TSO6,
+                VIRTIO_NET_F_HOST_ECN, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4,
+                VIRTIO_NET_F_GUEST_TSO6, VIRTIO_NET_F_GUEST_ECN,
+            ] {
+                features |= 1 << f;
+            }
+        }
+        let mut queue_sizes = vec![QUEUE_SIZE; 2 * pairs];
+        if pairs > 1 {
+            features |= 1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_MQ;
+            queue_sizes.push(CTRL_QUEUE_SIZE);
+        }
+        Ok(
+            Net {
+                taps: Arc::new(taps),
+                config_space: Self::config_space(&config, pairs as u16),
+                queue_sizes,
+                features,
+                acked_features: 0,
+                io_threads: Vec::new(),
+            }
+        )
+    }
+
+    /// Lay out struct virtio_net_config.
+    fn config_space(config: &NetConfig, pairs: u16) -> Vec<u8> {
+        let mut space = Vec::with_capacity(12);
+        space.extend_from_slice(&config.mac.map(|m| m.0).unwrap_or_default());
+        space.extend_from_slice(&VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        space.extend_from_slice(&pairs.to_le_bytes());
+        space.extend_from_slice(&config.mtu.unwrap_or_default().to_le_bytes());
+        space
+    }
+
+    /// `TUN_F_*` offloads of the packets the guest accepts.
+    fn tap_offload(&self) -> libc::c_uint {
+        let acked = |f: u32| self.acked_features & (1 << f) != 0;
+        // The TAP takes TSO only with checksum offload, and ECN only with
+        // TSO, as the features depend on each other.
+        if !acked(VIRTIO_NET_F_GUEST_CSUM) {
+            return 0;
+        }
+        let mut offload = libc::TUN_F_CSUM;
+        if acked(VIRTIO_NET_F_GUEST_TSO4) {
+            offload |= libc::TUN_F_TSO4;
+        }
+        if acked(VIRTIO_NET_F_GUEST_TSO6) {
+            offload |= libc::TUN_F_TSO6;
+        }
+        if acked(VIRTIO_NET_F_GUEST_ECN) && offload != libc::TUN_F_CSUM {
+            offload |= libc::TUN_F_TSO_ECN;
+        }
+        offload
+    }
+
+    /// Start a worker per queue pair.
+    fn spawn_workers(
+        &mut self,
+        memory: GuestMemoryMmap,
+        interrupt: Arc<VirtioInterrupt>,
+        queues: Vec<(Queue, EventFd)>,
+        mut ctrl: Option<(Queue, EventFd)>
+    ) -> Result<()> {
+        let error = |e: io::Error| Error::VirtioError(e.to_string());
+        let mut pairs = Vec::new();
+        let mut queues = queues.into_iter();
+        while let (Some(rx), Some(tx)) = (queues.next(), queues.next()) {
+            pairs.push((rx, tx));
+        }
+        // Only the worker of the first pair, with the control queue, signals
+        // the others.
+        let mut rx_events = pairs.iter()
+            .skip(1)
+            .map(|(rx, _)| rx.1.try_clone())
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(error)?;
+        for (index, (rx, tx)) in pairs.into_iter().enumerate() {
+            let mut worker = Worker {
+                index,
+                taps: self.taps.clone(),
+                memory: memory.clone(),
+                interrupt: interrupt.clone(),
+                rx,
+                tx,
+                ctrl: ctrl.take(),
+                rx_events: std::mem::take(&mut rx_events),
+                polling_tap: false,
+            };
+            self.io_threads.push(IoThread::spawn("virtio-net", move |kill| {
+                if worker.run(kill).is_err() {
+                    // The queues are unusable until the driver resets the device.
+                    worker.interrupt.signal_needs_reset();
+                }
+            })?);
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for Net {
+    fn device_type(&self) -> u32 {
+        TYPE_NET
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.features
+    }
+
+    fn ack_features(&mut self, features: u64) {
+        self.acked_features = features;
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        read_config_space(&self.config_space, offset, data)
+    }
+
+    fn activate(
+        &mut self,
+        memory: GuestMemoryMmap,
+        interrupt: Arc<VirtioInterrupt>,
+        mut queues: Vec<(Queue, EventFd)>
+    ) -> Result<()> {
+        // The control queue follows the pairs the driver may use.
+        let pairs = match self.acked_features & (1 << VIRTIO_NET_F_MQ) {
+            0 => 1,
+            _ => self.taps.len(),
+        };
+        let has_ctrl = self.acked_features & (1 << VIRTIO_NET_F_CTRL_VQ) != 0;
+        let expected = 2 * pairs + has_ctrl as usize;
+        if queues.len() != expected {
+            return Err(Error::VirtioError(format!("virtio-net takes {} queues", expected)));
+        }
+        let ctrl = if has_ctrl { queues.pop() } else { None };
+        self.taps[0].set_offload(self.tap_offload())?;
+        // Packets are steered to the first pair until the driver enables
+        // more.
+        for (i, tap) in self.taps.iter().enumerate() {
+            tap.set_queue_enabled(i == 0)?;
+        }
+        let result = self.spawn_workers(memory, interrupt, queues, ctrl);
+        if result.is_err() {
+            self.reset();
+        }
+        result
+    }
+
+    fn reset(&mut self) {
+        self.io_threads.drain(..).for_each(IoThread::stop);
+    }
+}
+
+impl Drop for Net {
+    fn drop(&mut self) {
+        self.reset();
+    }
+}
+
+#[test]
+fn test_net() {
+    use std::{io::{Read, Write}, thread, time::{Duration, Instant}};
+    use vm_memory::GuestAddress;
+    use crate::config::MacAddr;
+    use crate::tap;
+    use super::queue::{TestQueue, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE, desc};
+
+    // Creating interfaces needs CAP_NET_ADMIN.
+    let Ok(tap0) = Tap::open("", true) else { return };
+    let tap1 = Tap::open(tap0.name(), true).unwrap();
+    let mut socket = tap::test_socket(tap0.name());
+    let config = NetConfig {
+        mac: Some(MacAddr([0xfa, 0x16, 0x3e, 0x21, 0xc0, 0xc0])),
+        mtu: Some(1500),
+        queue_pairs: 2,
+        ..Default::default()
+    };
+    let mut net = Net::new(vec![tap0, tap1], config).unwrap();
+    assert_eq!(net.queue_max_sizes(), &[256, 256, 256, 256, 64]);
+    for f in [VIRTIO_NET_F_MAC, VIRTIO_NET_F_MTU, VIRTIO_NET_F_MQ, VIRTIO_NET_F_GUEST_TSO4] {
+        assert_ne!(net.features() & (1 << f), 0);
+    }
+    let mut data = [0u8; 12];
+    net.read_config(0, &mut data);
+    assert_eq!(data, [0xfa, 0x16, 0x3e, 0x21, 0xc0, 0xc0, 1, 0, 2, 0, 0xdc, 0x05]);
+
+    net.ack_features(
+        1 << VIRTIO_F_VERSION_1
+            | 1 << VIRTIO_NET_F_CTRL_VQ
+            | 1 << VIRTIO_NET_F_MQ
+            | 1 << VIRTIO_NET_F_GUEST_CSUM
+            | 1 << VIRTIO_NET_F_GUEST_TSO4
+    );
+    let memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x40000)]).unwrap();
+    let irqfd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+    let interrupt = Arc::new(VirtioInterrupt::new(irqfd.try_clone().unwrap()));
+    let mut qs: Vec<_> = (0..5).map(|i| TestQueue::new(16, 0x10000 + i * 0x2000)).collect();
+    let events: Vec<_> = (0..5).map(|_| EventFd::new(libc::EFD_NONBLOCK).unwrap()).collect();
+    let queues = |n: usize| -> Vec<_> {
+        qs.iter().zip(events.iter())
+            .take(n)
+            .map(|(q, e)| (q.queue.clone(), e.try_clone().unwrap()))
+            .collect()
+    };
+    // The driver sets up the queues of all the pairs and the control queue.
+    assert_eq!(
+        net.activate(memory.clone(), interrupt.clone(), queues(3)),
+        Err(Error::VirtioError("virtio-net takes 5 queues".to_string()))
+    );
+    net.activate(memory.clone(), interrupt.clone(), queues(5)).unwrap();
+    assert_eq!(net.io_threads.len(), 2);
+
+    let wait = |q: &TestQueue, used_idx: u16| {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while q.used_idx(&memory) == used_idx {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(1));
+        }
+    };
+
+    // A packet sent to the interface lands in the rx queue of the first
+    // pair, after the header.
+    for i in 0..4 {
+        qs[0].desc(&memory, i, desc(0x20000 + i as u64 * 0x1000, 0x1000, VIRTQ_DESC_F_WRITE, 0));
+        qs[0].offer(&memory, i);
+    }
+    events[0].write(1).unwrap();
+    let mut frame = vec![0xffu8; 6];
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 1, 0x88, 0xb5]);
+    frame.extend_from_slice(b"hello");
+    socket.write_all(&frame).unwrap();
+    wait(&qs[0], 0);
+    let (head, len) = qs[0].used(&memory, 0);
+    assert_eq!(len as usize, VNET_HDR_LEN + frame.len());
+    let mut packet = vec![0u8; len as usize];
+    memory.read_slice(&mut packet, GuestAddress(0x20000 + head as u64 * 0x1000)).unwrap();
+    assert_eq!(&packet[10..12], &[1, 0]);
+    assert_eq!(&packet[VNET_HDR_LEN..], &frame[..]);
+    assert!(irqfd.read().unwrap() >= 1);
+
+    // A packet of the tx queue is sent out of the interface.
+    frame[11] = 2;
+    let mut packet = vec![0u8; VNET_HDR_LEN];
+    packet.extend_from_slice(&frame);
+    memory.write_slice(&packet, GuestAddress(0x30000)).unwrap();
+    qs[1].desc(&memory, 0, desc(0x30000, packet.len() as u32, 0, 0));
+    qs[1].offer(&memory, 0);
+    events[1].write(1).unwrap();
+    wait(&qs[1], 0);
+    assert_eq!(qs[1].used(&memory, 0), (0, 0));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut buf = [0u8; 64];
+    loop {
+        assert!(Instant::now() < deadline);
+        match socket.read(&mut buf) {
+            Ok(n) if buf[..n] == frame[..] => break,
+            Ok(_) => {},
+            Err(_) => thread::sleep(Duration::from_millis(1)),
+        }
+    }
+
+    // The driver enables the second pair through the control queue, but
+    // can't enable more than there are.
+    let mut ctrl = |pairs: u16| {
+        let command = [VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET];
+        memory.write_slice(&command, GuestAddress(0x38000)).unwrap();
+        memory.write_slice(&pairs.to_le_bytes(), GuestAddress(0x38002)).unwrap();
+        qs[4].desc(&memory, 0, desc(0x38000, 2, VIRTQ_DESC_F_NEXT, 1));
+        qs[4].desc(&memory, 1, desc(0x38002, 2, VIRTQ_DESC_F_NEXT, 2));
+        qs[4].desc(&memory, 2, desc(0x38100, 1, VIRTQ_DESC_F_WRITE, 0));
+        let used_idx = qs[4].used_idx(&memory);
+        qs[4].offer(&memory, 0);
+        events[4].write(1).unwrap();
+        wait(&qs[4], used_idx);
+        assert_eq!(qs[4].used(&memory, used_idx), (0, 1));
+        memory.read_obj::<u8>(GuestAddress(0x38100)).unwrap()
+    };
+    assert_eq!(ctrl(2), VIRTIO_NET_OK);
+    assert_eq!(ctrl(3), VIRTIO_NET_ERR);
+    assert_eq!(ctrl(0), VIRTIO_NET_ERR);
+    assert_eq!(ctrl(1), VIRTIO_NET_OK);
+
+    // The I/O threads stop on reset.
+    net.reset();
+    assert!(net.io_threads.is_empty());
+}
@@ -0,0 +1,409 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    num::Wrapping,
+    sync::atomic::{fence, Ordering},
+};
+use vm_memory::{
+    Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap,
+};
+use crate::error::{Error, Result};
+
+/// The buffer continues via the `next` field.
+pub const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+/// The buffer is write-only for the device.
+pub const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+/// The buffer contains a table of descriptors.
+pub const VIRTQ_DESC_F_INDIRECT: u16 = 0x4;
+/// The driver doesn't want to be interrupted.
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 0x1;
+
+/// A descriptor in the descriptor table, as laid out in guest memory.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+
+// Safe because Descriptor only contains plain data.
+unsafe impl ByteValued for Descriptor {}
+
+impl Descriptor {
+    /// Guest physical address of the buffer.
+    pub fn addr(&self) -> GuestAddress {
+        GuestAddress(u64::from_le(self.addr))
+    }
+
+    /// Length of the buffer.
+    pub fn len(&self) -> u32 {
+        u32::from_le(self.len)
+    }
+
+    /// Whether the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the buffer is written by the device instead of read.
+    pub fn is_write_only(&self) -> bool {
+        u16::from_le(self.flags) & VIRTQ_DESC_F_WRITE != 0
+    }
+
+    fn has_next(&self) -> bool {
+        u16::from_le(self.flags) & VIRTQ_DESC_F_NEXT != 0
+    }
+
+    fn is_indirect(&self) -> bool {
+        u16::from_le(self.flags) & VIRTQ_DESC_F_INDIRECT != 0
+    }
+}
+
+/// Buffers made available by the driver in one go, indirect tables are
+/// flattened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DescriptorChain {
+    /// Index of the head descriptor, which is returned in the used ring.
+    pub head: u16,
+    /// Buffers in order.
+    pub descriptors: Vec<Descriptor>,
+}
+
+impl DescriptorChain {
+    /// Buffers the device reads from.
+    pub fn readable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descriptors.iter().filter(|d| !d.is_write_only())
+    }
+
+    /// Buffers the device writes to.
+    pub fn writable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descriptors.iter().filter(|d| d.is_write_only())
+    }
+}
+
+/// A split virtqueue.
+///
+/// Its addresses and size are set by the driver through the transport,
+/// then the device pops available chains and adds them to the used ring.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Queue {
+    /// Maximum size offered by the device.
+    pub max_size: u16,
+    /// Size set by the driver.
+    pub size: u16,
+    /// Whether the driver has set up the queue.
+    pub ready: bool,
+    /// Address of the descriptor table.
+    pub desc_table: GuestAddress,
+    /// Address of the available ring, i.e. the driver area.
+    pub avail_ring: GuestAddress,
+    /// Address of the used ring, i.e. the device area.
+    pub used_ring: GuestAddress,
+    /// Whether VIRTIO_RING_F_EVENT_IDX is negotiated.
+    pub event_idx: bool,
+    /// Index of the next available entry to pop.
+    next_avail: Wrapping<u16>,
+    /// Index of the next used entry to add.
+    next_used: Wrapping<u16>,
+    /// Used index when the driver was notified last time.
+    signalled_used: Option<Wrapping<u16>>,
+}
+
+impl Queue {
+    /// Create a queue which can hold up to `max_size` entries.
+    pub fn new(max_size: u16) -> Self {
+        Queue {
+            max_size,
+            size: max_size,
+            ready: false,
+            desc_table: GuestAddress(0),
+            avail_ring: GuestAddress(0),
+            used_ring: GuestAddress(0),
+            event_idx: false,
+            next_avail: Wrapping(0),
+            next_used: Wrapping(0),
+            signalled_used: None,
+        }
+    }
+
+    /// Restore the state before the driver set up the queue.
+    pub fn reset(&mut self) {
+        *self = Queue::new(self.max_size);
+    }
+
+    /// Check the queue set up by the driver.
+    pub fn validate(&self, memory: &GuestMemoryMmap) -> Result<()> {
+        let size = self.size as u64;
+        let invalid = |s: &str| Err(Error::VirtioError(format!("queue {}", s)));
+        if !self.ready {
+            return invalid("isn't ready");
+        }
+        if self.size == 0 || self.size > self.max_size || !self.size.is_power_of_two() {
+            return invalid("size is illegal");
+        }
+        // Alignments and sizes of the three areas.
+        for (addr, align, len) in [
+            (self.desc_table, 16, 16 * size),
+            (self.avail_ring, 2, 6 + 2 * size),
+            (self.used_ring, 4, 6 + 8 * size),
+        ] {
+            if addr.raw_value() % align != 0 {
+                return invalid("area is misaligned");
+            }
+            match addr.checked_add(len - 1) {
+                Some(end) if memory.address_in_range(addr)
+                    && memory.address_in_range(end) => {},
+                _ => return invalid("area is out of the guest memory"),
+            }
+        }
+        Ok(())
+    }
+
+    fn read_u16(memory: &GuestMemoryMmap, addr: GuestAddress) -> Result<u16> {
+        memory.read_obj::<u16>(addr)
+            .map(u16::from_le)
+            .map_err(|e| Error::VirtioError(e.to_string()))
+    }
+
+    fn write_u16(memory: &GuestMemoryMmap, addr: GuestAddress, v: u16) -> Result<()> {
+        memory.write_obj(v.to_le(), addr)
+            .map_err(|e| Error::VirtioError(e.to_string()))
+    }
+
+    /// Pop the next chain made available by the driver.
+    ///
+    /// A malformed chain is an error, the queue is unusable afterwards.
+    pub fn pop(&mut self, memory: &GuestMemoryMmap) -> Result<Option<DescriptorChain>> {
+        let avail_idx = Self::read_u16(memory, self.avail_ring.unchecked_add(2))?;
+        if Wrapping(avail_idx) == self.next_avail {
+            return Ok(None);
+        }
+        // Read the ring entry only after its index.
+        fence(Ordering::Acquire);
+        let slot = (self.next_avail.0 % self.size) as u64;
+        let head = Self::read_u16(memory, self.avail_ring.unchecked_add(4 + 2 * slot))?;
+        self.next_avail += Wrapping(1);
+        if self.event_idx {
+            // avail_event: notify the device once the next entry is added.
+            let avail_event = self.used_ring.unchecked_add(4 + 8 * self.size as u64);
+            Self::write_u16(memory, avail_event, self.next_avail.0)?;
+        }
+        let descriptors = self.walk(memory, head)?;
+        Ok(Some(DescriptorChain { head, descriptors }))
+    }
+
+    /// Push back the last popped chain, e.g. when the device can't handle it
+    /// for now.
+    pub fn undo_pop(&mut self) {
+        self.next_avail -= Wrapping(1);
+    }
+
+    /// Collect the descriptors of the chain starting at `head`.
+    fn walk(&self, memory: &GuestMemoryMmap, head: u16) -> Result<Vec<Descriptor>> {
+        let malformed = |s: &str| Error::VirtioError(format!("malformed chain: {}", s));
+        let read = |table: GuestAddress, index: u16| {
+            memory.read_obj::<Descriptor>(table.unchecked_add(16 * index as u64))
+                .map_err(|e| Error::VirtioError(e.to_string()))
+        };
+        let mut descriptors = Vec::new();
+        let (mut table, mut size, mut index) = (self.desc_table, self.size, head);
+        let mut indirect = false;
+        // A chain can't be longer than its table, which stops loops.
+        let mut budget = self.size as usize;
+        loop {
+            if index >= size {
+                return Err(malformed("index out of the table"));
+            }
+            if budget == 0 {
+                return Err(malformed("too long"));
+            }
+            budget -= 1;
+            let desc = read(table, index)?;
+            if desc.is_indirect() {
+                if indirect || desc.has_next() {
+                    return Err(malformed("nested indirect table"));
+                }
+                let len = desc.len() as usize;
+                if len == 0 || !len.is_multiple_of(16) || len / 16 > u16::MAX as usize {
+                    return Err(malformed("indirect table size"));
+                }
+                table = desc.addr();
+                size = (len / 16) as u16;
+                index = 0;
+                budget = size as usize;
+                indirect = true;
+                continue;
+            }
+            descriptors.push(desc);
+            if !desc.has_next() {
+                return Ok(descriptors);
+            }
+            index = u16::from_le(desc.next);
+        }
+    }
+
+    /// Return a chain to the driver with `len` bytes written to it.
+    pub fn add_used(&mut self, memory: &GuestMemoryMmap, head: u16, len: u32) -> Result<()> {
+        let slot = (self.next_used.0 % self.size) as u64;
+        let elem = self.used_ring.unchecked_add(4 + 8 * slot);
+        memory.write_obj((head as u32).to_le(), elem)
+            .and_then(|_| memory.write_obj(len.to_le(), elem.unchecked_add(4)))
+            .map_err(|e| Error::VirtioError(e.to_string()))?;
+        self.next_used += Wrapping(1);
+        // The entry must be visible before the index.
+        fence(Ordering::Release);
+        Self::write_u16(memory, self.used_ring.unchecked_add(2), self.next_used.0)
+    }
+
+    /// Whether the driver wants to be interrupted for the entries used since
+    /// the last notification.
+    pub fn needs_notification(&mut self, memory: &GuestMemoryMmap) -> Result<bool> {
+        // Read the driver's suppression only after the used index is written.
+        fence(Ordering::SeqCst);
+        if !self.event_idx {
+            let flags = Self::read_u16(memory, self.avail_ring)?;
+            return Ok(flags & VIRTQ_AVAIL_F_NO_INTERRUPT == 0);
+        }
+        let new = self.next_used;
+        let old = self.signalled_used.replace(new);
+        let used_event = Wrapping(Self::read_u16(
+            memory,
+            self.avail_ring.unchecked_add(4 + 2 * self.size as u64)
+        )?);
+        Ok(match old {
+            // vring_need_event() of the spec.
+            Some(old) => new - used_event - Wrapping(1) < new - old,
+            None => true,
+        })
+    }
+}
+
+/// A queue laid out in guest memory by a fake driver.
+#[cfg(test)]
+pub struct TestQueue {
+    pub queue: Queue,
+    avail_idx: u16,
+}
+
+#[cfg(test)]
+impl TestQueue {
+    /// Place the queue of `size` at `base`.
+    pub fn new(size: u16, base: u64) -> Self {
+        let mut queue = Queue::new(size);
+        queue.desc_table = GuestAddress(base);
+        queue.avail_ring = GuestAddress(base + 16 * size as u64);
+        queue.used_ring = GuestAddress(base + 0x1000);
+        queue.ready = true;
+        TestQueue { queue, avail_idx: 0 }
+    }
+
+    /// Write the descriptor at `index`.
+    pub fn desc(&self, memory: &GuestMemoryMmap, index: u16, desc: Descriptor) {
+        memory.write_obj(desc, self.queue.desc_table.unchecked_add(16 * index as u64))
+            .unwrap();
+    }
+
+    /// Make the chain starting at `head` available.
+    pub fn offer(&mut self, memory: &GuestMemoryMmap, head: u16) {
+        let slot = (self.avail_idx % self.queue.size) as u64;
+        memory.write_obj(head, self.queue.avail_ring.unchecked_add(4 + 2 * slot)).unwrap();
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        memory.write_obj(self.avail_idx, self.queue.avail_ring.unchecked_add(2)).unwrap();
+    }
+
+    /// The used entry at `slot`, as (head, len).
+    pub fn used(&self, memory: &GuestMemoryMmap, slot: u16) -> (u32, u32) {
+        let elem = self.queue.used_ring.unchecked_add(4 + 8 * slot as u64);
+        (
+            memory.read_obj(elem).unwrap(),
+            memory.read_obj(elem.unchecked_add(4)).unwrap(),
+        )
+    }
+
+    /// The used index.
+    pub fn used_idx(&self, memory: &GuestMemoryMmap) -> u16 {
+        memory.read_obj(self.queue.used_ring.unchecked_add(2)).unwrap()
+    }
+}
+
+/// A descriptor of `len` bytes at `addr`.
+#[cfg(test)]
+pub fn desc(addr: u64, len: u32, flags: u16, next: u16) -> Descriptor {
+    Descriptor { addr, len, flags, next }
+}
+
+#[test]
+fn test_queue() {
+    let memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let mut q = TestQueue::new(16, 0x1000);
+    assert_eq!(q.queue.validate(&memory), Ok(()));
+    assert_eq!(q.queue.pop(&memory), Ok(None));
+
+    // A chain of a readable and a writable buffer.
+    q.desc(&memory, 0, desc(0x8000, 16, VIRTQ_DESC_F_NEXT, 3));
+    q.desc(&memory, 3, desc(0x9000, 512, VIRTQ_DESC_F_WRITE, 0));
+    q.offer(&memory, 0);
+    let chain = q.queue.pop(&memory).unwrap().unwrap();
+    assert_eq!(chain.head, 0);
+    assert_eq!(chain.readable().map(|d| d.len()).collect::<Vec<_>>(), vec![16]);
+    assert_eq!(chain.writable().map(|d| d.addr()).collect::<Vec<_>>(), vec![GuestAddress(0x9000)]);
+    assert_eq!(q.queue.pop(&memory), Ok(None));
+    q.queue.add_used(&memory, chain.head, 512).unwrap();
+    assert_eq!(q.used(&memory, 0), (0, 512));
+    assert_eq!(q.used_idx(&memory), 1);
+    assert_eq!(q.queue.needs_notification(&memory), Ok(true));
+
+    // Indirect tables are flattened.
+    q.desc(&memory, 1, desc(0xa000, 32, VIRTQ_DESC_F_INDIRECT, 0));
+    memory.write_obj(desc(0x8000, 8, VIRTQ_DESC_F_NEXT, 1), GuestAddress(0xa000)).unwrap();
+    memory.write_obj(desc(0x8100, 8, VIRTQ_DESC_F_WRITE, 0), GuestAddress(0xa010)).unwrap();
+    q.offer(&memory, 1);
+    let chain = q.queue.pop(&memory).unwrap().unwrap();
+    assert_eq!(chain.head, 1);
+    assert_eq!(chain.descriptors.len(), 2);
+    q.queue.undo_pop();
+    assert_eq!(q.queue.pop(&memory).unwrap().map(|c| c.head), Some(1));
+
+    // Loops and out of range indexes are rejected.
+    q.desc(&memory, 2, desc(0x8000, 8, VIRTQ_DESC_F_NEXT, 2));
+    q.offer(&memory, 2);
+    assert!(q.queue.pop(&memory).is_err());
+    q.offer(&memory, 16);
+    assert!(q.queue.pop(&memory).is_err());
+
+    // Misaligned or oversized queues are rejected.
+    let mut queue = q.queue.clone();
+    queue.used_ring = GuestAddress(0x1002);
+    assert!(queue.validate(&memory).is_err());
+    queue.used_ring = GuestAddress(0xfff0);
+    assert!(queue.validate(&memory).is_err());
+    queue.size = 32;
+    assert!(queue.validate(&memory).is_err());
+}
+
+#[test]
+fn test_queue_event_idx() {
+    let memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let mut q = TestQueue::new(16, 0x1000);
+    q.queue.event_idx = true;
+    let used_event = q.queue.avail_ring.unchecked_add(4 + 2 * 16);
+    let avail_event = q.queue.used_ring.unchecked_add(4 + 8 * 16);
+
+    for head in 0..3 {
+        q.desc(&memory, head, desc(0x8000, 8, 0, 0));
+        q.offer(&memory, head);
+    }
+    q.queue.pop(&memory).unwrap();
+    assert_eq!(memory.read_obj::<u16>(avail_event).unwrap(), 1);
+    // The driver asks to be notified after the 2nd used entry.
+    memory.write_obj(1u16, used_event).unwrap();
+    q.queue.add_used(&memory, 0, 0).unwrap();
+    assert_eq!(q.queue.needs_notification(&memory), Ok(true));
+    q.queue.add_used(&memory, 1, 0).unwrap();
+    assert_eq!(q.queue.needs_notification(&memory), Ok(true));
+    q.queue.add_used(&memory, 2, 0).unwrap();
+    assert_eq!(q.queue.needs_notification(&memory), Ok(false));
+}
@@ -0,0 +1,663 @@
+// Copyright 2022 Garry Xu
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    io::{self, Read},
+    os::unix::{io::{AsRawFd, RawFd}, net::UnixStream},
+    sync::Arc,
+    time::Duration,
+};
+use vm_memory::{Address, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vmm_sys_util::{
+    epoll::{ControlOperation, Epoll, EpollEvent, EventSet},
+    eventfd::EventFd,
+    sock_ctrl_msg::ScmSocket,
+};
+use crate::config::{VhostUserConfig, VhostUserKind};
+use crate::error::{Error, Result};
+use super::{
+    TYPE_BLOCK, TYPE_NET, VIRTIO_F_VERSION_1, VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC,
+    IoThread, VirtioDevice, VirtioInterrupt, read_config_space,
+};
+use super::queue::Queue;
+
+/// Requests of the frontend.
+const VHOST_USER_GET_FEATURES: u32 = 1;
+const VHOST_USER_SET_FEATURES: u32 = 2;
+const VHOST_USER_SET_OWNER: u32 = 3;
+const VHOST_USER_SET_MEM_TABLE: u32 = 5;
+const VHOST_USER_SET_VRING_NUM: u32 = 8;
+const VHOST_USER_SET_VRING_ADDR: u32 = 9;
+const VHOST_USER_SET_VRING_BASE: u32 = 10;
+const VHOST_USER_GET_VRING_BASE: u32 = 11;
+const VHOST_USER_SET_VRING_KICK: u32 = 12;
+const VHOST_USER_SET_VRING_CALL: u32 = 13;
+const VHOST_USER_GET_PROTOCOL_FEATURES: u32 = 15;
+const VHOST_USER_SET_PROTOCOL_FEATURES: u32 = 16;
+const VHOST_USER_GET_QUEUE_NUM: u32 = 17;
+const VHOST_USER_SET_VRING_ENABLE: u32 = 18;
+const VHOST_USER_GET_CONFIG: u32 = 24;
+
+/// Flags of the message header.
+const VHOST_USER_VERSION: u32 = 0x1;
+const VHOST_USER_REPLY: u32 = 0x4;
+const VHOST_USER_NEED_REPLY: u32 = 0x8;
+
+/// Feature bit telling the backend takes protocol features.
+const VHOST_USER_F_PROTOCOL_FEATURES: u32 = 30;
+
+/// Protocol features.
+const VHOST_USER_PROTOCOL_F_MQ: u32 = 0;
+const VHOST_USER_PROTOCOL_F_REPLY_ACK: u32 = 3;
+const VHOST_USER_PROTOCOL_F_CONFIG: u32 = 9;
+
+/// Device features which need the frontend, e.g. a control queue, and
+/// aren't offered to the driver.
+const VIRTIO_BLK_F_MQ: u32 = 12;
+const VIRTIO_NET_F_MTU: u32 = 3;
+const VIRTIO_NET_F_MAC: u32 = 5;
+const VIRTIO_NET_F_STATUS: u32 = 16;
+const VIRTIO_NET_F_CTRL_VQ: u32 = 17;
+const VIRTIO_NET_F_CTRL_MAC_ADDR: u32 = 23;
+
+/// Length of the message header.
+const HEADER_LEN: usize = 12;
+/// Longest reply accepted, which is a configuration space at most.
+const MAX_REPLY_LEN: usize = 4096;
+const MAX_MEM_REGIONS: usize = 8;
+const QUEUE_SIZE: u16 = 256;
+/// Time for the backend to reply, it's waited for by vcpu threads.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connection to a vhost-user backend.
+struct Backend {
+    socket: UnixStream,
+    path: String,
+    /// Whether the backend acks requests without replies.
+    reply_ack: bool,
+}
+
+impl Backend {
+    fn connect(path: &str) -> Result<Self> {
+        let socket = UnixStream::connect(path)
+            .and_then(|s| s.set_read_timeout(Some(REPLY_TIMEOUT)).map(|_| s))
+            .map_err(|e| Error::VhostUserError(format!("{}: {}", path, e)))?;
+        Ok(Backend { socket, path: path.to_string(), reply_ack: false })
+    }
+
+    fn error(&self, s: impl std::fmt::Display) -> Error {
+        Error::VhostUserError(format!("{}: {}", self.path, s))
+    }
+
+    /// Send a request with `fds`, and wait for the ack of the backend if
+    /// it acks.
+    fn send(&mut self, request: u32, payload: &[u8], fds: &[RawFd]) -> Result<()> {
+        let flags = match self.reply_ack {
+            true => VHOST_USER_VERSION | VHOST_USER_NEED_REPLY,
+            false => VHOST_USER_VERSION,
+        };
+        self.write(request, flags, payload, fds)?;
+        if self.reply_ack {
+            let ack = self.read(request)?;
+            if ack.len() != 8 || ack.iter().any(|&b| b != 0) {
+                return Err(self.error(format!("request {} failed", request)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a request, and return the payload of its reply.
+    fn get(&mut self, request: u32, payload: &[u8]) -> Result<Vec<u8>> {
+        self.write(request, VHOST_USER_VERSION, payload, &[])?;
+        self.read(request)
+    }
+
+    /// Send a request, and return the u64 of its reply.
+    fn get_u64(&mut self, request: u32) -> Result<u64> {
+        let reply = self.get(request, &[])?;
+        let bytes = reply.try_into().map_err(|_| self.error("malformed reply"))?;
+        Ok(u64::from_ne_bytes(bytes))
+    }
+
+    fn write(&mut self, request: u32, flags: u32, payload: &[u8], fds: &[RawFd]) -> Result<()> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        for v in [request, flags, payload.len() as u32] {
+            header.extend_from_slice(&v.to_ne_bytes());
+        }
+        let len = HEADER_LEN + payload.len();
+        match self.socket.send_with_fds(&[&header[..], payload], fds) {
+            Ok(n) if n == len => Ok(()),
+            Ok(_) => Err(self.error("short write")),
+            Err(e) => Err(self.error(e)),
+        }
+    }
+
+    fn read(&mut self, request: u32) -> Result<Vec<u8>> {
+        let mut header = [0u8; HEADER_LEN];
+        self.socket.read_exact(&mut header).map_err(|e| self.error(e))?;
+        let field = |i: usize| u32::from_ne_bytes(header[4 * i..4 * i + 4].try_into().unwrap());
+        let (reply, flags, len) = (field(0), field(1), field(2) as usize);
+        if reply != request || flags & VHOST_USER_REPLY == 0 || len > MAX_REPLY_LEN {
+            return Err(self.error(format!("unexpected reply to request {}", request)));
+        }
+        let mut payload = vec![0u8; len];
+        self.socket.read_exact(&mut payload).map_err(|e| self.error(e))?;
+        Ok(payload)
+    }
+}
+
+/// Payload of the requests taking a queue index and a number.
+fn vring_state(index: usize, num: u32) -> Vec<u8> {
+    [(index as u32).to_ne_bytes(), num.to_ne_bytes()].concat()
+}
+
+/// A virtio device whose queues are served by a vhost-user backend, e.g.
+/// a storage or network stack on a DPU.
+///
+/// The backend maps the guest memory from its file and takes the queue
+/// notifications directly, its interrupts are forwarded to the driver by
+/// an I/O thread.
+pub struct VhostUser {
+    device_type: u32,
+    backend: Backend,
+    /// Features of the backend which can be offered to the driver.
+    features: u64,
+    acked_features: u64,
+    /// Whether the backend takes protocol features.
+    protocol: bool,
+    /// Configuration space read from the backend.
+    config_space: Vec<u8>,
+    queue_sizes: Vec<u16>,
+    /// Queues handed over to the backend, stopped on reset.
+    active_queues: usize,
+    io_thread: Option<IoThread>,
+}
+
+impl VhostUser {
+    /// Connect to the backend at `config.socket` and negotiate the protocol.
+    pub fn new(config: &VhostUserConfig) -> Result<Self> {
+        let mut backend = Backend::connect(&config.socket)?;
+        backend.send(VHOST_USER_SET_OWNER, &[], &[])?;
+        let backend_features = backend.get_u64(VHOST_USER_GET_FEATURES)?;
+        let protocol = backend_features & (1 << VHOST_USER_F_PROTOCOL_FEATURES) != 0;
+        let mut protocol_features = 0;
+        if protocol {
+            let supported = 1 << VHOST_USER_PROTOCOL_F_MQ
+                | 1 << VHOST_USER_PROTOCOL_F_REPLY_ACK
+                | 1 << VHOST_USER_PROTOCOL_F_CONFIG;
+            protocol_features = backend.get_u64(VHOST_USER_GET_PROTOCOL_FEATURES)? & supported;
+            backend.send(VHOST_USER_SET_PROTOCOL_FEATURES, &protocol_features.to_ne_bytes(), &[])?;
+            backend.reply_ack = protocol_features & (1 << VHOST_USER_PROTOCOL_F_REPLY_ACK) != 0;
+        }
+        let has = |f: u32| protocol_features & (1 << f) != 0;
+
+        // A single queue for block devices, and a single pair for network
+        // devices, as multiple pairs need a control queue in the frontend.
+        let (device_type, queues, config_len, unsupported) = match config.kind {
+            VhostUserKind::Block => (TYPE_BLOCK, 1, 60, 1 << VIRTIO_BLK_F_MQ),
+            VhostUserKind::Net => (
+                TYPE_NET, 2, 12,
+                (VIRTIO_NET_F_CTRL_VQ..=VIRTIO_NET_F_CTRL_MAC_ADDR).fold(0, |m, f| m | 1 << f)
+            ),
+        };
+        if has(VHOST_USER_PROTOCOL_F_MQ)
+            && backend.get_u64(VHOST_USER_GET_QUEUE_NUM)? < queues as u64 {
+            return Err(backend.error(format!("fewer than {} queues", queues)));
+        }
+        let mut features = backend_features
+            & (1 << VIRTIO_F_VERSION_1
+                | 1 << VIRTIO_RING_F_EVENT_IDX
+                | 1 << VIRTIO_RING_F_INDIRECT_DESC
+                | 0xff_ffff)
+            & !unsupported;
+        let config_space = match has(VHOST_USER_PROTOCOL_F_CONFIG) {
+            true => Self::get_config(&mut backend, config_len)?,
+            // The capacity of a block device is in its configuration space.
+            false if config.kind == VhostUserKind::Block => {
+                return Err(backend.error("no configuration space"));
+            },
+            false => {
+                features &= !(1 << VIRTIO_NET_F_MAC | 1 << VIRTIO_NET_F_MTU | 1 << VIRTIO_NET_F_STATUS);
+                vec![0; config_len as usize]
+            },
+        };
+        Ok(
+            VhostUser {
+                device_type,
+                backend,
+                features,
+                acked_features: 0,
+                protocol,
+                config_space,
+                queue_sizes: vec![QUEUE_SIZE; queues],
+                active_queues: 0,
+                io_thread: None,
+            }
+        )
+    }
+
+    /// Read `len` bytes of the configuration space of the backend.
+    fn get_config(backend: &mut Backend, len: u32) -> Result<Vec<u8>> {
+        let mut payload = Vec::new();
+        for v in [0, len, 0] {
+            payload.extend_from_slice(&u32::to_ne_bytes(v));
+        }
+        payload.resize(payload.len() + len as usize, 0);
+        let reply = backend.get(VHOST_USER_GET_CONFIG, &payload)?;
+        if reply.len() != payload.len() {
+            return Err(backend.error("malformed configuration space"));
+        }
+        Ok(reply[HEADER_LEN..].to_vec())
+    }
+
+    /// Share the guest memory with the backend, which must be mapped from
+    /// files.
+    fn set_mem_table(&mut self, memory: &GuestMemoryMmap) -> Result<()> {
+        if memory.num_regions() > MAX_MEM_REGIONS {
+            return Err(self.backend.error("too many memory regions"));
+        }
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&(memory.num_regions() as u32).to_ne_bytes());
+        payload.extend_from_slice(&[0; 4]);
+        let mut fds = Vec::new();
+        for region in memory.iter() {
+            let Some(file_offset) = region.file_offset() else {
+                return Err(self.backend.error("guest memory isn't backed by a file"));
+            };
+            for v in [
+                region.start_addr().raw_value(),
+                region.len(),
+                region.as_ptr() as u64,
+                file_offset.start(),
+            ] {
+                payload.extend_from_slice(&v.to_ne_bytes());
+            }
+            fds.push(file_offset.file().as_raw_fd());
+        }
+        self.backend.send(VHOST_USER_SET_MEM_TABLE, &payload, &fds)
+    }
+
+    /// Hand a queue over to the backend, which is notified by `kick` and
+    /// interrupts by `call`.
+    fn set_vring(
+        &mut self,
+        memory: &GuestMemoryMmap,
+        index: usize,
+        queue: &Queue,
+        kick: &EventFd,
+        call: &EventFd
+    ) -> Result<()> {
+        let backend = &mut self.backend;
+        backend.send(VHOST_USER_SET_VRING_NUM, &vring_state(index, queue.size as u32), &[])?;
+        // The backend translates the addresses of the frontend by the memory
+        // table.
+        let mut addr = vring_state(index, 0);
+        for ring in [queue.desc_table, queue.used_ring, queue.avail_ring] {
+            let host = memory.get_host_address(ring)
+                .map_err(|e| Error::VirtioError(e.to_string()))?;
+            addr.extend_from_slice(&(host as u64).to_ne_bytes());
+        }
+        addr.extend_from_slice(&0u64.to_ne_bytes());
+        backend.send(VHOST_USER_SET_VRING_ADDR, &addr, &[])?;
+        backend.send(VHOST_USER_SET_VRING_BASE, &vring_state(index, 0), &[])?;
+        let index = (index as u64).to_ne_bytes();
+        backend.send(VHOST_USER_SET_VRING_CALL, &index, &[call.as_raw_fd()])?;
+        backend.send(VHOST_USER_SET_VRING_KICK, &index, &[kick.as_raw_fd()])?;
+        Ok(())
+    }
+
+    /// Start the backend with the queues, and the thread forwarding its
+    /// interrupts.
+    fn start(
+        &mut self,
+        memory: GuestMemoryMmap,
+        interrupt: Arc<VirtioInterrupt>,
+        queues: Vec<(Queue, EventFd)>
+    ) -> Result<()> {
+        let error = |e: io::Error| Error::VirtioError(e.to_string());
+        let mut features = self.acked_features;
+        if self.protocol {
+            features |= 1 << VHOST_USER_F_PROTOCOL_FEATURES;
+        }
+        self.backend.send(VHOST_USER_SET_FEATURES, &features.to_ne_bytes(), &[])?;
+        self.set_mem_table(&memory)?;
+        let mut calls = Vec::new();
+        for (index, (queue, kick)) in queues.iter().enumerate() {
+            let call = EventFd::new(libc::EFD_NONBLOCK).map_err(error)?;
+            self.set_vring(&memory, index, queue, kick, &call)?;
+            self.active_queues = index + 1;
+            calls.push(call);
+        }
+        // Rings start disabled once protocol features are negotiated.
+        if self.protocol {
+            for index in 0..queues.len() {
+                self.backend.send(VHOST_USER_SET_VRING_ENABLE, &vring_state(index, 1), &[])?;
+            }
+        }
+        let epoll = Epoll::new().map_err(error)?;
+        for call in calls.iter() {
+            epoll.ctl(ControlOperation::Add, call.as_raw_fd(), EpollEvent::new(EventSet::IN, 0))
+                .map_err(error)?;
+        }
+        self.io_thread = Some(IoThread::spawn("vhost-user", move |kill| {
+            // Tokens are the indices of the calls, any other one is kill.
+            let kill_event = EpollEvent::new(EventSet::IN, u64::MAX);
+            if epoll.ctl(ControlOperation::Add, kill.as_raw_fd(), kill_event).is_err() {
+                return interrupt.signal_needs_reset();
+            }
+            let mut events = vec![EpollEvent::default(); calls.len() + 1];
+            loop {
+                let count = match epoll.wait(-1, &mut events) {
+                    Ok(count) => count,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => return interrupt.signal_needs_reset(),
+                };
+                for event in &events[..count] {
+                    match calls.get(event.data() as usize) {
+                        Some(call) => {
+                            let _ = call.read();
+                            interrupt.signal_used();
+                        },
+                        None => return,
+                    }
+                }
+            }
+        })?);
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VhostUser {
+    fn device_type(&self) -> u32 {
+        self.device_type
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.features
+    }
+
+    fn ack_features(&mut self, features: u64) {
+        self.acked_features = features;
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        read_config_space(&self.config_space, offset, data)
+    }
+
+    fn activate(
+        &mut self,
+        memory: GuestMemoryMmap,
+        interrupt: Arc<VirtioInterrupt>,
+        queues: Vec<(Queue, EventFd)>
+    ) -> Result<()> {
+        if queues.len() != self.queue_sizes.len() {
+            return Err(Error::VirtioError(format!(
+                "vhost-user device takes {} queues", self.queue_sizes.len()
+            )));
+        }
+        let result = self.start(memory, interrupt, queues);
+        if result.is_err() {
+            self.reset();
+        }
+        result
+    }
+
+    fn reset(&mut self) {
+        // GET_VRING_BASE stops a ring, nothing else to do if it fails.
+        for index in 0..self.active_queues {
+            let _ = self.backend.get(VHOST_USER_GET_VRING_BASE, &vring_state(index, 0));
+        }
+        self.active_queues = 0;
+        if let Some(io_thread) = self.io_thread.take() {
+            io_thread.stop();
+        }
+    }
+}
+
+impl Drop for VhostUser {
+    fn drop(&mut self) {
+        self.reset();
+    }
+}
+
+/// Serve a block device to the frontend on the other end of `socket`, which
+/// completes every request with `VIRTIO_BLK_S_OK`. Returns the requests and
+/// their payloads once the frontend hangs up.
+#[cfg(test)]
+fn test_backend(socket: UnixStream) -> Vec<(u32, Vec<u8>)> {
+    use std::{fs::File, io::Write, os::unix::io::{FromRawFd, IntoRawFd}};
+    use vm_memory::{Bytes, FileOffset, GuestAddress, GuestRegionMmap, MmapRegion};
+
+    let features: u64 = 1 << VIRTIO_F_VERSION_1
+        | 1 << VHOST_USER_F_PROTOCOL_FEATURES
+        | 1 << 9 // VIRTIO_BLK_F_FLUSH
+        | 1 << VIRTIO_BLK_F_MQ
+        | 1 << 34; // VIRTIO_F_RING_PACKED
+    let protocol_features: u64 = 1 << VHOST_USER_PROTOCOL_F_MQ
+        | 1 << VHOST_USER_PROTOCOL_F_REPLY_ACK
+        | 1 << VHOST_USER_PROTOCOL_F_CONFIG
+        | 1 << 5; // VHOST_USER_PROTOCOL_F_SLAVE_REQ
+    let mut requests = Vec::new();
+    let mut memory = None;
+    // Regions as (guest physical address, size, frontend address).
+    let mut table = Vec::new();
+    let mut queue = Queue::new(QUEUE_SIZE);
+    let (mut kick, mut call): (Option<EventFd>, Option<EventFd>) = (None, None);
+    let epoll = Epoll::new().unwrap();
+    epoll.ctl(ControlOperation::Add, socket.as_raw_fd(), EpollEvent::new(EventSet::IN, 0))
+        .unwrap();
+    let mut events = [EpollEvent::default(); 2];
+    loop {
+        let count = epoll.wait(-1, &mut events).unwrap();
+        for event in &events[..count] {
+            if event.data() == 1 {
+                let _ = kick.as_ref().unwrap().read();
+                let memory = memory.as_ref().unwrap();
+                while let Some(chain) = queue.pop(memory).unwrap() {
+                    let status = chain.writable().last().unwrap().addr();
+                    memory.write_obj(0u8, status).unwrap();
+                    queue.add_used(memory, chain.head, 1).unwrap();
+                }
+                call.as_ref().unwrap().write(1).unwrap();
+                continue;
+            }
+
+            let mut header = [0u8; HEADER_LEN];
+            let mut fds = [-1; MAX_MEM_REGIONS];
+            let mut iovecs = [libc::iovec {
+                iov_base: header.as_mut_ptr() as *mut libc::c_void,
+                iov_len: HEADER_LEN,
+            }];
+            let (n, nfds) = unsafe { socket.recv_with_fds(&mut iovecs, &mut fds) }.unwrap();
+            if n == 0 {
+                return requests;
+            }
+            let mut files: Vec<_> = fds[..nfds].iter()
+                .map(|&fd| unsafe { File::from_raw_fd(fd) })
+                .collect();
+            let field = |i: usize| u32::from_ne_bytes(header[4 * i..4 * i + 4].try_into().unwrap());
+            let (request, flags) = (field(0), field(1));
+            let mut payload = vec![0u8; field(2) as usize];
+            (&socket).read_exact(&mut payload).unwrap();
+            let u64_at = |i: usize| u64::from_ne_bytes(payload[i..i + 8].try_into().unwrap());
+            let reply = match request {
+                VHOST_USER_GET_FEATURES => Some(features.to_ne_bytes().to_vec()),
+                VHOST_USER_GET_PROTOCOL_FEATURES => Some(protocol_features.to_ne_bytes().to_vec()),
+                VHOST_USER_GET_QUEUE_NUM => Some(4u64.to_ne_bytes().to_vec()),
+                VHOST_USER_GET_CONFIG => {
+                    // A capacity of 16 sectors.
+                    let mut reply = payload.clone();
+                    reply[HEADER_LEN..HEADER_LEN + 8].copy_from_slice(&16u64.to_ne_bytes());
+                    Some(reply)
+                },
+                VHOST_USER_SET_MEM_TABLE => {
+                    let mut regions = Vec::new();
+                    for (i, file) in files.drain(..).enumerate() {
+                        let base = 8 + 32 * i;
+                        let (gpa, size, addr) = (u64_at(base), u64_at(base + 8), u64_at(base + 16));
+                        let region = MmapRegion::build(
+                            Some(FileOffset::new(file, u64_at(base + 24))),
+                            size as usize,
+                            libc::PROT_READ | libc::PROT_WRITE,
+                            libc::MAP_SHARED
+                        ).unwrap();
+                        regions.push(GuestRegionMmap::new(region, GuestAddress(gpa)).unwrap());
+                        table.push((gpa, size, addr));
+                    }
+                    memory = Some(GuestMemoryMmap::from_regions(regions).unwrap());
+                    None
+                },
+                VHOST_USER_SET_VRING_NUM => {
+                    queue.size = u32::from_ne_bytes(payload[4..8].try_into().unwrap()) as u16;
+                    None
+                },
+                VHOST_USER_SET_VRING_ADDR => {
+                    let gpa = |addr: u64| {
+                        let &(gpa, _, start) = table.iter()
+                            .find(|&&(_, size, start)| (start..start + size).contains(&addr))
+                            .unwrap();
+                        GuestAddress(addr - start + gpa)
+                    };
+                    queue.desc_table = gpa(u64_at(8));
+                    queue.used_ring = gpa(u64_at(16));
+                    queue.avail_ring = gpa(u64_at(24));
+                    None
+                },
+                VHOST_USER_SET_VRING_KICK => {
+                    let event = unsafe { EventFd::from_raw_fd(files.remove(0).into_raw_fd()) };
+                    let token = EpollEvent::new(EventSet::IN, 1);
+                    epoll.ctl(ControlOperation::Add, event.as_raw_fd(), token).unwrap();
+                    kick = Some(event);
+                    None
+                },
+                VHOST_USER_SET_VRING_CALL => {
+                    call = Some(unsafe { EventFd::from_raw_fd(files.remove(0).into_raw_fd()) });
+                    None
+                },
+                VHOST_USER_GET_VRING_BASE => {
+                    let event = kick.take().unwrap();
+                    epoll.ctl(ControlOperation::Delete, event.as_raw_fd(), EpollEvent::default())
+                        .unwrap();
+                    Some(vring_state(0, 1))
+                },
+                _ => None,
+            };
+            let reply = match reply {
+                None if flags & VHOST_USER_NEED_REPLY != 0 => Some(0u64.to_ne_bytes().to_vec()),
+                reply => reply,
+            };
+            if let Some(reply) = reply {
+                let mut message = Vec::new();
+                for v in [request, VHOST_USER_VERSION | VHOST_USER_REPLY, reply.len() as u32] {
+                    message.extend_from_slice(&v.to_ne_bytes());
+                }
+                message.extend_from_slice(&reply);
+                (&socket).write_all(&message).unwrap();
+            }
+            requests.push((request, payload));
+        }
+    }
+}
+
+#[test]
+fn test_vhost_user() {
+    use std::{os::unix::net::UnixListener, thread, time::Instant};
+    use vm_memory::{Bytes, GuestAddress};
+    use vmm_sys_util::tempdir::TempDir;
+    use crate::config::{MemoryBackendConfig, MemoryBackendType, MemoryConfig};
+    use crate::memory::{self, MemoryLayout};
+    use super::INTERRUPT_VRING;
+    use super::queue::{TestQueue, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE, desc};
+
+    let dir = TempDir::new().unwrap();
+    let path = dir.as_path().join("vhost-user.sock");
+    let listener = UnixListener::bind(&path).unwrap();
+    let backend = thread::spawn(move || test_backend(listener.accept().unwrap().0));
+    let config = VhostUserConfig {
+        kind: VhostUserKind::Block,
+        socket: path.to_str().unwrap().to_string(),
+    };
+    let mut device = VhostUser::new(&config).unwrap();
+    assert_eq!(device.device_type(), TYPE_BLOCK);
+    // Multiple queues, packed rings and protocol features aren't offered to
+    // the driver.
+    assert_eq!(device.features(), 1 << VIRTIO_F_VERSION_1 | 1 << 9);
+    let mut capacity = [0u8; 8];
+    device.read_config(0, &mut capacity);
+    assert_eq!(u64::from_ne_bytes(capacity), 16);
+
+    let irqfd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+    let interrupt = Arc::new(VirtioInterrupt::new(irqfd.try_clone().unwrap()));
+    let queue_event = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+    let mut q = TestQueue::new(16, 0x1000);
+    let queues = || vec![(q.queue.clone(), queue_event.try_clone().unwrap())];
+    device.ack_features(1 << VIRTIO_F_VERSION_1);
+    // Anonymous memory can't be mapped by the backend.
+    let anonymous = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+    assert!(device.activate(anonymous, interrupt.clone(), queues()).is_err());
+    let config = MemoryConfig {
+        size_mib: 16,
+        backend: MemoryBackendConfig {
+            backend: MemoryBackendType::Memfd,
+            shared: true,
+            ..Default::default()
+        }
+    };
+    let memory = memory::create_guest_memory(&config, &MemoryLayout::new(16)).unwrap();
+    device.activate(memory.clone(), interrupt.clone(), queues()).unwrap();
+
+    // The backend is kicked for a request, and the driver is interrupted
+    // once it's completed.
+    memory.write_obj(0xffu8, GuestAddress(0x6000)).unwrap();
+    q.desc(&memory, 0, desc(0x4000, 16, VIRTQ_DESC_F_NEXT, 1));
+    q.desc(&memory, 1, desc(0x6000, 1, VIRTQ_DESC_F_WRITE, 0));
+    q.offer(&memory, 0);
+    queue_event.write(1).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while q.used_idx(&memory) == 0 || interrupt.status() & INTERRUPT_VRING == 0 {
+        assert!(Instant::now() < deadline);
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(q.used(&memory, 0), (0, 1));
+    assert_eq!(memory.read_obj::<u8>(GuestAddress(0x6000)).unwrap(), 0);
+    assert!(irqfd.read().unwrap() >= 1);
+
+    device.reset();
+    assert!(device.io_thread.is_none());
+    drop(device);
+    let requests = backend.join().unwrap();
+    assert_eq!(
+        requests.iter().map(|r| r.0).collect::<Vec<_>>(),
+        vec![
+            VHOST_USER_SET_OWNER,
+            VHOST_USER_GET_FEATURES,
+            VHOST_USER_GET_PROTOCOL_FEATURES,
+            VHOST_USER_SET_PROTOCOL_FEATURES,
+            VHOST_USER_GET_QUEUE_NUM,
+            VHOST_USER_GET_CONFIG,
+            // The activation with anonymous memory fails before the memory
+            // table is sent.
+            VHOST_USER_SET_FEATURES,
+            VHOST_USER_SET_FEATURES,
+            VHOST_USER_SET_MEM_TABLE,
+            VHOST_USER_SET_VRING_NUM,
+            VHOST_USER_SET_VRING_ADDR,
+            VHOST_USER_SET_VRING_BASE,
+            VHOST_USER_SET_VRING_CALL,
+            VHOST_USER_SET_VRING_KICK,
+            VHOST_USER_SET_VRING_ENABLE,
+            VHOST_USER_GET_VRING_BASE,
+        ]
+    );
+    // Only the supported protocol features are acked.
+    let acked: u64 = 1 << VHOST_USER_PROTOCOL_F_MQ
+        | 1 << VHOST_USER_PROTOCOL_F_REPLY_ACK
+        | 1 << VHOST_USER_PROTOCOL_F_CONFIG;
+    assert_eq!(requests[3].1, acked.to_ne_bytes());
+    let features: u64 = 1 << VIRTIO_F_VERSION_1 | 1 << VHOST_USER_F_PROTOCOL_FEATURES;
+    assert_eq!(requests[7].1, features.to_ne_bytes());
+}
@@ -1,14 +1,26 @@
 // Copyright 2022 Garry Xu
 // SPDX-License-Identifier: Apache-2.0
 
-use kvm_ioctls::VmFd;
-use vm_memory::mmap::GuestMemoryMmap;
-use super::config::VmConfig;
-use super::error::Result;
+use std::{ops::RangeFrom, sync::{Arc, mpsc::Receiver}, time::Duration};
+use kvm_bindings::KVM_MAX_CPUID_ENTRIES;
+use kvm_ioctls::{IoEventAddress, Kvm, VmFd};
+use vm_memory::{GuestMemory, mmap::GuestMemoryMmap};
+use super::{acpi, boot, interrupt, numa};
+use super::bus::Buses;
+use super::config::{PciBdf, VmConfig};
+use super::error::{Error, IllegalTransition, Result};
+use super::interrupt::InterruptManager;
+use super::memory::{self, MemoryLayout, MMIO_HOLE_START};
+use super::pci::PciBus;
+use super::state::{Observers, State, StateMachine, Transition};
+use super::vcpu::VcpuManager;
+use super::vfio::{VfioContainer, pci::VfioPciDevice};
+use super::virtio::{VirtioDevice, VirtioInterrupt, mmio::{self, MmioTransport}};
 
 /// VmStatus represents the current status of a VM.
 ///
 /// Lifecycle of a VM: Epoch -> Paused -> Running -> Paused / Exit.  
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum VmStatus {
     /// VM is created, but not yet fully initialized.  
     Epoch,
@@ -16,18 +28,65 @@ pub enum VmStatus {
     Paused,
     /// VM is running.  
     Running,
+    /// VM has exited and its vcpus are gone.
+    Exit,
+}
+
+impl State for VmStatus {
+    fn can_transit(&self, to: Self) -> bool {
+        use VmStatus::*;
+
+        matches!(
+            (self, to),
+            (Epoch, Paused) | (Paused, Running) | (Running, Paused) 
+                | (Paused, Exit) | (Running, Exit)
+        )
+    }
+
+    fn illegal(&self, to: Self) -> IllegalTransition {
+        IllegalTransition::Vm(*self, to)
+    }
+}
+
+/// Counters of events in the VM's lifetime.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct VmMetrics {
+    /// Times the guest has been rebooted in place.
+    pub reboots: u64,
 }
 
 /// Contains operations and related metadata for a specific Vm.  
 pub struct Vm {
     /// File desicriptor used by VM ioctl.
-    fd: VmFd,
+    fd: Arc<VmFd>,
+    /// Routes interrupts of devices to the in-kernel irqchip.
+    interrupts: Arc<InterruptManager>,
+    /// Buses of devices emulated in user space.
+    buses: Arc<Buses>,
+    /// Transports of the virtio devices, placed from the start of the MMIO
+    /// hole in order.
+    virtio: Vec<Arc<MmioTransport>>,
+    /// Kernel command line parameters telling the guest about devices.
+    cmdline: Vec<String>,
+    /// PCI bus, created along with the first PCI device.
+    pci: Option<Arc<PciBus>>,
+    /// IOMMU context of the devices passed through, created along with the
+    /// first of them.
+    vfio: Option<VfioContainer>,
+    /// Free KVM memory slots, the ones before them are taken.
+    slots: RangeFrom<u32>,
     /// Configrations for the VM and its devices.
     config: VmConfig,
+    /// Layout of the guest physical address space.
+    layout: MemoryLayout,
     /// Use mmap as the memory backend for the VM.  
     memory: GuestMemoryMmap,
-    /// Current status of the VM.  
-    status: VmStatus,
+    /// Status of the VM.  
+    status: StateMachine<VmStatus>,
+    /// Manager of the VM's vcpus, available after the OS is loaded.
+    vcpus: Option<VcpuManager>,
+    /// Metrics of the VM.
+    metrics: VmMetrics,
 }
 
 impl Vm {
@@ -37,11 +96,271 @@ impl Vm {
     /// * `fd` - File discriptor for vm ioctls, it will be owned by this VM.  
     /// * `config` - VM configuration object, it will be owned by this VM.  
     pub fn new(fd: VmFd, config: VmConfig) -> Result<Self> {
+        let layout = MemoryLayout::new(config.memory.size_mib);
+        let memory = memory::create_guest_memory(&config.memory, &layout)?;
+        memory::register_guest_memory(&fd, &memory)?;
+        if let Some(nodes) = config.numa.as_ref() {
+            numa::bind_memory(&memory, &layout, nodes)?;
+        }
+        interrupt::create_irqchip(&fd)?;
+        let fd = Arc::new(fd);
         Ok(Vm {
+            interrupts: Arc::new(InterruptManager::new(fd.clone())?),
+            buses: Arc::new(Buses::new()),
+            virtio: Vec::new(),
+            cmdline: Vec::new(),
+            pci: None,
+            vfio: None,
+            slots: memory.num_regions() as u32..,
             fd,
             config,
-            memory: GuestMemoryMmap::new(),
-            status: VmStatus::Epoch,
+            layout,
+            memory,
+            status: StateMachine::new(0, VmStatus::Epoch, Observers::default()),
+            vcpus: None,
+            metrics: VmMetrics::default(),
         })
     }
+
+    /// Load the guest OS into memory and create vcpus ready to boot it.
+    ///
+    /// ## Arguments
+    /// * `kvm` - Used for querying the CPUID supported by KVM.  
+    pub fn load(&mut self, kvm: &Kvm) -> Result<()> {
+        self.status.check(VmStatus::Paused)?;
+        let entry = self.load_os()?;
+        let nodes = self.config.numa.as_deref().unwrap_or(&[]);
+        let affinity = numa::vcpu_affinity(self.config.cpu.count, nodes);
+        let cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)?;
+        let memory = &self.memory;
+        self.vcpus = Some(VcpuManager::new(
+            &self.fd,
+            self.config.cpu.clone(),
+            self.buses.clone(),
+            &|id, fd| boot::configure_vcpu(fd, id, entry, memory, &cpuid),
+            &affinity,
+        )?);
+        self.status.transit(VmStatus::Paused)
+    }
+
+    /// Write the ACPI tables and the guest OS into memory.
+    fn load_os(&self) -> Result<boot::KernelEntry> {
+        let nodes = self.config.numa.as_deref().unwrap_or(&[]);
+        acpi::create_acpi_tables(
+            &self.memory,
+            self.config.cpu.count,
+            &numa::guest_nodes(&self.layout, nodes),
+            self.pci.is_some(),
+        )?;
+        boot::load_os(&self.memory, &self.layout, &self.config.os, &self.cmdline)
+    }
+
+    /// Plug a virtio device into the VM over a virtio-mmio transport, the
+    /// guest finds it by the kernel command line.
+    pub fn add_virtio_device(&mut self, device: Box<dyn VirtioDevice>) -> Result<()> {
+        if self.status() != VmStatus::Epoch {
+            return Err(Error::VirtioError("devices can't be hotplugged".to_string()));
+        }
+        let base = MMIO_HOLE_START + self.virtio.len() as u64 * mmio::MMIO_LEN;
+        let irq = self.interrupts.allocate_irq()?;
+        let interrupt = Arc::new(VirtioInterrupt::new(self.interrupts.irqfd(irq)?));
+        let transport = Arc::new(MmioTransport::new(device, self.memory.clone(), interrupt)?);
+        // Notifications are caught by KVM, without exits to user space.
+        let notify = IoEventAddress::Mmio(base + mmio::QUEUE_NOTIFY);
+        for (index, event) in transport.queue_events()?.iter().enumerate() {
+            self.fd.register_ioevent(event, &notify, index as u32)?;
+        }
+        self.buses.mmio.insert(transport.clone(), base, mmio::MMIO_LEN)?;
+        self.virtio.push(transport);
+        self.cmdline.push(MmioTransport::cmdline(base, irq));
+        Ok(())
+    }
+
+    /// Pass the host PCI device `bdf` through to the guest over VFIO, the
+    /// guest finds it on the PCI bus.
+    pub fn add_vfio_device(&mut self, bdf: &PciBdf) -> Result<()> {
+        if self.status() != VmStatus::Epoch {
+            return Err(Error::VfioError("devices can't be hotplugged".to_string()));
+        }
+        if self.vfio.is_none() {
+            self.vfio = Some(VfioContainer::open(self.memory.clone())?);
+        }
+        let device = self.vfio.as_ref().unwrap().device(bdf)?;
+        let pci = match self.pci.as_ref() {
+            Some(pci) => pci.clone(),
+            None => {
+                let pci = Arc::new(PciBus::new());
+                pci.attach(&self.buses.pio)?;
+                self.pci.insert(pci).clone()
+            },
+        };
+        let device = VfioPciDevice::new(
+            device,
+            self.fd.clone(),
+            self.buses.clone(),
+            self.interrupts.clone(),
+            &pci,
+            &mut self.slots,
+        )?;
+        pci.add(Box::new(device))?;
+        Ok(())
+    }
+
+    /// Reboot the guest in place: reset all the vcpus, reload the OS and 
+    /// run the VM again.
+    ///
+    /// ## Arguments
+    /// * `kvm` - Used for querying the CPUID supported by KVM.  
+    /// * `timeout` - Time for vcpus to respond to each request.  
+    pub fn reboot(&mut self, kvm: &Kvm, timeout: Duration) -> Result<()> {
+        if self.status() == VmStatus::Running {
+            self.pause(timeout)?;
+        }
+        self.status.check(VmStatus::Running)?;
+        // Devices are stopped before the guest memory is rewritten.
+        self.virtio.iter().for_each(|t| t.reset());
+        if let Some(pci) = self.pci.as_ref() {
+            pci.reset();
+        }
+        let entry = self.load_os()?;
+        let cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)?;
+        let memory = &self.memory;
+        self.vcpus.as_mut().ok_or(Error::VcpuGone)?.reset(
+            &|id, fd| boot::configure_vcpu(fd, id, entry, memory, &cpuid),
+            timeout,
+        )?;
+        self.metrics.reboots += 1;
+        self.resume(timeout)
+    }
+
+    /// Pause all the vcpus of a running VM.
+    ///
+    /// The VM is 'paused' only if all the vcpus pause within `timeout`, 
+    /// otherwise the paused ones are resumed and the VM keeps running.
+    pub fn pause(&mut self, timeout: Duration) -> Result<()> {
+        // A VM is only 'paused' from 'epoch' by being loaded.
+        if self.status() != VmStatus::Running {
+            return Err(self.status.invalid(VmStatus::Paused));
+        }
+        let vcpus = self.vcpus.as_mut().ok_or(Error::VcpuGone)?;
+        if let Err(e) = vcpus.pause(timeout) {
+            // Best effort, the error of pausing is more interesting.
+            let _ = vcpus.resume(timeout);
+            return Err(e);
+        }
+        self.status.transit(VmStatus::Paused)
+    }
+
+    /// Resume all the vcpus of a paused VM, or start them after the OS is 
+    /// loaded.
+    ///
+    /// The VM is 'running' only if all the vcpus resume within `timeout`, 
+    /// otherwise the resumed ones are paused again.
+    pub fn resume(&mut self, timeout: Duration) -> Result<()> {
+        self.status.check(VmStatus::Running)?;
+        let vcpus = self.vcpus.as_mut().ok_or(Error::VcpuGone)?;
+        if let Err(e) = vcpus.resume(timeout) {
+            let _ = vcpus.pause(timeout);
+            return Err(e);
+        }
+        self.status.transit(VmStatus::Running)
+    }
+
+    /// Tell all the vcpus to exit and wait for their threads no longer 
+    /// than `timeout`.
+    ///
+    /// The VM is 'exit' even if some vcpu threads are left behind.
+    pub fn exit(&mut self, timeout: Duration) -> Result<()> {
+        self.status.check(VmStatus::Exit)?;
+        let result = match self.vcpus.as_mut() {
+            Some(vcpus) => vcpus.exit(timeout),
+            None => Ok(()),
+        };
+        self.status.transit(VmStatus::Exit)?;
+        result
+    }
+
+    /// Subscribe to the status transitions of the VM.
+    pub fn subscribe(&self) -> Receiver<Transition<VmStatus>> {
+        self.status.subscribe()
+    }
+
+    /// File descriptor used by VM ioctls.
+    pub fn fd(&self) -> &VmFd {
+        &self.fd
+    }
+
+    /// Routes interrupts of devices to the in-kernel irqchip.
+    pub fn interrupts(&self) -> Arc<InterruptManager> {
+        self.interrupts.clone()
+    }
+
+    /// Buses where devices emulated in user space claim their ranges.
+    pub fn buses(&self) -> &Buses {
+        &self.buses
+    }
+
+    /// Configurations for the VM and its devices.
+    pub fn config(&self) -> &VmConfig {
+        &self.config
+    }
+
+    /// Layout of the guest physical address space.
+    pub fn layout(&self) -> &MemoryLayout {
+        &self.layout
+    }
+
+    /// Guest memory of the VM.
+    pub fn memory(&self) -> &GuestMemoryMmap {
+        &self.memory
+    }
+
+    /// Current status of the VM.
+    pub fn status(&self) -> VmStatus {
+        self.status.current()
+    }
+
+    /// Metrics of the VM.
+    pub fn metrics(&self) -> &VmMetrics {
+        &self.metrics
+    }
+
+    /// Manager of the VM's vcpus.
+    pub fn vcpus(&mut self) -> Option<&mut VcpuManager> {
+        self.vcpus.as_mut()
+    }
+}
+
+#[test]
+fn test_vm_status() {
+    use std::str::FromStr;
+    use utils::json::Json;
+    use super::error::Error;
+
+    let config = VmConfig::from(Json::from_str(concat!(
+        r#"{"cpu":{"count":1},"memory":{"size_mib":16},"#,
+        r#""device":[],"os":{}}"#
+    )).unwrap()).unwrap();
+    let fd = Kvm::new().unwrap().create_vm().unwrap();
+    let mut vm = Vm::new(fd, config).unwrap();
+    let events = vm.subscribe();
+    assert_eq!(
+        vm.pause(Duration::from_secs(1)),
+        Err(Error::InvalidStateTransition(
+            IllegalTransition::Vm(VmStatus::Epoch, VmStatus::Paused)
+        ))
+    );
+    assert_eq!(
+        vm.exit(Duration::from_secs(1)),
+        Err(Error::InvalidStateTransition(
+            IllegalTransition::Vm(VmStatus::Epoch, VmStatus::Exit)
+        ))
+    );
+    assert_eq!(vm.status(), VmStatus::Epoch);
+    assert!(events.try_recv().is_err());
+
+    assert!(VmStatus::Running.can_transit(VmStatus::Paused));
+    assert!(VmStatus::Paused.can_transit(VmStatus::Exit));
+    assert!(!VmStatus::Exit.can_transit(VmStatus::Running));
+    assert!(!VmStatus::Running.can_transit(VmStatus::Running));
 }